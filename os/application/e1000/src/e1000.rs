@@ -53,8 +53,9 @@ pub fn main() {
         // This loop does nothing but waste time.
     }
 //TODO: change receive_data call to system call -> to return the data, try to give a &mut Vec<u8> as argument
-    let received_data: Vec<u8> = Vec::new();
-    let received_data_ptr = &received_data as *const _ as usize;
+    // The kernel can only fill the vector up to its capacity (maximum ethernet frame size)
+    let mut received_data: Vec<u8> = Vec::with_capacity(1518);
+    let received_data_ptr = &mut received_data as *mut _ as usize;
     syscall1(SystemCall::ReceiveData, received_data_ptr);
     println!("Received data: {:?}", received_data);
 }
@@ -0,0 +1,99 @@
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+#[allow(unused_imports)]
+use runtime::*;
+use io::{print, println};
+use syscall::{syscall2, PciDeviceInfo, SystemCall};
+
+fn device_infos() -> Vec<PciDeviceInfo> {
+    let mut infos = Vec::<PciDeviceInfo>::new();
+
+    loop {
+        let count = syscall2(SystemCall::PciList, infos.as_mut_ptr() as usize, infos.capacity());
+        if count <= infos.capacity() {
+            unsafe { infos.set_len(count); }
+            return infos;
+        }
+
+        infos.reserve(count);
+    }
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        _ => "Unknown device"
+    }
+}
+
+fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "PM",
+        0x05 => "MSI",
+        0x09 => "Vendor",
+        0x10 => "PCIe",
+        0x11 => "MSI-X",
+        _ => "?"
+    }
+}
+
+fn extended_capability_name(id: u16) -> &'static str {
+    match id {
+        0x0001 => "AER",
+        0x0002 => "VC",
+        0x0003 => "DSN",
+        0x000b => "Vendor",
+        0x000e => "ARI",
+        0x0010 => "SR-IOV",
+        0x0018 => "LTR",
+        0x001e => "L1SS",
+        _ => "?"
+    }
+}
+
+#[no_mangle]
+pub fn main() {
+    let mut infos = device_infos();
+    infos.sort_by_key(|info| (info.segment, info.bus, info.device, info.function));
+
+    for info in infos {
+        println!("{:0>4x}:{:0>2x}:{:0>2x}.{} {} [{:0>2x}{:0>2x}]: [{:0>4x}:{:0>4x}] (rev {:0>2x})", info.segment, info.bus, info.device, info.function,
+                 class_name(info.class, info.subclass), info.class, info.subclass, info.vendor_id, info.device_id, info.revision);
+
+        if info.capability_count > 0 {
+            let names = info.capabilities[..info.capability_count].iter()
+                .map(|id| capability_name(*id))
+                .collect::<Vec<&str>>();
+            println!("    Capabilities: {}", names.join(" "));
+        }
+
+        if info.extended_capability_count > 0 {
+            let names = info.extended_capabilities[..info.extended_capability_count].iter()
+                .map(|id| extended_capability_name(*id))
+                .collect::<Vec<&str>>();
+            println!("    Extended capabilities: {}", names.join(" "));
+        }
+    }
+}
@@ -0,0 +1,28 @@
+#![no_std]
+
+extern crate alloc;
+
+use concurrent::process;
+use concurrent::process::ProcessState;
+#[allow(unused_imports)]
+use runtime::*;
+use io::{print, println};
+
+fn state_name(state: ProcessState) -> &'static str {
+    match state {
+        ProcessState::Running => "running",
+        ProcessState::Exited => "exited"
+    }
+}
+
+#[no_mangle]
+pub fn main() {
+    let mut infos = process::infos();
+    infos.sort_by_key(|info| info.process_id);
+
+    println!("{:>5} {:>5} {:>7} {:>10} {:<8} {}", "PID", "PPID", "THREADS", "MEMORY", "STATE", "NAME");
+    for info in infos {
+        println!("{:>5} {:>5} {:>7} {:>9}K {:<8} {}", info.process_id, info.parent_id, info.thread_count,
+                 info.memory_usage / 1024, state_name(info.state), info.name());
+    }
+}
@@ -3,11 +3,168 @@
 extern crate alloc;
 
 use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use concurrent::thread;
 #[allow(unused_imports)]
 use runtime::*;
 use io::{print, println};
 use io::read::read;
+use io::keyboard::{self, KeyboardLayout};
+use syscall::{syscall2, syscall3, BlockDeviceInfo, MountInfo, SystemCall, BLOCK_SECTOR_SIZE};
+
+/// Run an application with the given priority (0 = highest): 'nice <priority> <application>'
+fn nice(arguments: &str) {
+    let mut arguments = arguments.split_whitespace();
+    let priority = arguments.next().and_then(|priority| priority.parse::<usize>().ok());
+    let application = arguments.next();
+
+    match (priority, application) {
+        (Some(priority), Some(application)) => match thread::start_application_with_priority(application, priority) {
+            Some(app) => app.join(),
+            None => println!("Command not found!")
+        },
+        _ => println!("Usage: nice <priority> <application>")
+    }
+}
+
+fn block_devices() -> Vec<BlockDeviceInfo> {
+    let mut infos = Vec::<BlockDeviceInfo>::new();
+
+    loop {
+        let count = syscall2(SystemCall::BlockDeviceList, infos.as_mut_ptr() as usize, infos.capacity());
+        if count <= infos.capacity() {
+            unsafe { infos.set_len(count); }
+            return infos;
+        }
+
+        infos.reserve(count);
+    }
+}
+
+fn print_sector(data: &[u8]) {
+    for (row, bytes) in data.chunks(16).enumerate() {
+        print!("{:04x}: ", row * 16);
+        for byte in bytes {
+            print!("{:02x} ", byte);
+        }
+
+        let text = bytes.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect::<String>();
+        println!(" {}", text);
+    }
+}
+
+/// Access disks by sector number (LBA) for debugging:
+/// 'disk' lists all disks, 'disk read <disk> <lba>' prints a sector and 'disk write <disk> <lba> <text>' writes text into a sector
+fn disk(arguments: &str) {
+    let devices = block_devices();
+    let mut arguments = arguments.split_whitespace();
+    let command = arguments.next();
+    if command.is_none() || command == Some("list") {
+        for device in devices.iter() {
+            println!("{:<10} {:>10} sectors ({} MiB){}", device.name(), device.sector_count, device.sector_count * BLOCK_SECTOR_SIZE as u64 / (1024 * 1024), if device.read_only { ", read only" } else { "" });
+        }
+
+        return;
+    }
+
+    let index = arguments.next().and_then(|name| devices.iter().position(|device| device.name() == name));
+    let lba = arguments.next().and_then(|lba| lba.parse::<usize>().ok());
+    let mut data = vec![0u8; BLOCK_SECTOR_SIZE];
+
+    match (command, index, lba) {
+        (Some("read"), Some(index), Some(lba)) => {
+            match syscall3(SystemCall::BlockDeviceRead, index, lba, data.as_mut_ptr() as usize) {
+                0 => println!("Failed to read sector [{}]!", lba),
+                _ => print_sector(&data)
+            }
+        }
+        (Some("write"), Some(index), Some(lba)) => {
+            let text = arguments.collect::<Vec<&str>>().join(" ");
+            let length = text.len().min(BLOCK_SECTOR_SIZE);
+            data[..length].copy_from_slice(&text.as_bytes()[..length]);
+
+            match syscall3(SystemCall::BlockDeviceWrite, index, lba, data.as_ptr() as usize) {
+                0 => println!("Failed to write sector [{}]!", lba),
+                _ => println!("Wrote [{}] bytes to sector [{}]", length, lba)
+            }
+        }
+        (Some("read" | "write"), None, _) => println!("Disk not found!"),
+        _ => println!("Usage: disk [list] | disk read <disk> <lba> | disk write <disk> <lba> <text>")
+    }
+}
+
+fn mounts() -> Vec<MountInfo> {
+    let mut infos = Vec::<MountInfo>::new();
+
+    loop {
+        let count = syscall2(SystemCall::MountList, infos.as_mut_ptr() as usize, infos.capacity());
+        if count <= infos.capacity() {
+            unsafe { infos.set_len(count); }
+            return infos;
+        }
+
+        infos.reserve(count);
+    }
+}
+
+/// Mount the file system on a disk (or a file system image in the initrd) at '/<disk>': 'mount [<disk>]'
+/// Without arguments, all mounted file systems are listed.
+fn mount(arguments: &str) {
+    let name = arguments.trim();
+    if name.is_empty() {
+        for mount in mounts() {
+            println!("/{:<10} {}", mount.name(), mount.file_system());
+        }
+
+        return;
+    }
+
+    match syscall2(SystemCall::Mount, name.as_ptr() as usize, name.len()) {
+        0 => println!("Failed to mount [{}]!", name),
+        _ => println!("Mounted [{}] at [/{}]", name, name)
+    }
+}
+
+/// Write back and remove a mounted file system: 'umount <disk>'
+fn umount(arguments: &str) {
+    let name = arguments.trim();
+    if name.is_empty() {
+        println!("Usage: umount <disk>");
+        return;
+    }
+
+    if syscall2(SystemCall::Unmount, name.as_ptr() as usize, name.len()) == 0 {
+        println!("Failed to unmount [{}]!", name);
+    }
+}
+
+/// Select the keyboard layout: 'layout <us|de>'
+fn layout(arguments: &str) {
+    let layout = match arguments.trim() {
+        "us" => KeyboardLayout::Us,
+        "de" => KeyboardLayout::German,
+        _ => {
+            println!("Usage: layout <us|de>");
+            return;
+        }
+    };
+
+    if !keyboard::set_layout(layout) {
+        println!("Failed to set keyboard layout!");
+    }
+}
+
+/// Skip the rest of an escape sequence (e.g. sent by the arrow keys), since the shell does not support line editing
+fn skip_escape_sequence() {
+    match read() {
+        '[' => while !matches!(read(), '@'..='~') {},
+        'O' => {
+            read();
+        }
+        _ => {}
+    }
+}
 
 #[no_mangle]
 pub fn main() {
@@ -17,7 +174,17 @@ pub fn main() {
     loop {
         match read() {
             '\n' => {
-                if !command.is_empty() {
+                if let Some(arguments) = command.strip_prefix("nice ") {
+                    nice(arguments);
+                } else if let Some(arguments) = command.strip_prefix("disk").filter(|arguments| arguments.is_empty() || arguments.starts_with(' ')) {
+                    disk(arguments);
+                } else if let Some(arguments) = command.strip_prefix("mount").filter(|arguments| arguments.is_empty() || arguments.starts_with(' ')) {
+                    mount(arguments);
+                } else if let Some(arguments) = command.strip_prefix("umount").filter(|arguments| arguments.is_empty() || arguments.starts_with(' ')) {
+                    umount(arguments);
+                } else if let Some(arguments) = command.strip_prefix("layout").filter(|arguments| arguments.is_empty() || arguments.starts_with(' ')) {
+                    layout(arguments);
+                } else if !command.is_empty() {
                     match thread::start_application(command.as_str()) {
                         Some(app) => app.join(),
                         None => println!("Command not found!")
@@ -27,6 +194,7 @@ pub fn main() {
                 command.clear();
                 print!("> ")
             },
+            '\x1b' => skip_escape_sequence(),
             c => command.push(char::from_u32(c as u32).unwrap())
         }
     }
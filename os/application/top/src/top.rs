@@ -0,0 +1,64 @@
+#![no_std]
+
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use concurrent::{process, thread};
+use concurrent::thread::ThreadState;
+#[allow(unused_imports)]
+use runtime::*;
+use io::{print, println};
+use io::read::read;
+use time::{idle_time, systime};
+
+const REFRESH_INTERVAL_MS: usize = 1000;
+
+fn state_name(state: ThreadState) -> &'static str {
+    match state {
+        ThreadState::Ready => "ready",
+        ThreadState::Running => "running",
+        ThreadState::Sleeping => "sleeping",
+        ThreadState::Blocked => "blocked",
+        ThreadState::Joining => "joining"
+    }
+}
+
+fn wait_for_quit() {
+    while read() != 'q' {}
+    process::exit();
+}
+
+#[no_mangle]
+pub fn main() {
+    thread::create(wait_for_quit);
+
+    let mut last_runtimes = BTreeMap::<usize, usize>::new();
+    let mut last_time = systime().num_milliseconds() as usize;
+    let mut last_idle_time = idle_time().num_milliseconds() as usize;
+
+    loop {
+        let mut infos = thread::infos();
+        infos.sort_by_key(|info| info.thread_id);
+
+        let time = systime().num_milliseconds() as usize;
+        let interval = (time - last_time).max(1);
+        let idle = idle_time().num_milliseconds() as usize;
+        let idle_usage = ((idle - last_idle_time.min(idle)) * 100 / interval).min(100);
+
+        print!("\x1b[2J");
+        println!("top - {} threads, cpu {}% idle (press 'q' to quit)\n", infos.len(), idle_usage);
+        println!("{:>5} {:>5} {:<9} {:>4} {:>10} {:>9} {:>5}", "TID", "PID", "STATE", "PRIO", "TIME (ms)", "SWITCHES", "CPU%");
+
+        for info in infos.iter() {
+            let last_runtime = last_runtimes.get(&info.thread_id).copied().unwrap_or(0);
+            let cpu_usage = (info.runtime_ms - last_runtime.min(info.runtime_ms)) * 100 / interval;
+
+            println!("{:>5} {:>5} {:<9} {:>4} {:>10} {:>9} {:>5}", info.thread_id, info.process_id, state_name(info.state), info.priority, info.runtime_ms, info.context_switches, cpu_usage);
+        }
+
+        last_runtimes = infos.iter().map(|info| (info.thread_id, info.runtime_ms)).collect();
+        last_time = time;
+        last_idle_time = idle;
+        thread::sleep(REFRESH_INTERVAL_MS);
+    }
+}
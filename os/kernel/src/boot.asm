@@ -2,10 +2,17 @@
 [EXTERN ___BSS_END__]
 [EXTERN ___KERNEL_DATA_START__]
 [EXTERN ___KERNEL_DATA_END__]
+[EXTERN ___KERNEL_ENTRY_PHYSICAL__]
 [EXTERN start]
 
 ; Kernel constants
 STACK_SIZE equ 0x10000
+PAGE_SIZE equ 0x1000
+PAGE_PRESENT_WRITABLE equ 0x03
+PAGE_PRESENT_WRITABLE_HUGE equ 0x83
+HUGE_PAGE_SIZE equ 0x200000
+PHYS_MAP_PML4_INDEX equ 256 ; Direct mapping of physical memory at 0xffff800000000000 (see 'consts.rs')
+KERNEL_IMAGE_PDPT_INDEX equ 510 ; Kernel image at 0xffffffff80000000 (see 'link.ld')
 
 ; Multiboot2 constants
 MULTIBOOT2_HEADER_MAGIC equ 0xe85250d6
@@ -79,7 +86,7 @@ multiboot2_header:
     dw MULTIBOOT2_TAG_EFI_AMD64_ENTRY_ADDRESS
     dw MULTIBOOT2_TAG_FLAG_REQUIRED
     dd 12
-    dd (boot)
+    dd ___KERNEL_ENTRY_PHYSICAL__
 
     ; EFI boot services tag
     align 8
@@ -132,32 +139,193 @@ multiboot2_header:
     dw MULTIBOOT2_TAG_FLAG_REQUIRED
     dd 8
 
+global boot
 boot:
     cld ; Expected by GCC
     cli ; Disable interrupts
 
+    ; Save multiboot2 magic number and address (initially located in eax and ebx)
+    mov r12d, eax
+    mov r13d, ebx
+
+    ; We are running at the physical load address, but the kernel is linked to the higher half.
+    ; Until the switch to the higher half, only rip-relative addressing must be used.
+
     ; Clear BSS section
-    mov rdi, ___BSS_START__
+    lea rdi, [rel ___BSS_START__]
+    lea rcx, [rel ___BSS_END__]
 clear_bss:
     mov byte [rdi], 0
     inc rdi
-    cmp rdi, ___BSS_END__
+    cmp rdi, rcx
     jne clear_bss
 
+    ; Create boot page table: The first 255 entries of the EFI page table (identity mapping) are used for the lower half
+    ; and for the direct mapping of physical memory. The last 2 GiB map the first GiB of physical memory (kernel image).
+    mov rsi, cr3
+    mov rax, 0x000ffffffffff000
+    and rsi, rax
+    lea rdi, [rel boot_pml4]
+    xor rcx, rcx
+copy_pml4:
+    mov rax, [rsi + rcx * 8]
+    mov [rdi + rcx * 8], rax
+    mov [rdi + rcx * 8 + PHYS_MAP_PML4_INDEX * 8], rax
+    inc rcx
+    cmp rcx, PHYS_MAP_PML4_INDEX - 1
+    jne copy_pml4
+
+    lea rax, [rel boot_pdpt]
+    or rax, PAGE_PRESENT_WRITABLE
+    mov [rdi + 511 * 8], rax
+
+    lea rsi, [rel boot_pdpt]
+    lea rax, [rel boot_pd]
+    or rax, PAGE_PRESENT_WRITABLE
+    mov [rsi + KERNEL_IMAGE_PDPT_INDEX * 8], rax
+
+    lea rsi, [rel boot_pd]
+    mov rax, PAGE_PRESENT_WRITABLE_HUGE
+    xor rcx, rcx
+fill_pd:
+    mov [rsi + rcx * 8], rax
+    add rax, HUGE_PAGE_SIZE
+    inc rcx
+    cmp rcx, 512
+    jne fill_pd
+
+    ; Load boot page table and jump to the higher half
+    mov cr3, rdi
+    mov rax, boot_higher_half
+    jmp rax
+
+boot_higher_half:
     ; Switch stack to our own stack, because the EFI stack may be located inside
     ; reserved memory and will thus be ignored by our paging implementation.
     mov rsp, init_stack.end
 
-    ; Call rust function with multiboot2 magic number and address (initially located in eax and ebx)
+    ; Call rust function with multiboot2 magic number and (physical) address
     xor rdi, rdi
     xor rsi, rsi
-    mov edi, eax
-    mov esi, ebx
+    mov edi, r12d
+    mov esi, r13d
     call start
 
-[SECTION .bss]
+[SECTION .bss align=4096]
+
+; Page tables used until the kernel address space is created (see 'process.rs')
+alignb PAGE_SIZE
+boot_pml4:
+    resb PAGE_SIZE
+boot_pdpt:
+    resb PAGE_SIZE
+boot_pd:
+    resb PAGE_SIZE
 
 global init_stack:data (init_stack.end - init_stack)
 init_stack:
 	  resb STACK_SIZE
-.end:
\ No newline at end of file
+.end:
+; Startup code for application processors.
+; It is copied to SMP_TRAMPOLINE_ADDRESS by 'smp.rs' and executed by each application processor after INIT-SIPI-SIPI.
+; The processor starts in real mode and switches to long mode, using the page table and control registers
+; provided in 'smp_trampoline_data' by the bootstrap processor. Finally, the rust function in 'smp_trampoline_data' is called.
+SMP_TRAMPOLINE_ADDRESS equ 0x8000
+%define TRAMPOLINE(label) (SMP_TRAMPOLINE_ADDRESS + ((label) - smp_trampoline_start))
+
+; Offsets in 'smp_trampoline_data' (see 'TrampolineData' in 'smp.rs')
+SMP_TRAMPOLINE_CR0 equ 0
+SMP_TRAMPOLINE_CR3 equ 8
+SMP_TRAMPOLINE_CR4 equ 16
+SMP_TRAMPOLINE_EFER equ 24
+SMP_TRAMPOLINE_STACK equ 32
+SMP_TRAMPOLINE_ENTRY equ 40
+SMP_TRAMPOLINE_CPU_ID equ 48
+
+[SECTION .text]
+
+global smp_trampoline_start
+global smp_trampoline_data
+global smp_trampoline_end
+
+[BITS 16]
+smp_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    lgdt [TRAMPOLINE(smp_trampoline_gdt_descriptor)]
+
+    ; Enable protected mode
+    mov eax, cr0
+    or eax, 0x00000001
+    mov cr0, eax
+    jmp dword 0x08:TRAMPOLINE(smp_trampoline_protected_mode)
+
+[BITS 32]
+smp_trampoline_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; Enable physical address extension
+    mov eax, cr4
+    or eax, 0x00000020
+    mov cr4, eax
+
+    ; Load page table of the kernel address space (must be located below 4 GiB)
+    mov eax, [TRAMPOLINE(smp_trampoline_data) + SMP_TRAMPOLINE_CR3]
+    mov cr3, eax
+
+    ; Enable long mode (and the no-execute bit, if used by the bootstrap processor)
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, [TRAMPOLINE(smp_trampoline_data) + SMP_TRAMPOLINE_EFER]
+    wrmsr
+
+    ; Enable paging
+    mov eax, cr0
+    or eax, 0x80000000
+    mov cr0, eax
+    jmp 0x18:TRAMPOLINE(smp_trampoline_long_mode)
+
+[BITS 64]
+smp_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    ; Use the same control registers as the bootstrap processor (e.g. write protection, SSE and SMEP/SMAP)
+    mov rax, [TRAMPOLINE(smp_trampoline_data) + SMP_TRAMPOLINE_CR0]
+    mov cr0, rax
+    mov rax, [TRAMPOLINE(smp_trampoline_data) + SMP_TRAMPOLINE_CR4]
+    mov cr4, rax
+
+    ; Call rust function with the processor id (does not return)
+    mov rsp, [TRAMPOLINE(smp_trampoline_data) + SMP_TRAMPOLINE_STACK]
+    mov rdi, [TRAMPOLINE(smp_trampoline_data) + SMP_TRAMPOLINE_CPU_ID]
+    mov rax, [TRAMPOLINE(smp_trampoline_data) + SMP_TRAMPOLINE_ENTRY]
+    call rax
+smp_trampoline_halt:
+    cli
+    hlt
+    jmp smp_trampoline_halt
+
+align 8
+smp_trampoline_gdt:
+    dq 0x0000000000000000 ; Null descriptor
+    dq 0x00cf9a000000ffff ; 32-bit code segment
+    dq 0x00cf92000000ffff ; Data segment
+    dq 0x00af9a000000ffff ; 64-bit code segment
+smp_trampoline_gdt_descriptor:
+    dw smp_trampoline_gdt_descriptor - smp_trampoline_gdt - 1
+    dd TRAMPOLINE(smp_trampoline_gdt)
+
+align 8
+smp_trampoline_data:
+    times 7 dq 0
+smp_trampoline_end:
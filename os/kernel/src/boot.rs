@@ -8,12 +8,17 @@
    ╚═════════════════════════════════════════════════════════════════════════╝
 */
 
-use crate::device::e1000_driver::{e1000_large_run, e1000_run};
+use crate::device::ahci::AhciDriver;
+use crate::device::e1000_driver::{e1000_large_run, e1000_run, E1000Driver};
+use crate::device::virtio_blk::VirtioBlkDriver;
+use crate::device::virtio_net::VirtioNetDriver;
 use crate::interrupt::interrupt_dispatcher;
 use crate::syscall::syscall_dispatcher;
 use crate::process::thread::Thread;
+use crate::process::scheduler::PRIORITY_LEVELS;
 use alloc::format;
 use alloc::string::ToString;
+use alloc::sync::Arc;
 use core::ffi::c_void;
 use core::mem::size_of;
 use core::ops::Deref;
@@ -22,29 +27,25 @@ use chrono::DateTime;
 use log::{debug, error, info};
 use multiboot2::{BootInformation, BootInformationHeader, EFIMemoryMapTag, MemoryAreaType, MemoryMapTag, Tag};
 use uefi::prelude::*;
-use uefi::table::boot::{MemoryMap, PAGE_SIZE};
+use uefi::table::boot::{MemoryAttribute, MemoryDescriptor, MemoryMap, PAGE_SIZE};
 use uefi::table::Runtime;
 use uefi_raw::table::boot::MemoryType;
 use x86_64::instructions::interrupts;
 use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
 use x86_64::instructions::tables::load_tss;
 use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::{Cr0, Cr0Flags};
 use x86_64::registers::segmentation::SegmentSelector;
 use x86_64::structures::gdt::Descriptor;
 use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
 use x86_64::PrivilegeLevel::Ring0;
 use x86_64::structures::paging::frame::PhysFrameRange;
 use x86_64::structures::paging::page::PageRange;
-use crate::{allocator, apic, built_info, efi_system_table, gdt, init_acpi_tables, init_apic, init_efi_system_table, init_initrd, init_keyboard, init_pci, init_e1000, init_serial_port, init_terminal, initrd, logger, memory, process_manager, ps2_devices, scheduler, serial_port, terminal, timer, tss};
+use crate::{allocator, apic, built_info, efi_system_table, gdt, init_acpi_tables, init_apic, init_tsc, init_efi_system_table, init_initrd, init_ps2_devices, init_pci, init_serial_port, init_terminal, initrd, logger, memory, process_manager, ps2_devices, scheduler, serial_port, smp, terminal, timer, tsc, tss, device_manager, e1000_device};
 use crate::memory::MemorySpace;
 
-// import labels from linker script 'link.ld'
-extern "C" {
-    static ___KERNEL_DATA_START__: u64; // start address of OS image
-    static ___KERNEL_DATA_END__: u64;   // end address of OS image
-}
-
 const INIT_HEAP_PAGES: usize = 0x400;   // number of heap pages for booting the OS
+const MAX_EFI_RUNTIME_REGIONS: usize = 64;   // number of memory regions, that can be remapped for EFI runtime services
 
 
 /**
@@ -52,7 +53,7 @@ const INIT_HEAP_PAGES: usize = 0x400;   // number of heap pages for booting the
 
  Parameters: \
    `multiboot2_magic` frequency of musical note \
-    `multiboot2_addr` address of multiboot2 info records
+    `multiboot2_addr` physical address of multiboot2 info records
 */
 #[no_mangle]
 pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInformationHeader) {
@@ -79,6 +80,7 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
     
     // The bootloader marks the kernel image region as available, so we need to reserve it manually
     unsafe { memory::physical::reserve(kernel_image_region()); }
+    smp::reserve_trampoline();
 
     // and initialize kernel heap, after which formatted strings may be used in logs and panics.
     info!("Initializing kernel heap");
@@ -89,7 +91,11 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
 
     // Initialize virtual memory management
     info!("Initializing paging");
-    let kernel_process = process_manager().write().create_process();
+    memory::r#virtual::enable_no_execute();
+    memory::r#virtual::enable_supervisor_protection();
+    memory::aslr::init(multiboot.command_line_tag().and_then(|tag| tag.cmdline().ok()).unwrap_or(""));
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)); } // Kernel writes to read-only pages must fault as well (needed for copy-on-write)
+    let kernel_process = process_manager().write().create_process("kernel");
     kernel_process.address_space().load();
 
     // Initialize serial port and enable serial logging
@@ -102,12 +108,13 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
     let fb_info = multiboot.framebuffer_tag()
         .expect("No framebuffer information provided by bootloader!")
         .expect("Unknown framebuffer type!");
-    let fb_start_page = Page::from_start_address(VirtAddr::new(fb_info.address())).expect("Framebuffer address is not page aligned");
-    let fb_end_page = Page::from_start_address(VirtAddr::new(fb_info.address() + (fb_info.height() * fb_info.pitch()) as u64).align_up(PAGE_SIZE as u64)).unwrap();
+    let fb_address = memory::phys_to_virt(PhysAddr::new(fb_info.address()));
+    let fb_start_page = Page::from_start_address(fb_address).expect("Framebuffer address is not page aligned");
+    let fb_end_page = Page::from_start_address((fb_address + (fb_info.height() * fb_info.pitch()) as u64).align_up(PAGE_SIZE as u64)).unwrap();
     kernel_process.address_space().map(PageRange { start: fb_start_page, end: fb_end_page }, MemorySpace::Kernel, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE);
 
     // Initialize terminal and enable terminal logging
-    init_terminal(fb_info.address() as *mut u8, fb_info.pitch(), fb_info.width(), fb_info.height(), fb_info.bpp());
+    init_terminal(fb_address.as_mut_ptr(), fb_info.pitch(), fb_info.width(), fb_info.height(), fb_info.bpp());
     logger().lock().register(terminal());
 
     // Dumping basic infos
@@ -131,14 +138,14 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
 
     // Initialize ACPI tables
     info!("Initializing ACPI tables");
-    let rsdp_addr: usize = if let Some(rsdp_tag) = multiboot.rsdp_v2_tag() {
-        ptr::from_ref(rsdp_tag) as usize + size_of::<Tag>()
+    let rsdp_addr = if let Some(rsdp_tag) = multiboot.rsdp_v2_tag() {
+        VirtAddr::from_ptr(rsdp_tag) + size_of::<Tag>() as u64
     } else if let Some(rsdp_tag) = multiboot.rsdp_v1_tag() {
-        ptr::from_ref(rsdp_tag) as usize + size_of::<Tag>()
+        VirtAddr::from_ptr(rsdp_tag) + size_of::<Tag>() as u64
     } else {
         panic!("ACPI not available!");
     };
-    init_acpi_tables(rsdp_addr);
+    init_acpi_tables(memory::virt_to_phys(rsdp_addr).as_u64() as usize); // The ACPI handler maps physical addresses
 
     // Initialize interrupts
     info!("Initializing IDT");
@@ -147,9 +154,12 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
     syscall_dispatcher::init();
     info!("Initializing APIC");
     init_apic();
+    info!("Calibrating TSC");
+    init_tsc();
 
-    // Initialize timer
-    {
+    // Initialize timer (periodic PIT interrupts are only needed to count the system time, if the TSC is not available,
+    // so that the cpu is not woken up every millisecond, while it is idle)
+    if tsc().is_none() {
         info!("Initializing timer");
         let mut timer = timer().write();
         timer.interrupt_rate(1);
@@ -160,18 +170,9 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
     info!("Enabling interrupts");
     interrupts::enable();
 
-    // Initialize EFI runtime service (if available and not done already during memory initialization)
-    if efi_system_table().is_none() {
-        if let Some(sdt_tag) = multiboot.efi_sdt64_tag() {
-            info!("Initializing EFI runtime services");
-            let system_table = unsafe { SystemTable::<Runtime>::from_ptr(sdt_tag.sdt_address() as *mut c_void) };
-            if system_table.is_some() {
-                init_efi_system_table(system_table.unwrap());
-            } else {
-                error!("Failed to create EFI system table struct from pointer!");
-            }
-        }
-    }
+    // Start application processors
+    info!("Starting application processors");
+    smp::start_application_processors();
 
     // Dump information about EFI runtime service (if available)
     if let Some(efi_system_table) = efi_system_table() {
@@ -179,24 +180,32 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
         info!("EFI runtime services available (Vendor: [{}], UEFI version: [{}])", system_table.firmware_vendor(), system_table.uefi_revision());
     }
 
-    // Initialize keyboard
+    // Initialize keyboard and mouse
     info!("Initializing PS/2 devices");
-    init_keyboard();
+    init_ps2_devices();
     ps2_devices().keyboard().plugin();
+    ps2_devices().mouse().plugin();
 
     // Enable serial port interrupts
     if let Some(serial) = serial_port() {
         serial.plugin();
     }
 
+    // Register PCI drivers, which are probed for matching devices during the PCI scan
+    device_manager().register(Arc::new(E1000Driver::default()));
+    device_manager().register(Arc::new(VirtioNetDriver::default()));
+    device_manager().register(Arc::new(VirtioBlkDriver::default()));
+    device_manager().register(Arc::new(AhciDriver::default()));
+
     // Scan PCI bus
     info!("Scanning PCI bus");
     init_pci();
 
-    // Initialize E1000 network device
-    init_e1000();
-    e1000_run();
-    e1000_large_run();
+    // Test E1000 network device (if present)
+    if e1000_device().is_some() {
+        e1000_run();
+        e1000_large_run();
+    }
 
     // Load initial ramdisk
     let initrd_tag = multiboot.module_tags()
@@ -206,18 +215,28 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
 
     // Create and register the cleanup thread in the scheduler
     // (If the last thread of a process terminates, it cannot delete its own address space)
-    scheduler().ready(Thread::new_kernel_thread(|| {
+    let cleanup_thread = Thread::new_kernel_thread(|| {
         loop {
             scheduler().sleep(100);
             process_manager().write().drop_exited_process();
         }
+    });
+    cleanup_thread.set_priority(PRIORITY_LEVELS - 1);
+    scheduler().ready(cleanup_thread);
+
+    // Create the idle thread, which halts the cpu, if no other thread is ready
+    scheduler().set_idle_thread(Thread::new_kernel_thread(|| {
+        loop {
+            scheduler().idle();
+        }
     }));
     
     // Create and register the 'shell' thread (from app image in ramdisk) in the scheduler
-    scheduler().ready(Thread::load_application(initrd().entries()
+    scheduler().ready(Thread::load_application("shell", initrd().entries()
         .find(|entry| entry.filename().as_str().unwrap() == "shell")
         .expect("Shell application not available!")
-        .data()));
+        .data())
+        .expect("Failed to load shell application!"));
 
     // Disable terminal logging (remove terminal outputstream)
     logger().lock().remove(terminal());  
@@ -229,6 +248,7 @@ pub extern "C" fn start(multiboot2_magic: u32, multiboot2_addr: *const BootInfor
     // Start APIC timer & scheduler
     info!("Starting scheduler");
     apic().start_timer(10);
+    smp::release_application_processors();
     scheduler().start();
 
 }
@@ -277,13 +297,9 @@ fn init_gdt() {
  Description: Return `PhysFrameRange` for memory occupied by the kernel image
 */
 fn kernel_image_region() -> PhysFrameRange {
-    let start: PhysFrame;
-    let end: PhysFrame;
-
-    unsafe {
-        start = PhysFrame::from_start_address(PhysAddr::new(ptr::from_ref(&___KERNEL_DATA_START__) as u64)).expect("Kernel code is not page aligned");
-        end = PhysFrame::from_start_address(PhysAddr::new(ptr::from_ref(&___KERNEL_DATA_END__) as u64).align_up(PAGE_SIZE as u64)).unwrap();
-    }
+    let pages = memory::kernel_image_pages();
+    let start = PhysFrame::from_start_address(memory::virt_to_phys(pages.start.start_address())).unwrap();
+    let end = PhysFrame::from_start_address(memory::virt_to_phys(pages.end.start_address())).unwrap();
 
     return PhysFrameRange { start, end };
 }
@@ -293,12 +309,13 @@ fn kernel_image_region() -> PhysFrameRange {
  Description: Search memory map, provided by bootloader of EFI, for usable memory and initialize physical memory management \
 
  Parameters: \
-    `multiboot2_addr` address of multiboot2 info records
+    `multiboot2_addr` physical address of multiboot2 info records
 
  Return: `BootInformation`
 */
 fn multiboot2_search_memory_map(multiboot2_addr: *const BootInformationHeader) -> BootInformation<'static> {
-    let multiboot = unsafe { BootInformation::load(multiboot2_addr).expect("Failed to get Multiboot2 information") };
+    let multiboot2_addr = memory::phys_to_virt(PhysAddr::new(multiboot2_addr as u64));
+    let multiboot = unsafe { BootInformation::load(multiboot2_addr.as_ptr()).expect("Failed to get Multiboot2 information") };
 
     // Search memory map, provided by bootloader of EFI, for usable memory and initialize physical memory management
     if let Some(_) = multiboot.efi_bs_not_exited_tag() {
@@ -318,13 +335,20 @@ fn multiboot2_search_memory_map(multiboot2_addr: *const BootInformationHeader) -
         info!("Exiting EFI boot services to obtain runtime system table and memory map");
         let (runtime_table, memory_map) = system_table.exit_boot_services(MemoryType::LOADER_DATA);
 
+        init_efi_runtime_services(runtime_table, memory_map.entries());
         scan_efi_memory_map(&memory_map);
-        init_efi_system_table(runtime_table);
     } else {
         info!("EFI boot services have been exited");
         if let Some(memory_map) = multiboot.efi_memory_map_tag() {
             // EFI services have been exited, but the bootloader has provided us with the EFI memory map
             info!("Bootloader provides EFI memory map");
+            if let Some(sdt_tag) = multiboot.efi_sdt64_tag() {
+                match unsafe { SystemTable::<Runtime>::from_ptr(sdt_tag.sdt_address() as *mut c_void) } {
+                    Some(system_table) => init_efi_runtime_services(system_table, memory_map.memory_areas()),
+                    None => error!("Failed to create EFI system table struct from pointer!")
+                }
+            }
+
             scan_efi_multiboot2_memory_map(memory_map);
         } else if let Some(memory_map) = multiboot.memory_map_tag() {
             // EFI services have been exited, but the bootloader has provided us with a Multiboot2 memory map
@@ -338,6 +362,39 @@ fn multiboot2_search_memory_map(multiboot2_addr: *const BootInformationHeader) -
 }
 
 
+/**
+ Description: Move EFI runtime services into the direct mapping of physical memory and initialize the EFI system table.
+              The identity mapping of the firmware is not part of the kernel address space. Hence, this must be done
+              while the boot page table (see 'boot.asm') is still active. The heap is not available yet.
+
+ Parameters: \
+   `system_table` EFI system table (at its physical address) \
+   `memory_map` EFI memory map, containing the runtime service regions
+*/
+fn init_efi_runtime_services<'a>(system_table: SystemTable<Runtime>, memory_map: impl Iterator<Item = &'a MemoryDescriptor>) {
+    info!("Mapping EFI runtime services to the higher half");
+    let mut runtime_regions = [MemoryDescriptor::default(); MAX_EFI_RUNTIME_REGIONS];
+    let mut count = 0;
+
+    for region in memory_map.filter(|region| region.att.contains(MemoryAttribute::RUNTIME)) {
+        if count == MAX_EFI_RUNTIME_REGIONS {
+            error!("Too many EFI runtime regions! EFI runtime services are not available");
+            return;
+        }
+
+        runtime_regions[count] = *region;
+        runtime_regions[count].virt_start = memory::phys_to_virt(PhysAddr::new(region.phys_start)).as_u64();
+        count += 1;
+    }
+
+    let system_table_addr = memory::phys_to_virt(PhysAddr::new(system_table.get_current_system_table_addr())).as_u64();
+    match unsafe { system_table.set_virtual_address_map(&mut runtime_regions[..count], system_table_addr) } {
+        Ok(system_table) => init_efi_system_table(system_table),
+        Err(_) => error!("Failed to map EFI runtime services! EFI runtime services are not available")
+    }
+}
+
+
 /**
  Description: Searching available memory regions provided by multiboot2
               Available only if efi boot services have been exited
@@ -1,6 +1,15 @@
 
 
+pub const PIE_IMAGE_START: usize = 0x10000000000;  // 1 TiB (applications are position independent, see 'link.ld')
+pub const SHARED_MEMORY_START: usize = 0x200000000000;  // 32 TiB
 pub const MAIN_USER_STACK_START: usize = 0x400000000000;  // 10 TiB
 pub const MAX_USER_STACK_SIZE: usize = 0x40000000;  // 1 GiB
+pub const USER_STACK_RANDOM_PAGES: usize = 0x1000000;  // 64 GiB
+pub const USER_HEAP_RANDOM_PAGES: usize = 0x40000;  // 1 GiB
+pub const PIE_IMAGE_RANDOM_PAGES: usize = 0x1000000;  // 64 GiB
+pub const USER_SPACE_END: usize = 0x800000000000;  // End of the lower half (user space, followed by non-canonical addresses)
+pub const PHYS_MAP_START: usize = 0xffff800000000000;  // Start of the higher half (direct mapping of all physical memory)
+pub const KERNEL_IMAGE_START: usize = 0xffffffff80000000;  // Last 2 GiB (kernel image, see 'link.ld')
 pub const KERNEL_STACK_PAGES: usize = 64;
 pub const STACK_ENTRY_SIZE: usize = 8;  
+pub const MAX_CPUS: usize = 64;  // Processors are tracked in 64-bit masks (see 'smp.rs' and 'scheduler.rs')
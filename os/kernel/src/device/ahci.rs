@@ -0,0 +1,505 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use log::{info, warn};
+use pci_types::{BaseClass, CommandRegister, EndpointHeader, SubClass};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::device::block::{BlockDevice, BlockError, Disk, StorageController, SECTOR_SIZE};
+use crate::device::driver::{DeviceInstance, PciDeviceId, PciDriver};
+use crate::device::pci::PciBus;
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::PhysFrame;
+use crate::memory::{physical, phys_to_virt, virt_to_phys, PAGE_SIZE};
+use crate::timer;
+
+const AHCI_BASE_CLASS: BaseClass = 0x01;
+const AHCI_SUB_CLASS: SubClass = 0x06;
+const AHCI_INTERFACE: u8 = 0x01;
+
+// The registers of the HBA are located in the memory BAR 5 (ABAR)
+const ABAR_INDEX: u8 = 5;
+
+// Generic host control registers
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0c;
+const HBA_VS: u64 = 0x10;
+const HBA_CAP2: u64 = 0x24;
+const HBA_BOHC: u64 = 0x28;
+
+const CAP_SSS: u32 = 1 << 27; // Supports staggered spin-up
+const CAP_S64A: u32 = 1 << 31; // Supports 64-bit addressing
+const CAP2_BOH: u32 = 1 << 0; // Supports BIOS/OS handoff
+const GHC_HR: u32 = 1 << 0; // HBA reset
+const GHC_IE: u32 = 1 << 1; // Interrupt enable
+const GHC_AE: u32 = 1 << 31; // AHCI enable
+const BOHC_BOS: u32 = 1 << 0; // BIOS owned semaphore
+const BOHC_OOS: u32 = 1 << 1; // OS owned semaphore
+
+// Port registers (relative to the port base at 0x100 + port * 0x80)
+const PORT_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const PORT_CLB: u64 = 0x00;
+const PORT_CLBU: u64 = 0x04;
+const PORT_FB: u64 = 0x08;
+const PORT_FBU: u64 = 0x0c;
+const PORT_IS: u64 = 0x10;
+const PORT_IE: u64 = 0x14;
+const PORT_CMD: u64 = 0x18;
+const PORT_TFD: u64 = 0x20;
+const PORT_SIG: u64 = 0x24;
+const PORT_SSTS: u64 = 0x28;
+const PORT_SERR: u64 = 0x30;
+const PORT_CI: u64 = 0x38;
+const PORT_COUNT: u8 = 32;
+
+const CMD_ST: u32 = 1 << 0; // Start processing the command list
+const CMD_SUD: u32 = 1 << 1; // Spin-up device
+const CMD_POD: u32 = 1 << 2; // Power on device
+const CMD_FRE: u32 = 1 << 4; // FIS receive enable
+const CMD_FR: u32 = 1 << 14; // FIS receive running
+const CMD_CR: u32 = 1 << 15; // Command list running
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+const IS_TFES: u32 = 1 << 30; // Task file error status
+
+const SSTS_DET_PRESENT: u32 = 0x3; // Device present and communication established
+const SSTS_IPM_ACTIVE: u32 = 0x1;
+
+const SIGNATURE_ATA: u32 = 0x00000101;
+
+// ATA commands
+const ATA_IDENTIFY: u8 = 0xec;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_H2D_LENGTH: u32 = 5; // in dwords
+const FIS_COMMAND: u8 = 1 << 7; // The FIS contains a command (and not a device control update)
+const DEVICE_LBA: u8 = 1 << 6;
+
+const HEADER_WRITE: u32 = 1 << 6; // Data is transferred from memory to the device
+
+// Layout of the DMA memory of a port (one page):
+// command list (32 headers with 32 bytes each, 1 KiB aligned), received FIS (256 bytes aligned) and the command table of slot 0 (128 bytes aligned)
+const COMMAND_LIST_OFFSET: u64 = 0x000;
+const RECEIVED_FIS_OFFSET: u64 = 0x400;
+const COMMAND_TABLE_OFFSET: u64 = 0x500;
+const PRDT_OFFSET: u64 = 0x80; // Relative to the command table
+
+// All requests are transferred via a physically contiguous buffer, described by a single PRDT entry
+const MAX_REQUEST_SECTORS: usize = 128;
+const BUFFER_PAGES: usize = MAX_REQUEST_SECTORS * SECTOR_SIZE / PAGE_SIZE;
+
+const RESET_TIMEOUT_MS: usize = 1000;
+const LINK_TIMEOUT_MS: usize = 10;
+const COMMAND_TIMEOUT_MS: usize = 5000;
+
+const AHCI_IDS: [PciDeviceId; 1] = [
+    PciDeviceId::Class { base_class: AHCI_BASE_CLASS, sub_class: AHCI_SUB_CLASS }
+];
+
+/// SATA controller, using the Advanced Host Controller Interface.
+/// Each port with an attached ATA disk is accessible as a separate disk. Commands are processed synchronously by polling.
+pub struct AhciController {
+    registers: u64,
+    disks: Vec<(usize, Arc<Disk>)>
+}
+
+/// ATA disk attached to a port of an AHCI controller
+pub struct AhciDisk {
+    port: Mutex<AhciPort>,
+    sector_count: u64,
+    model: String
+}
+
+struct AhciPort {
+    registers: u64,
+    memory: u64, // command list, received FIS and command table
+    buffer: u64 // data buffer for DMA transfers
+}
+
+#[derive(Default)]
+pub struct AhciDriver {}
+
+fn wait_until(timeout_ms: usize, condition: impl Fn() -> bool) -> bool {
+    let end_time = timer().read().systime_ms() + timeout_ms;
+    while !condition() {
+        if timer().read().systime_ms() >= end_time {
+            return false;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    true
+}
+
+/// Physical address of kernel memory, as seen by the HBA
+fn dma_address(address: u64) -> u64 {
+    virt_to_phys(VirtAddr::new(address)).as_u64()
+}
+
+/// ATA strings (e.g. the model number) consist of 16-bit words with swapped bytes
+fn ata_string(words: &[u16]) -> String {
+    let bytes = words.iter().flat_map(|word| word.to_be_bytes()).collect::<Vec<u8>>();
+    String::from_utf8_lossy(&bytes).trim().into()
+}
+
+impl AhciController {
+    pub fn new(pci_bus: &PciBus, device: &EndpointHeader) -> Option<Self> {
+        let (_, _, _, interface) = device.header().revision_and_class(pci_bus.config_space());
+        if interface != AHCI_INTERFACE {
+            return None;
+        }
+
+        device.update_command(pci_bus.config_space(), |command| command | CommandRegister::BUS_MASTER_ENABLE | CommandRegister::MEMORY_ENABLE);
+        let registers = pci_bus.map_memory_bar(device, ABAR_INDEX)?;
+        let mut controller = Self { registers, disks: Vec::new() };
+
+        let version = controller.read(HBA_VS);
+        info!("AHCI controller version [{}.{}] found", version >> 16, (version >> 8) & 0xff);
+
+        controller.take_ownership();
+        if !controller.reset() {
+            warn!("AHCI: Controller reset timed out");
+            return None;
+        }
+
+        // Disable interrupts, since all commands are processed by polling
+        controller.write(HBA_GHC, controller.read(HBA_GHC) & !GHC_IE);
+
+        let implemented_ports = controller.read(HBA_PI);
+        let staggered_spin_up = controller.read(HBA_CAP) & CAP_SSS != 0;
+        let dma_64bit = controller.read(HBA_CAP) & CAP_S64A != 0;
+        for number in (0..PORT_COUNT).filter(|number| implemented_ports & (1 << number) != 0) {
+            let port = AhciPort::new(registers + PORT_BASE + number as u64 * PORT_SIZE);
+            if let Some(disk) = port.init(staggered_spin_up, dma_64bit) {
+                info!("AHCI: Port [{}] has disk [{}] (Capacity: [{} MiB])", number, disk.model, disk.sector_count * SECTOR_SIZE as u64 / (1024 * 1024));
+                controller.disks.push((number as usize, Arc::new(Disk::new(Arc::new(disk)))));
+            }
+        }
+
+        Some(controller)
+    }
+
+    /// Stop all ports and disable bus mastering
+    pub fn shutdown(&self, pci_bus: &PciBus, device: &EndpointHeader) {
+        for (_, disk) in self.disks.iter() {
+            let _ = disk.sync();
+        }
+
+        let implemented_ports = self.read(HBA_PI);
+        for number in (0..PORT_COUNT).filter(|number| implemented_ports & (1 << number) != 0) {
+            AhciPort::new(self.registers + PORT_BASE + number as u64 * PORT_SIZE).stop();
+        }
+
+        device.update_command(pci_bus.config_space(), |command| command - CommandRegister::BUS_MASTER_ENABLE);
+    }
+
+    /// Request ownership of the controller from the firmware (if the BIOS/OS handoff is supported)
+    fn take_ownership(&self) {
+        if self.read(HBA_CAP2) & CAP2_BOH == 0 {
+            return;
+        }
+
+        self.write(HBA_BOHC, self.read(HBA_BOHC) | BOHC_OOS);
+        if !wait_until(RESET_TIMEOUT_MS, || self.read(HBA_BOHC) & BOHC_BOS == 0) {
+            warn!("AHCI: Firmware did not release the controller");
+        }
+    }
+
+    fn reset(&self) -> bool {
+        self.write(HBA_GHC, self.read(HBA_GHC) | GHC_AE);
+        self.write(HBA_GHC, self.read(HBA_GHC) | GHC_HR);
+        if !wait_until(RESET_TIMEOUT_MS, || self.read(HBA_GHC) & GHC_HR == 0) {
+            return false;
+        }
+
+        // The reset clears the AHCI enable bit
+        self.write(HBA_GHC, self.read(HBA_GHC) | GHC_AE);
+        self.write(HBA_IS, u32::MAX);
+        true
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.registers + register) as *const u32) }
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.registers + register) as *mut u32, value) }
+    }
+}
+
+impl StorageController for AhciController {
+    fn disks(&self) -> Vec<(usize, Arc<Disk>)> {
+        self.disks.clone()
+    }
+}
+
+impl AhciPort {
+    fn new(registers: u64) -> Self {
+        Self { registers, memory: 0, buffer: 0 }
+    }
+
+    /// Initialize the port and identify the attached device.
+    /// Returns `None`, if no ATA disk (e.g. no device or an ATAPI drive) is attached.
+    /// Without `dma_64bit`, the HBA can only access memory below 4 GiB.
+    fn init(mut self, staggered_spin_up: bool, dma_64bit: bool) -> Option<AhciDisk> {
+        if !self.stop() {
+            warn!("AHCI: Failed to stop port");
+            return None;
+        }
+
+        self.memory = phys_to_virt(physical::alloc(1).start.start_address()).as_u64();
+        self.buffer = phys_to_virt(physical::alloc(BUFFER_PAGES).start.start_address()).as_u64();
+        if !dma_64bit && (dma_address(self.memory) + PAGE_SIZE as u64 > u32::MAX as u64 || dma_address(self.buffer) + (BUFFER_PAGES * PAGE_SIZE) as u64 > u32::MAX as u64) {
+            warn!("AHCI: Controller does not support 64-bit addressing, but DMA memory is located above 4 GiB");
+            return None;
+        }
+
+        unsafe { ptr::write_bytes(self.memory as *mut u8, 0, PAGE_SIZE); }
+
+        let command_list = dma_address(self.memory + COMMAND_LIST_OFFSET);
+        let received_fis = dma_address(self.memory + RECEIVED_FIS_OFFSET);
+        self.write(PORT_CLB, command_list as u32);
+        self.write(PORT_CLBU, (command_list >> 32) as u32);
+        self.write(PORT_FB, received_fis as u32);
+        self.write(PORT_FBU, (received_fis >> 32) as u32);
+
+        // The command table of slot 0 is the only one used
+        unsafe {
+            let header = (self.memory + COMMAND_LIST_OFFSET) as *mut u32;
+            let table = dma_address(self.memory + COMMAND_TABLE_OFFSET);
+            header.add(2).write_volatile(table as u32);
+            header.add(3).write_volatile((table >> 32) as u32);
+        }
+
+        self.write(PORT_IE, 0);
+        self.write(PORT_SERR, u32::MAX);
+        self.write(PORT_IS, u32::MAX);
+
+        let mut command = self.read(PORT_CMD) | CMD_FRE | CMD_POD;
+        if staggered_spin_up {
+            command |= CMD_SUD;
+        }
+        self.write(PORT_CMD, command);
+
+        let device_present = wait_until(LINK_TIMEOUT_MS, || {
+            let status = self.read(PORT_SSTS);
+            status & 0xf == SSTS_DET_PRESENT && (status >> 8) & 0xf == SSTS_IPM_ACTIVE
+        });
+
+        if !device_present || !self.wait_ready() || self.read(PORT_SIG) != SIGNATURE_ATA {
+            self.stop();
+            return None;
+        }
+
+        self.write(PORT_SERR, u32::MAX);
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_ST);
+
+        if self.command(ATA_IDENTIFY, 0, 0, false).is_err() {
+            warn!("AHCI: Failed to identify disk");
+            self.stop();
+            return None;
+        }
+
+        let identify = unsafe { core::slice::from_raw_parts(self.buffer as *const u16, 256) };
+        let model = ata_string(&identify[27..47]);
+        if identify[83] & (1 << 10) == 0 {
+            warn!("AHCI: Disk [{}] does not support 48-bit addressing", model);
+            self.stop();
+            return None;
+        }
+
+        let sector_count = identify[100] as u64 | (identify[101] as u64) << 16 | (identify[102] as u64) << 32 | (identify[103] as u64) << 48;
+        Some(AhciDisk { port: Mutex::new(self), sector_count, model })
+    }
+
+    /// Stop processing commands and receiving FISes. Returns false, if the port did not stop in time.
+    fn stop(&self) -> bool {
+        self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_ST);
+        if !wait_until(RESET_TIMEOUT_MS, || self.read(PORT_CMD) & CMD_CR == 0) {
+            return false;
+        }
+
+        self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_FRE);
+        wait_until(RESET_TIMEOUT_MS, || self.read(PORT_CMD) & CMD_FR == 0)
+    }
+
+    fn wait_ready(&self) -> bool {
+        wait_until(COMMAND_TIMEOUT_MS, || self.read(PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0)
+    }
+
+    /// Execute an ATA command via slot 0 and wait for its completion.
+    /// Data is transferred via the buffer of the port (`sector_count` sectors, or one for IDENTIFY).
+    fn command(&mut self, ata_command: u8, lba: u64, sector_count: u16, write: bool) -> Result<(), BlockError> {
+        if !self.wait_ready() {
+            return Err(BlockError::DeviceError);
+        }
+
+        let length = match ata_command {
+            ATA_IDENTIFY => SECTOR_SIZE,
+            _ => sector_count as usize * SECTOR_SIZE
+        };
+
+        let table = self.memory + COMMAND_TABLE_OFFSET;
+        unsafe {
+            // Command FIS (register host to device)
+            let fis = table as *mut u8;
+            ptr::write_bytes(fis, 0, PRDT_OFFSET as usize);
+            fis.write_volatile(FIS_TYPE_REG_H2D);
+            fis.add(1).write_volatile(FIS_COMMAND);
+            fis.add(2).write_volatile(ata_command);
+            fis.add(4).write_volatile(lba as u8);
+            fis.add(5).write_volatile((lba >> 8) as u8);
+            fis.add(6).write_volatile((lba >> 16) as u8);
+            fis.add(7).write_volatile(DEVICE_LBA);
+            fis.add(8).write_volatile((lba >> 24) as u8);
+            fis.add(9).write_volatile((lba >> 32) as u8);
+            fis.add(10).write_volatile((lba >> 40) as u8);
+            fis.add(12).write_volatile(sector_count as u8);
+            fis.add(13).write_volatile((sector_count >> 8) as u8);
+
+            // Physical region descriptor (the byte count is stored minus one)
+            let prd = (table + PRDT_OFFSET) as *mut u32;
+            let buffer = dma_address(self.buffer);
+            prd.write_volatile(buffer as u32);
+            prd.add(1).write_volatile((buffer >> 32) as u32);
+            prd.add(2).write_volatile(0);
+            prd.add(3).write_volatile(length.saturating_sub(1) as u32);
+
+            let header = (self.memory + COMMAND_LIST_OFFSET) as *mut u32;
+            let prdt_length = if length > 0 { 1 } else { 0 };
+            let flags = FIS_H2D_LENGTH | if write { HEADER_WRITE } else { 0 };
+            header.write_volatile(flags | prdt_length << 16);
+            header.add(1).write_volatile(0); // Transferred byte count (updated by the HBA)
+        }
+
+        fence(Ordering::SeqCst);
+        self.write(PORT_IS, u32::MAX);
+        self.write(PORT_CI, 1);
+
+        let completed = wait_until(COMMAND_TIMEOUT_MS, || self.read(PORT_CI) & 1 == 0 || self.read(PORT_IS) & IS_TFES != 0);
+        fence(Ordering::SeqCst);
+
+        if !completed || self.read(PORT_IS) & IS_TFES != 0 || self.read(PORT_TFD) & TFD_ERR != 0 {
+            warn!("AHCI: Command [{:#x}] failed (Task file: [{:#x}])", ata_command, self.read(PORT_TFD));
+            return Err(BlockError::DeviceError);
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.registers + register) as *const u32) }
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.registers + register) as *mut u32, value) }
+    }
+}
+
+impl Drop for AhciPort {
+    /// Free the DMA memory of an initialized port (when initialization fails or the last reference to its disk is dropped).
+    /// The memory is leaked, if the port cannot be stopped, since the HBA might still access it.
+    fn drop(&mut self) {
+        if self.memory == 0 {
+            return;
+        }
+
+        if !self.stop() {
+            warn!("AHCI: Failed to stop port -> Not freeing its memory");
+            return;
+        }
+
+        for (address, pages) in [(self.memory, 1), (self.buffer, BUFFER_PAGES)] {
+            let start = PhysFrame::containing_address(virt_to_phys(VirtAddr::new(address)));
+            unsafe { physical::free(PhysFrameRange { start, end: start + pages as u64 }); }
+        }
+    }
+}
+
+impl AhciDisk {
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn check_access(&self, sector: u64, length: usize) -> Result<(), BlockError> {
+        if length % SECTOR_SIZE != 0 {
+            return Err(BlockError::InvalidBuffer);
+        }
+
+        match sector.checked_add((length / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sector_count => Ok(()),
+            _ => Err(BlockError::OutOfRange)
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_access(sector, buffer.len())?;
+
+        let mut port = self.port.lock();
+        for (i, chunk) in buffer.chunks_mut(MAX_REQUEST_SECTORS * SECTOR_SIZE).enumerate() {
+            port.command(ATA_READ_DMA_EXT, sector + (i * MAX_REQUEST_SECTORS) as u64, (chunk.len() / SECTOR_SIZE) as u16, false)?;
+            unsafe { ptr::copy_nonoverlapping(port.buffer as *const u8, chunk.as_mut_ptr(), chunk.len()); }
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.check_access(sector, buffer.len())?;
+
+        let mut port = self.port.lock();
+        for (i, chunk) in buffer.chunks(MAX_REQUEST_SECTORS * SECTOR_SIZE).enumerate() {
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), port.buffer as *mut u8, chunk.len()); }
+            port.command(ATA_WRITE_DMA_EXT, sector + (i * MAX_REQUEST_SECTORS) as u64, (chunk.len() / SECTOR_SIZE) as u16, true)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.port.lock().command(ATA_FLUSH_CACHE_EXT, 0, 0, false)
+    }
+}
+
+impl PciDriver for AhciDriver {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn device_prefix(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn ids(&self) -> &[PciDeviceId] {
+        &AHCI_IDS
+    }
+
+    fn probe(&self, pci_bus: &PciBus, device: &EndpointHeader) -> Option<DeviceInstance> {
+        let controller = AhciController::new(pci_bus, device)?;
+        Some(DeviceInstance::Storage(Arc::new(controller)))
+    }
+
+    fn remove(&self, pci_bus: &PciBus, device: &EndpointHeader, instance: DeviceInstance) {
+        if let Some(controller) = instance.downcast::<AhciController>() {
+            controller.shutdown(pci_bus, device);
+        }
+    }
+}
@@ -3,26 +3,35 @@ use crate::interrupt::interrupt_dispatcher::InterruptVector;
 use acpi::madt::Madt;
 use acpi::platform::interrupt::{InterruptSourceOverride, NmiSource, Polarity, TriggerMode};
 use acpi::InterruptModel;
+use acpi::platform::ProcessorState;
 use alloc::vec::Vec;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
 use log::info;
 use raw_cpuid::CpuId;
 use spin::Mutex;
 use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
-use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerDivide, TimerMode};
+use x2apic::lapic::{IpiAllShorthand, LocalApic, LocalApicBuilder, TimerDivide, TimerMode};
+use x86_64::instructions::interrupts;
 use x86_64::structures::paging::page::PageRange;
-use x86_64::VirtAddr;
+use x86_64::PhysAddr;
 use x86_64::structures::paging::{Page, PageTableFlags};
-use crate::{acpi_tables, allocator, apic, interrupt_dispatcher, process_manager, scheduler};
+use crate::{acpi_tables, allocator, apic, interrupt_dispatcher, memory, process_manager, scheduler};
 use crate::device::pit;
+use crate::device::pit::Timer;
 use crate::interrupt::interrupt_handler::InterruptHandler;
-use crate::memory::MemorySpace;
+use crate::memory::{MemorySpace, PAGE_SIZE};
 
 pub struct Apic {
-    local_apic: Mutex<LocalApic>,
+    local_apic: Mutex<LocalApic>, // each processor accesses its own local APIC via the same registers, so this is shared by all processors
     io_apic: Mutex<IoApic>,
     irq_overrides: Vec<InterruptSourceOverride>,
     nmi_sources: Vec<NmiSource>,
-    timer_ticks_per_ms: usize
+    timer_ticks_per_ms: usize,
+    timer_interval_ms: AtomicUsize, // interval of the periodic timer interrupt (0 = not started yet)
+    local_apic_address: u64,
+    boot_processor_id: u32,
+    application_processors: Vec<u32> // local APIC IDs of processors, which may be started via INIT-SIPI-SIPI
 }
 
 #[derive(Default)]
@@ -60,10 +69,14 @@ impl Apic {
 
         info!("[{}] application {} detected", cpu_info.application_processors.len(), if cpu_info.application_processors.len() == 1 { "processor" } else { "processors" });
         info!("CPU [{}] is the bootstrap processor", cpu_info.boot_processor.processor_uid);
+        let application_processors = cpu_info.application_processors.iter()
+            .filter(|processor| processor.state == ProcessorState::WaitingForSipi)
+            .map(|processor| processor.local_apic_id)
+            .collect::<Vec<u32>>();
 
         // Read physical APIC MMIO base address and map it to the kernel address space
         // Needs to be executed in unsafe block; APIC availability has been checked before, so this should work.
-        let apic_page = Page::from_start_address(VirtAddr::new(madt.local_apic_address as u64)).expect("Local Apic MMIO address is not page aligned");
+        let apic_page = Page::from_start_address(memory::phys_to_virt(PhysAddr::new(madt.local_apic_address as u64))).expect("Local Apic MMIO address is not page aligned");
         let address_space = process_manager().read().kernel_process().unwrap().address_space();
         address_space.map(PageRange { start: apic_page, end: apic_page + 1 }, MemorySpace::Kernel, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE);
 
@@ -96,7 +109,7 @@ impl Apic {
                     let io_apic_desc = apic_desc.io_apics.get(0).unwrap_or_else(|| panic!("No IO APIC described by MADT!"));
 
                     info!("Initializing IO APIC");
-                    let io_apic_page = Page::from_start_address(VirtAddr::new(io_apic_desc.address as u64)).expect("IO Apic MMIO address is not page aligned");
+                    let io_apic_page = Page::from_start_address(memory::phys_to_virt(PhysAddr::new(io_apic_desc.address as u64))).expect("IO Apic MMIO address is not page aligned");
                     address_space.map(PageRange { start: io_apic_page, end: io_apic_page + 1 }, MemorySpace::Kernel, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE);
                     unsafe { io_apic_mutex = Mutex::new(IoApic::new(io_apic_page.start_address().as_u64())); } // Needs to be executed in unsafe block; Since exactly one IO APIC has been detected, this should work
 
@@ -195,7 +208,11 @@ impl Apic {
             io_apic: io_apic_mutex,
             irq_overrides,
             nmi_sources,
-            timer_ticks_per_ms
+            timer_ticks_per_ms,
+            timer_interval_ms: AtomicUsize::new(0),
+            local_apic_address: apic_page.start_address().as_u64(),
+            boot_processor_id: cpu_info.boot_processor.local_apic_id,
+            application_processors
         };
     }
 
@@ -208,6 +225,81 @@ impl Apic {
         unsafe { self.io_apic.lock().enable_irq(target); }
     }
 
+    /// Mask an interrupt again, that has been allowed via `allow()` (e.g. after the last device using it has been removed)
+    pub fn disallow(&self, vector: InterruptVector) {
+        let target = target_gsi(&self.irq_overrides, vector as u8 - InterruptVector::Pit as u8);
+        if is_nmi(&self.nmi_sources, target) {
+            panic!("Trying to mask a non-maskable interrupt");
+        }
+
+        unsafe { self.io_apic.lock().disable_irq(target); }
+    }
+
+    /// Address for message signaled interrupts (MSI/MSI-X), delivering them to the bootstrap processor
+    pub fn msi_address(&self) -> u32 {
+        0xfee00000 | (self.boot_processor_id << 12)
+    }
+
+    pub fn application_processor_ids(&self) -> &[u32] {
+        &self.application_processors
+    }
+
+    /// Index of the processor with the given local APIC ID
+    /// (0 = bootstrap processor, application processors are numbered in the order of `application_processor_ids()`, starting at 1)
+    pub fn cpu_index(&self, apic_id: u32) -> usize {
+        if apic_id == self.boot_processor_id {
+            return 0;
+        }
+
+        match self.application_processors.iter().position(|&id| id == apic_id) {
+            Some(index) => index + 1,
+            None => panic!("APIC: Unknown local APIC ID [{}]!", apic_id)
+        }
+    }
+
+    /// Send an inter-processor interrupt to the processor with the given index (see `cpu_index()`)
+    pub fn send_ipi(&self, cpu: usize, vector: InterruptVector) {
+        let apic_id = if cpu == 0 { self.boot_processor_id } else { self.application_processors[cpu - 1] };
+
+        // Interrupts are disabled, so that no interrupt handler on this processor can find the local APIC locked
+        interrupts::without_interrupts(|| unsafe { self.local_apic.lock().send_ipi(vector as u8, apic_id); });
+    }
+
+    /// Send an inter-processor interrupt to all processors, except the calling one
+    pub fn broadcast_ipi(&self, vector: InterruptVector) {
+        interrupts::without_interrupts(|| unsafe { self.local_apic.lock().send_ipi_all(vector as u8, IpiAllShorthand::AllExcludingSelf); });
+    }
+
+    /// Start an application processor via INIT-SIPI-SIPI.
+    /// It begins execution in real mode at `startup_address`, which must be page aligned and below 1 MiB.
+    pub fn start_application_processor(&self, apic_id: u32, startup_address: PhysAddr) {
+        let vector = (startup_address.as_u64() / PAGE_SIZE as u64) as u8;
+
+        unsafe { self.local_apic.lock().send_init_ipi(apic_id); }
+        Timer::wait(10);
+
+        for _ in 0..2 {
+            unsafe { self.local_apic.lock().send_sipi(vector, apic_id); }
+            Timer::wait(1);
+        }
+    }
+
+    /// Enable the local APIC of the calling application processor (all local APICs share the same MMIO address)
+    pub fn enable_local_apic(&self) {
+        let mut local_apic = LocalApicBuilder::new()
+            .timer_vector(InterruptVector::ApicTimer as usize)
+            .error_vector(InterruptVector::ApicError as usize)
+            .spurious_vector(InterruptVector::Spurious as usize)
+            .set_xapic_base(self.local_apic_address)
+            .build()
+            .unwrap_or_else(|err| panic!("Failed to initialize Local APIC ({})!", err));
+
+        unsafe {
+            local_apic.enable();
+            local_apic.disable_timer(); // Started by `start_local_timer()`, when the processor begins scheduling
+        }
+    }
+
     pub fn end_of_interrupt(&self) {
         let mut local_apic = self.local_apic.try_lock();
         while local_apic.is_none() {
@@ -221,17 +313,54 @@ impl Apic {
     }
 
     pub fn start_timer(&self, interval_ms: usize) {
-        let mut local_apic = self.local_apic.lock();
+        self.timer_interval_ms.store(interval_ms, Relaxed);
+        Apic::set_timer(&mut self.local_apic.lock(), TimerMode::Periodic, self.timer_ticks_per_ms * interval_ms);
+
+        interrupt_dispatcher().assign(InterruptVector::ApicTimer, Box::new(ApicTimerInterruptHandler::default()));
+        apic().allow(InterruptVector::ApicTimer);
+    }
 
+    /// Start the periodic timer of an application processor with the interval of the bootstrap processor's timer.
+    /// The interrupt handler has already been assigned by `start_timer()`.
+    pub fn start_local_timer(&self) {
+        let interval_ms = self.timer_interval_ms.load(Relaxed);
+        interrupts::without_interrupts(|| Apic::set_timer(&mut self.local_apic.lock(), TimerMode::Periodic, self.timer_ticks_per_ms * interval_ms));
+    }
+
+    /// Switch the timer of the calling processor back to periodic interrupts (after `start_one_shot_timer()`).
+    /// Returns false, if the local APIC is currently locked.
+    pub fn resume_periodic_timer(&self) -> bool {
+        let interval_ms = self.timer_interval_ms.load(Relaxed);
+        interrupts::without_interrupts(|| match self.local_apic.try_lock() {
+            Some(mut local_apic) => {
+                Apic::set_timer(&mut local_apic, TimerMode::Periodic, self.timer_ticks_per_ms * interval_ms);
+                true
+            }
+            None => false
+        })
+    }
+
+    /// Let the timer of the calling processor fire only once, after `delay_ns` nanoseconds (used while the processor is idle).
+    /// Returns false, if the local APIC is currently locked.
+    pub fn start_one_shot_timer(&self, delay_ns: usize) -> bool {
+        let ticks = (self.timer_ticks_per_ms as u128 * delay_ns as u128 / 1000000) as usize;
+        interrupts::without_interrupts(|| match self.local_apic.try_lock() {
+            Some(mut local_apic) => {
+                Apic::set_timer(&mut local_apic, TimerMode::OneShot, ticks);
+                true
+            }
+            None => false
+        })
+    }
+
+    fn set_timer(local_apic: &mut LocalApic, mode: TimerMode, ticks: usize) {
         unsafe {
+            local_apic.disable_timer();
             local_apic.set_timer_divide(TimerDivide::Div256); // Div256 is labelled wrong and actually means Div1
-            local_apic.set_timer_mode(TimerMode::Periodic);
-            local_apic.set_timer_initial((self.timer_ticks_per_ms * interval_ms) as u32);
+            local_apic.set_timer_mode(mode);
+            local_apic.set_timer_initial(ticks.clamp(1, u32::MAX as usize) as u32);
             local_apic.enable_timer();
         }
-
-        interrupt_dispatcher().assign(InterruptVector::ApicTimer, Box::new(ApicTimerInterruptHandler::default()));
-        apic().allow(InterruptVector::ApicTimer);
     }
 
     fn calibrate_timer(local_apic: &mut LocalApic) -> usize {
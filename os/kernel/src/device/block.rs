@@ -0,0 +1,260 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use crate::sync::mutex::Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Number of sectors kept in the buffer cache of each disk (1 MiB)
+const CACHE_CAPACITY: usize = 2048;
+// Longer runs of sectors are split into multiple requests
+const MAX_REQUEST_SECTORS: usize = 128;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    OutOfRange, // access beyond the last sector
+    InvalidBuffer, // buffer length is not a multiple of the sector size
+    ReadOnly,
+    DeviceError
+}
+
+/// Interface of storage drivers (e.g. virtio-blk). All transfers consist of whole sectors.
+pub trait BlockDevice: Any + Send + Sync {
+    fn sector_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Make sure, that all written sectors have reached persistent storage
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Interface of controllers, that drive multiple disks (e.g. AHCI with one disk per port).
+/// Each disk is identified by a controller specific number (e.g. the port number).
+pub trait StorageController: Any + Send + Sync {
+    fn disks(&self) -> Vec<(usize, Arc<Disk>)>;
+}
+
+/// Block device with a write-back buffer cache. File systems access storage via this interface.
+pub struct Disk {
+    device: Arc<dyn BlockDevice>,
+    cache: Mutex<BufferCache>
+}
+
+/// Cached sectors of a disk. If the cache is full, the least recently used sector is evicted.
+struct BufferCache {
+    sectors: BTreeMap<u64, CachedSector>,
+    access_counter: u64
+}
+
+struct CachedSector {
+    data: Vec<u8>,
+    dirty: bool,
+    last_access: u64
+}
+
+/// Pending write requests, ordered by sector.
+/// Adjacent sectors are merged into a single request, so that the device sees few, large requests in ascending order.
+struct RequestQueue {
+    sectors: BTreeMap<u64, Vec<u8>>
+}
+
+struct Request {
+    sector: u64,
+    data: Vec<u8>
+}
+
+impl RequestQueue {
+    const fn new() -> Self {
+        Self { sectors: BTreeMap::new() }
+    }
+
+    fn add(&mut self, sector: u64, data: &[u8]) {
+        self.sectors.insert(sector, data.to_vec());
+    }
+
+    fn drain(&mut self) -> Vec<Request> {
+        let mut requests = Vec::<Request>::new();
+
+        for (sector, data) in core::mem::take(&mut self.sectors) {
+            match requests.last_mut() {
+                Some(request) if request.sector + (request.data.len() / SECTOR_SIZE) as u64 == sector && request.data.len() < MAX_REQUEST_SECTORS * SECTOR_SIZE => {
+                    request.data.extend_from_slice(&data);
+                }
+                _ => requests.push(Request { sector, data })
+            }
+        }
+
+        requests
+    }
+
+    fn submit(&mut self, device: &dyn BlockDevice) -> Result<(), BlockError> {
+        for request in self.drain() {
+            device.write_sectors(request.sector, &request.data)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BufferCache {
+    const fn new() -> Self {
+        Self { sectors: BTreeMap::new(), access_counter: 0 }
+    }
+
+    fn get(&mut self, sector: u64) -> Option<&mut CachedSector> {
+        self.access_counter += 1;
+        let access_counter = self.access_counter;
+
+        self.sectors.get_mut(&sector).map(|cached| {
+            cached.last_access = access_counter;
+            cached
+        })
+    }
+
+    fn insert(&mut self, device: &dyn BlockDevice, sector: u64, data: &[u8], dirty: bool) -> Result<(), BlockError> {
+        if !self.sectors.contains_key(&sector) && self.sectors.len() >= CACHE_CAPACITY {
+            self.evict(device)?;
+        }
+
+        self.access_counter += 1;
+        self.sectors.insert(sector, CachedSector { data: data.to_vec(), dirty, last_access: self.access_counter });
+
+        Ok(())
+    }
+
+    /// Remove the least recently used sector (writing it back first, if it is dirty)
+    fn evict(&mut self, device: &dyn BlockDevice) -> Result<(), BlockError> {
+        let sector = match self.sectors.iter().min_by_key(|(_, cached)| cached.last_access) {
+            Some((sector, _)) => *sector,
+            None => return Ok(())
+        };
+
+        let cached = &self.sectors[&sector];
+        if cached.dirty {
+            device.write_sectors(sector, &cached.data)?;
+        }
+
+        self.sectors.remove(&sector);
+        Ok(())
+    }
+
+    fn write_back(&mut self, device: &dyn BlockDevice) -> Result<(), BlockError> {
+        let mut queue = RequestQueue::new();
+        for (sector, cached) in self.sectors.iter().filter(|(_, cached)| cached.dirty) {
+            queue.add(*sector, &cached.data);
+        }
+
+        // Sectors stay dirty, if writing fails, so that they are not evicted without being written back
+        queue.submit(device)?;
+        self.sectors.values_mut().for_each(|cached| cached.dirty = false);
+
+        Ok(())
+    }
+}
+
+impl Disk {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Self { device, cache: Mutex::new(BufferCache::new()) }
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    /// Size of the disk in bytes
+    pub fn size(&self) -> u64 {
+        self.sector_count() * SECTOR_SIZE as u64
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    /// Read `buffer.len() / SECTOR_SIZE` sectors, starting at `sector`
+    pub fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = self.check_access(sector, buffer.len())?;
+        let mut cache = self.cache.lock();
+
+        let mut i = 0;
+        while i < count {
+            let offset = i * SECTOR_SIZE;
+            if let Some(cached) = cache.get(sector + i as u64) {
+                buffer[offset..offset + SECTOR_SIZE].copy_from_slice(&cached.data);
+                i += 1;
+                continue;
+            }
+
+            // Read the whole run of sectors, that are not cached, with a single request
+            let mut run = 1;
+            while i + run < count && run < MAX_REQUEST_SECTORS && !cache.sectors.contains_key(&(sector + (i + run) as u64)) {
+                run += 1;
+            }
+
+            let run_buffer = &mut buffer[offset..offset + run * SECTOR_SIZE];
+            self.device.read_sectors(sector + i as u64, run_buffer)?;
+            for (j, data) in run_buffer.chunks(SECTOR_SIZE).enumerate() {
+                cache.insert(self.device.as_ref(), sector + (i + j) as u64, data, false)?;
+            }
+
+            i += run;
+        }
+
+        Ok(())
+    }
+
+    /// Write `buffer.len() / SECTOR_SIZE` sectors, starting at `sector`.
+    /// The data is kept in the buffer cache and written to the device on eviction or `sync()`.
+    pub fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.check_access(sector, buffer.len())?;
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+
+        let mut cache = self.cache.lock();
+        for (i, data) in buffer.chunks(SECTOR_SIZE).enumerate() {
+            match cache.get(sector + i as u64) {
+                Some(cached) => {
+                    cached.data.copy_from_slice(data);
+                    cached.dirty = true;
+                }
+                None => cache.insert(self.device.as_ref(), sector + i as u64, data, true)?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write all modified sectors back to the device
+    pub fn sync(&self) -> Result<(), BlockError> {
+        self.cache.lock().write_back(self.device.as_ref())?;
+        self.device.flush()
+    }
+
+    /// Returns the number of sectors covered by an access
+    fn check_access(&self, sector: u64, length: usize) -> Result<usize, BlockError> {
+        if length % SECTOR_SIZE != 0 {
+            return Err(BlockError::InvalidBuffer);
+        }
+
+        let count = length / SECTOR_SIZE;
+        match sector.checked_add(count as u64) {
+            Some(end) if end <= self.sector_count() => {}
+            _ => return Err(BlockError::OutOfRange)
+        }
+
+        Ok(count)
+    }
+}
@@ -0,0 +1,214 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use log::{info, warn};
+use pci_types::{BaseClass, EndpointHeader, PciAddress, SubClass};
+use spin::RwLock;
+use crate::device::block::{Disk, StorageController};
+use crate::device::network::NetworkDevice;
+use crate::device::pci::PciBus;
+
+/// Driver specific state of an initialized device, accessible via the interface of its device class
+#[derive(Clone)]
+pub enum DeviceInstance {
+    Network(Arc<dyn NetworkDevice>),
+    Block(Arc<Disk>),
+    Storage(Arc<dyn StorageController>)
+}
+
+/// Identifies the PCI devices, a driver is responsible for
+#[derive(Copy, Clone, Debug)]
+pub enum PciDeviceId {
+    Id { vendor_id: u16, device_id: u16 },
+    Class { base_class: BaseClass, sub_class: SubClass }
+}
+
+pub trait PciDriver: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Devices driven by this driver are named `<prefix><index>` (e.g. "eth" -> eth0, eth1, ...)
+    fn device_prefix(&self) -> &'static str;
+
+    fn ids(&self) -> &[PciDeviceId];
+
+    /// Initialize a matching device. Returns `None`, if the device cannot be used by this driver.
+    fn probe(&self, pci_bus: &PciBus, device: &EndpointHeader) -> Option<DeviceInstance>;
+
+    /// Shut down a device, that has been initialized by `probe()`
+    fn remove(&self, pci_bus: &PciBus, device: &EndpointHeader, instance: DeviceInstance);
+}
+
+/// A PCI device, that has been bound to a driver
+pub struct Device {
+    name: String,
+    address: PciAddress,
+    driver: Arc<dyn PciDriver>,
+    instance: DeviceInstance
+}
+
+/// Registry of all drivers and the devices bound to them.
+/// Device names stay stable while the system is running (the index of a removed device is not reused).
+pub struct DeviceManager {
+    drivers: RwLock<Vec<Arc<dyn PciDriver>>>,
+    devices: RwLock<Vec<Arc<Device>>>,
+    next_index: RwLock<BTreeMap<&'static str, usize>>
+}
+
+impl PciDeviceId {
+    fn matches(&self, pci_bus: &PciBus, device: &EndpointHeader) -> bool {
+        let header = device.header();
+        match *self {
+            PciDeviceId::Id { vendor_id, device_id } => header.id(pci_bus.config_space()) == (vendor_id, device_id),
+            PciDeviceId::Class { base_class, sub_class } => {
+                let (_, class, subclass, _) = header.revision_and_class(pci_bus.config_space());
+                class == base_class && subclass == sub_class
+            }
+        }
+    }
+}
+
+impl DeviceInstance {
+    /// Get the driver specific state, if it has type `T` (e.g. `IntelE1000Device`)
+    pub fn downcast<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        let instance: Arc<dyn Any + Send + Sync> = match self {
+            DeviceInstance::Network(device) => device.clone(),
+            DeviceInstance::Block(disk) => disk.device().clone(),
+            DeviceInstance::Storage(controller) => controller.clone()
+        };
+
+        instance.downcast::<T>().ok()
+    }
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    pub fn driver_name(&self) -> &'static str {
+        self.driver.name()
+    }
+
+    pub fn instance(&self) -> &DeviceInstance {
+        &self.instance
+    }
+
+    /// Get the driver specific state, if the device is driven by a driver using type `T`
+    pub fn downcast<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.instance.downcast::<T>()
+    }
+}
+
+impl DeviceManager {
+    pub const fn new() -> Self {
+        Self { drivers: RwLock::new(Vec::new()), devices: RwLock::new(Vec::new()), next_index: RwLock::new(BTreeMap::new()) }
+    }
+
+    pub fn register(&self, driver: Arc<dyn PciDriver>) {
+        info!("Registering driver [{}]", driver.name());
+        self.drivers.write().push(driver);
+    }
+
+    /// Bind all devices on the PCI bus, that do not have a driver yet, to the first matching driver.
+    pub fn probe(&self, pci_bus: &PciBus) {
+        let drivers = self.drivers.read().clone();
+
+        for device in pci_bus.devices() {
+            let address = device.header().address();
+            if self.devices.read().iter().any(|bound| bound.address == address) {
+                continue;
+            }
+
+            for driver in drivers.iter().filter(|driver| driver.ids().iter().any(|id| id.matches(pci_bus, device))) {
+                // Probing may take a while, so no lock is held while the driver initializes the device
+                match driver.probe(pci_bus, device) {
+                    Some(instance) => {
+                        let name = self.next_name(driver.device_prefix());
+                        info!("Driver [{}] bound to PCI device on bus [{}] as [{}]", driver.name(), address.bus(), name);
+                        self.devices.write().push(Arc::new(Device { name, address, driver: Arc::clone(driver), instance }));
+                        break;
+                    }
+                    None => warn!("Driver [{}] failed to probe PCI device on bus [{}]", driver.name(), address.bus())
+                }
+            }
+        }
+    }
+
+    /// Shut down a device and unbind it from its driver. Returns false, if no device with the given name exists.
+    pub fn remove(&self, pci_bus: &PciBus, name: &str) -> bool {
+        let device = {
+            let mut devices = self.devices.write();
+            match devices.iter().position(|device| device.name == name) {
+                Some(index) => devices.remove(index),
+                None => return false
+            }
+        };
+
+        let header = pci_bus.device(device.address).expect("DeviceManager: Bound device is not on the PCI bus!");
+        device.driver.remove(pci_bus, header, device.instance.clone());
+        info!("Removed device [{}]", name);
+
+        true
+    }
+
+    pub fn device(&self, name: &str) -> Option<Arc<Device>> {
+        self.devices.read().iter().find(|device| device.name == name).cloned()
+    }
+
+    pub fn devices(&self) -> Vec<Arc<Device>> {
+        self.devices.read().clone()
+    }
+
+    /// Get the driver specific state of the first device, that is driven by a driver using type `T`
+    pub fn find<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.devices.read().iter().find_map(|device| device.downcast::<T>())
+    }
+
+    /// Get the first network device (in probing order, so usually eth0)
+    pub fn network_device(&self) -> Option<Arc<dyn NetworkDevice>> {
+        self.devices.read().iter().find_map(|device| match &device.instance {
+            DeviceInstance::Network(network) => Some(Arc::clone(network)),
+            _ => None
+        })
+    }
+
+    /// Get a disk by its name (e.g. "vd0" or "ahci0.1", see `block_devices()`)
+    pub fn block_device(&self, name: &str) -> Option<Arc<Disk>> {
+        self.block_devices().into_iter().find(|(disk_name, _)| disk_name == name).map(|(_, disk)| disk)
+    }
+
+    /// Get all disks together with their names.
+    /// Disks of a storage controller are named `<controller>.<number>` (e.g. "ahci0.1" for port 1 of the first AHCI controller).
+    pub fn block_devices(&self) -> Vec<(String, Arc<Disk>)> {
+        let mut disks = Vec::new();
+        for device in self.devices.read().iter() {
+            match &device.instance {
+                DeviceInstance::Block(disk) => disks.push((device.name.clone(), Arc::clone(disk))),
+                DeviceInstance::Storage(controller) => {
+                    for (number, disk) in controller.disks() {
+                        disks.push((format!("{}.{}", device.name, number), disk));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        disks
+    }
+
+    fn next_name(&self, prefix: &'static str) -> String {
+        let mut next_index = self.next_index.write();
+        let index = next_index.entry(prefix).or_insert(0);
+        let name = format!("{}{}", prefix, index);
+        *index += 1;
+
+        name
+    }
+}
@@ -10,6 +10,7 @@ use alloc::alloc::{GlobalAlloc, Layout, alloc_zeroed};
 use core::ptr;
 use crate::memory;
 use crate::PhysAddr;
+use x86_64::VirtAddr;
 
 use alloc::boxed::Box;
 use x86_64::registers;
@@ -92,9 +93,8 @@ pub fn set_up_rx_desc_ring(registers: &E1000Registers) -> Vec<E1000RxDescriptor>
     //}
 
     let phys_mem = memory::physical::alloc(1);
-    let phys_addr = phys_mem.start.start_address().as_u64();
 
-    let receive_ring_ptr = phys_addr as *mut E1000RxDescriptor;
+    let receive_ring_ptr = memory::phys_to_virt(phys_mem.start.start_address()).as_mut_ptr::<E1000RxDescriptor>();
 
     //build Vec from pointer - refactoring the rest of the code using the pointer is not worth it
     let mut receive_ring = unsafe {
@@ -163,7 +163,7 @@ pub fn set_up_rx_desc_ring(registers: &E1000Registers) -> Vec<E1000RxDescriptor>
 
 //    }
 
-    let ring_addr = receive_ring.as_ptr() as u64;
+    let ring_addr = memory::virt_to_phys(VirtAddr::from_ptr(receive_ring.as_ptr())).as_u64();
 //    let ring_addr = receive_ring_ptr as u64;
 
     //write base address of receive descriptor ring to card (RDBAL and RDBAH)
@@ -209,7 +209,7 @@ pub fn replenish_rx_desc_ring(receive_ring: &mut Vec<E1000RxDescriptor>, registe
             //dealloc old buffer
             if descriptor.buffer_addr != 0{
                 unsafe{
-                    let _old_buffer = Box::from_raw(descriptor.buffer_addr as *mut [MaybeUninit<u8>; BUFFER_SIZE]);
+                    let _old_buffer = Box::from_raw(memory::phys_to_virt(PhysAddr::new(descriptor.buffer_addr)).as_mut_ptr::<[MaybeUninit<u8>; BUFFER_SIZE]>());
                     //old_buffer dropped here
                 }
             }    
@@ -217,7 +217,7 @@ pub fn replenish_rx_desc_ring(receive_ring: &mut Vec<E1000RxDescriptor>, registe
             //inner Box now in first line, just a bit more readable
 //TODO: high memory use right now. check if reusing buffers is possible
             let buffer: Box<[MaybeUninit<u8>; BUFFER_SIZE]> = Box::new(unsafe { MaybeUninit::uninit().assume_init() });
-            let buffer_addr = Box::into_raw(buffer) as *mut _ as u64;
+            let buffer_addr = memory::virt_to_phys(VirtAddr::from_ptr(Box::into_raw(buffer))).as_u64();
 
             //update descriptor with new buffer address
             descriptor.buffer_addr = buffer_addr;
@@ -239,7 +239,7 @@ pub fn cleanup_receive_ring(receive_ring: &mut Vec<E1000RxDescriptor>){
         //dealloc old buffer
         if descriptor.buffer_addr != 0{
             unsafe{
-                let _old_buffer = Box::from_raw(descriptor.buffer_addr as *mut [MaybeUninit<u8>; BUFFER_SIZE]);
+                let _old_buffer = Box::from_raw(memory::phys_to_virt(PhysAddr::new(descriptor.buffer_addr)).as_mut_ptr::<[MaybeUninit<u8>; BUFFER_SIZE]>());
                 //old_buffer dropped here
             }
         }
@@ -342,10 +342,10 @@ pub fn set_up_tx_desc_ring(registers: &E1000Registers, tx_ring: &'static Mutex<O
     }
 
     if let Some(descriptors_vec) = descriptors.as_ref() {
-        let descriptors_ptr = descriptors_vec.as_ptr();
+        let descriptors_addr = memory::virt_to_phys(VirtAddr::from_ptr(descriptors_vec.as_ptr())).as_u64();
         
-        E1000Registers::write_tdbal(registers, descriptors_ptr as u32);
-        E1000Registers::write_tdbah(registers, ((descriptors_ptr as u64 )>>32) as u32);
+        E1000Registers::write_tdbal(registers, descriptors_addr as u32);
+        E1000Registers::write_tdbah(registers, (descriptors_addr >> 32) as u32);
     } else {
         info!("Tx Ring not initialized");
     }
@@ -424,7 +424,7 @@ pub fn tx_conncect_buffer_to_descriptors_vecless(tx_ring: &mut Vec<E1000TxDescri
         }
 
     //    descriptor.buffer_addr = 0 as u64;
-        descriptor.buffer_addr = packet as u64;
+        descriptor.buffer_addr = memory::virt_to_phys(VirtAddr::from_ptr(packet)).as_u64();
         descriptor.length = tx_packet.length as u16;
         descriptor.cmd = 0x1 | 0x8;
         descriptor.status = 0;
@@ -507,7 +507,7 @@ pub fn tx_conncect_buffer_to_descriptors(tx_ring: &mut Vec<E1000TxDescriptor>, t
             tdh = E1000Registers::read_tdh(registers) as usize;
             }
             let descriptor = &mut tx_ring[tdt];
-            descriptor.buffer_addr = chunk.as_ptr() as u64;
+            descriptor.buffer_addr = memory::virt_to_phys(VirtAddr::from_ptr(chunk.as_ptr())).as_u64();
             descriptor.length = chunk.len() as u16;
             descriptor.cmd = E1000_TXD_CMD_RS;
             descriptor.status = 0;
@@ -537,8 +537,7 @@ pub fn create_packet_vecless(tx_buffer: &TxBuffer) -> Vec<TxPacketWrapper>{
 
     for chunk in tx_buffer.data.chunks(16288) {
         let packet_mem = memory::physical::alloc((chunk.len() / 4096) + 1);
-        let packet_addr = packet_mem.start.start_address().as_u64();
-        let packet_ptr = packet_addr as *mut u8;
+        let packet_ptr = memory::phys_to_virt(packet_mem.start.start_address()).as_mut_ptr::<u8>();
         unsafe {
             ptr::copy_nonoverlapping(chunk.as_ptr(), packet_ptr, chunk.len());
         }
@@ -580,8 +579,7 @@ pub fn create_packets(tx_buffer: &TxBuffer) -> Vec<Vec<u8>>{
     //let header_len = header.len();
     for chunk in tx_buffer.data.chunks(MTU){
         let packet_mem = memory::physical::alloc(1);
-        let packet_addr = packet_mem.start.start_address().as_u64();
-        let packet_ptr = packet_addr as *mut u8;
+        let packet_ptr = memory::phys_to_virt(packet_mem.start.start_address()).as_mut_ptr::<u8>();
         info!("crashes after allocating packet memory");
 
         let mut packet = unsafe {
@@ -658,7 +656,7 @@ pub fn retrieve_packets(receive_ring: &mut Vec<E1000RxDescriptor>, registers: &E
             //retrieve packet data and its length
             let length = descriptor.length as usize;
             let packet_data = unsafe{
-                core::slice::from_raw_parts(descriptor.buffer_addr as *const u8, length)
+                core::slice::from_raw_parts(memory::phys_to_virt(PhysAddr::new(descriptor.buffer_addr)).as_ptr::<u8>(), length)
             };
 
             //error checking - drop packet if error
@@ -721,7 +719,7 @@ pub fn rx_ring_pop(receive_ring: &mut Vec<E1000RxDescriptor>, registers: &E1000R
             //packet is ready to be processed
             let length = descriptor.length as usize;
             let packet_data = unsafe{
-                core::slice::from_raw_parts(descriptor.buffer_addr as *const u8, length)
+                core::slice::from_raw_parts(memory::phys_to_virt(PhysAddr::new(descriptor.buffer_addr)).as_ptr::<u8>(), length)
             };
             descriptor.status = 0;
 
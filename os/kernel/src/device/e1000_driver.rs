@@ -12,12 +12,18 @@ use nolock::queues::mpmc::bounded;
 use crate::device::e1000_descriptor::RxBufferPacket;
 use crate::device::e1000_interface::transmit_test;
 use crate::device::pit::Timer;
-use crate::{e1000_device, memory, pci_bus};
+use crate::{apic, e1000_device, interrupt_dispatcher, memory};
+use crate::interrupt::interrupt_dispatcher::InterruptVector;
+use crate::device::driver::{DeviceInstance, PciDeviceId, PciDriver};
+use crate::device::network::NetworkDevice;
+use crate::device::pci::PciBus;
+use alloc::sync::Arc;
+use pci_types::EndpointHeader;
 use super::e1000_interface::{transmit, receive_data, NetworkProtocol};
 //use pci_types::{EndpointHeader, InterruptLine};
 use super::e1000_interrupt::{map_irq_to_vector, enable_interrupts};
 use super::e1000_register::E1000Registers;
-use super::e1000_pci::{enable_device, get_e1000_device, get_interrupt_line, map_mmio_space};
+use super::e1000_pci::{disable_device, enable_device, get_interrupt_line, map_mmio_space, E1000_DEVICE_ID, E1000_VENDOR_ID};
 use super::e1000_descriptor::{set_up_rx_desc_ring, set_up_tx_desc_ring, E1000RxDescriptor, E1000TxDescriptor, enable_receive, enable_transmit};
 //use crate::alloc::rc::Rc;
 
@@ -81,14 +87,66 @@ pub struct IntelE1000Device{
     //pub tx_desc_ring: Vec<E1000TxDescriptor>,
     pub mac_address: [u8; 6],
     pub rx_buffer_consumer: bounded::scq::Receiver<RxBufferPacket>,
+    //needed to remove the interrupt handler again on shutdown
+    interrupt: E1000Interrupt,
 }
 
-impl IntelE1000Device{
+//how the interrupt handler of a device has been registered
+pub enum E1000Interrupt {
+    //msi vector, allocated for the device only
+    Msi(u8),
+    //legacy interrupt line (possibly shared) and address of the handler (see interrupt_dispatcher::handler_address)
+    Legacy(InterruptVector, usize),
+}
+
+//registered at the device manager, which calls probe for every e1000 found by the pci scan
+#[derive(Default)]
+pub struct E1000Driver {}
+
+const E1000_IDS: [PciDeviceId; 1] = [PciDeviceId::Id { vendor_id: E1000_VENDOR_ID, device_id: E1000_DEVICE_ID }];
+
+impl PciDriver for E1000Driver {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+
+    fn device_prefix(&self) -> &'static str {
+        "eth"
+    }
+
+    fn ids(&self) -> &[PciDeviceId] {
+        &E1000_IDS
+    }
+
+    fn probe(&self, pci_bus: &PciBus, device: &EndpointHeader) -> Option<DeviceInstance> {
+        info!("E1000 device found");
+        Some(DeviceInstance::Network(Arc::new(IntelE1000Device::new(pci_bus, device))))
+    }
+
+    fn remove(&self, pci_bus: &PciBus, device: &EndpointHeader, instance: DeviceInstance) {
+        if let Some(e1000) = instance.downcast::<IntelE1000Device>() {
+            e1000.shutdown(pci_bus, device);
+        }
+    }
+}
+
+impl NetworkDevice for IntelE1000Device {
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    fn transmit(&self, frame: Vec<u8>) {
+        transmit(frame, NetworkProtocol::Ethernet, self);
+    }
 
-    pub fn new() -> Self{
-        let pci_bus = pci_bus();
+    fn receive(&self) -> Option<Vec<u8>> {
+        receive_data(self).map(|packet| packet.data[..packet.length].to_vec())
+    }
+}
 
-        let e1000_device = get_e1000_device(pci_bus);
+impl IntelE1000Device{
+
+    pub fn new(pci_bus: &PciBus, e1000_device: &EndpointHeader) -> Self{
         enable_device(e1000_device, pci_bus);
     //TODO: do rest of interrupt later
         let interrupt_line = get_interrupt_line(pci_bus, e1000_device);
@@ -129,7 +187,7 @@ impl IntelE1000Device{
         //let rx_buffer_ptr = RxBufferVecToPtr::new(&received_buffer);
         
         //also registers interrupt handler and configures apic
-        map_irq_to_vector(interrupt_line, registers.clone(), rx_desc_ring, rx_buffer_producer);
+        let interrupt = map_irq_to_vector(pci_bus, e1000_device, interrupt_line, registers.clone(), rx_desc_ring, rx_buffer_producer);
         enable_interrupts(&registers);
 
         //print_tx_ring();
@@ -153,10 +211,27 @@ impl IntelE1000Device{
             //tx_desc_ring,
             mac_address,
             rx_buffer_consumer,
+            interrupt,
         }
         
 
     }
+
+    //mask all interrupts before the device stops, so that no handler runs for a device without driver
+    pub fn shutdown(&self, pci_bus: &PciBus, e1000_device: &EndpointHeader){
+        self.registers.write_imc(0xFFFFFFFF);
+        disable_device(e1000_device, pci_bus);
+
+        match self.interrupt {
+            E1000Interrupt::Msi(vector) => interrupt_dispatcher().free(vector),
+            E1000Interrupt::Legacy(vector, handler) => {
+                //mask the line only, if no other device is using it
+                if interrupt_dispatcher().unassign(vector, handler) {
+                    apic().disallow(vector);
+                }
+            }
+        }
+    }
 }
 
 fn initialize_tx_ring() {
@@ -168,10 +243,8 @@ fn initialize_tx_ring() {
     //}
 
     let phys_mem = memory::physical::alloc(1);
-    let phys_addr = phys_mem.start.start_address().as_u64();
-    //let addr_bool = phys_addr.is_aligned(16);
 
-    let transmit_ring_ptr = phys_addr as *mut E1000TxDescriptor;
+    let transmit_ring_ptr = memory::phys_to_virt(phys_mem.start.start_address()).as_mut_ptr::<E1000TxDescriptor>();
 
     
 
@@ -200,14 +273,16 @@ fn print_tx_ring() {
 }
 
 pub fn e1000_run(){
-    let device = e1000_device();
+    let Some(device) = e1000_device() else {
+        return;
+    };
     let mac = device.mac_address;
     let ethernet_header = build_ethernet_header(mac);
     let data_array: [u8; 64] = [0b01010101; 64];
     let mut data_vec = Vec::from(ethernet_header.to_bytes().to_vec());
     //IPv4 header is missing here
     data_vec.extend_from_slice(&data_array);
-    transmit_test(data_vec, NetworkProtocol::Ethernet, device);
+    transmit_test(data_vec, NetworkProtocol::Ethernet, &device);
     info!("Data sent");
 //    Timer::wait(5000);
     let mut rx_data = Vec::new();
@@ -220,7 +295,9 @@ pub fn e1000_run(){
 }
 
 pub fn e1000_large_run(){
-    let device = e1000_device();
+    let Some(device) = e1000_device() else {
+        return;
+    };
     let mac = device.mac_address;
     let ethernet_header = build_ethernet_header(mac);
     //worst case header size 142 bytes, 1522 (Maximum standard frame size) - 142 = 1380 bytes payload
@@ -264,9 +341,9 @@ pub fn e1000_large_run(){
     for i in 0..1{
         info!("Transmit number: {:?}", i);
         if(i % 2 == 0){
-            transmit_test(data_vec.clone(), NetworkProtocol::Ethernet, device);
+            transmit_test(data_vec.clone(), NetworkProtocol::Ethernet, &device);
         }else{
-            transmit_test(data_vec_2.clone(), NetworkProtocol::Ethernet, device);
+            transmit_test(data_vec_2.clone(), NetworkProtocol::Ethernet, &device);
         }
         info!("Data sent");
         let mut rx_data = Vec::new();
@@ -287,7 +364,9 @@ pub fn e1000_large_run(){
 }
 
 fn fetch_rx_data(rx_data: &mut Vec<u8>){
-    let device = e1000_device();
+    let Some(device) = e1000_device() else {
+        return;
+    };
     let received_data = receive_data(&device);
     match received_data {
         Some(data) => {
@@ -1,7 +1,7 @@
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use log::info;
-use pci_types::InterruptLine;
+use pci_types::{EndpointHeader, InterruptLine};
 use nolock::queues::mpmc::bounded;
 
 //use core::sync::atomic::{AtomicBool, Ordering};
@@ -9,10 +9,12 @@ use nolock::queues::mpmc::bounded;
 use crate::device::e1000_descriptor::{retrieve_packets, rx_ring_pop, E1000RxDescriptor, RxBufferPacket};
 use crate::device::e1000_test::{fake_transmit, fake_transmit_lbm};
 use crate::interrupt::interrupt_handler::InterruptHandler;
+use crate::interrupt::interrupt_dispatcher;
 use crate::interrupt::interrupt_dispatcher::{InterruptVector};
 use crate::{apic, interrupt_dispatcher};
 use crate::device::e1000_register::E1000Registers;
-use crate::device::e1000_driver::{IntelE1000Device, RxBufferVecToPtr, RxRingVecToPtr};
+use crate::device::pci::PciBus;
+use crate::device::e1000_driver::{E1000Interrupt, IntelE1000Device, RxBufferVecToPtr, RxRingVecToPtr};
 //use crate::device::e1000_driver::{RX_NEW_DATA, RECEIVED_BUFFER};
 //use crate::alloc::rc::Rc;
 
@@ -143,12 +145,26 @@ impl InterruptHandler for E1000InterruptHandler{
 }
 
 
-pub fn map_irq_to_vector(interrupt_line: InterruptLine, registers: E1000Registers, rx_desc_ring: Vec<E1000RxDescriptor>, rx_buffer_producer: bounded::scq::Sender<RxBufferPacket>){
+//returns the msi vector, if message signaled interrupts are used (it has to be freed, when the device is removed)
+pub fn map_irq_to_vector(pci_bus: &PciBus, e1000_device: &EndpointHeader, interrupt_line: InterruptLine, registers: E1000Registers, rx_desc_ring: Vec<E1000RxDescriptor>, rx_buffer_producer: bounded::scq::Sender<RxBufferPacket>) -> E1000Interrupt{
+    let handler: Box<dyn InterruptHandler> = Box::new(E1000InterruptHandler::new(registers, rx_desc_ring, rx_buffer_producer));
+
+    //prefer msi/msi-x if the device supports it - no io apic routing and no shared interrupt line
+    let handler = match pci_bus.allocate_msi(e1000_device, handler) {
+        Ok(vector) => {
+            info!("E1000 uses message signaled interrupts (vector {})", vector);
+            return E1000Interrupt::Msi(vector);
+        }
+        Err(handler) => handler
+    };
+
     //add 32 because first 32 are reserved for cpu exceptions
     let interrupt_vector = InterruptVector::try_from(interrupt_line as u8 + 32).unwrap();
-    let handler = Box::new(E1000InterruptHandler::new(registers, rx_desc_ring, rx_buffer_producer));
+    //the line may be shared with other devices, so the handler is remembered to remove only this one on shutdown
+    let handler_address = interrupt_dispatcher::handler_address(handler.as_ref());
     interrupt_dispatcher().assign(interrupt_vector, handler);
     apic().allow(interrupt_vector);
+    E1000Interrupt::Legacy(interrupt_vector, handler_address)
 }
 
 pub fn enable_interrupts(registers: &E1000Registers) {
@@ -10,24 +10,8 @@ use x86_64::{structures::paging::{page::PageRange, Page, PageTableFlags}, VirtAd
 
 
 
-//e1000 has functions not implemented in pci crate, so if planning to use them, return something else than endpointheader
-pub fn get_e1000_device(pci_bus: &PciBus)->&EndpointHeader{
-    const E1000_VENDOR_ID: u16 = 0x8086;
-    const E1000_DEVICE_ID: u16 = 0x100e;
-    //look for device on pci bus
-    let e1000_devices = pci_bus.search_by_ids(E1000_VENDOR_ID, E1000_DEVICE_ID);
-    //check if device is found
-    if e1000_devices.len() == 0 {
-        panic!("No e1000 device found");
-    }
-    else if e1000_devices.len() > 1{
-        panic!("Multiple e1000 devices found");
-    }
-    else{
-        info!("E1000 device found");
-        return e1000_devices[0];
-    }
-}
+pub const E1000_VENDOR_ID: u16 = 0x8086;
+pub const E1000_DEVICE_ID: u16 = 0x100e;
 
 pub fn enable_device(device: &EndpointHeader, pci_bus: &PciBus){
     device.update_command(pci_bus.config_space(), |command| {
@@ -36,6 +20,14 @@ pub fn enable_device(device: &EndpointHeader, pci_bus: &PciBus){
     info!("E1000 enabled");
 }
 
+//stops dma and legacy interrupts, used when the driver is removed
+pub fn disable_device(device: &EndpointHeader, pci_bus: &PciBus){
+    device.update_command(pci_bus.config_space(), |command| {
+        (command - CommandRegister::BUS_MASTER_ENABLE - CommandRegister::MEMORY_ENABLE) | CommandRegister::INTERRUPT_DISABLE
+    });
+    info!("E1000 disabled");
+}
+
 //ditch interrupt_pin, since e1000 only uses INTA# anyways
 pub fn get_interrupt_line(pci_bus: &PciBus, e1000_device: &EndpointHeader)->InterruptLine{
     let (_,interrupt_line) = e1000_device.interrupt(pci_bus.config_space());
@@ -71,8 +63,8 @@ pub fn map_mmio_space(pci_bus: &PciBus, e1000_device: &EndpointHeader) -> VirtAd
     }
 
     //set up mmio space
-    //mapping from virtual space to physical space - one to one?
-    let virt_mmio_address = VirtAddr::new(mmio_address);
+    //mapping into the direct mapping of physical memory
+    let virt_mmio_address = crate::memory::phys_to_virt(x86_64::PhysAddr::new(mmio_address));
     let pages = mmio_size / PAGE_SIZE as u64;
     let mmio_start_page = Page::from_start_address(virt_mmio_address).expect("e1000 mmio address seems to not be page aligned");
     //does this do the same thing?
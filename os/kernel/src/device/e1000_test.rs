@@ -1,14 +1,17 @@
 use log::info;
 use alloc::vec::Vec;
 
-use crate::e1000_device;
+use x86_64::PhysAddr;
+use crate::{e1000_device, memory};
 use super::e1000_driver::{IntelE1000Device, build_ethernet_header};
 use super::e1000_descriptor::{E1000RxDescriptor, E1000TxDescriptor};
 use super::e1000_register::E1000Registers;
 
 
 pub fn e1000_fake_test(){
-    let device = e1000_device();
+    let Some(device) = e1000_device() else {
+        return;
+    };
     let mac = device.mac_address;
 //    let ethernet_header = build_ethernet_header(mac);
     let data_array: [u8; 64] = [0b01010101; 64];
@@ -43,14 +46,14 @@ pub fn fake_transmit_lbm(rx_ring: &mut Vec<E1000RxDescriptor>, registers: &E1000
         (tx_ring_tail - 1) % (tx_ring_len / 16)
     };
     //get the descriptor that was sent
-    let tx_ring_ptr = tx_ring_address as *mut E1000TxDescriptor;
+    let tx_ring_ptr = memory::phys_to_virt(PhysAddr::new(tx_ring_address)).as_mut_ptr::<E1000TxDescriptor>();
     let sent_descriptor_ptr = unsafe { tx_ring_ptr.offset(sent_index as isize) };
     let sent_descriptor = unsafe { sent_descriptor_ptr.as_mut().unwrap_or_else(|| {
         info!("Sent descriptor is null");
         panic!();
     }) };
     //get the data that was sent
-    let sent_data = sent_descriptor.buffer_addr as *const u8;
+    let sent_data = memory::phys_to_virt(PhysAddr::new(sent_descriptor.buffer_addr)).as_ptr::<u8>();
     let sent_data_len = sent_descriptor.length as usize;
 
     //copy data to rx_ring
@@ -61,7 +64,7 @@ pub fn fake_transmit_lbm(rx_ring: &mut Vec<E1000RxDescriptor>, registers: &E1000
     for descriptor in rx_ring.iter_mut(){
         if i == rx_ring_head as usize{
             //copy data to rx_descriptor
-            let rx_data =   descriptor.buffer_addr as *mut u8;
+            let rx_data = memory::phys_to_virt(PhysAddr::new(descriptor.buffer_addr)).as_mut_ptr::<u8>();
             unsafe {
                 core::ptr::copy_nonoverlapping(sent_data, rx_data, sent_data_len);
             }
@@ -6,15 +6,17 @@ use graphic::color::{Color, INVISIBLE};
 use graphic::lfb::LFB;
 use graphic::{color, lfb};
 use stream::{InputStream, OutputStream};
+use alloc::collections::VecDeque;
+use alloc::string::String;
 use alloc::vec::Vec;
 use anstyle_parse::{Params, ParamsIter, Parser, Perform, Utf8Parser};
 use core::cell::RefCell;
 use core::mem::size_of;
 use core::ptr;
 use chrono::TimeDelta;
-use pc_keyboard::layouts::{AnyLayout, De105Key};
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{DecodedKey, KeyCode};
 use spin::Mutex;
+use syscall::{KEY_MODIFIER_ALT, KEY_MODIFIER_CTRL, KEY_MODIFIER_SHIFT};
 use crate::{built_info, efi_system_table, process_manager, ps2_devices, scheduler, speaker, timer};
 
 const CURSOR: char = if let Some(cursor) = char::from_u32(0x2588) { cursor } else { '_' };
@@ -49,7 +51,7 @@ pub struct LFBTerminal {
     cursor: Mutex<CursorState>,
     color: Mutex<ColorState>,
     parser: Mutex<RefCell<Parser>>,
-    decoder: Mutex<Keyboard<AnyLayout, ScancodeSet1>>,
+    input: Mutex<VecDeque<i16>>, // remaining characters of an escape sequence, generated by a single key
 }
 
 pub struct CursorThread {
@@ -159,33 +161,88 @@ impl OutputStream for LFBTerminal {
     }
 }
 
+/// ANSI escape sequence for a special key (as sent by xterm). Modifiers are encoded as parameter (e.g. "\x1b[1;5A" for Ctrl+Up).
+fn escape_sequence(key: KeyCode, modifiers: u8) -> Option<String> {
+    let (number, final_char) = match key {
+        KeyCode::ArrowUp => (1, 'A'),
+        KeyCode::ArrowDown => (1, 'B'),
+        KeyCode::ArrowRight => (1, 'C'),
+        KeyCode::ArrowLeft => (1, 'D'),
+        KeyCode::Home => (1, 'H'),
+        KeyCode::End => (1, 'F'),
+        KeyCode::F1 => (1, 'P'),
+        KeyCode::F2 => (1, 'Q'),
+        KeyCode::F3 => (1, 'R'),
+        KeyCode::F4 => (1, 'S'),
+        KeyCode::Insert => (2, '~'),
+        KeyCode::Delete => (3, '~'),
+        KeyCode::PageUp => (5, '~'),
+        KeyCode::PageDown => (6, '~'),
+        KeyCode::F5 => (15, '~'),
+        KeyCode::F6 => (17, '~'),
+        KeyCode::F7 => (18, '~'),
+        KeyCode::F8 => (19, '~'),
+        KeyCode::F9 => (20, '~'),
+        KeyCode::F10 => (21, '~'),
+        KeyCode::F11 => (23, '~'),
+        KeyCode::F12 => (24, '~'),
+        _ => return None
+    };
+
+    let mut parameter = 1;
+    for (flag, value) in [(KEY_MODIFIER_SHIFT, 1), (KEY_MODIFIER_ALT, 2), (KEY_MODIFIER_CTRL, 4)] {
+        if modifiers & flag != 0 {
+            parameter += value;
+        }
+    }
+
+    Some(match (parameter, final_char) {
+        (1, '~') => format!("\x1b[{}~", number),
+        (1, 'P'..='S') => format!("\x1bO{}", final_char),
+        (1, _) => format!("\x1b[{}", final_char),
+        _ => format!("\x1b[{};{}{}", number, parameter, final_char)
+    })
+}
+
 impl InputStream for LFBTerminal {
     fn read_byte(&self) -> i16 {
-        let keyboard = ps2_devices().keyboard();
-        let read_byte;
+        if let Some(c) = self.input.lock().pop_front() {
+            return c;
+        }
 
+        let keyboard = ps2_devices().keyboard();
         loop {
-            let mut decoder = self.decoder.lock();
-            let scancode = keyboard.read_byte();
-            if scancode == -1 {
-                panic!("Keyboard stream closed!");
+            let (event, decoded) = keyboard.read_event();
+            if !event.pressed {
+                continue;
             }
 
-            if let Ok(Some(event)) = decoder.add_byte(scancode as u8) {
-                if let Some(key) = decoder.process_keyevent(event) {
-                    match key {
-                        DecodedKey::Unicode(c) => {
-                            read_byte = c;
-                            break;
-                        }
-                        _ => {}
+            // Special keys are sent as escape sequences, which are not echoed (the parser would interpret them)
+            let key = match decoded {
+                Some(DecodedKey::RawKey(key)) => key,
+                Some(DecodedKey::Unicode('\x7f')) => KeyCode::Delete,
+                Some(DecodedKey::Unicode(c)) => {
+                    if c >= ' ' || c == '\n' || c == '\t' || c == '\x08' {
+                        self.write_str(c.encode_utf8(&mut [0; 4]));
+                    }
+
+                    // Alt prefixes the character with escape (like the "meta sends escape" mode of xterm)
+                    if event.modifiers & KEY_MODIFIER_ALT != 0 {
+                        self.input.lock().push_back(c as i16);
+                        return 0x1b;
                     }
+
+                    return c as i16;
                 }
+                None => continue
+            };
+
+            if let Some(sequence) = escape_sequence(key, event.modifiers) {
+                let mut input = self.input.lock();
+                input.extend(sequence.bytes().skip(1).map(|b| b as i16));
+                return 0x1b;
             }
         }
-
-        self.write_byte(read_byte as u8);
-        return read_byte as i16;
     }
 }
 
@@ -207,7 +264,7 @@ impl LFBTerminal {
             cursor: Mutex::new(CursorState::new()),
             color: Mutex::new(ColorState::new()),
             parser: Mutex::new(RefCell::new(Parser::<Utf8Parser>::new())),
-            decoder: Mutex::new(Keyboard::new(ScancodeSet1::new(), AnyLayout::De105Key(De105Key), HandleControl::Ignore))
+            input: Mutex::new(VecDeque::new())
         }
     }
 
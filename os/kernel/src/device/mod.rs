@@ -1,5 +1,6 @@
 pub mod apic;
 pub mod pit;
+pub mod tsc;
 pub mod ps2;
 pub mod qemu_cfg;
 pub mod speaker;
@@ -8,6 +9,14 @@ pub mod terminal;
 pub mod lfb_terminal;
 pub mod serial;
 pub mod pci;
+pub mod driver;
+pub mod network;
+pub mod block;
+pub mod ramdisk;
+pub mod virtio;
+pub mod virtio_net;
+pub mod virtio_blk;
+pub mod ahci;
 pub mod e1000_pci;
 pub mod e1000_driver;
 pub mod e1000_interrupt;
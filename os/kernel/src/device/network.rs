@@ -0,0 +1,14 @@
+use alloc::vec::Vec;
+use core::any::Any;
+
+/// Common interface of all network cards (e.g. e1000 and virtio-net).
+/// The system calls for sending and receiving data use the first network device (eth0).
+pub trait NetworkDevice: Any + Send + Sync {
+    fn mac_address(&self) -> [u8; 6];
+
+    /// Send an ethernet frame (including the ethernet header)
+    fn transmit(&self, frame: Vec<u8>);
+
+    /// Take the oldest received ethernet frame. Returns `None`, if no frame has been received.
+    fn receive(&self) -> Option<Vec<u8>>;
+}
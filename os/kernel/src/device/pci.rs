@@ -1,20 +1,44 @@
+use acpi::mcfg::PciConfigRegions;
+use alloc::boxed::Box;
 use alloc::vec::Vec;
-use log::{info};
-use pci_types::{BaseClass, ConfigRegionAccess, EndpointHeader, HeaderType, PciAddress, PciHeader, PciPciBridgeHeader, SubClass};
+use core::ops::RangeInclusive;
+use core::ptr;
+use log::{info, warn};
+use pci_types::{Bar, BaseClass, CommandRegister, ConfigRegionAccess, EndpointHeader, HeaderType, PciAddress, PciHeader, PciPciBridgeHeader, SubClass};
+use pci_types::capability::{MsiCapability, MsixCapability, MultipleMessageSupport, PciCapability, TriggerMode};
 use spin::Mutex;
+use syscall::{PciDeviceInfo, PCI_MAX_CAPABILITIES};
 use x86_64::instructions::port::{Port, PortWriteOnly};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::PhysAddr;
+use crate::{acpi_tables, apic, interrupt_dispatcher, memory, process_manager};
+use crate::interrupt::interrupt_handler::InterruptHandler;
+use crate::memory::{MemorySpace, PAGE_SIZE};
 
 const MAX_DEVICES_PER_BUS: u8 = 32;
 const MAX_FUNCTIONS_PER_DEVICE: u8 = 8;
 const INVALID: u16 = 0xffff;
 
+// Size of the configuration space of a single function (legacy port access only reaches the first 256 bytes)
+const CONFIG_SPACE_SIZE: u16 = 4096;
+const LEGACY_CONFIG_SPACE_SIZE: u16 = 256;
+const EXTENDED_CAPABILITIES_OFFSET: u16 = 0x100;
+
+// Each MSI-X table entry consists of the message address (low and high), the message data and the vector control
+const MSIX_TABLE_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_CONTROL_MASKED: u32 = 1;
+
 pub struct PciBus {
     config_space: ConfigurationSpace,
     devices: Vec<EndpointHeader>
 }
 
+/// Configuration space access, either memory mapped via ECAM (PCI Express) or via the legacy I/O ports.
+/// ECAM regions are taken from the ACPI MCFG table. Buses not covered by any region are accessed via the ports.
 pub struct ConfigurationSpace {
-    ports: Mutex<ConfigurationPorts>
+    ports: Mutex<ConfigurationPorts>,
+    ecam_regions: Vec<EcamRegion>
 }
 
 struct ConfigurationPorts {
@@ -22,15 +46,89 @@ struct ConfigurationPorts {
     data_port: Port<u32>,
 }
 
+/// Memory mapped configuration space for the buses of one PCI segment group.
+/// Each function gets 4 KiB, addressed by `base + (bus << 20 | device << 15 | function << 12)`.
+struct EcamRegion {
+    segment: u16,
+    buses: RangeInclusive<u8>,
+    base: u64
+}
+
+/// Entry in the extended capability list of a PCI Express function (starting at offset 0x100)
+#[derive(Copy, Clone, Debug)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16
+}
+
 impl ConfigurationPorts {
     const fn new() -> Self {
         Self { address_port: PortWriteOnly::new(0xcf8), data_port: Port::new(0xcfc) }
     }
 }
 
+impl EcamRegion {
+    fn address(&self, address: PciAddress, offset: u16) -> Option<*mut u32> {
+        if address.segment() != self.segment || !self.buses.contains(&address.bus()) {
+            return None;
+        }
+
+        let function_offset = (((address.bus() - self.buses.start()) as u64) << 20)
+            | (address.device() as u64) << 15
+            | (address.function() as u64) << 12;
+
+        Some((self.base + function_offset + (offset & 0xffc) as u64) as *mut u32)
+    }
+}
+
 impl ConfigurationSpace {
-    const fn new() -> Self {
-        Self { ports: Mutex::new(ConfigurationPorts::new()) }
+    fn new() -> Self {
+        Self { ports: Mutex::new(ConfigurationPorts::new()), ecam_regions: Self::map_ecam_regions() }
+    }
+
+    /// Read the ECAM regions from the MCFG table and map them into the direct mapping of the kernel address space.
+    fn map_ecam_regions() -> Vec<EcamRegion> {
+        let tables = acpi_tables().lock();
+        let regions = match PciConfigRegions::new(&tables) {
+            Ok(regions) => regions,
+            Err(_) => {
+                warn!("MCFG table not available, falling back to legacy PCI configuration space access");
+                return Vec::new();
+            }
+        };
+
+        let address_space = process_manager().read().kernel_process().unwrap().address_space();
+        regions.iter().map(|entry| {
+            let bus_count = (*entry.bus_range.end() as usize - *entry.bus_range.start() as usize) + 1;
+            let size = bus_count << 20;
+            info!("Found ECAM region for segment [{}] (Buses: [{}-{}], Address: [0x{:x}])", entry.segment_group, entry.bus_range.start(), entry.bus_range.end(), entry.physical_address);
+
+            let start_page = Page::from_start_address(memory::phys_to_virt(PhysAddr::new(entry.physical_address as u64))).expect("ECAM region is not page aligned");
+            address_space.map(PageRange { start: start_page, end: start_page + (size / PAGE_SIZE) as u64 }, MemorySpace::Kernel, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE);
+
+            EcamRegion { segment: entry.segment_group, buses: entry.bus_range, base: start_page.start_address().as_u64() }
+        }).collect()
+    }
+
+    /// Segment groups and their first bus number. Without ECAM, only segment 0 is reachable.
+    fn segments(&self) -> Vec<(u16, u8)> {
+        if self.ecam_regions.is_empty() {
+            return Vec::from([(0, 0)]);
+        }
+
+        self.ecam_regions.iter()
+            .map(|region| (region.segment, *region.buses.start()))
+            .collect()
+    }
+
+    /// Check if the extended configuration space (offsets 0x100 to 0xfff) of a function is accessible
+    pub fn has_extended_config_space(&self, address: PciAddress) -> bool {
+        self.ecam_address(address, 0).is_some()
+    }
+
+    fn ecam_address(&self, address: PciAddress, offset: u16) -> Option<*mut u32> {
+        self.ecam_regions.iter().find_map(|region| region.address(address, offset))
     }
 
     unsafe fn prepare_access(ports: &mut ConfigurationPorts, address: PciAddress, offset: u16) {
@@ -45,11 +143,22 @@ impl ConfigurationSpace {
 }
 
 impl ConfigRegionAccess for ConfigurationSpace {
-    fn function_exists(&self, _address: PciAddress) -> bool {
-        false
+    fn function_exists(&self, address: PciAddress) -> bool {
+        let vendor_id = unsafe { self.read(address, 0) } as u16;
+        vendor_id != INVALID
     }
 
     unsafe fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        assert!(offset < CONFIG_SPACE_SIZE);
+        if let Some(register) = self.ecam_address(address, offset) {
+            return unsafe { ptr::read_volatile(register) };
+        }
+
+        // Extended configuration space is not reachable via ports; reads behave like a non-existent register
+        if offset >= LEGACY_CONFIG_SPACE_SIZE {
+            return 0xffffffff;
+        }
+
         let mut ports = self.ports.lock();
 
         unsafe {
@@ -59,6 +168,16 @@ impl ConfigRegionAccess for ConfigurationSpace {
     }
 
     unsafe fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        assert!(offset < CONFIG_SPACE_SIZE);
+        if let Some(register) = self.ecam_address(address, offset) {
+            unsafe { ptr::write_volatile(register, value); }
+            return;
+        }
+
+        if offset >= LEGACY_CONFIG_SPACE_SIZE {
+            return;
+        }
+
         let mut ports = self.ports.lock();
 
         unsafe {
@@ -72,24 +191,25 @@ impl PciBus {
     pub fn scan() -> Self {
         let mut pci = Self { config_space: ConfigurationSpace::new(), devices: Vec::new() };
 
-        let root = PciHeader::new(PciAddress::new(0x8000, 0, 0, 0));
-        if root.has_multiple_functions(&pci.config_space) {
-            info!("Multiple PCI host controllers detected");
-            for i in 0..MAX_FUNCTIONS_PER_DEVICE {
-                let address = PciAddress::new(0x8000, 0, 0, i);
-                let header = PciHeader::new(address);
-                if header.id(&pci.config_space).0 == INVALID {
-                    break;
-                }
+        for (segment, bus) in pci.config_space.segments() {
+            let root = PciHeader::new(PciAddress::new(segment, bus, 0, 0));
+            if root.has_multiple_functions(&pci.config_space) {
+                info!("Multiple PCI host controllers detected in segment [{}]", segment);
+                // Each function of the root device is a host controller, responsible for the bus with the same number
+                for i in 0..MAX_FUNCTIONS_PER_DEVICE {
+                    let address = PciAddress::new(segment, bus, 0, i);
+                    if !pci.config_space.function_exists(address) {
+                        break;
+                    }
 
-                pci.scan_bus(address);
+                    pci.scan_bus(PciAddress::new(segment, bus + i, 0, 0));
+                }
+            } else {
+                info!("Single PCI host controller detected in segment [{}]", segment);
+                pci.scan_bus(PciAddress::new(segment, bus, 0, 0));
             }
-        } else {
-            info!("Single PCI host controller detected");
-            pci.scan_bus(PciAddress::new(0x8000, 0, 0, 0));
         }
 
-
         return pci;
     }
 
@@ -98,6 +218,15 @@ impl PciBus {
         &self.config_space
     }
 
+    /// All found endpoints (bridges are not included)
+    pub fn devices(&self) -> &[EndpointHeader] {
+        &self.devices
+    }
+
+    pub fn device(&self, address: PciAddress) -> Option<&EndpointHeader> {
+        self.devices.iter().find(|device| device.header().address() == address)
+    }
+
     pub fn search_by_ids(&self, vendor_id: u16, device_id: u16) -> Vec<&EndpointHeader> {
         self.devices.iter()
             .filter(|device| device.header().id(self.config_space()) == (vendor_id, device_id))
@@ -113,6 +242,154 @@ impl PciBus {
             .collect()
     }
 
+    /// Walk the standard capability list of a device (e.g. MSI, MSI-X, power management, PCI Express)
+    pub fn capabilities(&self, device: &EndpointHeader) -> Vec<PciCapability> {
+        device.capabilities(self.config_space()).collect()
+    }
+
+    /// Walk the extended capability list of a PCI Express function.
+    /// Returns an empty list, if the extended configuration space is not accessible (no ECAM).
+    pub fn extended_capabilities(&self, address: PciAddress) -> Vec<ExtendedCapability> {
+        let mut capabilities = Vec::new();
+        if !self.config_space.has_extended_config_space(address) {
+            return capabilities;
+        }
+
+        let mut offset = EXTENDED_CAPABILITIES_OFFSET;
+        // Each entry takes at least 4 bytes, which bounds the walk even for a malformed (cyclic) list
+        let max_entries = (CONFIG_SPACE_SIZE - EXTENDED_CAPABILITIES_OFFSET) / 4;
+        for _ in 0..max_entries {
+            let header = unsafe { self.config_space.read(address, offset) };
+            if header == 0 || header == 0xffffffff {
+                break;
+            }
+
+            capabilities.push(ExtendedCapability { id: header as u16, version: ((header >> 16) & 0xf) as u8, offset });
+
+            offset = ((header >> 20) & 0xffc) as u16;
+            if offset < EXTENDED_CAPABILITIES_OFFSET {
+                break;
+            }
+        }
+
+        capabilities
+    }
+
+    /// Let a device signal its interrupts via MSI-X or MSI (preferring MSI-X), instead of its legacy interrupt line.
+    /// The handler is assigned to a newly allocated vector, which is returned.
+    /// If the device supports neither MSI-X nor MSI, or no vector is free, the handler is handed back,
+    /// so that the caller can fall back to the legacy interrupt line.
+    pub fn allocate_msi(&self, device: &EndpointHeader, handler: Box<dyn InterruptHandler>) -> Result<u8, Box<dyn InterruptHandler>> {
+        let capabilities = self.capabilities(device);
+        let msix = capabilities.iter().find_map(|capability| match capability {
+            PciCapability::MsiX(msix) => Some(*msix),
+            _ => None
+        });
+        let msi = capabilities.iter().find_map(|capability| match capability {
+            PciCapability::Msi(msi) => Some(*msi),
+            _ => None
+        });
+
+        // MSI-X can only be used, if its table lies in a memory BAR
+        let msix_table = msix.and_then(|msix| self.msix_table(device, &msix));
+        if msix_table.is_none() && msi.is_none() {
+            return Err(handler);
+        }
+
+        let vector = interrupt_dispatcher().allocate(handler)?;
+
+        if let (Some(mut msix), Some(table)) = (msix, msix_table) {
+            self.program_msix(&mut msix, table, vector);
+            info!("Enabled MSI-X for PCI device on bus [{}] (Vector: [{}])", device.header().address().bus(), vector);
+        } else if let Some(msi) = msi {
+            self.program_msi(&msi, vector);
+            info!("Enabled MSI for PCI device on bus [{}] (Vector: [{}])", device.header().address().bus(), vector);
+        }
+
+        // Messages replace the legacy interrupt line, which must not fire anymore
+        device.update_command(self.config_space(), |command| command | CommandRegister::INTERRUPT_DISABLE | CommandRegister::BUS_MASTER_ENABLE);
+
+        Ok(vector)
+    }
+
+    /// Map a memory BAR of a device (uncached) into the direct mapping of physical memory and return its virtual address.
+    /// Returns `None`, if the BAR does not exist or is an I/O port BAR.
+    pub fn map_memory_bar(&self, device: &EndpointHeader, index: u8) -> Option<u64> {
+        let (address, size) = match device.bar(index, self.config_space())? {
+            Bar::Memory32 { address, size, .. } => (address as u64, size as u64),
+            Bar::Memory64 { address, size, .. } => (address, size),
+            Bar::Io { .. } => return None
+        };
+
+        let address = memory::phys_to_virt(PhysAddr::new(address));
+        let start_page = Page::containing_address(address);
+        let end_page = Page::containing_address(address + (size - 1)) + 1;
+
+        let address_space = process_manager().read().kernel_process().unwrap().address_space();
+        address_space.map(PageRange { start: start_page, end: end_page }, MemorySpace::Kernel, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE);
+
+        Some(address.as_u64())
+    }
+
+    /// Map the MSI-X table of a device and return its address
+    fn msix_table(&self, device: &EndpointHeader, msix: &MsixCapability) -> Option<u64> {
+        let bar_address = self.map_memory_bar(device, msix.table_bar())?;
+        Some(bar_address + msix.table_offset() as u64)
+    }
+
+    /// Route the first MSI-X table entry to `vector` and mask all others
+    fn program_msix(&self, msix: &mut MsixCapability, table: u64, vector: u8) {
+        for i in 0..msix.table_size() as u64 {
+            let entry = (table + i * MSIX_TABLE_ENTRY_SIZE) as *mut u32;
+
+            unsafe {
+                if i == 0 {
+                    entry.write_volatile(apic().msi_address());
+                    entry.add(1).write_volatile(0);
+                    entry.add(2).write_volatile(vector as u32); // Edge triggered, fixed delivery mode
+                    entry.add(3).write_volatile(0);
+                } else {
+                    entry.add(3).write_volatile(MSIX_VECTOR_CONTROL_MASKED);
+                }
+            }
+        }
+
+        msix.set_enabled(true, self.config_space());
+    }
+
+    fn program_msi(&self, msi: &MsiCapability, vector: u8) {
+        msi.set_message_info(apic().msi_address(), vector, TriggerMode::Edge, self.config_space());
+        msi.set_multiple_message_enable(MultipleMessageSupport::Int1, self.config_space());
+        msi.set_enabled(true, self.config_space());
+    }
+
+    /// Information about all found devices, as returned by the `PciList` system call
+    pub fn device_infos(&self) -> Vec<PciDeviceInfo> {
+        self.devices.iter().map(|device| {
+            let header = device.header();
+            let address = header.address();
+            let (vendor_id, device_id) = header.id(self.config_space());
+            let (revision, class, subclass, interface) = header.revision_and_class(self.config_space());
+
+            let mut info = PciDeviceInfo {
+                segment: address.segment(), bus: address.bus(), device: address.device(), function: address.function(),
+                vendor_id, device_id, class, subclass, interface, revision, ..PciDeviceInfo::default()
+            };
+
+            for capability in self.capabilities(device).iter().take(PCI_MAX_CAPABILITIES) {
+                info.capabilities[info.capability_count] = capability_id(capability);
+                info.capability_count += 1;
+            }
+
+            for capability in self.extended_capabilities(address).iter().take(PCI_MAX_CAPABILITIES) {
+                info.extended_capabilities[info.extended_capability_count] = capability.id;
+                info.extended_capability_count += 1;
+            }
+
+            info
+        }).collect()
+    }
+
     fn scan_bus(&mut self, address: PciAddress) {
         assert_eq!(address.device(), 0);
         assert_eq!(address.function(), 0);
@@ -125,19 +402,17 @@ impl PciBus {
     fn check_device(&mut self, address: PciAddress) {
         assert_eq!(address.function(), 0);
 
-        let device = PciHeader::new(address);
-        let id = device.id(self.config_space());
-        if id.0 == INVALID {
+        if !self.config_space.function_exists(address) {
             return;
         }
 
         self.check_function(address);
 
+        let device = PciHeader::new(address);
         if device.has_multiple_functions(self.config_space()) {
             for i in 1..MAX_FUNCTIONS_PER_DEVICE {
                 let address = PciAddress::new(address.segment(), address.bus(), address.device(), i);
-                let device = PciHeader::new(address);
-                if device.id(self.config_space()).0 == INVALID {
+                if !self.config_space.function_exists(address) {
                     break;
                 }
 
@@ -153,10 +428,35 @@ impl PciBus {
         if device.header_type(self.config_space()) == HeaderType::PciPciBridge {
             info!("Found PCI-to-PCI bridge on bus [{}]", address.bus());
             let bridge = PciPciBridgeHeader::from_header(device, self.config_space()).unwrap();
-            self.scan_bus(PciAddress::new(0x8000, bridge.secondary_bus_number(self.config_space()), 0 , 0));
+            self.scan_bus(PciAddress::new(address.segment(), bridge.secondary_bus_number(self.config_space()), 0 , 0));
         } else {
-            info!("Found PCI device [0x{:0>4x}:0x{:0>4x}] on bus [{}]", id.0, id.1, address.bus());
-            self.devices.push(EndpointHeader::from_header(device, self.config_space()).unwrap());
+            let endpoint = EndpointHeader::from_header(device, self.config_space()).unwrap();
+            info!("Found PCI device [0x{:0>4x}:0x{:0>4x}] on bus [{}] (Capabilities: {:?}, Extended capabilities: {:?})", id.0, id.1, address.bus(),
+                self.capabilities(&endpoint).iter().map(capability_id).collect::<Vec<u8>>(),
+                self.extended_capabilities(address).iter().map(|capability| capability.id).collect::<Vec<u16>>());
+            self.devices.push(endpoint);
         }
     }
-}
\ No newline at end of file
+}
+
+fn capability_id(capability: &PciCapability) -> u8 {
+    match capability {
+        PciCapability::PowerManagement(_) => 0x01,
+        PciCapability::AcceleratedGraphicsPort(_) => 0x02,
+        PciCapability::VitalProductData(_) => 0x03,
+        PciCapability::SlotIdentification(_) => 0x04,
+        PciCapability::Msi(_) => 0x05,
+        PciCapability::CompactPCIHotswap(_) => 0x06,
+        PciCapability::PciX(_) => 0x07,
+        PciCapability::HyperTransport(_) => 0x08,
+        PciCapability::Vendor(_) => 0x09,
+        PciCapability::DebugPort(_) => 0x0a,
+        PciCapability::CompactPCICentralResourceControl(_) => 0x0b,
+        PciCapability::PciHotPlugControl(_) => 0x0c,
+        PciCapability::BridgeSubsystemVendorId(_) => 0x0d,
+        PciCapability::AGP3(_) => 0x0e,
+        PciCapability::PciExpress(_) => 0x10,
+        PciCapability::MsiX(_) => 0x11,
+        PciCapability::Unknown { id, .. } => *id
+    }
+}
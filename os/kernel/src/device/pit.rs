@@ -6,7 +6,7 @@ use core::arch::asm;
 use core::hint::spin_loop;
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortWriteOnly};
-use crate::{apic, interrupt_dispatcher, timer};
+use crate::{apic, interrupt_dispatcher, timer, tsc};
 
 pub const BASE_FREQUENCY: usize = 1193182;
 
@@ -78,8 +78,16 @@ impl Timer {
         apic().allow(InterruptVector::Pit);
     }
 
+    /// System time in nanoseconds, taken from the TSC (if calibrated) or counted in PIT interrupts otherwise.
+    pub fn systime_ns(&self) -> usize {
+        match tsc() {
+            Some(tsc) => tsc.nanos() as usize,
+            None => self.systime_ns
+        }
+    }
+
     pub fn systime_ms(&self) -> usize {
-        return self.systime_ns / 1000000;
+        return self.systime_ns() / 1000000;
     }
 
     pub fn wait(ms: usize) {
@@ -1,52 +1,317 @@
 use crate::interrupt::interrupt_dispatcher::InterruptVector;
 use crate::interrupt::interrupt_handler::InterruptHandler;
+use crate::sync::mutex::Mutex as BlockingMutex;
+use crate::sync::wait_queue::WaitQueue;
 use stream::InputStream;
 use alloc::boxed::Box;
 use log::info;
 use nolock::queues::mpmc::bounded::scq::{Receiver, Sender};
 use nolock::queues::{mpmc, DequeueError};
 use ps2::flags::{ControllerConfigFlags, KeyboardLedFlags};
-use ps2::{Controller, KeyboardType};
-use ps2::error::{ControllerError, KeyboardError};
+use ps2::{Controller, KeyboardType, MouseType};
+use ps2::error::{ControllerError, KeyboardError, MouseError};
+use pc_keyboard::{DecodedKey, EventDecoder, HandleControl, KeyState, ScancodeSet, ScancodeSet1};
+use pc_keyboard::layouts::{AnyLayout, De105Key, Us104Key};
 use spin::Mutex;
+use syscall::{KeyCode, KeyEvent, KeyboardLayout, KEY_MODIFIER_ALT, KEY_MODIFIER_ALT_GR, KEY_MODIFIER_CAPS_LOCK, KEY_MODIFIER_CTRL, KEY_MODIFIER_NUM_LOCK, KEY_MODIFIER_SHIFT};
+use syscall::{MouseEvent, MOUSE_BUTTON_4, MOUSE_BUTTON_5, MOUSE_BUTTON_LEFT, MOUSE_BUTTON_MIDDLE, MOUSE_BUTTON_RIGHT};
 use crate::{apic, interrupt_dispatcher, ps2_devices};
 
 const KEYBOARD_BUFFER_CAPACITY: usize = 128;
+const MOUSE_BUFFER_CAPACITY: usize = 128;
+
+// Bits in the first byte of a mouse packet
+const MOUSE_PACKET_LEFT: u8 = 0x01;
+const MOUSE_PACKET_RIGHT: u8 = 0x02;
+const MOUSE_PACKET_MIDDLE: u8 = 0x04;
+const MOUSE_PACKET_ALWAYS_ONE: u8 = 0x08;
+const MOUSE_PACKET_X_SIGN: u8 = 0x10;
+const MOUSE_PACKET_Y_SIGN: u8 = 0x20;
+const MOUSE_PACKET_X_OVERFLOW: u8 = 0x40;
+const MOUSE_PACKET_Y_OVERFLOW: u8 = 0x80;
 
 pub struct PS2 {
     controller: Mutex<Controller>,
     keyboard: Keyboard,
+    mouse: Mouse,
 }
 
 pub struct Keyboard {
     buffer: (Receiver<u8>, Sender<u8>),
+    readers: WaitQueue, // threads waiting for input
+    decoder: BlockingMutex<KeyDecoder>, // held while waiting, so that the scancodes of a key are not split between readers
+    layout: Mutex<Option<AnyLayout>> // selected by `set_layout()` and applied by the next reader
+}
+
+/// Translates scancodes into key events, using the selected layout
+struct KeyDecoder {
+    scancodes: ScancodeSet1,
+    events: EventDecoder<AnyLayout>,
+    modifiers: ModifierKeys
+}
+
+/// State of the modifier keys. The decoder tracks them as well, but does not expose its state (and ignores the left alt key).
+struct ModifierKeys {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    alt_gr: bool,
+    caps_lock: bool,
+    num_lock: bool,
+    pause: bool, // the pause key sends the scancodes of a hidden control key and num lock, which must not toggle num lock
 }
 
 #[derive(Default)]
 struct KeyboardInterruptHandler;
 
+pub struct Mouse {
+    buffer: (Receiver<MouseEvent>, Sender<MouseEvent>),
+    readers: WaitQueue, // threads waiting for events
+    available: bool, // the second port is connected to a working mouse
+    mouse_type: MouseType // determines the packet format (standard mice send 3 bytes, mice with a scroll wheel send 4 bytes)
+}
+
+/// Collects the bytes of a packet, since the mouse triggers an interrupt for each byte
+#[derive(Default)]
+struct MouseInterruptHandler {
+    packet: [u8; 4],
+    received: usize
+}
+
 impl Keyboard {
     fn new(buffer_cap: usize) -> Self {
         Self {
             buffer: mpmc::bounded::scq::queue(buffer_cap),
+            readers: WaitQueue::new(),
+            decoder: BlockingMutex::new(KeyDecoder {
+                scancodes: ScancodeSet1::new(),
+                events: EventDecoder::new(AnyLayout::De105Key(De105Key), HandleControl::MapLettersToUnicode),
+                modifiers: ModifierKeys::new()
+            }),
+            layout: Mutex::new(None)
+        }
+    }
+
+    /// Sleep until a key is pressed or released and decode it with the selected layout.
+    /// Besides the event, the meaning of the key in the layout is returned for presses
+    /// (e.g. the keys on the numpad act as arrow keys, if num lock is off).
+    pub fn read_event(&self) -> (KeyEvent, Option<DecodedKey>) {
+        let mut decoder = self.decoder.lock();
+        loop {
+            let scancode = self.read_byte();
+            if scancode == -1 {
+                panic!("Keyboard stream closed!");
+            }
+
+            if let Some(layout) = self.layout.lock().take() {
+                decoder.events.change_layout(layout);
+            }
+
+            if let Ok(Some(event)) = decoder.scancodes.advance_state(scancode as u8) {
+                decoder.modifiers.update(event.code, event.state);
+
+                let key = key_code(event.code);
+                let pressed = event.state != KeyState::Up;
+                let modifiers = decoder.modifiers.flags();
+                let decoded = decoder.events.process_keyevent(event);
+
+                if let Some(key) = key {
+                    let unicode = match decoded {
+                        Some(DecodedKey::Unicode(c)) if pressed => c as u32,
+                        _ => 0
+                    };
+
+                    return (KeyEvent { key, pressed, modifiers, unicode }, decoded);
+                }
+            }
         }
     }
 
+    /// Select the layout for decoding keys. It takes effect with the next scancode,
+    /// since the decoder may be locked by a reader waiting for input.
+    pub fn set_layout(&self, layout: KeyboardLayout) {
+        let layout = match layout {
+            KeyboardLayout::Us => AnyLayout::Us104Key(Us104Key),
+            KeyboardLayout::German => AnyLayout::De105Key(De105Key)
+        };
+
+        *self.layout.lock() = Some(layout);
+    }
+
     pub fn plugin(&self) {
         interrupt_dispatcher().assign(InterruptVector::Keyboard, Box::new(KeyboardInterruptHandler::default()));
         apic().allow(InterruptVector::Keyboard);
     }
 }
 
+impl ModifierKeys {
+    const fn new() -> Self {
+        // Num lock is on by default (as assumed by the decoder)
+        Self { left_shift: false, right_shift: false, left_ctrl: false, right_ctrl: false, alt: false, alt_gr: false, caps_lock: false, num_lock: true, pause: false }
+    }
+
+    fn update(&mut self, key: pc_keyboard::KeyCode, state: KeyState) {
+        let pressed = state == KeyState::Down;
+        match key {
+            pc_keyboard::KeyCode::LShift => self.left_shift = pressed,
+            pc_keyboard::KeyCode::RShift => self.right_shift = pressed,
+            pc_keyboard::KeyCode::LControl => self.left_ctrl = pressed,
+            pc_keyboard::KeyCode::RControl => self.right_ctrl = pressed,
+            pc_keyboard::KeyCode::LAlt => self.alt = pressed,
+            pc_keyboard::KeyCode::RAltGr => self.alt_gr = pressed,
+            pc_keyboard::KeyCode::RControl2 => self.pause = pressed,
+            pc_keyboard::KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            pc_keyboard::KeyCode::NumpadLock if pressed && !self.pause => self.num_lock = !self.num_lock,
+            _ => {}
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        for (active, flag) in [(self.left_shift || self.right_shift, KEY_MODIFIER_SHIFT), (self.left_ctrl || self.right_ctrl, KEY_MODIFIER_CTRL),
+                               (self.alt, KEY_MODIFIER_ALT), (self.alt_gr, KEY_MODIFIER_ALT_GR),
+                               (self.caps_lock, KEY_MODIFIER_CAPS_LOCK), (self.num_lock, KEY_MODIFIER_NUM_LOCK)] {
+            if active {
+                flags |= flag;
+            }
+        }
+
+        return flags;
+    }
+}
+
+/// Translate a key code of the decoder into a key code of the system call interface.
+/// Returns `None` for codes, that are only used internally by the decoder (e.g. the hidden control key of the pause key).
+fn key_code(code: pc_keyboard::KeyCode) -> Option<KeyCode> {
+    macro_rules! map_keys {
+        ($($key:ident),*) => {
+            match code {
+                $(pc_keyboard::KeyCode::$key => Some(KeyCode::$key),)*
+                _ => None
+            }
+        }
+    }
+
+    map_keys!(
+        Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, PrintScreen, SysRq, ScrollLock, PauseBreak,
+        Oem8, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, OemMinus, OemPlus, Backspace,
+        Insert, Home, PageUp, NumpadLock, NumpadDivide, NumpadMultiply, NumpadSubtract,
+        Tab, Q, W, E, R, T, Y, U, I, O, P, Oem4, Oem6, Oem5, Oem7, Delete, End, PageDown, Numpad7, Numpad8, Numpad9, NumpadAdd,
+        CapsLock, A, S, D, F, G, H, J, K, L, Oem1, Oem3, Return, Numpad4, Numpad5, Numpad6,
+        LShift, Z, X, C, V, B, N, M, OemComma, OemPeriod, Oem2, RShift, ArrowUp, Numpad1, Numpad2, Numpad3, NumpadEnter,
+        LControl, LWin, LAlt, Spacebar, RAltGr, RWin, Apps, RControl, ArrowLeft, ArrowDown, ArrowRight, Numpad0, NumpadPeriod,
+        Oem9, Oem10, Oem11, Oem12, Oem13,
+        PrevTrack, NextTrack, Mute, Calculator, Play, Stop, VolumeDown, VolumeUp, WWWHome
+    )
+}
+
+impl Mouse {
+    fn new(buffer_cap: usize) -> Self {
+        Self {
+            buffer: mpmc::bounded::scq::queue(buffer_cap),
+            readers: WaitQueue::new(),
+            available: false,
+            mouse_type: MouseType::Standard,
+        }
+    }
+
+    pub fn plugin(&self) {
+        if self.available {
+            interrupt_dispatcher().assign(InterruptVector::Mouse, Box::new(MouseInterruptHandler::default()));
+            apic().allow(InterruptVector::Mouse);
+        }
+    }
+
+    /// Sleep until the mouse has been moved or a button has been pressed or released.
+    /// Returns `None`, if there is no mouse.
+    pub fn read_event(&self) -> Option<MouseEvent> {
+        if !self.available {
+            return None;
+        }
+
+        let mut event = None;
+        self.readers.wait_while(|| match self.buffer.0.try_dequeue() {
+            Ok(received) => {
+                event = Some(received);
+                false
+            }
+            Err(DequeueError::Closed) => false,
+            Err(_) => true
+        });
+
+        return event;
+    }
+
+    /// Get the next event without waiting
+    pub fn try_read_event(&self) -> Option<MouseEvent> {
+        self.buffer.0.try_dequeue().ok()
+    }
+
+    fn packet_size(&self) -> usize {
+        match self.mouse_type {
+            MouseType::IntelliMouse | MouseType::IntelliMouseExplorer => 4,
+            _ => 3
+        }
+    }
+
+    fn decode_packet(&self, packet: &[u8]) -> MouseEvent {
+        let flags = packet[0];
+
+        // Movements are 9 bit two's complement values (the sign is stored in the first byte)
+        let dx = match flags & MOUSE_PACKET_X_OVERFLOW {
+            0 => packet[1] as i16 - if flags & MOUSE_PACKET_X_SIGN != 0 { 0x100 } else { 0 },
+            _ => 0
+        };
+        let dy = match flags & MOUSE_PACKET_Y_OVERFLOW {
+            0 => packet[2] as i16 - if flags & MOUSE_PACKET_Y_SIGN != 0 { 0x100 } else { 0 },
+            _ => 0
+        };
+
+        let mut buttons = 0;
+        for (bit, button) in [(MOUSE_PACKET_LEFT, MOUSE_BUTTON_LEFT), (MOUSE_PACKET_RIGHT, MOUSE_BUTTON_RIGHT), (MOUSE_PACKET_MIDDLE, MOUSE_BUTTON_MIDDLE)] {
+            if flags & bit != 0 {
+                buttons |= button;
+            }
+        }
+
+        let wheel = match self.mouse_type {
+            MouseType::IntelliMouse => packet[3] as i8,
+            MouseType::IntelliMouseExplorer => {
+                // The fourth byte contains the state of two additional buttons and a 4 bit wheel movement
+                if packet[3] & 0x10 != 0 {
+                    buttons |= MOUSE_BUTTON_4;
+                }
+                if packet[3] & 0x20 != 0 {
+                    buttons |= MOUSE_BUTTON_5;
+                }
+
+                ((packet[3] << 4) as i8) >> 4
+            }
+            _ => 0
+        };
+
+        // The mouse counts upwards movements as positive, but screen coordinates grow downwards
+        MouseEvent { dx, dy: -dy, wheel, buttons }
+    }
+}
+
 impl InputStream for Keyboard {
     fn read_byte(&self) -> i16 {
-        loop {
-            match self.buffer.0.try_dequeue() {
-                Ok(code) => return code as i16,
-                Err(DequeueError::Closed) => return -1,
-                Err(_) => {}
+        let mut code = -1;
+
+        // Sleep until the interrupt handler has stored a byte in the buffer
+        self.readers.wait_while(|| match self.buffer.0.try_dequeue() {
+            Ok(byte) => {
+                code = byte as i16;
+                false
             }
-        }
+            Err(DequeueError::Closed) => false,
+            Err(_) => true
+        });
+
+        return code;
     }
 }
 
@@ -60,6 +325,8 @@ impl InterruptHandler for KeyboardInterruptHandler {
                         panic!("Keyboard: Failed to store received byte in buffer!");
                     }
                 }
+
+                keyboard.readers.wake_all();
             }
         } else {
             panic!("Keyboard: Controller is locked during interrupt!");
@@ -67,15 +334,52 @@ impl InterruptHandler for KeyboardInterruptHandler {
     }
 }
 
+impl InterruptHandler for MouseInterruptHandler {
+    fn trigger(&mut self) {
+        // The controller is only locked briefly by other code, so the byte is dropped instead of waiting.
+        // The incomplete packet is discarded as well and the next packet is found via its first byte (see below).
+        let Some(mut controller) = ps2_devices().controller.try_lock() else {
+            self.received = 0;
+            return;
+        };
+
+        if let Ok(data) = controller.read_data() {
+            // The first byte of a packet always has bit 3 set, which is used to get back in sync after a lost byte
+            if self.received == 0 && data & MOUSE_PACKET_ALWAYS_ONE == 0 {
+                return;
+            }
+
+            self.packet[self.received] = data;
+            self.received += 1;
+
+            let mouse = ps2_devices().mouse();
+            if self.received == mouse.packet_size() {
+                self.received = 0;
+
+                let event = mouse.decode_packet(&self.packet);
+                while mouse.buffer.1.try_enqueue(event).is_err() {
+                    if mouse.buffer.0.try_dequeue().is_err() {
+                        panic!("Mouse: Failed to store event in buffer!");
+                    }
+                }
+
+                // Each event is consumed by a single reader
+                mouse.readers.wake_one();
+            }
+        }
+    }
+}
+
 impl PS2 {
     pub fn new() -> Self {
         Self {
             controller: unsafe { Mutex::new(Controller::with_timeout(1000000)) },
             keyboard: Keyboard::new(KEYBOARD_BUFFER_CAPACITY),
+            mouse: Mouse::new(MOUSE_BUFFER_CAPACITY),
         }
     }
 
-    pub fn init_controller(&self) -> Result<(), ControllerError> {
+    pub fn init_controller(&mut self) -> Result<(), ControllerError> {
         info!("Initializing controller");
         let mut controller = self.controller.lock();
 
@@ -112,6 +416,17 @@ impl PS2 {
             info!("First port enabled");
         }
 
+        // Check if a mouse is present (single channel controllers do not have a second port and fail this test)
+        if controller.test_mouse().is_ok() {
+            info!("Second port detected");
+            controller.enable_mouse()?;
+            config.set(ControllerConfigFlags::DISABLE_MOUSE, false);
+            config.set(ControllerConfigFlags::ENABLE_MOUSE_INTERRUPT, true);
+            controller.write_config(config)?;
+            self.mouse.available = true;
+            info!("Second port enabled");
+        }
+
         return test_result;
     }
 
@@ -147,7 +462,60 @@ impl PS2 {
         controller.keyboard().enable_scanning()
     }
 
+    pub fn init_mouse(&mut self) -> Result<(), MouseError> {
+        if !self.mouse.available {
+            return Ok(());
+        }
+
+        info!("Initializing mouse");
+        let mut controller = self.controller.lock();
+        match Self::setup_mouse(&mut controller) {
+            Ok(mouse_type) => {
+                info!("Detected mouse type [{:?}]", mouse_type);
+                self.mouse.mouse_type = mouse_type;
+                Ok(())
+            }
+            Err(error) => {
+                self.mouse.available = false;
+                Err(error)
+            }
+        }
+    }
+
+    fn setup_mouse(controller: &mut Controller) -> Result<MouseType, MouseError> {
+        // Perform self test on mouse
+        controller.mouse().reset_and_self_test()?;
+        info!("Mouse has been reset and self test result is OK");
+
+        // Mice with a scroll wheel (and additional buttons) switch to a 4 byte packet format,
+        // after receiving a "magic" sequence of sample rates and report this with a new device id
+        let mut mouse_type = Self::set_sample_rates(controller, [200, 100, 80])?;
+        if mouse_type == MouseType::IntelliMouse && Self::set_sample_rates(controller, [200, 200, 80])? == MouseType::IntelliMouseExplorer {
+            mouse_type = MouseType::IntelliMouseExplorer;
+        }
+
+        // Setup mouse (not using `set_defaults()`, since this would also reset the packet format)
+        info!("Enabling mouse");
+        controller.mouse().set_sample_rate(100)?;
+        controller.mouse().set_resolution(3)?;
+        controller.mouse().enable_data_reporting()?;
+
+        return Ok(mouse_type);
+    }
+
+    fn set_sample_rates(controller: &mut Controller, sample_rates: [u8; 3]) -> Result<MouseType, MouseError> {
+        for rate in sample_rates {
+            controller.mouse().set_sample_rate(rate)?;
+        }
+
+        controller.mouse().get_mouse_type()
+    }
+
     pub fn keyboard(&self) -> &Keyboard {
         return &self.keyboard;
     }
+
+    pub fn mouse(&self) -> &Mouse {
+        return &self.mouse;
+    }
 }
@@ -0,0 +1,51 @@
+use alloc::vec::Vec;
+use spin::RwLock;
+use crate::device::block::{BlockDevice, BlockError, SECTOR_SIZE};
+
+/// Disk in main memory, e.g. for a file system image shipped in the initrd.
+/// The image is copied, so writes only modify the copy in memory.
+pub struct RamDisk {
+    data: RwLock<Vec<u8>>
+}
+
+impl RamDisk {
+    /// Create a disk from an image (the last sector is padded with zeros, if the image size is not a multiple of the sector size)
+    pub fn new(image: &[u8]) -> Self {
+        let mut data = image.to_vec();
+        data.resize(image.len().next_multiple_of(SECTOR_SIZE), 0);
+
+        Self { data: RwLock::new(data) }
+    }
+
+    fn range(&self, sector: u64, length: usize) -> Result<core::ops::Range<usize>, BlockError> {
+        if length % SECTOR_SIZE != 0 {
+            return Err(BlockError::InvalidBuffer);
+        }
+
+        let start = (sector as usize).checked_mul(SECTOR_SIZE).ok_or(BlockError::OutOfRange)?;
+        match start.checked_add(length) {
+            Some(end) if end <= self.data.read().len() => Ok(start..end),
+            _ => Err(BlockError::OutOfRange)
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        (self.data.read().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let range = self.range(sector, buffer.len())?;
+        buffer.copy_from_slice(&self.data.read()[range]);
+
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let range = self.range(sector, buffer.len())?;
+        self.data.write()[range].copy_from_slice(buffer);
+
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use crate::device::serial::ComPort::{Com1, Com2, Com3, Com4};
 use crate::interrupt::interrupt_dispatcher::InterruptVector;
 use crate::interrupt::interrupt_handler::InterruptHandler;
+use crate::sync::wait_queue::WaitQueue;
 use stream::{InputStream, OutputStream};
 use alloc::boxed::Box;
 use alloc::string::String;
@@ -119,6 +120,7 @@ pub enum BaudRate {
 pub struct SerialPort {
     port: ComPort,
     buffer: Once<(Receiver<u8>, Sender<u8>)>,
+    readers: WaitQueue, // threads waiting for input
 }
 
 struct SerialInterruptHandler {
@@ -173,17 +175,20 @@ impl OutputStream for SerialPort {
 
 impl InputStream for SerialPort {
     fn read_byte(&self) -> i16 {
-        loop {
-            if let Some(buffer) = self.buffer.get() {
-                match buffer.0.try_dequeue() {
-                    Ok(byte) => return byte as i16,
-                    Err(DequeueError::Closed) => return -1,
-                    Err(_) => {}
-                }
-            } else {
-                panic!("Serial: Trying to read before initialization!");
+        let buffer = self.buffer.get().expect("Serial: Trying to read before initialization!");
+        let mut byte = -1;
+
+        // Sleep until the interrupt handler has stored a byte in the buffer
+        self.readers.wait_while(|| match buffer.0.try_dequeue() {
+            Ok(received) => {
+                byte = received as i16;
+                false
             }
-        }
+            Err(DequeueError::Closed) => false,
+            Err(_) => true
+        });
+
+        return byte;
     }
 }
 
@@ -212,6 +217,8 @@ impl InterruptHandler for SerialInterruptHandler {
                         None => panic!("Serial: Interrupt handler called before initialization!"),
                     }
                 }
+
+                serial.readers.wake_all();
             }
         }
     }
@@ -222,6 +229,7 @@ impl SerialPort {
         Self {
             port,
             buffer: Once::new(),
+            readers: WaitQueue::new(),
         }
     }
 
@@ -0,0 +1,42 @@
+use core::arch::x86_64::_rdtsc;
+use log::{info, warn};
+use raw_cpuid::CpuId;
+use crate::device::pit;
+
+/// Time stamp counter, calibrated against the PIT. Used as monotonic clock with nanosecond resolution.
+pub struct Tsc {
+    frequency: u64, // ticks per second
+    start: u64 // counter value at calibration (i.e. system time 0)
+}
+
+impl Tsc {
+    /// Measure the TSC frequency. Must be called before the PIT is programmed to generate periodic interrupts,
+    /// since the calibration uses PIT channel 0 in one-shot mode.
+    pub fn calibrate() -> Self {
+        let cpuid = CpuId::new();
+        if !cpuid.get_advanced_power_mgmt_info().is_some_and(|info| info.has_invariant_tsc()) {
+            warn!("TSC is not invariant (system time may drift, if the CPU frequency changes)");
+        }
+
+        let (start, end) = unsafe {
+            let start = _rdtsc();
+            pit::early_delay_50ms();
+            (start, _rdtsc())
+        };
+
+        let frequency = (end - start) * 20;
+        info!("TSC frequency is [{} MHz]", frequency / 1000000);
+
+        Self { frequency, start }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Nanoseconds since calibration.
+    pub fn nanos(&self) -> u64 {
+        let ticks = unsafe { _rdtsc() } - self.start;
+        (ticks as u128 * 1000000000 / self.frequency as u128) as u64
+    }
+}
@@ -0,0 +1,349 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use log::{info, warn};
+use pci_types::{ConfigRegionAccess, EndpointHeader};
+use pci_types::capability::PciCapability;
+use crate::device::pci::PciBus;
+use x86_64::VirtAddr;
+use crate::memory::{physical, phys_to_virt, virt_to_phys, PAGE_SIZE};
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+// Feature bits, that are not specific to a device type
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+// Types of the vendor specific PCI capabilities, describing where the configuration structures are located
+const CAP_COMMON_CONFIG: u8 = 1;
+const CAP_NOTIFY_CONFIG: u8 = 2;
+const CAP_DEVICE_CONFIG: u8 = 4;
+
+// Offsets of the registers in the common configuration structure
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const MSIX_CONFIG: u64 = 0x10;
+const DEVICE_STATUS: u64 = 0x14;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const QUEUE_DESC: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+
+const NO_VECTOR: u16 = 0xffff;
+
+// Descriptor flags
+const DESC_F_NEXT: u16 = 1; // The descriptor is continued by the one in the 'next' field
+const DESC_F_WRITE: u16 = 2; // The buffer is written by the device
+
+// Tells the device, that the driver polls the used ring and does not want interrupts
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// Access to a virtio device via the virtio 1.0 PCI transport ("modern" interface).
+/// The configuration structures are located in memory BARs, described by vendor specific PCI capabilities.
+pub struct VirtioPciTransport {
+    common_config: u64,
+    notify_base: u64,
+    notify_multiplier: u32,
+    device_config: Option<u64>
+}
+
+/// Split virtqueue, consisting of the descriptor table, the available ring (driver -> device)
+/// and the used ring (device -> driver), placed in physically contiguous memory.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    descriptors: u64,
+    available_ring: u64,
+    used_ring: u64,
+    notify_address: u64,
+    free_descriptors: Vec<u16>,
+    next_available: u16,
+    last_used: u16
+}
+
+/// Memory, that is handed to the device as part of a descriptor chain.
+/// Device writable buffers are filled by the device (e.g. received packets), others are read by the device (e.g. packets to send).
+/// Buffers must be physically contiguous kernel memory (heap, kernel stacks or kernel image).
+#[derive(Copy, Clone, Debug)]
+pub struct VirtqueueBuffer {
+    address: u64,
+    length: u32,
+    device_writable: bool
+}
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16
+}
+
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    length: u32
+}
+
+impl VirtioPciTransport {
+    /// Locate the configuration structures of a device.
+    /// Returns `None`, if the device does not offer the virtio 1.0 interface (legacy only devices).
+    pub fn new(pci_bus: &PciBus, device: &EndpointHeader) -> Option<Self> {
+        let mut common_config = None;
+        let mut notify = None;
+        let mut device_config = None;
+
+        for capability in pci_bus.capabilities(device) {
+            if let PciCapability::Vendor(address) = capability {
+                let read = |offset: u16| unsafe { pci_bus.config_space().read(address.address, address.offset + offset) };
+                let cfg_type = (read(0) >> 24) as u8;
+                let bar = read(4) as u8;
+                let offset = read(8) as u64;
+
+                // Only the first capability of each type is used (later ones are alternatives)
+                match cfg_type {
+                    CAP_COMMON_CONFIG if common_config.is_none() => common_config = Some((bar, offset)),
+                    CAP_NOTIFY_CONFIG if notify.is_none() => notify = Some((bar, offset, read(16))),
+                    CAP_DEVICE_CONFIG if device_config.is_none() => device_config = Some((bar, offset)),
+                    _ => {}
+                }
+            }
+        }
+
+        let (common_bar, common_offset) = common_config?;
+        let (notify_bar, notify_offset, notify_multiplier) = notify?;
+        let device_config = match device_config {
+            Some((bar, offset)) => Some(pci_bus.map_memory_bar(device, bar)? + offset),
+            None => None
+        };
+
+        Some(Self {
+            common_config: pci_bus.map_memory_bar(device, common_bar)? + common_offset,
+            notify_base: pci_bus.map_memory_bar(device, notify_bar)? + notify_offset,
+            notify_multiplier,
+            device_config
+        })
+    }
+
+    /// Reset the device and negotiate features. The driver accepts all `wanted` features, that the device offers.
+    /// Returns the accepted features or `None`, if the device does not support virtio 1.0 or rejects the features.
+    pub fn negotiate_features(&self, wanted: u64) -> Option<u64> {
+        self.reset();
+        self.write_common::<u8>(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        self.write_common::<u32>(DEVICE_FEATURE_SELECT, 0);
+        let mut device_features = self.read_common::<u32>(DEVICE_FEATURE) as u64;
+        self.write_common::<u32>(DEVICE_FEATURE_SELECT, 1);
+        device_features |= (self.read_common::<u32>(DEVICE_FEATURE) as u64) << 32;
+
+        let features = device_features & (wanted | VIRTIO_F_VERSION_1);
+        if features & VIRTIO_F_VERSION_1 == 0 {
+            warn!("Virtio device does not support version 1.0");
+            self.write_common::<u8>(DEVICE_STATUS, STATUS_FAILED);
+            return None;
+        }
+
+        self.write_common::<u32>(DRIVER_FEATURE_SELECT, 0);
+        self.write_common::<u32>(DRIVER_FEATURE, features as u32);
+        self.write_common::<u32>(DRIVER_FEATURE_SELECT, 1);
+        self.write_common::<u32>(DRIVER_FEATURE, (features >> 32) as u32);
+
+        self.write_common::<u8>(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if self.read_common::<u8>(DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            warn!("Virtio device rejected features [0x{:x}]", features);
+            self.write_common::<u8>(DEVICE_STATUS, STATUS_FAILED);
+            return None;
+        }
+
+        // Configuration changes are not signaled, since the driver does not use interrupts
+        self.write_common::<u16>(MSIX_CONFIG, NO_VECTOR);
+
+        info!("Negotiated virtio features [0x{:x}]", features);
+        Some(features)
+    }
+
+    /// Create and enable a virtqueue with at most `max_size` entries.
+    /// Returns `None`, if the device does not provide a queue with the given index.
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Option<Virtqueue> {
+        self.write_common::<u16>(QUEUE_SELECT, index);
+        let device_size = self.read_common::<u16>(QUEUE_SIZE);
+        if device_size == 0 {
+            return None;
+        }
+
+        // Queue sizes are powers of two, so the smaller one of both is one as well
+        let size = device_size.min(max_size.next_power_of_two());
+        self.write_common::<u16>(QUEUE_SIZE, size);
+
+        let notify_offset = self.read_common::<u16>(QUEUE_NOTIFY_OFF) as u64;
+        let queue = Virtqueue::new(index, size, self.notify_base + notify_offset * self.notify_multiplier as u64);
+
+        self.write_common::<u16>(QUEUE_MSIX_VECTOR, NO_VECTOR);
+        self.write_common::<u64>(QUEUE_DESC, virt_to_phys(VirtAddr::new(queue.descriptors)).as_u64());
+        self.write_common::<u64>(QUEUE_DRIVER, virt_to_phys(VirtAddr::new(queue.available_ring)).as_u64());
+        self.write_common::<u64>(QUEUE_DEVICE, virt_to_phys(VirtAddr::new(queue.used_ring)).as_u64());
+        self.write_common::<u16>(QUEUE_ENABLE, 1);
+
+        Some(queue)
+    }
+
+    /// Stop the device. Afterward, it does not access any virtqueue anymore.
+    pub fn reset(&self) {
+        self.write_common::<u8>(DEVICE_STATUS, 0);
+        while self.read_common::<u8>(DEVICE_STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Tell the device, that the driver is ready (after all queues have been set up)
+    pub fn driver_ok(&self) {
+        let status = self.read_common::<u8>(DEVICE_STATUS);
+        self.write_common::<u8>(DEVICE_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// Read from the device specific configuration structure (e.g. the MAC address of a network device)
+    pub fn read_device_config(&self, offset: u64) -> u8 {
+        let device_config = self.device_config.expect("Virtio device has no device specific configuration!");
+        unsafe { ptr::read_volatile((device_config + offset) as *const u8) }
+    }
+
+    fn read_common<T>(&self, offset: u64) -> T {
+        unsafe { ptr::read_volatile((self.common_config + offset) as *const T) }
+    }
+
+    fn write_common<T>(&self, offset: u64, value: T) {
+        unsafe { ptr::write_volatile((self.common_config + offset) as *mut T, value) }
+    }
+}
+
+impl VirtqueueBuffer {
+    pub fn readable(buffer: &[u8]) -> Self {
+        Self { address: virt_to_phys(VirtAddr::from_ptr(buffer.as_ptr())).as_u64(), length: buffer.len() as u32, device_writable: false }
+    }
+
+    pub fn writable(buffer: &mut [u8]) -> Self {
+        Self { address: virt_to_phys(VirtAddr::from_ptr(buffer.as_mut_ptr())).as_u64(), length: buffer.len() as u32, device_writable: true }
+    }
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16, notify_address: u64) -> Self {
+        let descriptors_size = size as u64 * size_of::<Descriptor>() as u64;
+        let available_size = 6 + 2 * size as u64;
+        let used_size = 6 + size as u64 * size_of::<UsedElement>() as u64;
+
+        // The descriptor table needs 16 byte alignment, the available ring 2 bytes and the used ring 4 bytes
+        let available_offset = descriptors_size;
+        let used_offset = (available_offset + available_size).next_multiple_of(4);
+        let page_count = (used_offset + used_size).div_ceil(PAGE_SIZE as u64) as usize;
+
+        let memory = phys_to_virt(physical::alloc(page_count).start.start_address()).as_u64();
+        unsafe { ptr::write_bytes(memory as *mut u8, 0, page_count * PAGE_SIZE); }
+
+        let queue = Self {
+            index, size,
+            descriptors: memory,
+            available_ring: memory + available_offset,
+            used_ring: memory + used_offset,
+            notify_address,
+            free_descriptors: (0..size).rev().collect(),
+            next_available: 0,
+            last_used: 0
+        };
+
+        // The driver polls the used ring
+        unsafe { ptr::write_volatile(queue.available_ring as *mut u16, AVAIL_F_NO_INTERRUPT); }
+        queue
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Offer a single buffer to the device (see `push_chain()`).
+    pub fn push(&mut self, buffer: VirtqueueBuffer) -> Option<u16> {
+        self.push_chain(&[buffer])
+    }
+
+    /// Offer a chain of buffers to the device, which processes them as a single request.
+    /// Returns the id of the first descriptor, which identifies the request, when it is returned by `pop_used()`,
+    /// or `None`, if the queue has not enough free descriptors. The buffers must stay valid until then.
+    pub fn push_chain(&mut self, buffers: &[VirtqueueBuffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_descriptors.len() {
+            return None;
+        }
+
+        let ids = self.free_descriptors.split_off(self.free_descriptors.len() - buffers.len());
+        let id = ids[0];
+
+        unsafe {
+            for (i, buffer) in buffers.iter().enumerate() {
+                let last = i == buffers.len() - 1;
+                let descriptor = (self.descriptors as *mut Descriptor).add(ids[i] as usize);
+                descriptor.write_volatile(Descriptor {
+                    address: buffer.address,
+                    length: buffer.length,
+                    flags: if buffer.device_writable { DESC_F_WRITE } else { 0 } | if last { 0 } else { DESC_F_NEXT },
+                    next: if last { 0 } else { ids[i + 1] }
+                });
+            }
+
+            let ring = (self.available_ring + 4) as *mut u16;
+            ring.add((self.next_available % self.size) as usize).write_volatile(id);
+
+            // The descriptor must be visible to the device before the index is incremented
+            fence(Ordering::SeqCst);
+            self.next_available = self.next_available.wrapping_add(1);
+            ptr::write_volatile((self.available_ring + 2) as *mut u16, self.next_available);
+        }
+
+        Some(id)
+    }
+
+    /// Take a request, that the device has finished processing.
+    /// Returns the id of its first descriptor and the number of bytes written by the device.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = unsafe { ptr::read_volatile((self.used_ring + 2) as *const u16) };
+        if used_index == self.last_used {
+            return None;
+        }
+
+        // Read the element only after the index, which signals that the element is valid
+        fence(Ordering::SeqCst);
+        let element = unsafe { ((self.used_ring + 4) as *const UsedElement).add((self.last_used % self.size) as usize).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Return all descriptors of the chain to the free list
+        let mut id = element.id as u16;
+        loop {
+            self.free_descriptors.push(id);
+            let descriptor = unsafe { (self.descriptors as *const Descriptor).add(id as usize).read_volatile() };
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+
+            id = descriptor.next;
+        }
+
+        Some((element.id as u16, element.length))
+    }
+
+    /// Tell the device, that new buffers are available
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.notify_address as *mut u16, self.index); }
+    }
+}
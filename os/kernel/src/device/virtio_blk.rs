@@ -0,0 +1,192 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+use log::info;
+use pci_types::{CommandRegister, EndpointHeader};
+use spin::Mutex;
+use crate::device::block::{BlockDevice, BlockError, Disk, SECTOR_SIZE};
+use crate::device::driver::{DeviceInstance, PciDeviceId, PciDriver};
+use crate::device::pci::PciBus;
+use crate::device::virtio::{Virtqueue, VirtqueueBuffer, VirtioPciTransport, VIRTIO_VENDOR_ID};
+
+// Block devices are offered as transitional devices (legacy and virtio 1.0 interface) or as virtio 1.0 only devices
+const TRANSITIONAL_DEVICE_ID: u16 = 0x1001;
+const DEVICE_ID: u16 = 0x1042;
+
+// Feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+const REQUEST_QUEUE: u16 = 0;
+// Each request uses three descriptors (header, data, status) and only one request is in flight at a time
+const QUEUE_SIZE: u16 = 16;
+const MAX_REQUEST_SECTORS: usize = 128;
+
+// The capacity (in 512 byte sectors) is the first field of the device specific configuration
+const CONFIG_CAPACITY: u64 = 0;
+
+const VIRTIO_BLK_IDS: [PciDeviceId; 2] = [
+    PciDeviceId::Id { vendor_id: VIRTIO_VENDOR_ID, device_id: TRANSITIONAL_DEVICE_ID },
+    PciDeviceId::Id { vendor_id: VIRTIO_VENDOR_ID, device_id: DEVICE_ID }
+];
+
+/// Disk emulated by QEMU/KVM via virtio (e.g. `-drive if=virtio`).
+/// Requests are processed synchronously by polling the used ring.
+pub struct VirtioBlkDevice {
+    transport: VirtioPciTransport,
+    sector_count: u64,
+    features: u64,
+    queue: Mutex<Virtqueue>
+}
+
+#[derive(Default)]
+pub struct VirtioBlkDriver {}
+
+impl VirtioBlkDevice {
+    /// Initialize the device. Returns `None`, if it does not support virtio 1.0.
+    pub fn new(pci_bus: &PciBus, device: &EndpointHeader) -> Option<Self> {
+        device.update_command(pci_bus.config_space(), |command| command | CommandRegister::BUS_MASTER_ENABLE | CommandRegister::MEMORY_ENABLE);
+
+        let transport = VirtioPciTransport::new(pci_bus, device)?;
+        let features = transport.negotiate_features(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH)?;
+        let queue = transport.setup_queue(REQUEST_QUEUE, QUEUE_SIZE)?;
+
+        let mut sector_count = 0;
+        for i in 0..8 {
+            sector_count |= (transport.read_device_config(CONFIG_CAPACITY + i) as u64) << (i * 8);
+        }
+
+        transport.driver_ok();
+        info!("Virtio block device initialized (Capacity: [{} MiB], Read only: [{}])", sector_count * SECTOR_SIZE as u64 / (1024 * 1024), features & VIRTIO_BLK_F_RO != 0);
+
+        Some(Self { transport, sector_count, features, queue: Mutex::new(queue) })
+    }
+
+    pub fn shutdown(&self, pci_bus: &PciBus, device: &EndpointHeader) {
+        self.transport.reset();
+        device.update_command(pci_bus.config_space(), |command| command - CommandRegister::BUS_MASTER_ENABLE);
+    }
+
+    /// Submit a request and wait for its completion.
+    /// All buffers are allocated on the kernel heap, which is physically contiguous and thus accessible by the device.
+    fn request(&self, request_type: u32, sector: u64, data: Option<VirtqueueBuffer>) -> Result<(), BlockError> {
+        let mut header = vec![0u8; 16];
+        header[0..4].copy_from_slice(&request_type.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        let mut status = vec![0xffu8; 1];
+
+        let mut chain = Vec::from([VirtqueueBuffer::readable(&header)]);
+        chain.extend(data);
+        chain.push(VirtqueueBuffer::writable(&mut status));
+
+        let mut queue = self.queue.lock();
+        let id = queue.push_chain(&chain).expect("Virtio: Request queue is full!");
+        queue.notify();
+
+        loop {
+            match queue.pop_used() {
+                Some((used, _)) if used == id => break,
+                Some(_) => panic!("Virtio: Device completed an unknown request!"),
+                None => core::hint::spin_loop()
+            }
+        }
+
+        match unsafe { ptr::read_volatile(status.as_ptr()) } {
+            VIRTIO_BLK_S_OK => Ok(()),
+            _ => Err(BlockError::DeviceError)
+        }
+    }
+
+    fn check_access(&self, sector: u64, length: usize) -> Result<(), BlockError> {
+        if length % SECTOR_SIZE != 0 {
+            return Err(BlockError::InvalidBuffer);
+        }
+
+        match sector.checked_add((length / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sector_count => Ok(()),
+            _ => Err(BlockError::OutOfRange)
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlkDevice {
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.features & VIRTIO_BLK_F_RO != 0
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_access(sector, buffer.len())?;
+
+        for (i, chunk) in buffer.chunks_mut(MAX_REQUEST_SECTORS * SECTOR_SIZE).enumerate() {
+            let mut data = vec![0u8; chunk.len()];
+            self.request(VIRTIO_BLK_T_IN, sector + (i * MAX_REQUEST_SECTORS) as u64, Some(VirtqueueBuffer::writable(&mut data)))?;
+            chunk.copy_from_slice(&data);
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.check_access(sector, buffer.len())?;
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+
+        for (i, chunk) in buffer.chunks(MAX_REQUEST_SECTORS * SECTOR_SIZE).enumerate() {
+            let data = chunk.to_vec();
+            self.request(VIRTIO_BLK_T_OUT, sector + (i * MAX_REQUEST_SECTORS) as u64, Some(VirtqueueBuffer::readable(&data)))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+            // Without a volatile write cache, written sectors are persistent as soon as the request completes
+            return Ok(());
+        }
+
+        self.request(VIRTIO_BLK_T_FLUSH, 0, None)
+    }
+}
+
+impl PciDriver for VirtioBlkDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn device_prefix(&self) -> &'static str {
+        "vd"
+    }
+
+    fn ids(&self) -> &[PciDeviceId] {
+        &VIRTIO_BLK_IDS
+    }
+
+    fn probe(&self, pci_bus: &PciBus, device: &EndpointHeader) -> Option<DeviceInstance> {
+        let block = VirtioBlkDevice::new(pci_bus, device)?;
+        Some(DeviceInstance::Block(Arc::new(Disk::new(Arc::new(block)))))
+    }
+
+    fn remove(&self, pci_bus: &PciBus, device: &EndpointHeader, instance: DeviceInstance) {
+        if let DeviceInstance::Block(disk) = &instance {
+            let _ = disk.sync();
+        }
+
+        if let Some(block) = instance.downcast::<VirtioBlkDevice>() {
+            block.shutdown(pci_bus, device);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::{info, warn};
+use pci_types::{CommandRegister, EndpointHeader};
+use spin::Mutex;
+use crate::device::driver::{DeviceInstance, PciDeviceId, PciDriver};
+use crate::device::network::NetworkDevice;
+use crate::device::pci::PciBus;
+use crate::device::virtio::{Virtqueue, VirtqueueBuffer, VirtioPciTransport, VIRTIO_VENDOR_ID};
+
+// Network cards are offered as transitional devices (legacy and virtio 1.0 interface) or as virtio 1.0 only devices
+const TRANSITIONAL_DEVICE_ID: u16 = 0x1000;
+const DEVICE_ID: u16 = 0x1041;
+
+// Feature bit for devices, which provide their MAC address in the device specific configuration
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 64;
+
+// Each packet is preceded by a header for checksum and segmentation offloading (which is not negotiated, so it stays zeroed)
+const NET_HEADER_SIZE: usize = 12;
+// Largest ethernet frame (including VLAN tag)
+const FRAME_SIZE: usize = 1522;
+const BUFFER_SIZE: usize = NET_HEADER_SIZE + FRAME_SIZE;
+
+// Used if the device does not provide a MAC address (locally administered)
+const FALLBACK_MAC_ADDRESS: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+const VIRTIO_NET_IDS: [PciDeviceId; 2] = [
+    PciDeviceId::Id { vendor_id: VIRTIO_VENDOR_ID, device_id: TRANSITIONAL_DEVICE_ID },
+    PciDeviceId::Id { vendor_id: VIRTIO_VENDOR_ID, device_id: DEVICE_ID }
+];
+
+/// Network card emulated by QEMU/KVM via virtio. Both queues are polled, so the device does not use interrupts.
+pub struct VirtioNetDevice {
+    transport: VirtioPciTransport,
+    mac_address: [u8; 6],
+    receive_queue: Mutex<Queue>,
+    transmit_queue: Mutex<Queue>
+}
+
+/// Virtqueue together with the buffers, that are currently owned by the device (indexed by descriptor id)
+struct Queue {
+    virtqueue: Virtqueue,
+    buffers: Vec<Option<Vec<u8>>>
+}
+
+#[derive(Default)]
+pub struct VirtioNetDriver {}
+
+impl Queue {
+    fn new(virtqueue: Virtqueue) -> Self {
+        let buffers = (0..virtqueue.size()).map(|_| None).collect();
+        Self { virtqueue, buffers }
+    }
+
+    fn push(&mut self, mut buffer: Vec<u8>, device_writable: bool) -> Result<(), Vec<u8>> {
+        let entry = if device_writable { VirtqueueBuffer::writable(&mut buffer) } else { VirtqueueBuffer::readable(&buffer) };
+        match self.virtqueue.push(entry) {
+            Some(id) => {
+                self.buffers[id as usize] = Some(buffer);
+                Ok(())
+            }
+            None => Err(buffer)
+        }
+    }
+
+    /// Take a buffer back from the device, together with the number of bytes written by the device
+    fn pop_used(&mut self) -> Option<(Vec<u8>, usize)> {
+        let (id, length) = self.virtqueue.pop_used()?;
+        let buffer = self.buffers[id as usize].take().expect("Virtio: Device returned an unused descriptor!");
+
+        Some((buffer, length as usize))
+    }
+}
+
+impl VirtioNetDevice {
+    /// Initialize the device. Returns `None`, if it does not support virtio 1.0.
+    pub fn new(pci_bus: &PciBus, device: &EndpointHeader) -> Option<Self> {
+        device.update_command(pci_bus.config_space(), |command| command | CommandRegister::BUS_MASTER_ENABLE | CommandRegister::MEMORY_ENABLE);
+
+        let transport = VirtioPciTransport::new(pci_bus, device)?;
+        let features = transport.negotiate_features(VIRTIO_NET_F_MAC)?;
+
+        let mut receive_queue = Queue::new(transport.setup_queue(RECEIVE_QUEUE, QUEUE_SIZE)?);
+        let transmit_queue = Queue::new(transport.setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE)?);
+
+        let mac_address = if features & VIRTIO_NET_F_MAC != 0 {
+            let mut mac_address = [0; 6];
+            for (i, byte) in mac_address.iter_mut().enumerate() {
+                *byte = transport.read_device_config(i as u64);
+            }
+
+            mac_address
+        } else {
+            warn!("Virtio network device has no MAC address, using fallback address");
+            FALLBACK_MAC_ADDRESS
+        };
+
+        // Fill the receive queue with empty buffers
+        for _ in 0..receive_queue.virtqueue.size() {
+            receive_queue.push(vec![0; BUFFER_SIZE], true).expect("Virtio: Receive queue is full!");
+        }
+
+        transport.driver_ok();
+        receive_queue.virtqueue.notify();
+
+        info!("Virtio network device initialized (MAC: [{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}])",
+            mac_address[0], mac_address[1], mac_address[2], mac_address[3], mac_address[4], mac_address[5]);
+
+        Some(Self { transport, mac_address, receive_queue: Mutex::new(receive_queue), transmit_queue: Mutex::new(transmit_queue) })
+    }
+
+    pub fn shutdown(&self, pci_bus: &PciBus, device: &EndpointHeader) {
+        self.transport.reset();
+        device.update_command(pci_bus.config_space(), |command| command - CommandRegister::BUS_MASTER_ENABLE);
+    }
+}
+
+impl NetworkDevice for VirtioNetDevice {
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    fn transmit(&self, frame: Vec<u8>) {
+        if frame.len() > FRAME_SIZE {
+            warn!("Virtio: Dropping frame, which is larger than [{}] bytes", FRAME_SIZE);
+            return;
+        }
+
+        let mut buffer = vec![0; NET_HEADER_SIZE];
+        buffer.extend_from_slice(&frame);
+
+        let mut queue = self.transmit_queue.lock();
+        loop {
+            // Buffers of sent packets can be dropped
+            while queue.pop_used().is_some() {}
+
+            match queue.push(buffer, false) {
+                Ok(()) => break,
+                Err(returned) => {
+                    // The queue is full, so we wait for the device to send some packets
+                    buffer = returned;
+                    core::hint::spin_loop();
+                }
+            }
+        }
+
+        queue.virtqueue.notify();
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut queue = self.receive_queue.lock();
+        let (buffer, length) = queue.pop_used()?;
+        let frame = buffer[NET_HEADER_SIZE..length].to_vec();
+
+        // Hand the buffer back to the device for the next packet
+        queue.push(buffer, true).expect("Virtio: Receive queue is full!");
+        queue.virtqueue.notify();
+
+        Some(frame)
+    }
+}
+
+impl PciDriver for VirtioNetDriver {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn device_prefix(&self) -> &'static str {
+        "eth"
+    }
+
+    fn ids(&self) -> &[PciDeviceId] {
+        &VIRTIO_NET_IDS
+    }
+
+    fn probe(&self, pci_bus: &PciBus, device: &EndpointHeader) -> Option<DeviceInstance> {
+        let network = VirtioNetDevice::new(pci_bus, device)?;
+        Some(DeviceInstance::Network(Arc::new(network)))
+    }
+
+    fn remove(&self, pci_bus: &PciBus, device: &EndpointHeader, instance: DeviceInstance) {
+        if let Some(network) = instance.downcast::<VirtioNetDevice>() {
+            network.shutdown(pci_bus, device);
+        }
+    }
+}
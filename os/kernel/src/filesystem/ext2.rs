@@ -0,0 +1,1110 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: ext2                                                            ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Driver for the second extended file system (ext2), supporting   ║
+   ║         regular files, directories, symbolic links and permissions.     ║
+   ║         Works on any disk implementing the 'BlockDevice' trait (e.g. a  ║
+   ║         disk driver or a RAM disk with an image from the initrd).       ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::{info, warn};
+use crate::device::block::{Disk, SECTOR_SIZE};
+use crate::filesystem::{path_components, split_path, DirectoryEntry, FileSystem, FileType, FsError};
+use crate::sync::mutex::Mutex;
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+// Offsets in the superblock
+const SB_INODES_COUNT: usize = 0;
+const SB_BLOCKS_COUNT: usize = 4;
+const SB_FREE_BLOCKS_COUNT: usize = 12;
+const SB_FREE_INODES_COUNT: usize = 16;
+const SB_FIRST_DATA_BLOCK: usize = 20;
+const SB_LOG_BLOCK_SIZE: usize = 24;
+const SB_BLOCKS_PER_GROUP: usize = 32;
+const SB_INODES_PER_GROUP: usize = 40;
+const SB_WTIME: usize = 48;
+const SB_MAGIC: usize = 56;
+const SB_REV_LEVEL: usize = 76;
+const SB_FIRST_INO: usize = 84;
+const SB_INODE_SIZE: usize = 88;
+const SB_FEATURE_INCOMPAT: usize = 96;
+const SB_FEATURE_RO_COMPAT: usize = 100;
+const SB_WANT_EXTRA_ISIZE: usize = 350;
+
+// Features, that change the on-disk format
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002; // directory entries contain the file type
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const SUPPORTED_RO_COMPAT: u32 = FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE;
+
+// Offsets in a block group descriptor
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+const BG_BLOCK_BITMAP: usize = 0;
+const BG_INODE_BITMAP: usize = 4;
+const BG_INODE_TABLE: usize = 8;
+const BG_FREE_BLOCKS_COUNT: usize = 12;
+const BG_FREE_INODES_COUNT: usize = 14;
+const BG_USED_DIRS_COUNT: usize = 16;
+
+// Offsets in an inode
+const I_MODE: usize = 0;
+const I_UID: usize = 2;
+const I_SIZE: usize = 4;
+const I_DTIME: usize = 20;
+const I_GID: usize = 24;
+const I_LINKS_COUNT: usize = 26;
+const I_BLOCKS: usize = 28;
+const I_FLAGS: usize = 32;
+const I_BLOCK: usize = 40;
+const I_FILE_ACL: usize = 104;
+const I_SIZE_HIGH: usize = 108;
+const I_UID_HIGH: usize = 120;
+const I_GID_HIGH: usize = 122;
+const I_EXTRA_ISIZE: usize = 128;
+
+// The first 12 block pointers are direct, followed by a single, double and triple indirect pointer
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT_LEVELS: usize = 3;
+
+const INDEX_FLAG: u32 = 0x1000; // the directory uses a hashed index (which is not maintained by this driver)
+
+// File types and permissions in the mode of an inode
+const S_IFMT: u16 = 0xf000;
+const S_IFLNK: u16 = 0xa000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const PERMISSION_MASK: u16 = 0o7777;
+const DEFAULT_FILE_PERMISSIONS: u16 = 0o644;
+const DEFAULT_DIRECTORY_PERMISSIONS: u16 = 0o755;
+const SYMLINK_PERMISSIONS: u16 = 0o777;
+
+// File types in directory entries
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const DIR_ENTRY_HEADER_SIZE: usize = 8;
+const MAX_NAME_LENGTH: usize = 255;
+
+// Targets of up to 60 bytes are stored directly in the block pointers of the inode ("fast" symbolic links)
+const FAST_SYMLINK_SIZE: usize = 60;
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// A mounted ext2 file system. All operations are serialized by a single lock.
+pub struct Ext2FileSystem {
+    disk: Arc<Disk>,
+    first_sector: u64, // start of the file system on the disk (e.g. the first sector of a partition)
+    block_size: usize,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    first_inode: u32, // first inode, that is not reserved
+    extra_inode_size: u16,
+    group_count: u32,
+    inode_tables: Vec<u32>, // first block of the inode table of each group
+    file_type_feature: bool,
+    writable: bool, // false, if the file system uses features, that this driver cannot maintain
+    state: Mutex<State>
+}
+
+/// Superblock and block group descriptors, which are modified when allocating blocks and inodes
+struct State {
+    superblock: Vec<u8>,
+    group_descriptors: Vec<u8>
+}
+
+struct Inode {
+    number: u32,
+    data: Vec<u8>
+}
+
+struct DirEntry {
+    inode: u32,
+    name: String,
+    block: u32, // physical block containing the entry
+    offset: usize,
+    record_length: usize
+}
+
+/// Information about a file, as stored in its inode
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub inode: u32,
+    pub file_type: FileType,
+    pub permissions: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub links: u16
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Directory entries are aligned to 4 bytes
+fn dir_entry_size(name_length: usize) -> usize {
+    (DIR_ENTRY_HEADER_SIZE + name_length).next_multiple_of(4)
+}
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    match name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME_LENGTH || name.contains('\0') {
+        true => Err(FsError::InvalidName),
+        false => Ok(())
+    }
+}
+
+impl Inode {
+    fn mode(&self) -> u16 {
+        read_u16(&self.data, I_MODE)
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.mode() & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::File
+        }
+    }
+
+    fn is_directory(&self) -> bool {
+        self.file_type() == FileType::Directory
+    }
+
+    fn size(&self) -> u64 {
+        // The upper half of the size is only used by regular files (directories store an ACL there in revision 0)
+        let high = match self.mode() & S_IFMT {
+            S_IFREG => read_u32(&self.data, I_SIZE_HIGH) as u64,
+            _ => 0
+        };
+
+        high << 32 | read_u32(&self.data, I_SIZE) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        write_u32(&mut self.data, I_SIZE, size as u32);
+        if self.mode() & S_IFMT == S_IFREG {
+            write_u32(&mut self.data, I_SIZE_HIGH, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        read_u16(&self.data, I_LINKS_COUNT)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        write_u16(&mut self.data, I_LINKS_COUNT, links);
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        read_u32(&self.data, I_BLOCK + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        write_u32(&mut self.data, I_BLOCK + index * 4, block);
+    }
+
+    /// Number of 512 byte sectors, occupied by the inode (including indirect blocks)
+    fn sectors(&self) -> u32 {
+        read_u32(&self.data, I_BLOCKS)
+    }
+}
+
+impl Ext2FileSystem {
+    /// Mount the file system, starting at `first_sector` of the disk (0, if the disk is not partitioned).
+    pub fn mount(disk: Arc<Disk>, first_sector: u64) -> Result<Self, FsError> {
+        let mut superblock = vec![0u8; SUPERBLOCK_SIZE];
+        disk.read(first_sector + (SUPERBLOCK_OFFSET / SECTOR_SIZE) as u64, &mut superblock)?;
+
+        if read_u16(&superblock, SB_MAGIC) != EXT2_MAGIC {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        let (first_inode, inode_size, incompat_features, ro_compat_features) = match read_u32(&superblock, SB_REV_LEVEL) {
+            0 => (11, 128, 0, 0),
+            _ => (read_u32(&superblock, SB_FIRST_INO), read_u16(&superblock, SB_INODE_SIZE) as usize,
+                  read_u32(&superblock, SB_FEATURE_INCOMPAT), read_u32(&superblock, SB_FEATURE_RO_COMPAT))
+        };
+
+        // Features like extents or journaling (ext3/ext4) change the on-disk format in incompatible ways
+        if incompat_features & !FEATURE_INCOMPAT_FILETYPE != 0 {
+            warn!("Ext2: Unsupported incompatible features [{:#x}]", incompat_features & !FEATURE_INCOMPAT_FILETYPE);
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        let writable = ro_compat_features & !SUPPORTED_RO_COMPAT == 0;
+        if !writable {
+            warn!("Ext2: Unsupported read-only compatible features [{:#x}], mounting read-only", ro_compat_features & !SUPPORTED_RO_COMPAT);
+        }
+
+        // Block sizes range from 1 KiB to 64 KiB (checked before shifting, so that a corrupted superblock cannot overflow the shift)
+        let log_block_size = read_u32(&superblock, SB_LOG_BLOCK_SIZE);
+        if log_block_size > 6 {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        let block_size = 1024usize << log_block_size;
+        let blocks_count = read_u32(&superblock, SB_BLOCKS_COUNT);
+        let first_data_block = read_u32(&superblock, SB_FIRST_DATA_BLOCK);
+        let blocks_per_group = read_u32(&superblock, SB_BLOCKS_PER_GROUP);
+        let inodes_per_group = read_u32(&superblock, SB_INODES_PER_GROUP);
+        // Inodes must not cross block boundaries
+        if blocks_per_group == 0 || inodes_per_group == 0 || inode_size < 128 || !inode_size.is_power_of_two() || inode_size > block_size || blocks_count <= first_data_block {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        // The block and inode bitmaps of a group occupy a single block each
+        let bits_per_block = (block_size * 8) as u32;
+        if blocks_per_group > bits_per_block || inodes_per_group > bits_per_block {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        if first_sector + (blocks_count as u64 * (block_size / SECTOR_SIZE) as u64) > disk.sector_count() {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        let extra_inode_size = match inode_size {
+            128 => 0,
+            _ => read_u16(&superblock, SB_WANT_EXTRA_ISIZE)
+        };
+
+        // The block group descriptor table starts in the block following the superblock
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if group_count.checked_mul(inodes_per_group).is_none() {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        let descriptor_table_size = (group_count as usize * GROUP_DESCRIPTOR_SIZE).next_multiple_of(block_size);
+        let mut group_descriptors = vec![0u8; descriptor_table_size];
+        disk.read(first_sector + ((first_data_block as usize + 1) * block_size / SECTOR_SIZE) as u64, &mut group_descriptors)?;
+
+        let inode_tables = (0..group_count as usize).map(|group| read_u32(&group_descriptors, group * GROUP_DESCRIPTOR_SIZE + BG_INODE_TABLE)).collect();
+
+        info!("Mounted ext2 file system ([{}] blocks with [{}] bytes, [{}] inodes)", blocks_count, block_size, read_u32(&superblock, SB_INODES_COUNT));
+
+        Ok(Self {
+            disk, first_sector, block_size, blocks_count, first_data_block, blocks_per_group, inodes_per_group,
+            inode_size, first_inode, extra_inode_size, group_count, inode_tables,
+            file_type_feature: incompat_features & FEATURE_INCOMPAT_FILETYPE != 0,
+            writable,
+            state: Mutex::new(State { superblock, group_descriptors })
+        })
+    }
+
+    /// Get the metadata of a file (symbolic links are not followed)
+    pub fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        let _state = self.state.lock();
+        let inode = self.read_inode(self.resolve(path, false)?)?;
+
+        Ok(Metadata {
+            inode: inode.number,
+            file_type: inode.file_type(),
+            permissions: inode.mode() & PERMISSION_MASK,
+            uid: read_u16(&inode.data, I_UID) as u32 | (read_u16(&inode.data, I_UID_HIGH) as u32) << 16,
+            gid: read_u16(&inode.data, I_GID) as u32 | (read_u16(&inode.data, I_GID_HIGH) as u32) << 16,
+            size: inode.size(),
+            links: inode.links()
+        })
+    }
+
+    /// Change the permission bits (e.g. 0o755) of a file
+    pub fn set_permissions(&self, path: &str, permissions: u16) -> Result<(), FsError> {
+        self.check_writable()?;
+        let _state = self.state.lock();
+        let mut inode = self.read_inode(self.resolve(path, true)?)?;
+        let mode = (inode.mode() & S_IFMT) | (permissions & PERMISSION_MASK);
+        write_u16(&mut inode.data, I_MODE, mode);
+
+        self.write_inode(&inode)
+    }
+
+    /// Create a symbolic link at `path`, pointing to `target`
+    pub fn create_symlink(&self, path: &str, target: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        if target.is_empty() || target.len() >= self.block_size {
+            return Err(FsError::InvalidName);
+        }
+
+        let mut state = self.state.lock();
+        let (parent, name) = split_path(path)?;
+        let mut directory = self.read_inode(self.resolve(parent, true)?)?;
+        self.check_new_entry(&directory, name)?;
+
+        let mut inode = self.allocate_inode(&mut state, directory.number, S_IFLNK | SYMLINK_PERMISSIONS)?;
+        if target.len() < FAST_SYMLINK_SIZE {
+            inode.data[I_BLOCK..I_BLOCK + target.len()].copy_from_slice(target.as_bytes());
+        } else {
+            let block = self.allocate_block(&mut state, self.group_of_inode(inode.number))?;
+            let mut data = vec![0; self.block_size];
+            data[..target.len()].copy_from_slice(target.as_bytes());
+            self.write_block(block, &data)?;
+            self.set_data_blocks(&mut state, &mut inode, &[block])?;
+        }
+
+        inode.set_size(target.len() as u64);
+        self.write_inode(&inode)?;
+        self.add_entry(&mut state, &mut directory, name, inode.number, FT_SYMLINK)
+    }
+
+    /// Get the target of a symbolic link
+    pub fn read_link(&self, path: &str) -> Result<String, FsError> {
+        let _state = self.state.lock();
+        let inode = self.read_inode(self.resolve(path, false)?)?;
+        if inode.file_type() != FileType::Symlink {
+            return Err(FsError::InvalidName);
+        }
+
+        self.link_target(&inode)
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        match self.writable && !self.disk.is_read_only() {
+            true => Ok(()),
+            false => Err(FsError::ReadOnly)
+        }
+    }
+
+    fn block_sector(&self, block: u32) -> u64 {
+        self.first_sector + block as u64 * (self.block_size / SECTOR_SIZE) as u64
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, FsError> {
+        let mut data = vec![0; self.block_size];
+        self.disk.read(self.block_sector(block), &mut data)?;
+
+        Ok(data)
+    }
+
+    fn write_block(&self, block: u32, data: &[u8]) -> Result<(), FsError> {
+        self.disk.write(self.block_sector(block), data)?;
+        Ok(())
+    }
+
+    fn write_superblock(&self, state: &State) -> Result<(), FsError> {
+        self.disk.write(self.first_sector + (SUPERBLOCK_OFFSET / SECTOR_SIZE) as u64, &state.superblock)?;
+        Ok(())
+    }
+
+    /// Write the block of the descriptor table, that contains the descriptor of the given group
+    fn write_group_descriptor(&self, state: &State, group: u32) -> Result<(), FsError> {
+        let block_index = group as usize * GROUP_DESCRIPTOR_SIZE / self.block_size;
+        let start = block_index * self.block_size;
+        self.write_block(self.first_data_block + 1 + block_index as u32, &state.group_descriptors[start..start + self.block_size])
+    }
+
+    fn group_field(&self, state: &State, group: u32, offset: usize) -> u32 {
+        let base = group as usize * GROUP_DESCRIPTOR_SIZE;
+        match offset {
+            BG_FREE_BLOCKS_COUNT | BG_FREE_INODES_COUNT | BG_USED_DIRS_COUNT => read_u16(&state.group_descriptors, base + offset) as u32,
+            _ => read_u32(&state.group_descriptors, base + offset)
+        }
+    }
+
+    /// Add `delta` to a counter of a group descriptor and to the corresponding counter of the superblock (if any)
+    fn update_counters(&self, state: &mut State, group: u32, offset: usize, delta: i32) -> Result<(), FsError> {
+        let base = group as usize * GROUP_DESCRIPTOR_SIZE;
+        let value = read_u16(&state.group_descriptors, base + offset) as i32 + delta;
+        write_u16(&mut state.group_descriptors, base + offset, value as u16);
+        self.write_group_descriptor(state, group)?;
+
+        let superblock_offset = match offset {
+            BG_FREE_BLOCKS_COUNT => SB_FREE_BLOCKS_COUNT,
+            BG_FREE_INODES_COUNT => SB_FREE_INODES_COUNT,
+            _ => return Ok(())
+        };
+
+        let value = read_u32(&state.superblock, superblock_offset) as i64 + delta as i64;
+        write_u32(&mut state.superblock, superblock_offset, value as u32);
+        self.write_superblock(state)
+    }
+
+    fn group_of_inode(&self, inode: u32) -> u32 {
+        (inode - 1) / self.inodes_per_group
+    }
+
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        (self.blocks_count - self.first_data_block - group * self.blocks_per_group).min(self.blocks_per_group)
+    }
+
+    /// Find and set a free bit in a bitmap block. Returns the index of the bit.
+    fn allocate_bit(&self, bitmap_block: u32, bit_count: u32) -> Result<Option<u32>, FsError> {
+        let mut bitmap = self.read_block(bitmap_block)?;
+        for bit in 0..bit_count {
+            let (byte, mask) = ((bit / 8) as usize, 1u8 << (bit % 8));
+            if bitmap[byte] & mask == 0 {
+                bitmap[byte] |= mask;
+                self.write_block(bitmap_block, &bitmap)?;
+                return Ok(Some(bit));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn free_bit(&self, bitmap_block: u32, bit: u32) -> Result<(), FsError> {
+        let mut bitmap = self.read_block(bitmap_block)?;
+        bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+        self.write_block(bitmap_block, &bitmap)
+    }
+
+    /// Allocate a block, preferably in the given group (to keep the blocks of a file close to its inode)
+    fn allocate_block(&self, state: &mut State, preferred_group: u32) -> Result<u32, FsError> {
+        for group in (preferred_group..self.group_count).chain(0..preferred_group) {
+            if self.group_field(state, group, BG_FREE_BLOCKS_COUNT) == 0 {
+                continue;
+            }
+
+            let bitmap = self.group_field(state, group, BG_BLOCK_BITMAP);
+            if let Some(bit) = self.allocate_bit(bitmap, self.blocks_in_group(group))? {
+                self.update_counters(state, group, BG_FREE_BLOCKS_COUNT, -1)?;
+                return Ok(self.first_data_block + group * self.blocks_per_group + bit);
+            }
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, state: &mut State, block: u32) -> Result<(), FsError> {
+        let group = (block - self.first_data_block) / self.blocks_per_group;
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+        self.free_bit(self.group_field(state, group, BG_BLOCK_BITMAP), bit)?;
+
+        self.update_counters(state, group, BG_FREE_BLOCKS_COUNT, 1)
+    }
+
+    /// Allocate an inode (preferably in the group of the parent directory) and initialize it with the given mode
+    fn allocate_inode(&self, state: &mut State, parent: u32, mode: u16) -> Result<Inode, FsError> {
+        let preferred_group = self.group_of_inode(parent);
+        for group in (preferred_group..self.group_count).chain(0..preferred_group) {
+            if self.group_field(state, group, BG_FREE_INODES_COUNT) == 0 {
+                continue;
+            }
+
+            let bitmap = self.group_field(state, group, BG_INODE_BITMAP);
+            if let Some(bit) = self.allocate_bit(bitmap, self.inodes_per_group)? {
+                let number = group * self.inodes_per_group + bit + 1;
+                if number < self.first_inode {
+                    // Reserved inodes are always marked as used by mkfs, so this indicates a corrupted bitmap
+                    return Err(FsError::InvalidFileSystem);
+                }
+
+                self.update_counters(state, group, BG_FREE_INODES_COUNT, -1)?;
+                if mode & S_IFMT == S_IFDIR {
+                    self.update_counters(state, group, BG_USED_DIRS_COUNT, 1)?;
+                }
+
+                let mut inode = Inode { number, data: vec![0; self.inode_size] };
+                write_u16(&mut inode.data, I_MODE, mode);
+                inode.set_links(1);
+                if self.inode_size > I_EXTRA_ISIZE {
+                    write_u16(&mut inode.data, I_EXTRA_ISIZE, self.extra_inode_size);
+                }
+
+                return Ok(inode);
+            }
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    /// Release an inode, that is no longer referenced by any directory (including its data blocks)
+    fn free_inode(&self, state: &mut State, inode: &mut Inode) -> Result<(), FsError> {
+        if !self.is_fast_symlink(inode) {
+            self.release_data(state, inode)?;
+        }
+
+        // A non-zero deletion time marks the inode as deleted for file system checkers.
+        // There is no wall clock, so the last write time of the file system is used instead
+        // (values smaller than the inode count would be taken for an orphan list entry).
+        let deletion_time = read_u32(&state.superblock, SB_WTIME).max(read_u32(&state.superblock, SB_INODES_COUNT));
+        let directory = inode.is_directory();
+        inode.set_links(0);
+        write_u32(&mut inode.data, I_DTIME, deletion_time);
+        self.write_inode(inode)?;
+
+        let group = self.group_of_inode(inode.number);
+        self.free_bit(self.group_field(state, group, BG_INODE_BITMAP), (inode.number - 1) % self.inodes_per_group)?;
+        self.update_counters(state, group, BG_FREE_INODES_COUNT, 1)?;
+        if directory {
+            self.update_counters(state, group, BG_USED_DIRS_COUNT, -1)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_inode(&self, number: u32) -> Result<Inode, FsError> {
+        let (block, offset) = self.inode_location(number)?;
+        let data = self.read_block(block)?;
+
+        Ok(Inode { number, data: data[offset..offset + self.inode_size].to_vec() })
+    }
+
+    fn write_inode(&self, inode: &Inode) -> Result<(), FsError> {
+        let (block, offset) = self.inode_location(inode.number)?;
+        let mut data = self.read_block(block)?;
+        data[offset..offset + self.inode_size].copy_from_slice(&inode.data);
+
+        self.write_block(block, &data)
+    }
+
+    /// Location of an inode in the inode table of its group as (block, offset in block)
+    fn inode_location(&self, number: u32) -> Result<(u32, usize), FsError> {
+        if number == 0 || self.group_count.checked_mul(self.inodes_per_group).is_none_or(|count| number > count) {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        let offset = ((number - 1) % self.inodes_per_group) as usize * self.inode_size;
+        let block = self.inode_tables[self.group_of_inode(number) as usize] + (offset / self.block_size) as u32;
+
+        Ok((block, offset % self.block_size))
+    }
+
+    fn pointers_per_block(&self) -> usize {
+        self.block_size / 4
+    }
+
+    /// Collect the block numbers of `remaining` logical blocks, referenced by a (possibly indirect) block pointer.
+    /// Holes in sparse files are returned as block 0.
+    fn collect_blocks(&self, block: u32, depth: u32, remaining: &mut usize, blocks: &mut Vec<u32>) -> Result<(), FsError> {
+        if *remaining == 0 {
+            return Ok(());
+        }
+
+        if depth == 0 || block == 0 {
+            let covered = self.pointers_per_block().pow(depth).min(*remaining);
+            blocks.extend(core::iter::repeat(block).take(covered));
+            *remaining -= covered;
+            return Ok(());
+        }
+
+        let pointers = self.read_block(block)?;
+        for i in 0..self.pointers_per_block() {
+            if *remaining == 0 {
+                break;
+            }
+
+            self.collect_blocks(read_u32(&pointers, i * 4), depth - 1, remaining, blocks)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the block numbers of all logical blocks of an inode
+    fn data_blocks(&self, inode: &Inode) -> Result<Vec<u32>, FsError> {
+        let mut remaining = inode.size().div_ceil(self.block_size as u64) as usize;
+        let mut blocks = Vec::with_capacity(remaining);
+
+        for i in 0..DIRECT_BLOCKS {
+            self.collect_blocks(inode.block(i), 0, &mut remaining, &mut blocks)?;
+        }
+
+        for level in 1..=INDIRECT_LEVELS {
+            self.collect_blocks(inode.block(DIRECT_BLOCKS + level - 1), level as u32, &mut remaining, &mut blocks)?;
+        }
+
+        match remaining {
+            0 => Ok(blocks),
+            _ => Err(FsError::NoSpace) // the file is larger than the maximum, addressable with triple indirect blocks
+        }
+    }
+
+    /// Free an indirect block and all blocks referenced by it (data blocks only, if `free_data` is set)
+    fn free_indirect(&self, state: &mut State, block: u32, depth: u32, free_data: bool) -> Result<(), FsError> {
+        if block == 0 {
+            return Ok(());
+        }
+
+        if depth > 0 {
+            let pointers = self.read_block(block)?;
+            for i in 0..self.pointers_per_block() {
+                self.free_indirect(state, read_u32(&pointers, i * 4), depth - 1, free_data)?;
+            }
+        }
+
+        if depth > 0 || free_data {
+            self.free_block(state, block)?;
+        }
+
+        Ok(())
+    }
+
+    /// Free all blocks of an inode and set its size to 0
+    fn release_data(&self, state: &mut State, inode: &mut Inode) -> Result<(), FsError> {
+        for i in 0..DIRECT_BLOCKS + INDIRECT_LEVELS {
+            let depth = i.saturating_sub(DIRECT_BLOCKS - 1) as u32;
+            self.free_indirect(state, inode.block(i), depth, true)?;
+            inode.set_block(i, 0);
+        }
+
+        let sectors = self.acl_sectors(inode);
+        write_u32(&mut inode.data, I_BLOCKS, sectors);
+        inode.set_size(0);
+        Ok(())
+    }
+
+    /// Write the pointers to the next blocks of `blocks` into a newly allocated indirect block
+    fn build_indirect(&self, state: &mut State, group: u32, depth: u32, blocks: &mut &[u32], allocated: &mut usize) -> Result<u32, FsError> {
+        if depth == 0 {
+            let block = blocks[0];
+            *blocks = &blocks[1..];
+            return Ok(block);
+        }
+
+        let indirect = self.allocate_block(state, group)?;
+        *allocated += 1;
+
+        let mut pointers = vec![0; self.block_size];
+        for i in 0..self.pointers_per_block() {
+            if blocks.is_empty() {
+                break;
+            }
+
+            let pointer = self.build_indirect(state, group, depth - 1, blocks, allocated)?;
+            write_u32(&mut pointers, i * 4, pointer);
+        }
+
+        self.write_block(indirect, &pointers)?;
+        Ok(indirect)
+    }
+
+    /// Replace the block pointers of an inode with the given data blocks (indirect blocks are rebuilt)
+    fn set_data_blocks(&self, state: &mut State, inode: &mut Inode, blocks: &[u32]) -> Result<(), FsError> {
+        for level in 1..=INDIRECT_LEVELS {
+            let index = DIRECT_BLOCKS + level - 1;
+            self.free_indirect(state, inode.block(index), level as u32, false)?;
+            inode.set_block(index, 0);
+        }
+
+        let group = self.group_of_inode(inode.number);
+        let mut remaining = blocks;
+        let mut allocated = blocks.len();
+        for i in 0..DIRECT_BLOCKS + INDIRECT_LEVELS {
+            let pointer = match remaining.is_empty() {
+                true => 0,
+                false => self.build_indirect(state, group, i.saturating_sub(DIRECT_BLOCKS - 1) as u32, &mut remaining, &mut allocated)?
+            };
+            inode.set_block(i, pointer);
+        }
+
+        if !remaining.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+
+        let sectors = (allocated * self.block_size / SECTOR_SIZE) as u32 + self.acl_sectors(inode);
+        write_u32(&mut inode.data, I_BLOCKS, sectors);
+        Ok(())
+    }
+
+    /// Number of sectors occupied by the extended attribute block of an inode (which is not touched by this driver)
+    fn acl_sectors(&self, inode: &Inode) -> u32 {
+        match read_u32(&inode.data, I_FILE_ACL) {
+            0 => 0,
+            _ => (self.block_size / SECTOR_SIZE) as u32
+        }
+    }
+
+    /// Fast symbolic links do not occupy any blocks (except for an extended attribute block)
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        inode.file_type() == FileType::Symlink && inode.sectors() == self.acl_sectors(inode)
+    }
+
+    fn read_data(&self, inode: &Inode) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::with_capacity(inode.size() as usize);
+        for block in self.data_blocks(inode)? {
+            match block {
+                0 => data.resize(data.len() + self.block_size, 0),
+                block => data.extend_from_slice(&self.read_block(block)?)
+            }
+        }
+
+        data.truncate(inode.size() as usize);
+        Ok(data)
+    }
+
+    /// Replace the content of an inode
+    fn write_data(&self, state: &mut State, inode: &mut Inode, data: &[u8]) -> Result<(), FsError> {
+        self.release_data(state, inode)?;
+
+        let group = self.group_of_inode(inode.number);
+        let mut blocks = Vec::new();
+        for chunk in data.chunks(self.block_size) {
+            let block = match self.allocate_block(state, group) {
+                Ok(block) => block,
+                Err(error) => {
+                    for block in blocks {
+                        self.free_block(state, block)?;
+                    }
+
+                    return Err(error);
+                }
+            };
+
+            let mut buffer = vec![0; self.block_size];
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            self.write_block(block, &buffer)?;
+            blocks.push(block);
+        }
+
+        self.set_data_blocks(state, inode, &blocks)?;
+        inode.set_size(data.len() as u64);
+        self.write_inode(inode)
+    }
+
+    fn link_target(&self, inode: &Inode) -> Result<String, FsError> {
+        let size = inode.size() as usize;
+        let target = match self.is_fast_symlink(inode) {
+            true => inode.data[I_BLOCK..I_BLOCK + size.min(FAST_SYMLINK_SIZE)].to_vec(),
+            false => self.read_data(inode)?
+        };
+
+        String::from_utf8(target).map_err(|_| FsError::InvalidFileSystem)
+    }
+
+    fn dir_entries(&self, directory: &Inode) -> Result<Vec<DirEntry>, FsError> {
+        let mut entries = Vec::new();
+        for block in self.data_blocks(directory)? {
+            let data = self.read_block(block)?;
+            let mut offset = 0;
+
+            while offset + DIR_ENTRY_HEADER_SIZE <= self.block_size {
+                let inode = read_u32(&data, offset);
+                let record_length = read_u16(&data, offset + 4) as usize;
+                let name_length = match self.file_type_feature {
+                    true => data[offset + 6] as usize,
+                    false => read_u16(&data, offset + 6) as usize
+                };
+
+                if record_length < DIR_ENTRY_HEADER_SIZE || offset + record_length > self.block_size || DIR_ENTRY_HEADER_SIZE + name_length > record_length {
+                    return Err(FsError::InvalidFileSystem);
+                }
+
+                if inode != 0 {
+                    let name = String::from_utf8_lossy(&data[offset + DIR_ENTRY_HEADER_SIZE..offset + DIR_ENTRY_HEADER_SIZE + name_length]).into();
+                    entries.push(DirEntry { inode, name, block, offset, record_length });
+                }
+
+                offset += record_length;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn find_entry(&self, directory: &Inode, name: &str) -> Result<Option<DirEntry>, FsError> {
+        if !directory.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        Ok(self.dir_entries(directory)?.into_iter().find(|entry| entry.name == name))
+    }
+
+    fn check_new_entry(&self, directory: &Inode, name: &str) -> Result<(), FsError> {
+        validate_name(name)?;
+        match self.find_entry(directory, name)? {
+            Some(_) => Err(FsError::AlreadyExists),
+            None => Ok(())
+        }
+    }
+
+    fn write_dir_entry(&self, data: &mut [u8], offset: usize, inode: u32, record_length: usize, name: &str, file_type: u8) {
+        write_u32(data, offset, inode);
+        write_u16(data, offset + 4, record_length as u16);
+        match self.file_type_feature {
+            true => {
+                data[offset + 6] = name.len() as u8;
+                data[offset + 7] = file_type;
+            }
+            false => write_u16(data, offset + 6, name.len() as u16)
+        }
+
+        data[offset + DIR_ENTRY_HEADER_SIZE..offset + DIR_ENTRY_HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    /// Add an entry to a directory, using free space in an existing block or appending a new block
+    fn add_entry(&self, state: &mut State, directory: &mut Inode, name: &str, inode: u32, file_type: u8) -> Result<(), FsError> {
+        let needed = dir_entry_size(name.len());
+        let blocks = self.data_blocks(directory)?;
+
+        for &block in blocks.iter() {
+            let mut data = self.read_block(block)?;
+            let mut offset = 0;
+
+            while offset + DIR_ENTRY_HEADER_SIZE <= self.block_size {
+                let record_length = read_u16(&data, offset + 4) as usize;
+                if record_length < DIR_ENTRY_HEADER_SIZE {
+                    return Err(FsError::InvalidFileSystem);
+                }
+
+                // Unused entries can be taken over completely, used entries are split, if they have enough padding
+                let used = match read_u32(&data, offset) {
+                    0 => 0,
+                    _ => dir_entry_size(if self.file_type_feature { data[offset + 6] as usize } else { read_u16(&data, offset + 6) as usize })
+                };
+
+                if record_length - used >= needed {
+                    if used > 0 {
+                        write_u16(&mut data, offset + 4, used as u16);
+                    }
+
+                    self.write_dir_entry(&mut data, offset + used, inode, record_length - used, name, file_type);
+                    self.write_block(block, &data)?;
+                    return self.clear_index(directory);
+                }
+
+                offset += record_length;
+            }
+        }
+
+        // No space left, so the directory is extended by a block containing only the new entry
+        let block = self.allocate_block(state, self.group_of_inode(directory.number))?;
+        let mut data = vec![0; self.block_size];
+        self.write_dir_entry(&mut data, 0, inode, self.block_size, name, file_type);
+        self.write_block(block, &data)?;
+
+        let mut blocks = blocks;
+        blocks.push(block);
+        self.set_data_blocks(state, directory, &blocks)?;
+        directory.set_size((blocks.len() * self.block_size) as u64);
+        self.clear_index(directory)
+    }
+
+    fn remove_entry(&self, directory: &mut Inode, entry: &DirEntry) -> Result<(), FsError> {
+        let mut data = self.read_block(entry.block)?;
+
+        // The first entry of a block is marked as unused, other entries are merged into their predecessor
+        let mut offset = 0;
+        let mut previous = None;
+        while offset < entry.offset {
+            previous = Some(offset);
+            offset += read_u16(&data, offset + 4) as usize;
+        }
+
+        match previous {
+            Some(previous) => {
+                let record_length = read_u16(&data, previous + 4) as usize + entry.record_length;
+                write_u16(&mut data, previous + 4, record_length as u16);
+            }
+            None => write_u32(&mut data, entry.offset, 0)
+        }
+
+        self.write_block(entry.block, &data)?;
+        self.clear_index(directory)
+    }
+
+    /// The hashed index of a directory becomes invalid, when entries are added or removed.
+    /// Clearing the flag makes other implementations fall back to a linear search (e2fsck may rebuild the index).
+    fn clear_index(&self, directory: &mut Inode) -> Result<(), FsError> {
+        let flags = read_u32(&directory.data, I_FLAGS);
+        write_u32(&mut directory.data, I_FLAGS, flags & !INDEX_FLAG);
+
+        self.write_inode(directory)
+    }
+
+    /// Find the inode at the given path. Symbolic links are followed, except for the last component (if `follow_last` is not set).
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<u32, FsError> {
+        let mut components = path_components(path).map(String::from).collect::<VecDeque<String>>();
+        let mut current = ROOT_INODE;
+        let mut links = 0;
+
+        while let Some(component) = components.pop_front() {
+            let directory = self.read_inode(current)?;
+            let entry = self.find_entry(&directory, &component)?.ok_or(FsError::NotFound)?;
+            let inode = self.read_inode(entry.inode)?;
+
+            if inode.file_type() == FileType::Symlink && (follow_last || !components.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINK_DEPTH {
+                    return Err(FsError::SymlinkLoop);
+                }
+
+                // The target replaces the link in the remaining path (relative targets are resolved in the current directory)
+                let target = self.link_target(&inode)?;
+                if target.starts_with('/') {
+                    current = ROOT_INODE;
+                }
+
+                for component in path_components(&target).collect::<Vec<&str>>().into_iter().rev() {
+                    components.push_front(String::from(component));
+                }
+
+                continue;
+            }
+
+            current = entry.inode;
+        }
+
+        Ok(current)
+    }
+
+    /// Find the directory containing the last component of a path and the name of the component
+    fn resolve_parent<'a>(&self, path: &'a str) -> Result<(Inode, &'a str), FsError> {
+        let (parent, name) = split_path(path)?;
+        let directory = self.read_inode(self.resolve(parent, true)?)?;
+        if !directory.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        Ok((directory, name))
+    }
+
+    fn create_inode(&self, state: &mut State, path: &str, mode: u16) -> Result<(Inode, Inode), FsError> {
+        let (mut directory, name) = self.resolve_parent(path)?;
+        self.check_new_entry(&directory, name)?;
+
+        let inode = self.allocate_inode(state, directory.number, mode)?;
+        self.write_inode(&inode)?;
+
+        let file_type = match mode & S_IFMT {
+            S_IFDIR => FT_DIR,
+            _ => FT_REG_FILE
+        };
+        self.add_entry(state, &mut directory, name, inode.number, file_type)?;
+
+        Ok((directory, inode))
+    }
+}
+
+impl FileSystem for Ext2FileSystem {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let _state = self.state.lock();
+        let directory = self.read_inode(self.resolve(path, true)?)?;
+        if !directory.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut entries = Vec::new();
+        for entry in self.dir_entries(&directory)?.into_iter().filter(|entry| entry.name != "." && entry.name != "..") {
+            let inode = self.read_inode(entry.inode)?;
+            entries.push(DirectoryEntry { name: entry.name, file_type: inode.file_type(), size: inode.size() });
+        }
+
+        Ok(entries)
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        let _state = self.state.lock();
+        let inode = self.read_inode(self.resolve(path, true)?)?;
+        if inode.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.read_data(&inode)
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut state = self.state.lock();
+
+        let mut inode = match self.resolve(path, true) {
+            Ok(number) => self.read_inode(number)?,
+            Err(FsError::NotFound) => self.create_inode(&mut state, path, S_IFREG | DEFAULT_FILE_PERMISSIONS)?.1,
+            Err(error) => return Err(error)
+        };
+
+        if inode.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.write_data(&mut state, &mut inode, data)
+    }
+
+    fn create_file(&self, path: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut state = self.state.lock();
+
+        self.create_inode(&mut state, path, S_IFREG | DEFAULT_FILE_PERMISSIONS).map(|_| ())
+    }
+
+    fn create_directory(&self, path: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut state = self.state.lock();
+        let (mut parent, mut directory) = self.create_inode(&mut state, path, S_IFDIR | DEFAULT_DIRECTORY_PERMISSIONS)?;
+
+        // Every directory starts with the entries "." and ".."
+        let block = self.allocate_block(&mut state, self.group_of_inode(directory.number))?;
+        let mut data = vec![0; self.block_size];
+        self.write_dir_entry(&mut data, 0, directory.number, dir_entry_size(1), ".", FT_DIR);
+        self.write_dir_entry(&mut data, dir_entry_size(1), parent.number, self.block_size - dir_entry_size(1), "..", FT_DIR);
+        self.write_block(block, &data)?;
+
+        self.set_data_blocks(&mut state, &mut directory, &[block])?;
+        directory.set_size(self.block_size as u64);
+        directory.set_links(2);
+        self.write_inode(&directory)?;
+
+        // The parent is referenced by the ".." entry
+        parent.set_links(parent.links() + 1);
+        self.write_inode(&parent)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut state = self.state.lock();
+        let (mut directory, name) = self.resolve_parent(path)?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidName);
+        }
+
+        let entry = self.find_entry(&directory, name)?.ok_or(FsError::NotFound)?;
+        let mut inode = self.read_inode(entry.inode)?;
+
+        if inode.is_directory() {
+            if self.dir_entries(&inode)?.iter().any(|child| child.name != "." && child.name != "..") {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+
+            // Removes the reference of the ".." entry to the parent
+            directory.set_links(directory.links() - 1);
+        }
+
+        self.remove_entry(&mut directory, &entry)?;
+
+        // Directories are referenced by their own "." entry as well
+        let links = match inode.is_directory() {
+            true => 0,
+            false => inode.links() - 1
+        };
+
+        match links {
+            0 => self.free_inode(&mut state, &mut inode),
+            links => {
+                inode.set_links(links);
+                self.write_inode(&inode)
+            }
+        }
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let _state = self.state.lock();
+        self.disk.sync()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,923 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: fat                                                             ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Driver for the FAT12, FAT16 and FAT32 file systems with long    ║
+   ║         file name (VFAT) support. Works on any disk implementing the    ║
+   ║         'BlockDevice' trait (e.g. a disk driver or a RAM disk with an   ║
+   ║         image from the initrd).                                         ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::info;
+use crate::device::block::{Disk, SECTOR_SIZE};
+use crate::filesystem::{path_components, split_path, DirectoryEntry, FileSystem, FileType, FsError};
+use crate::sync::mutex::Mutex;
+
+const BOOT_SIGNATURE: u16 = 0xaa55;
+const FS_INFO_SIGNATURE: u32 = 0x41615252;
+
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / DIRECTORY_ENTRY_SIZE;
+
+// Attributes of directory entries
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f; // read only | hidden | system | volume id
+const ATTR_LONG_NAME_MASK: u8 = 0x3f;
+
+// Special values of the first byte of a directory entry
+const ENTRY_END: u8 = 0x00; // this and all following entries are unused
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_KANJI_E5: u8 = 0x05; // the name starts with 0xe5
+
+// Long file names are stored in up to 20 entries with 13 UCS-2 characters each, preceding the short entry
+const LFN_LAST: u8 = 0x40;
+const LFN_SEQUENCE_MASK: u8 = 0x1f;
+const LFN_CHARS: usize = 13;
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LENGTH: usize = 255;
+
+// Case flags of short entries without long file name (e.g. "readme.txt" is stored as "README  TXT")
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXTENSION: u8 = 0x10;
+
+const SHORT_NAME_SPECIAL_CHARS: &[u8] = b"$%'-_@~`!(){}^#&";
+const INVALID_NAME_CHARS: &str = "\"*/:<>?\\|";
+
+// Timestamps of created entries are set to 1980-01-01 00:00 (the earliest date, FAT can represent)
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+// Offsets in the FSInfo sector of FAT32
+const FS_INFO_FREE_COUNT: usize = 488;
+const FS_INFO_NEXT_FREE: usize = 492;
+const FS_INFO_UNKNOWN: u32 = 0xffffffff;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32
+}
+
+/// A mounted FAT file system. All operations are serialized by a single lock.
+pub struct FatFileSystem {
+    disk: Arc<Disk>,
+    fat_type: FatType,
+    first_sector: u64, // start of the file system on the disk (e.g. the first sector of a partition)
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    fat_count: u32,
+    fat_size: u32, // in sectors
+    root_directory_sector: u32, // only FAT12/16
+    root_directory_sectors: u32,
+    first_data_sector: u32,
+    cluster_count: u32,
+    root_cluster: u32, // only FAT32
+    fs_info_sector: u32,
+    state: Mutex<State>
+}
+
+struct State {
+    next_free: u32, // cluster, at which the search for a free cluster starts
+    modified: bool
+}
+
+/// Location of a directory's entries. On FAT12/16 the root directory has a fixed size and is located in front of the data area.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Directory {
+    FixedRoot,
+    Clusters(u32)
+}
+
+/// Position of a 32 byte entry in a directory
+#[derive(Copy, Clone, Debug)]
+struct Slot {
+    sector: u32,
+    index: usize
+}
+
+/// Parsed directory entry. `slots` contains the long file name entries (if any), followed by the short entry.
+struct Entry {
+    name: String,
+    short_name: [u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+    slots: Vec<Slot>
+}
+
+/// Collects the long file name entries, preceding a short entry
+#[derive(Default)]
+struct LongName {
+    characters: Vec<u16>,
+    checksum: u8,
+    next_sequence: u8,
+    slots: Vec<Slot>
+}
+
+impl FatType {
+    /// Value marking the end of a cluster chain
+    fn end_of_chain(&self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fffffff
+        }
+    }
+
+    /// Values from 0xff8 (0xfff8 for FAT16 and 0x0ffffff8 for FAT32) on mark the end of a cluster chain
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+}
+
+impl Entry {
+    fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || short_name_string(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+impl LongName {
+    fn add(&mut self, raw: &[u8], slot: Slot) {
+        let sequence = raw[0] & LFN_SEQUENCE_MASK;
+        if raw[0] & LFN_LAST != 0 {
+            self.characters = vec![0xffff; sequence as usize * LFN_CHARS];
+            self.checksum = raw[13];
+            self.next_sequence = sequence;
+            self.slots.clear();
+        }
+
+        // Entries must appear in descending order with the same checksum, otherwise the long name is orphaned
+        if sequence == 0 || sequence != self.next_sequence || raw[13] != self.checksum {
+            self.clear();
+            return;
+        }
+
+        let start = (sequence as usize - 1) * LFN_CHARS;
+        if start + LFN_CHARS > self.characters.len() {
+            self.clear();
+            return;
+        }
+
+        for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.characters[start + i] = read_u16(raw, *offset);
+        }
+
+        self.next_sequence = sequence - 1; // 'sequence' is at least 1 (checked above)
+        self.slots.push(slot);
+    }
+
+    /// Get the long name and its slots, if they belong to the short entry with the given checksum
+    fn take(&mut self, checksum: u8) -> Option<(String, Vec<Slot>)> {
+        let complete = !self.characters.is_empty() && self.next_sequence == 0 && self.checksum == checksum;
+        let characters = core::mem::take(&mut self.characters);
+        let slots = core::mem::take(&mut self.slots);
+        if !complete {
+            return None;
+        }
+
+        let length = characters.iter().position(|&character| character == 0x0000 || character == 0xffff).unwrap_or(characters.len());
+        Some((String::from_utf16_lossy(&characters[..length]), slots))
+    }
+
+    fn clear(&mut self) {
+        self.characters.clear();
+        self.checksum = 0;
+        self.next_sequence = 0;
+        self.slots.clear();
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &character| sum.rotate_right(1).wrapping_add(character))
+}
+
+/// Convert a short name (e.g. "README  TXT") into its displayed form (e.g. "README.TXT")
+fn short_name_string(short_name: &[u8; 11], case_flags: u8) -> String {
+    let convert = |part: &[u8], lower: bool| -> String {
+        let part = part.iter().map(|&character| if lower { character.to_ascii_lowercase() as char } else { character as char }).collect::<String>();
+        String::from(part.trim_end())
+    };
+
+    let mut base = *short_name;
+    if base[0] == ENTRY_KANJI_E5 {
+        base[0] = ENTRY_FREE;
+    }
+
+    let name = convert(&base[..8], case_flags & CASE_LOWER_BASE != 0);
+    let extension = convert(&base[8..], case_flags & CASE_LOWER_EXTENSION != 0);
+    match extension.is_empty() {
+        true => name,
+        false => format!("{}.{}", name, extension)
+    }
+}
+
+fn is_short_name_char(character: u8) -> bool {
+    character.is_ascii_uppercase() || character.is_ascii_digit() || SHORT_NAME_SPECIAL_CHARS.contains(&character)
+}
+
+/// Get the short name for a name, that is a valid 8.3 name in upper case (so no long file name is needed)
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    let valid = |part: &str, max_length: usize| part.len() <= max_length && part.bytes().all(is_short_name_char);
+    if base.is_empty() || name.ends_with('.') || !valid(base, 8) || !valid(extension, 3) {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+
+    Some(short_name)
+}
+
+/// Generate a unique short name (e.g. "LONGFI~1TXT") for a name, that needs a long file name
+fn generate_short_name(name: &str, entries: &[Entry]) -> Result<[u8; 11], FsError> {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, "")
+    };
+
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars().filter(|&character| character != ' ' && character != '.').map(|character| {
+            match character.is_ascii() && is_short_name_char(character.to_ascii_uppercase() as u8) {
+                true => character.to_ascii_uppercase() as u8,
+                false => b'_'
+            }
+        }).collect()
+    };
+
+    let base = convert(base);
+    let extension = convert(extension);
+    let extension_length = extension.len().min(3);
+
+    for number in 1..1000000 {
+        let suffix = format!("~{}", number);
+        let base_length = base.len().min(8 - suffix.len());
+
+        let mut short_name = [b' '; 11];
+        short_name[..base_length].copy_from_slice(&base[..base_length]);
+        short_name[base_length..base_length + suffix.len()].copy_from_slice(suffix.as_bytes());
+        short_name[8..8 + extension_length].copy_from_slice(&extension[..extension_length]);
+
+        if !entries.iter().any(|entry| entry.short_name == short_name) {
+            return Ok(short_name);
+        }
+    }
+
+    Err(FsError::NoSpace)
+}
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    let invalid = name.is_empty() || name == "." || name == ".."
+        || name.ends_with('.') || name.ends_with(' ')
+        || name.encode_utf16().count() > MAX_NAME_LENGTH
+        || name.chars().any(|character| character < ' ' || INVALID_NAME_CHARS.contains(character));
+
+    match invalid {
+        true => Err(FsError::InvalidName),
+        false => Ok(())
+    }
+}
+
+impl FatFileSystem {
+    /// Mount the file system, starting at `first_sector` of the disk (0, if the disk is not partitioned).
+    pub fn mount(disk: Arc<Disk>, first_sector: u64) -> Result<Self, FsError> {
+        let mut boot_sector = vec![0u8; SECTOR_SIZE];
+        disk.read(first_sector, &mut boot_sector)?;
+
+        // Only 512 byte sectors are supported, since the block layer uses this sector size
+        if read_u16(&boot_sector, 510) != BOOT_SIGNATURE || read_u16(&boot_sector, 11) as usize != SECTOR_SIZE {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        let sectors_per_cluster = boot_sector[13] as u32;
+        let reserved_sectors = read_u16(&boot_sector, 14) as u32;
+        let fat_count = boot_sector[16] as u32;
+        let root_entry_count = read_u16(&boot_sector, 17) as usize;
+        let total_sectors = match read_u16(&boot_sector, 19) {
+            0 => read_u32(&boot_sector, 32),
+            count => count as u32
+        };
+        let fat_size = match read_u16(&boot_sector, 22) {
+            0 => read_u32(&boot_sector, 36),
+            size => size as u32
+        };
+
+        if !sectors_per_cluster.is_power_of_two() || reserved_sectors == 0 || fat_count == 0 || fat_size == 0 {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        let root_directory_sector = reserved_sectors + fat_count * fat_size;
+        let root_directory_sectors = (root_entry_count * DIRECTORY_ENTRY_SIZE).div_ceil(SECTOR_SIZE) as u32;
+        let first_data_sector = root_directory_sector + root_directory_sectors;
+        if total_sectors <= first_data_sector || first_sector + total_sectors as u64 > disk.sector_count() {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        // The FAT type is determined by the number of clusters only
+        let cluster_count = (total_sectors - first_data_sector) / sectors_per_cluster;
+        let fat_type = match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32
+        };
+
+        let (root_cluster, fs_info_sector) = match fat_type {
+            FatType::Fat32 => (read_u32(&boot_sector, 44), read_u16(&boot_sector, 48) as u32),
+            _ => (0, 0)
+        };
+
+        info!("Mounted {:?} file system ([{}] clusters with [{}] bytes)", fat_type, cluster_count, sectors_per_cluster as usize * SECTOR_SIZE);
+
+        Ok(Self {
+            disk, fat_type, first_sector,
+            sectors_per_cluster, reserved_sectors, fat_count, fat_size,
+            root_directory_sector, root_directory_sectors, first_data_sector,
+            cluster_count, root_cluster, fs_info_sector,
+            state: Mutex::new(State { next_free: 2, modified: false })
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    fn read_sectors(&self, sector: u32, count: usize) -> Result<Vec<u8>, FsError> {
+        let mut buffer = vec![0; count * SECTOR_SIZE];
+        self.disk.read(self.first_sector + sector as u64, &mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    fn write_sectors(&self, sector: u32, data: &[u8]) -> Result<(), FsError> {
+        self.disk.write(self.first_sector + sector as u64, data)?;
+        Ok(())
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.first_data_sector + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn clear_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        self.write_sectors(self.cluster_sector(cluster), &vec![0; self.cluster_size()])
+    }
+
+    /// Byte offset of a cluster's entry in the FAT
+    fn fat_offset(&self, cluster: u32) -> usize {
+        match self.fat_type {
+            FatType::Fat12 => cluster as usize + cluster as usize / 2,
+            FatType::Fat16 => cluster as usize * 2,
+            FatType::Fat32 => cluster as usize * 4
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.fat_offset(cluster);
+        let sector = self.reserved_sectors + (offset / SECTOR_SIZE) as u32;
+        let offset = offset % SECTOR_SIZE;
+
+        // A FAT12 entry may span two sectors
+        let data = self.read_sectors(sector, if offset == SECTOR_SIZE - 1 { 2 } else { 1 })?;
+        Ok(match self.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => (read_u16(&data, offset) >> 4) as u32,
+            FatType::Fat12 => (read_u16(&data, offset) & 0x0fff) as u32,
+            FatType::Fat16 => read_u16(&data, offset) as u32,
+            FatType::Fat32 => read_u32(&data, offset) & 0x0fffffff
+        })
+    }
+
+    /// Update the entry of a cluster in all copies of the FAT
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let offset = self.fat_offset(cluster);
+        let count = if offset % SECTOR_SIZE == SECTOR_SIZE - 1 { 2 } else { 1 };
+
+        for fat in 0..self.fat_count {
+            let sector = self.reserved_sectors + fat * self.fat_size + (offset / SECTOR_SIZE) as u32;
+            let mut data = self.read_sectors(sector, count)?;
+            let offset = offset % SECTOR_SIZE;
+
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let old = read_u16(&data, offset);
+                    let new = match cluster % 2 {
+                        1 => (old & 0x000f) | ((value as u16) << 4),
+                        _ => (old & 0xf000) | (value as u16 & 0x0fff)
+                    };
+                    write_u16(&mut data, offset, new);
+                }
+                FatType::Fat16 => write_u16(&mut data, offset, value as u16),
+                // The upper four bits are reserved and must be preserved
+                FatType::Fat32 => {
+                    let old = read_u32(&data, offset);
+                    write_u32(&mut data, offset, (old & 0xf0000000) | (value & 0x0fffffff));
+                }
+            }
+
+            self.write_sectors(sector, &data)?;
+        }
+
+        Ok(())
+    }
+
+    fn cluster_chain(&self, first_cluster: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first_cluster;
+
+        while cluster >= 2 && !self.fat_type.is_end_of_chain(cluster) {
+            // Reject clusters outside the data area (e.g. bad clusters) and cyclic chains
+            if cluster >= self.cluster_count + 2 || chain.len() >= self.cluster_count as usize {
+                return Err(FsError::InvalidFileSystem);
+            }
+
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+
+        Ok(chain)
+    }
+
+    /// Allocate a free cluster and append it to the chain ending with `previous` (if given)
+    fn allocate_cluster(&self, state: &mut State, previous: Option<u32>) -> Result<u32, FsError> {
+        let last_cluster = self.cluster_count + 1;
+        let start = state.next_free.clamp(2, last_cluster);
+
+        let mut free_cluster = None;
+        for cluster in (start..=last_cluster).chain(2..start) {
+            if self.fat_entry(cluster)? == 0 {
+                free_cluster = Some(cluster);
+                break;
+            }
+        }
+
+        let cluster = free_cluster.ok_or(FsError::NoSpace)?;
+        self.set_fat_entry(cluster, self.fat_type.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+
+        state.next_free = cluster + 1;
+        state.modified = true;
+        Ok(cluster)
+    }
+
+    fn free_cluster_chain(&self, state: &mut State, first_cluster: u32) -> Result<(), FsError> {
+        for cluster in self.cluster_chain(first_cluster)? {
+            self.set_fat_entry(cluster, 0)?;
+        }
+
+        state.modified = true;
+        Ok(())
+    }
+
+    /// Write data into a newly allocated cluster chain. Returns the first cluster (0 for empty data).
+    fn write_clusters(&self, state: &mut State, data: &[u8]) -> Result<u32, FsError> {
+        let mut first_cluster = 0;
+        let mut previous = None;
+
+        for chunk in data.chunks(self.cluster_size()) {
+            let cluster = match self.allocate_cluster(state, previous) {
+                Ok(cluster) => cluster,
+                Err(error) => {
+                    // Release the clusters allocated so far
+                    if first_cluster != 0 {
+                        self.free_cluster_chain(state, first_cluster)?;
+                    }
+
+                    return Err(error);
+                }
+            };
+
+            if first_cluster == 0 {
+                first_cluster = cluster;
+            }
+
+            let mut buffer = vec![0; self.cluster_size()];
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            self.write_sectors(self.cluster_sector(cluster), &buffer)?;
+            previous = Some(cluster);
+        }
+
+        Ok(first_cluster)
+    }
+
+    fn root_directory(&self) -> Directory {
+        match self.fat_type {
+            FatType::Fat32 => Directory::Clusters(self.root_cluster),
+            _ => Directory::FixedRoot
+        }
+    }
+
+    /// Directory referenced by an entry (the first cluster of ".." is 0, if it refers to the root directory)
+    fn entry_directory(&self, first_cluster: u32) -> Directory {
+        match first_cluster {
+            0 => self.root_directory(),
+            cluster => Directory::Clusters(cluster)
+        }
+    }
+
+    fn directory_sectors(&self, directory: Directory) -> Result<Vec<u32>, FsError> {
+        match directory {
+            Directory::FixedRoot => Ok((self.root_directory_sector..self.root_directory_sector + self.root_directory_sectors).collect()),
+            Directory::Clusters(first_cluster) => Ok(self.cluster_chain(first_cluster)?.iter()
+                .flat_map(|&cluster| {
+                    let sector = self.cluster_sector(cluster);
+                    sector..sector + self.sectors_per_cluster
+                })
+                .collect())
+        }
+    }
+
+    fn entries(&self, directory: Directory) -> Result<Vec<Entry>, FsError> {
+        let mut entries = Vec::new();
+        let mut long_name = LongName::default();
+
+        for sector in self.directory_sectors(directory)? {
+            let data = self.read_sectors(sector, 1)?;
+            for (index, raw) in data.chunks(DIRECTORY_ENTRY_SIZE).enumerate() {
+                let slot = Slot { sector, index };
+                match raw[0] {
+                    ENTRY_END => return Ok(entries),
+                    ENTRY_FREE => {
+                        long_name.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                let attributes = raw[11];
+                if attributes & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+                    long_name.add(raw, slot);
+                    continue;
+                }
+
+                if attributes & ATTR_VOLUME_ID != 0 {
+                    long_name.clear();
+                    continue;
+                }
+
+                let mut short_name = [0u8; 11];
+                short_name.copy_from_slice(&raw[..11]);
+
+                let (name, mut slots) = long_name.take(short_name_checksum(&short_name))
+                    .unwrap_or_else(|| (short_name_string(&short_name, raw[12]), Vec::new()));
+                slots.push(slot);
+
+                // The upper half of the first cluster is only used by FAT32
+                let first_cluster_high = match self.fat_type {
+                    FatType::Fat32 => read_u16(raw, 20) as u32,
+                    _ => 0
+                };
+
+                entries.push(Entry {
+                    name, short_name, attributes, slots,
+                    first_cluster: first_cluster_high << 16 | read_u16(raw, 26) as u32,
+                    size: read_u32(raw, 28)
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn find(&self, directory: Directory, name: &str) -> Result<Option<Entry>, FsError> {
+        Ok(self.entries(directory)?.into_iter().find(|entry| entry.matches(name)))
+    }
+
+    /// Find the directory at the given path
+    fn directory(&self, path: &str) -> Result<Directory, FsError> {
+        let mut directory = self.root_directory();
+
+        for component in path_components(path) {
+            let entry = self.find(directory, component)?.ok_or(FsError::NotFound)?;
+            if !entry.is_directory() {
+                return Err(FsError::NotADirectory);
+            }
+
+            directory = self.entry_directory(entry.first_cluster);
+        }
+
+        Ok(directory)
+    }
+
+    /// Find the entry at the given path, together with the directory containing it
+    fn lookup(&self, path: &str) -> Result<(Directory, Entry), FsError> {
+        let (parent, name) = split_path(path)?;
+        let directory = self.directory(parent)?;
+        let entry = self.find(directory, name)?.ok_or(FsError::NotFound)?;
+
+        Ok((directory, entry))
+    }
+
+    fn write_slot(&self, slot: Slot, raw: &[u8]) -> Result<(), FsError> {
+        let mut data = self.read_sectors(slot.sector, 1)?;
+        let offset = slot.index * DIRECTORY_ENTRY_SIZE;
+        data[offset..offset + DIRECTORY_ENTRY_SIZE].copy_from_slice(raw);
+
+        self.write_sectors(slot.sector, &data)
+    }
+
+    /// Find `count` consecutive unused slots in a directory (extending the directory by a cluster, if necessary)
+    fn free_slots(&self, state: &mut State, directory: Directory, count: usize) -> Result<Vec<Slot>, FsError> {
+        loop {
+            let mut slots = Vec::new();
+            for sector in self.directory_sectors(directory)? {
+                let data = self.read_sectors(sector, 1)?;
+                for index in 0..ENTRIES_PER_SECTOR {
+                    match data[index * DIRECTORY_ENTRY_SIZE] {
+                        ENTRY_END | ENTRY_FREE => slots.push(Slot { sector, index }),
+                        _ => slots.clear()
+                    }
+
+                    if slots.len() == count {
+                        return Ok(slots);
+                    }
+                }
+            }
+
+            match directory {
+                Directory::FixedRoot => return Err(FsError::NoSpace),
+                Directory::Clusters(first_cluster) => {
+                    let last_cluster = *self.cluster_chain(first_cluster)?.last().ok_or(FsError::InvalidFileSystem)?;
+                    let cluster = self.allocate_cluster(state, Some(last_cluster))?;
+                    self.clear_cluster(cluster)?;
+                }
+            }
+        }
+    }
+
+    /// Create a directory entry (with long file name entries, if the name is not a valid upper case 8.3 name)
+    fn create_entry(&self, state: &mut State, directory: Directory, name: &str, attributes: u8, first_cluster: u32) -> Result<Entry, FsError> {
+        validate_name(name)?;
+
+        let entries = self.entries(directory)?;
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, long_name) = match exact_short_name(name) {
+            Some(short_name) => (short_name, Vec::new()),
+            None => (generate_short_name(name, &entries)?, name.encode_utf16().collect::<Vec<u16>>())
+        };
+
+        let long_name_entries = long_name.len().div_ceil(LFN_CHARS);
+        let slots = self.free_slots(state, directory, long_name_entries + 1)?;
+        let checksum = short_name_checksum(&short_name);
+
+        // Long file name entries are stored in reverse order (the first slot contains the last part of the name)
+        for (i, slot) in slots[..long_name_entries].iter().enumerate() {
+            let sequence = long_name_entries - i;
+            let mut raw = [0u8; DIRECTORY_ENTRY_SIZE];
+            raw[0] = sequence as u8 | if i == 0 { LFN_LAST } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+
+            for (j, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                // The name is terminated by 0x0000 and padded with 0xffff
+                let position = (sequence - 1) * LFN_CHARS + j;
+                let character = match position.cmp(&long_name.len()) {
+                    core::cmp::Ordering::Less => long_name[position],
+                    core::cmp::Ordering::Equal => 0x0000,
+                    core::cmp::Ordering::Greater => 0xffff
+                };
+                write_u16(&mut raw, *offset, character);
+            }
+
+            self.write_slot(*slot, &raw)?;
+        }
+
+        let mut raw = [0u8; DIRECTORY_ENTRY_SIZE];
+        raw[..11].copy_from_slice(&short_name);
+        raw[11] = attributes;
+        write_u16(&mut raw, 16, DEFAULT_DATE); // creation date
+        write_u16(&mut raw, 18, DEFAULT_DATE); // last access date
+        write_u16(&mut raw, 20, (first_cluster >> 16) as u16);
+        write_u16(&mut raw, 24, DEFAULT_DATE); // modification date
+        write_u16(&mut raw, 26, first_cluster as u16);
+        self.write_slot(*slots.last().unwrap(), &raw)?;
+
+        Ok(Entry { name: String::from(name), short_name, attributes, first_cluster, size: 0, slots })
+    }
+
+    fn update_entry(&self, entry: &Entry, first_cluster: u32, size: u32) -> Result<(), FsError> {
+        let slot = *entry.slots.last().unwrap();
+        let mut data = self.read_sectors(slot.sector, 1)?;
+        let raw = &mut data[slot.index * DIRECTORY_ENTRY_SIZE..(slot.index + 1) * DIRECTORY_ENTRY_SIZE];
+        write_u16(raw, 20, (first_cluster >> 16) as u16);
+        write_u16(raw, 26, first_cluster as u16);
+        write_u32(raw, 28, size);
+
+        self.write_sectors(slot.sector, &data)
+    }
+
+    fn remove_entry(&self, entry: &Entry) -> Result<(), FsError> {
+        for slot in entry.slots.iter() {
+            let mut data = self.read_sectors(slot.sector, 1)?;
+            data[slot.index * DIRECTORY_ENTRY_SIZE] = ENTRY_FREE;
+            self.write_sectors(slot.sector, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Mark the free cluster count and next free cluster hints in the FSInfo sector as unknown,
+    /// since they are not maintained by this driver
+    fn invalidate_fs_info(&self) -> Result<(), FsError> {
+        if self.fat_type != FatType::Fat32 || self.fs_info_sector == 0 {
+            return Ok(());
+        }
+
+        let mut data = self.read_sectors(self.fs_info_sector, 1)?;
+        if read_u32(&data, 0) != FS_INFO_SIGNATURE {
+            return Ok(());
+        }
+
+        write_u32(&mut data, FS_INFO_FREE_COUNT, FS_INFO_UNKNOWN);
+        write_u32(&mut data, FS_INFO_NEXT_FREE, FS_INFO_UNKNOWN);
+        self.write_sectors(self.fs_info_sector, &data)
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn name(&self) -> &'static str {
+        match self.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32"
+        }
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let _state = self.state.lock();
+        let directory = self.directory(path)?;
+
+        Ok(self.entries(directory)?.into_iter()
+            .filter(|entry| !entry.is_dot())
+            .map(|entry| DirectoryEntry {
+                file_type: if entry.is_directory() { FileType::Directory } else { FileType::File },
+                size: entry.size as u64,
+                name: entry.name
+            })
+            .collect())
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        let _state = self.state.lock();
+        let (_, entry) = self.lookup(path)?;
+        if entry.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+
+        let mut data = Vec::with_capacity(entry.size as usize);
+        for cluster in self.cluster_chain(entry.first_cluster)? {
+            if data.len() >= entry.size as usize {
+                break;
+            }
+
+            data.extend_from_slice(&self.read_sectors(self.cluster_sector(cluster), self.sectors_per_cluster as usize)?);
+        }
+
+        if data.len() < entry.size as usize {
+            return Err(FsError::InvalidFileSystem);
+        }
+
+        data.truncate(entry.size as usize);
+        Ok(data)
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let size = u32::try_from(data.len()).map_err(|_| FsError::NoSpace)?;
+        let mut state = self.state.lock();
+        let (parent, name) = split_path(path)?;
+        let directory = self.directory(parent)?;
+
+        let entry = match self.find(directory, name)? {
+            Some(entry) if entry.is_directory() => return Err(FsError::IsADirectory),
+            Some(entry) if entry.attributes & ATTR_READ_ONLY != 0 => return Err(FsError::ReadOnly),
+            Some(entry) => {
+                self.update_entry(&entry, 0, 0)?;
+                self.free_cluster_chain(&mut state, entry.first_cluster)?;
+                entry
+            }
+            None => self.create_entry(&mut state, directory, name, ATTR_ARCHIVE, 0)?
+        };
+
+        let first_cluster = self.write_clusters(&mut state, data)?;
+        self.update_entry(&entry, first_cluster, size)
+    }
+
+    fn create_file(&self, path: &str) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        let (parent, name) = split_path(path)?;
+        let directory = self.directory(parent)?;
+
+        self.create_entry(&mut state, directory, name, ATTR_ARCHIVE, 0).map(|_| ())
+    }
+
+    fn create_directory(&self, path: &str) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        let (parent, name) = split_path(path)?;
+        let parent = self.directory(parent)?;
+        validate_name(name)?;
+        if self.find(parent, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let cluster = self.allocate_cluster(&mut state, None)?;
+        self.clear_cluster(cluster)?;
+
+        // Every directory (except the root directory) starts with the entries "." and ".."
+        let parent_cluster = match parent {
+            Directory::Clusters(cluster) if parent != self.root_directory() => cluster,
+            _ => 0
+        };
+
+        let sector = self.cluster_sector(cluster);
+        for (index, (short_name, first_cluster)) in [(b".          ", cluster), (b"..         ", parent_cluster)].iter().enumerate() {
+            let mut raw = [0u8; DIRECTORY_ENTRY_SIZE];
+            raw[..11].copy_from_slice(*short_name);
+            raw[11] = ATTR_DIRECTORY;
+            write_u16(&mut raw, 16, DEFAULT_DATE);
+            write_u16(&mut raw, 18, DEFAULT_DATE);
+            write_u16(&mut raw, 20, (first_cluster >> 16) as u16);
+            write_u16(&mut raw, 24, DEFAULT_DATE);
+            write_u16(&mut raw, 26, *first_cluster as u16);
+            self.write_slot(Slot { sector, index }, &raw)?;
+        }
+
+        if let Err(error) = self.create_entry(&mut state, parent, name, ATTR_DIRECTORY, cluster) {
+            self.free_cluster_chain(&mut state, cluster)?;
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        let (_, entry) = self.lookup(path)?;
+        if entry.is_dot() {
+            return Err(FsError::InvalidName);
+        }
+
+        if entry.attributes & ATTR_READ_ONLY != 0 {
+            return Err(FsError::ReadOnly);
+        }
+
+        if entry.is_directory() && self.entries(self.entry_directory(entry.first_cluster))?.iter().any(|child| !child.is_dot()) {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        self.remove_entry(&entry)?;
+        if entry.first_cluster != 0 {
+            self.free_cluster_chain(&mut state, entry.first_cluster)?;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if state.modified {
+            self.invalidate_fs_info()?;
+            state.modified = false;
+        }
+
+        self.disk.sync()?;
+        Ok(())
+    }
+}
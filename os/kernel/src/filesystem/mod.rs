@@ -0,0 +1,150 @@
+pub mod fat;
+pub mod ext2;
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::info;
+use spin::RwLock;
+use crate::device::block::{BlockError, Disk};
+use crate::device::ramdisk::RamDisk;
+use crate::filesystem::ext2::Ext2FileSystem;
+use crate::filesystem::fat::FatFileSystem;
+use crate::{device_manager, initrd};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    InvalidName,
+    NoSpace,
+    InvalidFileSystem, // the disk does not contain a supported file system (or it is corrupted)
+    ReadOnly,
+    SymlinkLoop, // too many symbolic links while resolving a path
+    Io(BlockError)
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        FsError::Io(error)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink
+}
+
+#[derive(Clone, Debug)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub file_type: FileType,
+    pub size: u64
+}
+
+/// Common interface of all file system drivers.
+/// Paths are absolute and use '/' as separator (e.g. "/boot/kernel.elf").
+pub trait FileSystem: Send + Sync {
+    /// Type of the file system (e.g. "fat32")
+    fn name(&self) -> &'static str;
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirectoryEntry>, FsError>;
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FsError>;
+
+    /// Replace the content of a file. The file is created, if it does not exist.
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<(), FsError>;
+
+    /// Create an empty file
+    fn create_file(&self, path: &str) -> Result<(), FsError>;
+
+    fn create_directory(&self, path: &str) -> Result<(), FsError>;
+
+    /// Delete a file or an empty directory
+    fn remove(&self, path: &str) -> Result<(), FsError>;
+
+    /// Write all modifications back to the disk
+    fn sync(&self) -> Result<(), FsError>;
+}
+
+/// A file system, that is accessible below `/<name>` (see `mount()`)
+struct Mount {
+    name: String,
+    file_system: Arc<dyn FileSystem>
+}
+
+// mounted file systems
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+/// Find a disk by its name: Either a block device (e.g. "vd0", see `DeviceManager::block_devices()`)
+/// or a file system image in the initrd (e.g. "fat.img"), which is copied into a RAM disk.
+pub fn find_disk(name: &str) -> Option<Arc<Disk>> {
+    if let Some(disk) = device_manager().block_device(name) {
+        return Some(disk);
+    }
+
+    initrd().entries()
+        .find(|entry| entry.filename().as_str() == Ok(name))
+        .map(|entry| Arc::new(Disk::new(Arc::new(RamDisk::new(entry.data())))))
+}
+
+/// Detect the file system on a disk (partition tables are not supported, so the file system must start at the first sector)
+pub fn open(disk: Arc<Disk>) -> Result<Arc<dyn FileSystem>, FsError> {
+    // The ext2 superblock is located behind the boot sector, so it does not match a FAT file system by accident
+    match Ext2FileSystem::mount(Arc::clone(&disk), 0) {
+        Ok(file_system) => Ok(Arc::new(file_system)),
+        Err(FsError::InvalidFileSystem) => Ok(Arc::new(FatFileSystem::mount(disk, 0)?)),
+        Err(error) => Err(error)
+    }
+}
+
+/// Make a file system accessible below `/<name>`
+pub fn mount(name: &str, file_system: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::InvalidName);
+    }
+
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|mount| mount.name == name) {
+        return Err(FsError::AlreadyExists);
+    }
+
+    info!("Mounting [{}] file system at [/{}]", file_system.name(), name);
+    mounts.push(Mount { name: name.to_string(), file_system });
+    Ok(())
+}
+
+/// Write all modifications of the file system at `/<name>` back to its disk and remove it.
+/// The file system stays mounted, if writing fails.
+pub fn unmount(name: &str) -> Result<(), FsError> {
+    let file_system = MOUNTS.read().iter().find(|mount| mount.name == name).map(|mount| Arc::clone(&mount.file_system)).ok_or(FsError::NotFound)?;
+    file_system.sync()?;
+
+    MOUNTS.write().retain(|mount| mount.name != name);
+    Ok(())
+}
+
+/// Names of all mount points together with the types of their file systems
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.read().iter().map(|mount| (mount.name.clone(), mount.file_system.name())).collect()
+}
+
+/// Split a path into its components (empty components, e.g. from "//", are skipped)
+pub fn path_components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+/// Split a path into the path of its parent directory and its last component (e.g. "/a/b" -> ("/a", "b"))
+pub fn split_path(path: &str) -> Result<(&str, &str), FsError> {
+    let path = path.trim_end_matches('/');
+    match path.rsplit_once('/') {
+        Some((parent, name)) if !name.is_empty() => Ok((parent, name)),
+        None if !path.is_empty() => Ok(("", path)),
+        _ => Err(FsError::InvalidName)
+    }
+}
@@ -4,9 +4,10 @@ use alloc::vec::Vec;
 use core::ops::Deref;
 use core::ptr;
 use spin::Mutex;
+use x86_64::instructions::interrupts;
 use x86_64::registers::control::Cr2;
 use x86_64::set_general_handler;
-use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 use crate::{apic, idt, interrupt_dispatcher, scheduler};
 use crate::memory::PAGE_SIZE;
 
@@ -59,6 +60,10 @@ pub enum InterruptVector {
     SecondaryAta = 0x2f,
     // Possibly some other interrupts supported by IO APICs
 
+    // Inter-processor interrupts (245 - 246)
+    Reschedule = 0xf5,
+    TlbShootdown = 0xf6,
+
     // Local APIC interrupts (247 - 254)
     Cmci = 0xf8,
     ApicTimer = 0xf9,
@@ -158,6 +163,9 @@ impl TryFrom<u8> for InterruptVector {
                 Ok(InterruptVector::SecondaryAta)
             }
 
+            value if value == InterruptVector::Reschedule as u8 => Ok(InterruptVector::Reschedule),
+            value if value == InterruptVector::TlbShootdown as u8 => Ok(InterruptVector::TlbShootdown),
+
             value if value == InterruptVector::Cmci as u8 => Ok(InterruptVector::Cmci),
             value if value == InterruptVector::ApicTimer as u8 => Ok(InterruptVector::ApicTimer),
             value if value == InterruptVector::Thermal as u8 => Ok(InterruptVector::Thermal),
@@ -175,6 +183,10 @@ impl TryFrom<u8> for InterruptVector {
 
 const MAX_VECTORS: usize = 256;
 
+// Vectors between the PC/AT compatible interrupts and the inter-processor interrupts are handed out dynamically (e.g. for MSI)
+const FIRST_FREE_VECTOR: u8 = 0x30;
+const LAST_FREE_VECTOR: u8 = 0xf4;
+
 pub struct InterruptDispatcher {
     int_vectors: Vec<Mutex<Vec<Box<dyn InterruptHandler>>>>,
 }
@@ -198,6 +210,11 @@ pub fn setup_idt() {
     }
 }
 
+/// Address identifying an assigned handler (stays the same, when the box is moved)
+pub fn handler_address(handler: &dyn InterruptHandler) -> usize {
+    ptr::from_ref(handler) as *const () as usize
+}
+
 fn handle_exception(frame: InterruptStackFrame, index: u8, error: Option<u64>) {
     panic!("CPU Exception: [{} - {:?}]\nError code: [{:?}]\n{:?}", index, InterruptVector::try_from(index).unwrap(), error, frame);
 }
@@ -206,9 +223,14 @@ fn handle_page_fault(frame: InterruptStackFrame, _index: u8, error: Option<u64>)
     let fault_addr = Cr2::read().expect("Invalid address in CR2 during page fault");
     let thread = scheduler().current_thread();
 
+    // Check if page fault has been caused by writing to a present, but read-only page
+    let write_violation = error.is_some_and(|code| PageFaultErrorCode::from_bits_truncate(code).contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE));
+
     // Check if page fault occurred right below the user stack
     if !thread.is_kernel_thread() && !thread.stacks_locked() && fault_addr > (thread.user_stack_start() - PAGE_SIZE as u64) && fault_addr < thread.user_stack_start() {
         thread.grow_user_stack(); // Grow stack by one page
+    } else if write_violation && !thread.is_kernel_thread() && thread.process().address_space().resolve_copy_on_write(fault_addr) {
+        // Page was shared copy-on-write after fork and is now copied
     } else {
         panic!("Page Fault!\nError code: [{:?}]\nAddress: [0x{:0>16x}]\n{:?}", error, fault_addr, frame);
     }
@@ -235,6 +257,37 @@ impl InterruptDispatcher {
         }
     }
 
+    /// Remove a handler, that has been assigned via `assign()`. Since a vector may be shared by several devices,
+    /// the handler is identified by its address (see `handler_address()`). Returns true, if no handlers are left for the vector.
+    pub fn unassign(&self, vector: InterruptVector, handler: usize) -> bool {
+        // Interrupts are disabled, so that the dispatcher does not find the vector locked on this processor
+        interrupts::without_interrupts(|| {
+            let mut handlers = self.int_vectors[vector as usize].lock();
+            handlers.retain(|assigned| handler_address(assigned.as_ref()) != handler);
+            handlers.is_empty()
+        })
+    }
+
+    /// Assign a handler to a vector, that has no handlers yet, and return the vector number.
+    /// If all dynamically allocatable vectors are in use, the handler is handed back.
+    pub fn allocate(&self, handler: Box<dyn InterruptHandler>) -> Result<u8, Box<dyn InterruptHandler>> {
+        for vector in FIRST_FREE_VECTOR..=LAST_FREE_VECTOR {
+            let mut handlers = self.int_vectors[vector as usize].lock();
+            if handlers.is_empty() {
+                handlers.push(handler);
+                return Ok(vector);
+            }
+        }
+
+        Err(handler)
+    }
+
+    /// Remove all handlers from a vector, that has been returned by `allocate()`
+    pub fn free(&self, vector: u8) {
+        assert!((FIRST_FREE_VECTOR..=LAST_FREE_VECTOR).contains(&vector), "Freeing interrupt vector {}, which has not been allocated dynamically!", vector);
+        self.int_vectors[vector as usize].lock().clear();
+    }
+
     pub fn dispatch(&self, interrupt: u8) {
         let handler_vec_mutex = self.int_vectors.get(interrupt as usize).unwrap_or_else(|| panic!("Interrupt Dispatcher: No handler vec assigned for interrupt [{}]!", interrupt));
         let mut handler_vec = handler_vec_mutex.try_lock();
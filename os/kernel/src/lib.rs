@@ -23,6 +23,7 @@
 use crate::device::apic::Apic;
 use crate::device::lfb_terminal::{CursorThread, LFBTerminal};
 use crate::device::pit::Timer;
+use crate::device::tsc::Tsc;
 use crate::device::ps2::PS2;
 use crate::device::serial;
 use crate::device::serial::{BaudRate, ComPort, SerialPort};
@@ -31,9 +32,10 @@ use crate::device::terminal::Terminal;
 use crate::memory::alloc::{AcpiHandler, KernelAllocator};
 use crate::interrupt::interrupt_dispatcher::InterruptDispatcher;
 use crate::log::Logger;
-use crate::process::scheduler::Scheduler;
+use crate::process::scheduler::{Scheduler, PRIORITY_LEVELS};
 use crate::process::thread::Thread;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use device::e1000_driver::IntelE1000Device;
 use core::fmt::Arguments;
 use core::panic::PanicInfo;
@@ -50,8 +52,12 @@ use x86_64::structures::paging::PhysFrame;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::PhysAddr;
 use crate::device::pci::PciBus;
+use crate::device::driver::DeviceManager;
 use crate::memory::PAGE_SIZE;
 use crate::process::process::ProcessManager;
+use crate::memory::shared::SharedMemoryManager;
+use crate::sync::futex::FutexTable;
+use crate::sync::message_queue::MessageQueueManager;
 use crate::syscall::syscall_dispatcher::CoreLocalStorage;
 
 extern crate alloc;
@@ -65,6 +71,9 @@ pub mod log;
 pub mod syscall;
 pub mod process;
 pub mod consts;
+pub mod smp;
+pub mod sync;
+pub mod filesystem;
 
 pub mod built_info {
     // The file has been placed there by the build script.
@@ -117,17 +126,21 @@ static INIT_RAMDISK: Once<TarArchiveRef> = Once::new();
 static ALLOCATOR: KernelAllocator = KernelAllocator::new();
 static LOGGER: Mutex<Logger> = Mutex::new(Logger::new());
 static PROCESS_MANAGER: RwLock<ProcessManager> = RwLock::new(ProcessManager::new());
+static SHARED_MEMORY_MANAGER: Mutex<SharedMemoryManager> = Mutex::new(SharedMemoryManager::new());
+static FUTEX_TABLE: Mutex<FutexTable> = Mutex::new(FutexTable::new());
+static MESSAGE_QUEUE_MANAGER: Mutex<MessageQueueManager> = Mutex::new(MessageQueueManager::new());
 static SCHEDULER: Once<Scheduler> = Once::new();
 static INTERRUPT_DISPATCHER: Once<InterruptDispatcher> = Once::new();
 
 static APIC: Once<Apic> = Once::new();
 static TIMER: RwLock<Timer> = RwLock::new(Timer::new());
+static TSC: Once<Tsc> = Once::new();
 static SPEAKER: Mutex<Speaker> = Mutex::new(Speaker::new());
 static SERIAL_PORT: Once<SerialPort> = Once::new();
 static TERMINAL: Once<LFBTerminal> = Once::new();
 static PS2: Once<PS2> = Once::new();
 static PCI: Once<PciBus> = Once::new();
-static E1000: Once<IntelE1000Device> = Once::new();
+static DEVICE_MANAGER: DeviceManager = DeviceManager::new();
 
 pub fn init_efi_system_table(table: SystemTable<Runtime>) {
     EFI_SYSTEM_TABLE.call_once(|| EfiSystemTable::new(table));
@@ -151,6 +164,10 @@ pub fn init_apic() {
     APIC.call_once(|| Apic::new());
 }
 
+pub fn init_tsc() {
+    TSC.call_once(|| Tsc::calibrate());
+}
+
 pub fn init_serial_port() {
     let mut serial: Option<SerialPort> = None;
     if serial::check_port(ComPort::Com1) {
@@ -174,13 +191,15 @@ pub fn init_terminal(buffer: *mut u8, pitch: u32, width: u32, height: u32, bpp:
     terminal.clear();
     TERMINAL.call_once(|| terminal);
 
-    scheduler().ready(Thread::new_kernel_thread(|| {
+    let cursor_thread = Thread::new_kernel_thread(|| {
         let mut cursor_thread = CursorThread::new(&TERMINAL.get().unwrap());
         cursor_thread.run();
-    }));
+    });
+    cursor_thread.set_priority(PRIORITY_LEVELS - 1); // Cursor blinking should not compete with interactive work
+    scheduler().ready(cursor_thread);
 }
 
-pub fn init_keyboard() {
+pub fn init_ps2_devices() {
     PS2.call_once(|| {
         let mut ps2 = PS2::new();
         match ps2.init_controller() {
@@ -193,16 +212,18 @@ pub fn init_keyboard() {
             Err(error) => error!("PS/2 controller initialization failed: {:?}", error)
         }
 
+        // The mouse is optional and independent of the keyboard (its port may work, even if the keyboard test failed)
+        if let Err(error) = ps2.init_mouse() {
+            error!("Mouse initialization failed: {:?}", error)
+        }
+
         return ps2;
     });
 }
 
 pub fn init_pci() {
     PCI.call_once(|| PciBus::scan());
-}
-
-pub fn init_e1000(){
-    E1000.call_once(|| IntelE1000Device::new());
+    device_manager().probe(pci_bus());
 }
 
 pub fn init_initrd(module: &ModuleTag) {
@@ -213,7 +234,7 @@ pub fn init_initrd(module: &ModuleTag) {
         };
         unsafe { memory::physical::reserve(initrd_frames); }
 
-        let initrd_bytes = unsafe { core::slice::from_raw_parts(module.start_address() as *const u8, (module.end_address() - module.start_address()) as usize) };
+        let initrd_bytes = unsafe { core::slice::from_raw_parts(memory::phys_to_virt(initrd_frames.start.start_address()).as_ptr::<u8>(), (module.end_address() - module.start_address()) as usize) };
         TarArchiveRef::new(initrd_bytes).expect("Failed to create TarArchiveRef from Multiboot2 module")
     });
 }
@@ -270,6 +291,18 @@ pub fn process_manager() -> &'static RwLock<ProcessManager> {
     &PROCESS_MANAGER
 }
 
+pub fn shared_memory_manager() -> &'static Mutex<SharedMemoryManager> {
+    &SHARED_MEMORY_MANAGER
+}
+
+pub fn futex_table() -> &'static Mutex<FutexTable> {
+    &FUTEX_TABLE
+}
+
+pub fn message_queue_manager() -> &'static Mutex<MessageQueueManager> {
+    &MESSAGE_QUEUE_MANAGER
+}
+
 pub fn scheduler() -> &'static Scheduler {
     SCHEDULER.call_once(|| Scheduler::new());
     &SCHEDULER.get().unwrap()
@@ -287,6 +320,10 @@ pub fn speaker() -> &'static Mutex<Speaker> {
     &SPEAKER
 }
 
+pub fn tsc() -> Option<&'static Tsc> {
+    TSC.get()
+}
+
 pub fn serial_port() -> Option<&'static SerialPort> {
     SERIAL_PORT.get()
 }
@@ -303,6 +340,11 @@ pub fn pci_bus() -> &'static PciBus {
     PCI.get().expect("Trying to access PCI bus before initialization!")
 }
 
-pub fn e1000_device() -> &'static IntelE1000Device{
-    E1000.get().expect("Trying to access e1000 device before initialization!")
+pub fn device_manager() -> &'static DeviceManager {
+    &DEVICE_MANAGER
+}
+
+/// The first e1000 network card (`None`, if no e1000 is present)
+pub fn e1000_device() -> Option<Arc<IntelE1000Device>> {
+    device_manager().find::<IntelE1000Device>()
 }
\ No newline at end of file
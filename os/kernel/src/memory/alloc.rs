@@ -2,11 +2,11 @@ use acpi::PhysicalMapping;
 use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
 use core::ptr::NonNull;
 use linked_list_allocator::LockedHeap;
-use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
 use x86_64::structures::paging::frame::PhysFrameRange;
 use x86_64::structures::paging::PhysFrame;
-use crate::memory::{PAGE_SIZE, physical};
-use crate::memory::physical::phys_limit;
+use crate::memory::{PAGE_SIZE, physical, phys_to_virt, virt_to_phys};
 
 pub struct KernelAllocator {
     heap: LockedHeap,
@@ -24,7 +24,7 @@ impl KernelAllocator {
 
     pub unsafe fn init(&self, frames: &PhysFrameRange) {
         let mut heap = self.heap.lock();
-        unsafe { heap.init(frames.start.start_address().as_u64() as *mut u8, (frames.end - frames.start) as usize * PAGE_SIZE); }
+        unsafe { heap.init(phys_to_virt(frames.start.start_address()).as_mut_ptr::<u8>(), (frames.end - frames.start) as usize * PAGE_SIZE); }
     }
 
     pub fn is_initialized(&self) -> bool {
@@ -42,7 +42,7 @@ unsafe impl Allocator for KernelAllocator {
             return Ok(NonNull::slice_from_raw_parts(layout.dangling(), 0));
         }
 
-        match self.heap.lock().allocate_first_fit(layout) {
+        match interrupts::without_interrupts(|| self.heap.lock().allocate_first_fit(layout)) {
             Ok(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, layout.size())),
             Err(()) => Err(AllocError),
         }
@@ -50,23 +50,22 @@ unsafe impl Allocator for KernelAllocator {
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         if layout.size() != 0 {
-            let mut heap = self.heap.lock();
-            unsafe { heap.deallocate(ptr, layout); }
+            interrupts::without_interrupts(|| unsafe { self.heap.lock().deallocate(ptr, layout); });
         }
     }
 }
 
+// The heap is only locked with interrupts disabled, so that a thread holding the lock is never preempted
+// and interrupt handlers may allocate memory without deadlocking on the processor they interrupted
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        return self.heap.lock()
-            .allocate_first_fit(layout)
+        return interrupts::without_interrupts(|| self.heap.lock().allocate_first_fit(layout))
             .ok()
             .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr());
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut heap = self.heap.lock();
-        unsafe { heap.deallocate(NonNull::new_unchecked(ptr), layout); }
+        interrupts::without_interrupts(|| unsafe { self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout); });
     }
 }
 
@@ -85,16 +84,16 @@ unsafe impl Allocator for StackAllocator {
         let frame_count = if layout.size() % PAGE_SIZE == 0 { layout.size() / PAGE_SIZE } else { (layout.size() / PAGE_SIZE) + 1 };
         let frames = physical::alloc(frame_count);
 
-        return Ok(NonNull::slice_from_raw_parts(NonNull::new(frames.start.start_address().as_u64() as *mut u8).unwrap(), (frames.end - frames.start) as usize * PAGE_SIZE))
+        return Ok(NonNull::slice_from_raw_parts(NonNull::new(phys_to_virt(frames.start.start_address()).as_mut_ptr::<u8>()).unwrap(), (frames.end - frames.start) as usize * PAGE_SIZE))
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        // Ignore virtual addresses
-        if (ptr.as_ptr() as usize) < phys_limit().start_address().as_u64() as usize {
+        // Ignore user addresses
+        if VirtAddr::from_ptr(ptr.as_ptr()) >= phys_to_virt(PhysAddr::zero()) {
             assert_eq!(PAGE_SIZE % layout.align(), 0);
             assert_eq!(layout.size() % PAGE_SIZE, 0);
 
-            let start = PhysFrame::from_start_address(PhysAddr::new(ptr.as_ptr() as u64)).unwrap();
+            let start = PhysFrame::from_start_address(virt_to_phys(VirtAddr::from_ptr(ptr.as_ptr()))).unwrap();
             unsafe { physical::free(PhysFrameRange { start, end: start + (layout.size() / PAGE_SIZE) as u64 }); }
         }
     }
@@ -102,7 +101,7 @@ unsafe impl Allocator for StackAllocator {
 
 impl acpi::AcpiHandler for AcpiHandler {
     unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
-        unsafe { PhysicalMapping::new(physical_address, NonNull::new(physical_address as *mut T).unwrap(), size, size, AcpiHandler) }
+        unsafe { PhysicalMapping::new(physical_address, NonNull::new(phys_to_virt(PhysAddr::new(physical_address as u64)).as_mut_ptr::<T>()).unwrap(), size, size, AcpiHandler) }
     }
 
     fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
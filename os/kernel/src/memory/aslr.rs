@@ -0,0 +1,45 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::Relaxed;
+use log::info;
+use x86_64::instructions::random::RdRand;
+
+/// Address space layout randomization for user space (stack, heap and image base of position independent executables).
+/// May be disabled for debugging by passing 'noaslr' on the kernel command line.
+static ASLR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn init(command_line: &str) {
+    if command_line.split_whitespace().any(|option| option == "noaslr") {
+        ASLR_ENABLED.store(false, Relaxed);
+        info!("Address space layout randomization disabled");
+    } else {
+        info!("Address space layout randomization enabled (Entropy source: {})", if RdRand::new().is_some() { "RDRAND" } else { "TSC" });
+    }
+}
+
+pub fn enabled() -> bool {
+    ASLR_ENABLED.load(Relaxed)
+}
+
+/// Get a random number from RDRAND, or derive one from the time stamp counter, if RDRAND is not available (or fails).
+fn entropy() -> u64 {
+    if let Some(random) = RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+        return random;
+    }
+
+    // Mix the bits of the time stamp counter (splitmix64 finalizer), since its lower bits change much faster than the upper ones
+    let mut random = unsafe { _rdtsc() };
+    random = (random ^ (random >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    random = (random ^ (random >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return random ^ (random >> 31);
+}
+
+/// Get a random offset in bytes, which is page aligned and smaller than `max_pages` pages.
+/// Returns 0, if randomization is disabled.
+pub fn random_page_offset(max_pages: usize) -> u64 {
+    if !enabled() || max_pages == 0 {
+        return 0;
+    }
+
+    (entropy() % max_pages as u64) * super::PAGE_SIZE as u64
+}
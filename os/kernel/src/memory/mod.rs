@@ -1,6 +1,14 @@
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::Page;
+use x86_64::structures::paging::page::PageRange;
+use crate::consts::{KERNEL_IMAGE_START, PHYS_MAP_START};
+
 pub mod alloc;
+pub mod aslr;
 pub mod physical;
 pub mod r#virtual;
+pub mod shared;
 
 #[derive(Clone, Copy)]
 pub enum MemorySpace {
@@ -8,4 +16,39 @@ pub enum MemorySpace {
     User
 }
 
-pub const PAGE_SIZE: usize = 0x1000;
\ No newline at end of file
+pub const PAGE_SIZE: usize = 0x1000;
+
+// import labels from linker script 'link.ld'
+extern "C" {
+    static ___KERNEL_DATA_START__: u64; // start address of OS image
+    static ___KERNEL_DATA_END__: u64;   // end address of OS image
+}
+
+/// Address of physical memory in the direct mapping of the kernel (all physical memory is mapped at `PHYS_MAP_START`)
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYS_MAP_START as u64)
+}
+
+/// Physical address of kernel memory (part of the direct mapping or of the kernel image).
+/// User memory is not mapped linearly and must be translated via its address space.
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    let addr = addr.as_u64();
+    if addr >= KERNEL_IMAGE_START as u64 {
+        PhysAddr::new(addr - KERNEL_IMAGE_START as u64)
+    } else if addr >= PHYS_MAP_START as u64 {
+        PhysAddr::new(addr - PHYS_MAP_START as u64)
+    } else {
+        panic!("Trying to get the physical address of a user address!");
+    }
+}
+
+/// Pages occupied by the kernel image, which is linked to `KERNEL_IMAGE_START` + its physical load address
+pub fn kernel_image_pages() -> PageRange {
+    let start = VirtAddr::from_ptr(unsafe { ptr::from_ref(&___KERNEL_DATA_START__) });
+    let end = VirtAddr::from_ptr(unsafe { ptr::from_ref(&___KERNEL_DATA_END__) });
+
+    PageRange {
+        start: Page::from_start_address(start).expect("Kernel code is not page aligned"),
+        end: Page::containing_address(end.align_up(PAGE_SIZE as u64))
+    }
+}
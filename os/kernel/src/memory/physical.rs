@@ -1,3 +1,4 @@
+use alloc::collections::BTreeMap;
 use alloc::format;
 use alloc::string::String;
 use core::cell::Cell;
@@ -5,14 +6,19 @@ use core::fmt::{Debug, Formatter};
 use core::ptr;
 use spin::Mutex;
 use spin::once::Once;
-use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
 use x86_64::structures::paging::frame::PhysFrameRange;
 use x86_64::structures::paging::PhysFrame;
-use crate::memory::PAGE_SIZE;
+use crate::memory::{PAGE_SIZE, phys_to_virt, virt_to_phys};
 
 static PAGE_FRAME_ALLOCATOR: Mutex<PageFrameListAllocator> = Mutex::new(PageFrameListAllocator::new());
 static PHYS_LIMIT: Once<Mutex<Cell<PhysFrame>>> = Once::new();
 
+/// Reference counts of page frames, which are mapped into more than one address space (e.g. copy-on-write pages).
+/// Frames without an entry have exactly one owner.
+static FRAME_REFERENCES: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+
 /// Insert an available memory region obtained during the boot process.
 pub unsafe fn insert(mut region: PhysFrameRange) {
     PHYS_LIMIT.call_once(|| Mutex::new(Cell::new(PhysFrame::from_start_address(PhysAddr::zero()).unwrap())));
@@ -36,19 +42,56 @@ pub unsafe fn insert(mut region: PhysFrameRange) {
 }
 
 /// Allocate `frame_count` contiguous page frames.
+/// Like the kernel heap, the allocator is only locked with interrupts disabled (see 'alloc.rs').
 pub fn alloc(frame_count: usize) -> PhysFrameRange {
-    PAGE_FRAME_ALLOCATOR.lock().alloc_block(frame_count)
+    interrupts::without_interrupts(|| PAGE_FRAME_ALLOCATOR.lock().alloc_block(frame_count))
 }
 
 /// Free `frame_count` contiguous page frames.
 /// Unsafe because invalid parameters may break the list allocator.
 pub unsafe fn free(frames: PhysFrameRange) {
-    unsafe { PAGE_FRAME_ALLOCATOR.lock().free_block(frames); }
+    interrupts::without_interrupts(|| unsafe { PAGE_FRAME_ALLOCATOR.lock().free_block(frames); });
 }
 
 /// Permanently reserve a block of free memory.
 pub unsafe fn reserve(frames: PhysFrameRange) {
-    unsafe { PAGE_FRAME_ALLOCATOR.lock().reserve_block(frames); }
+    interrupts::without_interrupts(|| unsafe { PAGE_FRAME_ALLOCATOR.lock().reserve_block(frames); });
+}
+
+/// Add a reference to a page frame, which is going to be mapped into another address space.
+pub fn share(frame: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut references = FRAME_REFERENCES.lock();
+        *references.entry(frame).or_insert(1) += 1;
+    });
+}
+
+/// Get the number of address spaces, a page frame is mapped into.
+pub fn reference_count(frame: PhysFrame) -> usize {
+    interrupts::without_interrupts(|| *FRAME_REFERENCES.lock().get(&frame).unwrap_or(&1))
+}
+
+/// Drop a reference to a page frame. The frame is freed, when the last reference is dropped.
+/// Unsafe because the frame must not be accessed anymore by the caller.
+pub unsafe fn release(frame: PhysFrame) {
+    let last_reference = interrupts::without_interrupts(|| {
+        let mut references = FRAME_REFERENCES.lock();
+        match references.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    references.remove(&frame);
+                }
+
+                false
+            }
+            None => true
+        }
+    });
+
+    if last_reference {
+        unsafe { free(PhysFrameRange { start: frame, end: frame + 1 }); }
+    }
 }
 
 /// Get the highest physical address, managed by PAGE_FRAME_ALLOCATOR.
@@ -74,7 +117,7 @@ impl PageFrameNode {
     }
 
     fn start(&self) -> PhysFrame {
-        return PhysFrame::from_start_address(PhysAddr::new(ptr::from_ref(self) as u64)).unwrap();
+        return PhysFrame::from_start_address(virt_to_phys(VirtAddr::from_ptr(ptr::from_ref(self)))).unwrap();
     }
 
     fn end(&self) -> PhysFrame {
@@ -113,7 +156,7 @@ impl PageFrameListAllocator {
     /// Insert a new block, sorted ascending by its memory address.
     unsafe fn insert(&mut self, frames: PhysFrameRange) {
         let mut new_block = PageFrameNode::new((frames.end - frames.start) as usize);
-        let new_block_ptr = frames.start.start_address().as_u64() as *mut PageFrameNode;
+        let new_block_ptr = phys_to_virt(frames.start.start_address()).as_mut_ptr::<PageFrameNode>();
 
         // Check if list is empty
         if self.head.next.is_none() {
@@ -197,7 +240,7 @@ impl PageFrameListAllocator {
                 let mut new_block = PageFrameNode::new(block.frame_count + (frames.end - frames.start) as usize);
 
                 unsafe {
-                    new_block_ptr = frames.start.start_address().as_u64() as *mut PageFrameNode;
+                    new_block_ptr = phys_to_virt(frames.start.start_address()).as_mut_ptr::<PageFrameNode>();
                     new_block.next = block.next.take();
                     new_block_ptr.write(new_block);
 
@@ -249,7 +292,7 @@ impl PageFrameListAllocator {
                     block.frame_count = below_size as usize;
 
                     let mut above_block = PageFrameNode::new(above_size as usize);
-                    let above_block_ptr = reserved.end.start_address().as_u64() as *mut PageFrameNode;
+                    let above_block_ptr = phys_to_virt(reserved.end.start_address()).as_mut_ptr::<PageFrameNode>();
 
                     unsafe {
                         above_block.next = block.next.take();
@@ -263,7 +306,7 @@ impl PageFrameListAllocator {
                 } else { // Block starts within and ends above the reserved region
                     let overlapping = reserved.end - block.start();
                     let mut new_block = PageFrameNode::new(block.frame_count - overlapping as usize);
-                    let new_block_ptr = (block.start() + overlapping).start_address().as_u64() as *mut PageFrameNode;
+                    let new_block_ptr = phys_to_virt((block.start() + overlapping).start_address()).as_mut_ptr::<PageFrameNode>();
 
                     unsafe {
                         new_block.next = block.next.take();
@@ -0,0 +1,65 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use crate::memory::{PAGE_SIZE, physical, phys_to_virt};
+
+/// A block of physical memory, which can be mapped into several address spaces.
+/// Each mapping process holds a reference to the object and the frames are freed, when the last reference is dropped.
+pub struct SharedMemory {
+    frames: PhysFrameRange
+}
+
+/// Registry of all named shared memory objects.
+/// Only weak references are stored, so that an object vanishes as soon as no process maps it anymore.
+pub struct SharedMemoryManager {
+    objects: BTreeMap<String, Weak<SharedMemory>>
+}
+
+impl SharedMemory {
+    fn new(size: usize) -> Self {
+        let frame_count = size.div_ceil(PAGE_SIZE);
+        let frames = physical::alloc(frame_count);
+        unsafe { phys_to_virt(frames.start.start_address()).as_mut_ptr::<u8>().write_bytes(0, frame_count * PAGE_SIZE); }
+
+        Self { frames }
+    }
+
+    pub fn frames(&self) -> PhysFrameRange {
+        self.frames
+    }
+
+    pub fn size(&self) -> usize {
+        (self.frames.end - self.frames.start) as usize * PAGE_SIZE
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe { physical::free(self.frames); }
+    }
+}
+
+impl SharedMemoryManager {
+    pub const fn new() -> Self {
+        Self { objects: BTreeMap::new() }
+    }
+
+    /// Open the shared memory object called `name`. If it does not exist yet, it is created with at least `size` bytes.
+    /// Returns `None`, if an existing object is smaller than `size`.
+    pub fn open(&mut self, name: &str, size: usize) -> Option<Arc<SharedMemory>> {
+        self.objects.retain(|_, object| object.strong_count() > 0);
+
+        if let Some(object) = self.objects.get(name).and_then(|object| object.upgrade()) {
+            return if object.size() >= size { Some(object) } else { None };
+        }
+
+        if size == 0 {
+            return None;
+        }
+
+        let object = Arc::new(SharedMemory::new(size));
+        self.objects.insert(name.to_string(), Arc::downgrade(&object));
+        return Some(object);
+    }
+}
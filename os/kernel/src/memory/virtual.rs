@@ -1,13 +1,22 @@
+use core::arch::asm;
 use core::cmp::min;
+use core::mem::size_of_val;
 use core::ptr;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::Relaxed;
+use log::info;
+use raw_cpuid::CpuId;
 use spin::RwLock;
+use x86_64::instructions::tlb;
 use x86_64::structures::paging::{Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame};
+use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::{PhysAddr, VirtAddr};
-use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags, Efer, EferFlags};
 use x86_64::structures::paging::frame::PhysFrameRange;
 use x86_64::structures::paging::page::PageRange;
-use crate::memory::{MemorySpace, PAGE_SIZE, physical};
-use crate::process_manager;
+use crate::consts::USER_SPACE_END;
+use crate::memory::{MemorySpace, PAGE_SIZE, physical, phys_to_virt, virt_to_phys};
+use crate::{process_manager, smp};
 
 pub struct AddressSpace {
     root_table: RwLock<*mut PageTable>,
@@ -22,9 +31,18 @@ pub struct VirtualMemoryArea {
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum VmaType {
-    Code, Heap, Stack
+    Code, ReadOnlyData, Data, Heap, Stack, SharedMemory
 }
 
+/// Marks read-only pages, which are shared between address spaces after fork and copied on the first write access
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Set, if EFER.NXE has been enabled and the NO_EXECUTE bit may be used in page table entries
+static NO_EXECUTE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set, if CR4.SMAP has been enabled and user memory may only be accessed inside `with_user_access()`
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
 unsafe impl Send for AddressSpace {}
 unsafe impl Sync for AddressSpace {}
 
@@ -32,6 +50,113 @@ fn page_table_index(virt_addr: VirtAddr, level: usize) -> PageTableIndex {
     return PageTableIndex::new_truncate((virt_addr.as_u64() >> 12 >> ((level as u8 - 1) * 9)) as u16);
 }
 
+/// Enable the NO_EXECUTE page table bit via EFER.NXE, if supported by the CPU.
+/// Must be called before the first address space is loaded.
+pub fn enable_no_execute() {
+    let supported = CpuId::new().get_extended_processor_and_feature_identifiers()
+        .is_some_and(|features| features.has_execute_disable());
+
+    if supported {
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)); }
+        NO_EXECUTE_ENABLED.store(true, Relaxed);
+        info!("No-execute bit enabled");
+    } else {
+        info!("No-execute bit not supported by CPU");
+    }
+}
+
+/// Enable supervisor mode execution and access prevention (SMEP/SMAP), if supported by the CPU.
+/// Afterwards, the kernel faults when executing code in user pages, or accessing user pages outside of `with_user_access()`.
+pub fn enable_supervisor_protection() {
+    let features = CpuId::new().get_extended_feature_info();
+
+    if features.as_ref().is_some_and(|features| features.has_smep()) {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION)); }
+        info!("Supervisor mode execution prevention enabled");
+    } else {
+        info!("Supervisor mode execution prevention not supported by CPU");
+    }
+
+    if features.as_ref().is_some_and(|features| features.has_smap()) {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)); }
+        SMAP_ENABLED.store(true, Relaxed);
+        info!("Supervisor mode access prevention enabled");
+    } else {
+        info!("Supervisor mode access prevention not supported by CPU");
+    }
+}
+
+/// Execute `access` with user pages accessible for the kernel (stac/clac window for SMAP).
+/// Windows must not be nested, since the inner one would close the outer one on return.
+pub fn with_user_access<R>(access: impl FnOnce() -> R) -> R {
+    let smap = SMAP_ENABLED.load(Relaxed);
+    if smap {
+        unsafe { asm!("stac", options(nostack)); }
+    }
+
+    let ret = access();
+
+    if smap {
+        unsafe { asm!("clac", options(nostack)); }
+    }
+
+    return ret;
+}
+
+/// Check, that `length` bytes starting at `address` lie completely in the lower half (user space).
+/// Overflowing ranges and non-canonical addresses are rejected as well, since they cannot lie in the lower half.
+pub fn is_user_range(address: usize, length: usize) -> bool {
+    address.checked_add(length).is_some_and(|end| end <= USER_SPACE_END)
+}
+
+/// Copy `destination.len()` elements from user space into the kernel.
+/// Returns false without accessing user memory, if the source does not lie in user space.
+/// The source does not need to be aligned, since it is copied bytewise.
+pub fn copy_from_user<T: Copy>(destination: &mut [T], source: *const T) -> bool {
+    let size = size_of_val(destination);
+    if !is_user_range(source as usize, size) {
+        return false;
+    }
+
+    if size > 0 {
+        with_user_access(|| unsafe { destination.as_mut_ptr().cast::<u8>().copy_from_nonoverlapping(source.cast::<u8>(), size) });
+    }
+
+    return true;
+}
+
+/// Copy the elements of `source` from the kernel into user space.
+/// Returns false without accessing user memory, if the destination does not lie in user space.
+/// The destination does not need to be aligned, since it is copied bytewise.
+pub fn copy_to_user<T: Copy>(destination: *mut T, source: &[T]) -> bool {
+    let size = size_of_val(source);
+    if !is_user_range(destination as usize, size) {
+        return false;
+    }
+
+    if size > 0 {
+        with_user_access(|| unsafe { destination.cast::<u8>().copy_from_nonoverlapping(source.as_ptr().cast::<u8>(), size) });
+    }
+
+    return true;
+}
+
+/// Remove the NO_EXECUTE bit, if it is not enabled (it is a reserved bit in this case and would cause page faults)
+fn page_flags(flags: PageTableFlags) -> PageTableFlags {
+    if NO_EXECUTE_ENABLED.load(Relaxed) {
+        flags
+    } else {
+        flags - PageTableFlags::NO_EXECUTE
+    }
+}
+
+/// Flags for entries pointing to next level tables.
+/// Access rights are checked on all levels, so these need to be as permissive as possible and
+/// the actual protection is done by the level 1 entries.
+fn table_flags(flags: PageTableFlags) -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE)
+}
+
 impl Drop for AddressSpace {
     fn drop(&mut self) {
         let depth = self.depth;
@@ -82,7 +207,7 @@ impl VirtualMemoryArea {
         let new_pages = PageRange { start: self.range.start - pages as u64, end: self.range.start };
         let process = process_manager().read().current_process();
 
-        process.address_space().map(new_pages, MemorySpace::User, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+        process.address_space().map(new_pages, MemorySpace::User, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE);
         process.update_vma(*self, |vma| vma.range.start = new_pages.start);
     }
 }
@@ -90,7 +215,7 @@ impl VirtualMemoryArea {
 impl AddressSpace {
     pub fn new(depth: usize) -> Self {
         let table_addr = physical::alloc(1).start;
-        let root_table = table_addr.start_address().as_u64() as *mut PageTable;
+        let root_table = phys_to_virt(table_addr.start_address()).as_mut_ptr::<PageTable>();
         unsafe { root_table.as_mut().unwrap().zero(); }
 
         Self { root_table: RwLock::new(root_table), depth }
@@ -120,7 +245,7 @@ impl AddressSpace {
         // We cannot use the lock here, because this function is called by the scheduler.
         // This is still safe, since we only return an address and not a reference.
         let root_table = unsafe { self.root_table.as_mut_ptr().read() };
-        PhysAddr::new(root_table as u64)
+        virt_to_phys(VirtAddr::from_ptr(root_table))
     }
 
     pub fn map(&self, pages: PageRange, space: MemorySpace, flags: PageTableFlags) {
@@ -129,7 +254,7 @@ impl AddressSpace {
         let root_table = unsafe { root_table_guard.as_mut().unwrap() };
         let frames = PhysFrameRange { start: PhysFrame::from_start_address(PhysAddr::zero()).unwrap(), end: PhysFrame::from_start_address(PhysAddr::zero()).unwrap() };
 
-        AddressSpace::map_in_table(root_table, frames, pages, space, flags, depth);
+        AddressSpace::map_in_table(root_table, frames, pages, space, page_flags(flags), depth);
     }
 
     pub fn map_physical(&self, frames: PhysFrameRange, pages: PageRange, space: MemorySpace, flags: PageTableFlags) {
@@ -138,7 +263,7 @@ impl AddressSpace {
         let root_table = unsafe { root_table_guard.as_mut().unwrap() };
 
         assert_eq!(frames.end - frames.start, pages.end - pages.start);
-        AddressSpace::map_in_table(root_table, frames, pages, space, flags, depth);
+        AddressSpace::map_in_table(root_table, frames, pages, space, page_flags(flags), depth);
     }
 
     pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
@@ -155,6 +280,8 @@ impl AddressSpace {
         let root_table = unsafe { root_table_guard.as_mut().unwrap() };
 
         AddressSpace::unmap_in_table(root_table, pages, depth, free_physical);
+        drop(root_table_guard);
+        smp::flush_tlb(pages);
     }
 
     pub fn set_flags(&self, pages: PageRange, flags: PageTableFlags) {
@@ -162,7 +289,83 @@ impl AddressSpace {
         let root_table_guard = self.root_table.write();
         let root_table = unsafe { root_table_guard.as_mut().unwrap() };
 
-        AddressSpace::set_flags_in_table(root_table, pages, flags, depth);
+        AddressSpace::set_flags_in_table(root_table, pages, page_flags(flags), depth);
+        drop(root_table_guard);
+        smp::flush_tlb(pages);
+    }
+
+    /// Map all pages of `pages`, that are present in this address space, to the same page frames in `target`.
+    /// Writable pages are marked as copy-on-write in both address spaces, so that they are copied on the first write access.
+    pub fn share_copy_on_write(&self, pages: PageRange, target: &AddressSpace) {
+        let depth = self.depth;
+        let root_table_guard = self.root_table.write();
+        let root_table = unsafe { root_table_guard.as_mut().unwrap() };
+
+        for page in pages {
+            if let Some(entry) = AddressSpace::find_entry(root_table, page.start_address(), depth) {
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+
+                let frame = PhysFrame::from_start_address(entry.addr()).unwrap();
+                physical::share(frame);
+                target.map_physical(PhysFrameRange { start: frame, end: frame + 1 }, PageRange { start: page, end: page + 1 }, MemorySpace::User, flags);
+            }
+        }
+
+        // Write access has been revoked for pages of this address space -> Flush stale TLB entries on all processors
+        drop(root_table_guard);
+        smp::flush_tlb(pages);
+    }
+
+    /// Handle a write access to a copy-on-write page, by giving this address space its own copy of the page.
+    /// Returns false, if the page at `addr` is not a copy-on-write page.
+    pub fn resolve_copy_on_write(&self, addr: VirtAddr) -> bool {
+        let depth = self.depth;
+        let root_table_guard = self.root_table.write();
+        let root_table = unsafe { root_table_guard.as_mut().unwrap() };
+
+        let entry = match AddressSpace::find_entry(root_table, addr, depth) {
+            Some(entry) => entry,
+            None => return false
+        };
+
+        let flags = entry.flags();
+        if !flags.contains(COPY_ON_WRITE) {
+            // Another thread has resolved the page on another processor, while the TLB of this one was still stale
+            if flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE) {
+                tlb::flush(addr.align_down(PAGE_SIZE as u64));
+                return true;
+            }
+
+            return false;
+        }
+
+        let frame = PhysFrame::from_start_address(entry.addr()).unwrap();
+        let new_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        if physical::reference_count(frame) > 1 {
+            // Page is still shared -> Copy it into a new page frame and drop the reference to the shared one
+            let new_frame = physical::alloc(1).start;
+            unsafe {
+                let source = phys_to_virt(frame.start_address()).as_ptr::<u8>();
+                let target = phys_to_virt(new_frame.start_address()).as_mut_ptr::<u8>();
+                target.copy_from(source, PAGE_SIZE);
+                physical::release(frame);
+            }
+
+            entry.set_frame(new_frame, new_flags);
+        } else {
+            // All other address spaces have already copied the page or have exited -> Just take it over
+            entry.set_flags(new_flags);
+        }
+
+        drop(root_table_guard);
+        let page = Page::containing_address(addr);
+        smp::flush_tlb(PageRange { start: page, end: page + 1 });
+        return true;
     }
 
     fn copy_table(source: &PageTable, target: &mut PageTable, level: usize) {
@@ -178,8 +381,8 @@ impl AddressSpace {
                 let flags = source[index].flags();
                 target_entry.set_frame(phys_frame, flags);
 
-                let next_level_source = unsafe { (source_entry.addr().as_u64() as *mut PageTable).as_mut().unwrap() };
-                let next_level_target = unsafe { (target_entry.addr().as_u64() as *mut PageTable).as_mut().unwrap() };
+                let next_level_source = unsafe { phys_to_virt(source_entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
+                let next_level_target = unsafe { phys_to_virt(target_entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
                 AddressSpace::copy_table(next_level_source, next_level_target, level - 1);
             }
         } else { // Only on the last level, we create a 1:1 copy of the page table
@@ -199,12 +402,13 @@ impl AddressSpace {
                 let next_level_table;
                 if entry.is_unused() { // Entry is empty -> Allocate new page frame
                     let phys_frame = physical::alloc(1).start;
-                    entry.set_frame(phys_frame, flags);
+                    entry.set_frame(phys_frame, table_flags(flags));
 
-                    next_level_table = unsafe { (entry.addr().as_u64() as *mut PageTable).as_mut().unwrap() };
+                    next_level_table = unsafe { phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
                     next_level_table.zero();
                 } else {
-                    next_level_table = unsafe { (entry.addr().as_u64() as *mut PageTable).as_mut().unwrap() };
+                    entry.set_flags(entry.flags() | table_flags(flags));
+                    next_level_table = unsafe { phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
                 }
 
                 let allocated_pages = AddressSpace::map_in_table(next_level_table, frames, pages, space, flags, level - 1);
@@ -220,14 +424,12 @@ impl AddressSpace {
                 }
             }
         } else { // Reached level 1 page table
-            total_allocated_pages += match space {
-                MemorySpace::Kernel => AddressSpace::identity_map_kernel(table, pages, flags),
-                MemorySpace::User => {
-                    if frames.start == frames.end {
-                        AddressSpace::map_user(table, pages, flags)
-                    } else {
-                        AddressSpace::map_user_physical(table, frames, pages, flags)
-                    }
+            total_allocated_pages += if frames.start != frames.end {
+                AddressSpace::map_physical_frames(table, frames, pages, flags)
+            } else {
+                match space {
+                    MemorySpace::Kernel => AddressSpace::map_kernel(table, pages, flags),
+                    MemorySpace::User => AddressSpace::map_user(table, pages, flags)
                 }
             }
         }
@@ -245,7 +447,7 @@ impl AddressSpace {
                     continue;
                 }
 
-                let next_level_table = unsafe { (entry.addr().as_u64() as *mut PageTable).as_mut().unwrap() };
+                let next_level_table = unsafe { phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
                 let freed_pages = AddressSpace::unmap_in_table(next_level_table, pages, level - 1, free_physical);
                 pages = PageRange { start: pages.start + freed_pages as u64, end: pages.end };
                 total_freed_pages += freed_pages;
@@ -271,8 +473,9 @@ impl AddressSpace {
 
                 if !entry.is_unused() {
                     if free_physical {
+                        // Frame may be shared with other address spaces -> Only free it, if this is the last reference
                         let frame = PhysFrame::from_start_address(entry.addr()).unwrap();
-                        unsafe { physical::free(PhysFrameRange { start: frame, end: frame + 1 }); }
+                        unsafe { physical::release(frame); }
                     }
 
                     entry.set_unused();
@@ -292,7 +495,7 @@ impl AddressSpace {
                     continue;
                 }
 
-                let next_level_table = unsafe { (entry.addr().as_u64() as *mut PageTable).as_mut().unwrap() };
+                let next_level_table = unsafe { phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
                 AddressSpace::drop_table(next_level_table, level - 1);
             }
         }
@@ -300,7 +503,7 @@ impl AddressSpace {
         // Clear table
         table.iter_mut().for_each(|entry| entry.set_unused());
 
-        let table_frame = PhysFrame::from_start_address(PhysAddr::new(ptr::from_ref(table) as u64)).unwrap();
+        let table_frame = PhysFrame::from_start_address(virt_to_phys(VirtAddr::from_ptr(ptr::from_ref(table)))).unwrap();
         unsafe { physical::free(PhysFrameRange { start: table_frame, end: table_frame + 1 }); }
     }
 
@@ -314,7 +517,7 @@ impl AddressSpace {
                     continue;
                 }
 
-                let next_level_table = unsafe { (entry.addr().as_u64() as *mut PageTable).as_mut().unwrap() };
+                let next_level_table = unsafe { phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
 
                 let edited_pages = AddressSpace::set_flags_in_table(next_level_table, pages, flags, level - 1);
                 pages = PageRange { start: pages.start + edited_pages as u64, end: pages.end };
@@ -351,17 +554,33 @@ impl AddressSpace {
         }
 
         if level > 1 { // Calculate next level page table until level == 1
-            let next_level_table = unsafe { (entry.addr().as_u64() as *mut PageTable).as_mut().unwrap() };
+            let next_level_table = unsafe { phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
             return AddressSpace::translate_in_table(next_level_table, addr, level - 1);
         } else { // Reached level 1 page table
             return Some(entry.addr() + (addr - aligned_addr));
         }
     }
 
-    fn identity_map_kernel(table: &mut PageTable, pages: PageRange, flags: PageTableFlags) -> usize {
+    fn find_entry(table: &mut PageTable, addr: VirtAddr, level: usize) -> Option<&mut PageTableEntry> {
+        let index = usize::from(page_table_index(addr, level));
+        let entry = &mut table[index];
+        if entry.is_unused() {
+            return None;
+        }
+
+        if level > 1 { // Calculate next level page table until level == 1
+            let next_level_table = unsafe { phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>().as_mut().unwrap() };
+            return AddressSpace::find_entry(next_level_table, addr, level - 1);
+        } else { // Reached level 1 page table
+            return Some(entry);
+        }
+    }
+
+    /// Map kernel pages (direct mapping of physical memory or kernel image) to their linear page frames
+    fn map_kernel(table: &mut PageTable, pages: PageRange, flags: PageTableFlags) -> usize {
         let start_index = usize::from(page_table_index(pages.start.start_address(), 1));
         let alloc_count = min((pages.end - pages.start) as usize, 512 - start_index);
-        let mut frame_addr = PhysAddr::new(pages.start.start_address().as_u64());
+        let mut frame_addr = virt_to_phys(pages.start.start_address());
 
         for (count, entry) in table.iter_mut().skip(start_index).enumerate() {
             if count >= alloc_count {
@@ -391,10 +610,10 @@ impl AddressSpace {
         return alloc_count;
     }
 
-    fn map_user_physical(table: &mut PageTable, frames: PhysFrameRange, pages: PageRange, flags: PageTableFlags) -> usize {
+    fn map_physical_frames(table: &mut PageTable, frames: PhysFrameRange, pages: PageRange, flags: PageTableFlags) -> usize {
         let start_index = usize::from(page_table_index(pages.start.start_address(), 1));
         let alloc_count = min((pages.end - pages.start) as usize, 512 - start_index);
-        let mut frame_iter = frames.into_iter().skip(start_index);
+        let mut frame_iter = frames.into_iter();
 
         for (count, entry) in table.iter_mut().skip(start_index).enumerate() {
             if count >= alloc_count {
@@ -1,16 +1,21 @@
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cmp::Ordering;
 use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering::Relaxed;
 use spin::RwLock;
+use syscall::{ProcessInfo, ProcessState, PROCESS_NAME_LENGTH};
 use x86_64::structures::paging::{Page, PageTableFlags};
 use x86_64::structures::paging::page::PageRange;
-use x86_64::VirtAddr;
-use crate::{ process_manager, scheduler};
-use crate::memory::MemorySpace;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::{futex_table, process_manager, scheduler};
+use crate::consts::SHARED_MEMORY_START;
+use crate::memory::{kernel_image_pages, phys_to_virt, MemorySpace};
 use crate::memory::physical::phys_limit;
 use crate::memory::r#virtual::{AddressSpace, VirtualMemoryArea, VmaType};
+use crate::memory::shared::SharedMemory;
+use crate::sync::message_queue::MessageQueue;
 
 static PROCESS_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
 
@@ -29,7 +34,9 @@ impl ProcessManager {
     }
 
     // MS ?
-    pub fn create_process(&mut self) -> Arc<Process> {
+    /// Create a new process for the binary `name` (the first process created is the kernel process).
+    /// The calling process becomes the parent of the new process.
+    pub fn create_process(&mut self, name: &str) -> Arc<Process> {
         let address_space = match self.kernel_process() {
             Some(kernel_process) => { // Create user address space
                 Arc::new(AddressSpace::from_other(&kernel_process.address_space()))
@@ -37,23 +44,67 @@ impl ProcessManager {
             None => { // Create kernel address space (only once)
                 let address_space = AddressSpace::new(4);
                 let max_phys_addr = phys_limit().start_address();
-                let range = PageRange { start: Page::containing_address(VirtAddr::zero()), end: Page::containing_address(VirtAddr::new(max_phys_addr.as_u64())) };
+                let range = PageRange { start: Page::containing_address(phys_to_virt(PhysAddr::zero())), end: Page::containing_address(phys_to_virt(max_phys_addr)) };
 
+                // Map all physical memory into the higher half and the kernel image into the last 2 GiB (see 'link.ld').
+                // The lower half is left empty for user space.
                 address_space.map(range, MemorySpace::Kernel, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                address_space.map(kernel_image_pages(), MemorySpace::Kernel, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
                 Arc::new(address_space)
             }
         };
 
-        let process = Arc::new(Process::new(address_space));
+        let parent_id = match self.active_processes.is_empty() {
+            true => 0,
+            false => self.current_process().id()
+        };
+
+        let process = Arc::new(Process::new(address_space, name, parent_id));
         self.active_processes.push(Arc::clone(&process));
 
         return process;
     }
 
+    /// Create a copy of `parent`, sharing all pages of its memory areas copy-on-write.
+    /// Shared memory objects stay shared and are mapped writable into the child. Opened message queues are inherited.
+    pub fn fork_process(&mut self, parent: &Arc<Process>) -> Arc<Process> {
+        let kernel_process = self.kernel_process().expect("Trying to fork a process before process initialization!");
+        let address_space = Arc::new(AddressSpace::from_other(&kernel_process.address_space()));
+        let process = Arc::new(Process::new(address_space, parent.name(), parent.id()));
+
+        for vma in parent.memory_areas.read().iter().filter(|area| area.typ() != VmaType::SharedMemory) {
+            parent.address_space.share_copy_on_write(vma.range(), &process.address_space);
+            process.add_vma(*vma);
+        }
+
+        for (vma, object) in parent.shared_memory.read().iter() {
+            process.address_space.map_physical(object.frames(), vma.range(), MemorySpace::User, SHARED_MEMORY_FLAGS);
+            process.add_vma(*vma);
+            process.shared_memory.write().push((*vma, Arc::clone(object)));
+        }
+
+        *process.message_queues.write() = parent.message_queues.read().clone();
+
+        self.active_processes.push(Arc::clone(&process));
+        return process;
+    }
+
     pub fn active_process_ids(&self) -> Vec<usize> {
         self.active_processes.iter().map(|process| process.id()).collect()
     }
 
+    /// Get information about all processes, which have not been cleaned up yet
+    pub fn process_infos(&self) -> Vec<ProcessInfo> {
+        let threads = scheduler().thread_infos();
+        let running = self.active_processes.iter().map(|process| (process, ProcessState::Running));
+        let exited = self.exited_processes.iter().map(|process| (process, ProcessState::Exited));
+
+        running.chain(exited).map(|(process, state)| {
+            let thread_count = threads.iter().filter(|thread| thread.process_id == process.id()).count();
+            process.info(thread_count, state)
+        }).collect()
+    }
+
     pub fn kernel_process(&self) -> Option<Arc<Process>> {
         match self.active_processes.get(0) {
             Some(kernel_process) => Some(Arc::clone(kernel_process)),
@@ -79,6 +130,7 @@ impl ProcessManager {
 
         self.active_processes.swap_remove(index);
         self.exited_processes.push(process);
+        futex_table().lock().remove_process(process_id);
     }
 
     pub fn kill(&mut self, process_id: usize) {
@@ -93,6 +145,7 @@ impl ProcessManager {
 
         self.active_processes.swap_remove(index);
         self.exited_processes.push(process);
+        futex_table().lock().remove_process(process_id);
     }
 
     pub fn drop_exited_process(&mut self) {
@@ -102,27 +155,64 @@ impl ProcessManager {
 
 pub struct Process {
     id: usize,
+    parent_id: usize,
+    name: String,
     address_space: Arc<AddressSpace>,
-    memory_areas: RwLock<Vec<VirtualMemoryArea>>
+    memory_areas: RwLock<Vec<VirtualMemoryArea>>,
+    shared_memory: RwLock<Vec<(VirtualMemoryArea, Arc<SharedMemory>)>>,
+    message_queues: RwLock<Vec<Option<Arc<MessageQueue>>>> // index is the handle used by the process
 }
 
+const SHARED_MEMORY_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE).union(PageTableFlags::USER_ACCESSIBLE).union(PageTableFlags::NO_EXECUTE);
+
 impl Drop for Process {
     fn drop(&mut self) {
+        // Frames of shared memory objects are owned by the objects and freed, when the last reference is dropped
         for vma in self.memory_areas.read().iter() {
-            self.address_space.unmap(vma.range(), true);
+            self.address_space.unmap(vma.range(), vma.typ() != VmaType::SharedMemory);
         }
     }
 }
 
 impl Process {
-    fn new(address_space: Arc<AddressSpace>) -> Self {
-        Self { id: next_process_id(), address_space, memory_areas: RwLock::new(Vec::new()) }
+    fn new(address_space: Arc<AddressSpace>, name: &str, parent_id: usize) -> Self {
+        Self { id: next_process_id(), parent_id, name: name.to_string(), address_space, memory_areas: RwLock::new(Vec::new()), shared_memory: RwLock::new(Vec::new()), message_queues: RwLock::new(Vec::new()) }
     }
 
     pub fn id(&self) -> usize {
         self.id
     }
 
+    pub fn parent_id(&self) -> usize {
+        self.parent_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size of all memory areas in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.memory_areas.read().iter()
+            .map(|area| (area.end() - area.start()) as usize)
+            .sum()
+    }
+
+    fn info(&self, thread_count: usize, state: ProcessState) -> ProcessInfo {
+        let mut info = ProcessInfo {
+            process_id: self.id,
+            parent_id: self.parent_id,
+            name_length: self.name.len().min(PROCESS_NAME_LENGTH),
+            thread_count,
+            memory_usage: self.memory_usage(),
+            state,
+            ..Default::default()
+        };
+
+        info.name[..info.name_length].copy_from_slice(&self.name.as_bytes()[..info.name_length]);
+        return info;
+    }
+
     pub fn address_space(&self) -> Arc<AddressSpace> {
         Arc::clone(&self.address_space)
     }
@@ -157,6 +247,81 @@ impl Process {
         return found;
     }
 
+    /// Check, if `length` bytes starting at `address` lie in a single memory area of this process (and are thus mapped)
+    pub fn is_mapped(&self, address: VirtAddr, length: usize) -> bool {
+        let Some(end) = address.as_u64().checked_add(length as u64) else {
+            return false;
+        };
+
+        self.memory_areas.read().iter().any(|area| area.start() <= address && end <= area.end().as_u64())
+    }
+
+    /// End address of the highest vma, belonging to the loaded application image (code and data segments)
+    pub fn image_end(&self) -> Option<VirtAddr> {
+        self.memory_areas.read().iter()
+            .filter(|area| area.typ() == VmaType::Code || area.typ() == VmaType::ReadOnlyData || area.typ() == VmaType::Data)
+            .map(|area| area.end())
+            .max()
+    }
+
+    /// Map a shared memory object into the user space of this process and return its start address.
+    /// Objects are placed consecutively, starting at SHARED_MEMORY_START.
+    pub fn map_shared_memory(&self, object: Arc<SharedMemory>) -> VirtAddr {
+        let start = self.find_vmas(VmaType::SharedMemory).iter()
+            .map(|area| area.end())
+            .max()
+            .unwrap_or(VirtAddr::new(SHARED_MEMORY_START as u64));
+        let vma = VirtualMemoryArea::from_address(start, object.size(), VmaType::SharedMemory);
+
+        self.address_space.map_physical(object.frames(), vma.range(), MemorySpace::User, SHARED_MEMORY_FLAGS);
+        self.add_vma(vma);
+        self.shared_memory.write().push((vma, object));
+
+        return start;
+    }
+
+    /// Unmap the shared memory object, starting at `start`, from the user space of this process.
+    /// Returns false, if no shared memory object is mapped at the given address.
+    pub fn unmap_shared_memory(&self, start: VirtAddr) -> bool {
+        let mut shared_memory = self.shared_memory.write();
+        match shared_memory.iter().position(|(vma, _)| vma.start() == start) {
+            Some(index) => {
+                let (vma, _) = shared_memory.swap_remove(index);
+                self.address_space.unmap(vma.range(), false);
+                self.memory_areas.write().retain(|area| *area != vma);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Add a message queue to the opened queues of this process and return the handle for it.
+    pub fn open_message_queue(&self, queue: Arc<MessageQueue>) -> usize {
+        let mut queues = self.message_queues.write();
+        match queues.iter().position(|slot| slot.is_none()) {
+            Some(handle) => {
+                queues[handle] = Some(queue);
+                handle
+            }
+            None => {
+                queues.push(Some(queue));
+                queues.len() - 1
+            }
+        }
+    }
+
+    pub fn message_queue(&self, handle: usize) -> Option<Arc<MessageQueue>> {
+        self.message_queues.read().get(handle).cloned().flatten()
+    }
+
+    /// Close the message queue with the given handle. Returns false, if the handle is invalid.
+    pub fn close_message_queue(&self, handle: usize) -> bool {
+        match self.message_queues.write().get_mut(handle) {
+            Some(slot) => slot.take().is_some(),
+            None => false
+        }
+    }
+
     pub fn update_vma(&self, vma: VirtualMemoryArea, update: impl Fn(&mut VirtualMemoryArea)) {
         let mut areas = self.memory_areas.write();
         match areas.iter_mut().find(|area| **area == vma) {
@@ -1,22 +1,37 @@
 /* ╔═════════════════════════════════════════════════════════════════════════╗
    ║ Module: scheduler                                                       ║
    ╟─────────────────────────────────────────────────────────────────────────╢
-   ║ Descr.: Implementation of the scheduler.                                ║
+   ║ Descr.: Implementation of the scheduler. Each processor manages its     ║
+   ║         ready threads in a multi-level feedback queue. Threads using    ║
+   ║         up their whole time slice are moved down one level, while       ║
+   ║         threads waiting for a long time are moved up again (aging).     ║
+   ║         New threads are put on the least loaded processor and idle      ║
+   ║         processors steal waiting threads from the others. Threads       ║
+   ║         blocked in a wait queue are kept in the blocked list, until     ║
+   ║         they are woken up. If no thread is ready, the idle thread       ║
+   ║         halts the cpu and the timer only fires, when the next sleeping  ║
+   ║         thread is due.                                                  ║
    ╟─────────────────────────────────────────────────────────────────────────╢
    ║ Author: Fabian Ruhland, HHU                                             ║
    ╚═════════════════════════════════════════════════════════════════════════╝
 */
 use crate::process::thread::Thread;
-use alloc::collections::VecDeque;
-use alloc::format;
-use alloc::rc::Rc;
+use syscall::{ThreadInfo, ThreadState};
+use alloc::collections::{BinaryHeap, VecDeque};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::ptr;
-use core::sync::atomic::AtomicUsize;
+use core::cmp::Ordering;
+use core::hint::spin_loop;
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicU64, AtomicUsize};
 use core::sync::atomic::Ordering::Relaxed;
 use smallmap::Map;
 use spin::{Mutex, MutexGuard};
-use crate::{allocator, apic, scheduler, timer, tss};
+use x86_64::instructions::interrupts;
+use crate::{apic, scheduler, smp, timer};
+use crate::consts::MAX_CPUS;
+use crate::interrupt::interrupt_dispatcher::InterruptVector;
+use crate::sync::wait_queue::WaitQueue;
 
 // thread IDs
 static THREAD_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
@@ -25,116 +40,511 @@ pub fn next_thread_id() -> usize {
     THREAD_ID_COUNTER.fetch_add(1, Relaxed)
 }
 
-// everything related to the ready state in the scheduler
+// number of priority levels (0 = highest priority)
+pub const PRIORITY_LEVELS: usize = 8;
+pub const DEFAULT_PRIORITY: usize = 3;
+
+// threads waiting longer than this number of timer ticks are moved up one level
+const AGING_TICKS: usize = 50;
+
+// maximum time, the cpu is halted by the idle thread without a timer interrupt
+const MAX_IDLE_NS: usize = 1000000000;
+
+// time slice of a thread in timer ticks, depending on its level (lower priorities get longer time slices)
+fn time_slice(level: usize) -> usize {
+    level + 1
+}
+
+// multi-level feedback queue, each entry contains the tick, at which the thread has been enqueued
+struct ReadyQueue {
+    levels: [VecDeque<(Arc<Thread>, usize)>; PRIORITY_LEVELS],
+    ticks: usize
+}
+
+impl ReadyQueue {
+    fn new() -> Self {
+        Self { levels: core::array::from_fn(|_| VecDeque::new()), ticks: 0 }
+    }
+
+    fn push(&mut self, thread: Arc<Thread>) {
+        // A killed thread may still have been sleeping or blocked -> It is dropped instead of being scheduled again
+        if thread.is_killed() {
+            return;
+        }
+
+        let level = thread.level();
+        thread.set_state(ThreadState::Ready);
+        self.levels[level].push_front((thread, self.ticks));
+    }
+
+    // get thread with the highest priority
+    // (threads, that are still being switched away from on another processor, are skipped)
+    fn pop(&mut self) -> Option<Arc<Thread>> {
+        for level in self.levels.iter_mut() {
+            if let Some(index) = level.iter().rposition(|entry| !entry.0.is_on_cpu()) {
+                return level.remove(index).map(|entry| entry.0);
+            }
+        }
+
+        None
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.iter().all(|level| level.is_empty())
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    // check if a thread with at least the given priority is waiting
+    fn contains_level(&self, max_level: usize) -> bool {
+        self.levels[..=max_level].iter().any(|level| level.iter().any(|entry| !entry.0.is_on_cpu()))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Arc<Thread>> {
+        self.levels.iter().flat_map(|level| level.iter().map(|entry| &entry.0))
+    }
+
+    // remove a thread from the queue, regardless of its level
+    fn remove(&mut self, thread_id: usize) -> Option<Arc<Thread>> {
+        for level in self.levels.iter_mut() {
+            if let Some(index) = level.iter().position(|entry| entry.0.id() == thread_id) {
+                return level.remove(index).map(|entry| entry.0);
+            }
+        }
+
+        None
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Arc<Thread>) -> bool) {
+        for level in self.levels.iter_mut() {
+            level.retain(|entry| keep(&entry.0));
+        }
+    }
+
+    // called on each timer tick; moves threads, that have been waiting too long, up one level
+    fn tick(&mut self) {
+        self.ticks += 1;
+
+        for index in 1..PRIORITY_LEVELS {
+            let (upper, lower) = self.levels.split_at_mut(index);
+            let ticks = self.ticks;
+
+            lower[0].retain(|entry| {
+                if ticks - entry.1 >= AGING_TICKS && entry.0.priority() < index {
+                    entry.0.promote();
+                    upper[entry.0.level().min(index - 1)].push_front((Arc::clone(&entry.0), ticks));
+                    return false;
+                }
+
+                return true;
+            });
+        }
+    }
+}
+
+// entry of the sleep list; the list is a heap, ordered by wakeup time (earliest first)
+struct Sleeper {
+    wakeup_time: usize, // system time in nanoseconds
+    thread: Arc<Thread>
+}
+
+impl PartialEq for Sleeper {
+    fn eq(&self, other: &Self) -> bool {
+        self.wakeup_time == other.wakeup_time
+    }
+}
+
+impl Eq for Sleeper {}
+
+impl PartialOrd for Sleeper {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sleeper {
+    // reversed, since BinaryHeap is a max-heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.wakeup_time.cmp(&self.wakeup_time)
+    }
+}
+
+
+// everything related to the ready state of one processor in the scheduler
 struct ReadyState {
+    cpu: usize, // index of the processor (see `smp::current_cpu()`)
     initialized: bool,
-    current_thread: Option<Rc<Thread>>,
-    ready_queue: VecDeque<Rc<Thread>>
+    current_thread: Option<Arc<Thread>>,
+    previous_thread: Option<Arc<Thread>>, // thread, that is being switched away from (released by `unlock_scheduler()`)
+    current_ticks: usize, // timer ticks, the current thread has been running
+    current_start: usize, // system time in nanoseconds, at which the current thread has been switched to
+    ready_queue: ReadyQueue,
+    idle_thread: Option<Arc<Thread>>, // runs, if no other thread is ready (never enqueued in the ready queue)
+    tickless: bool // the timer has been switched to one-shot mode by the idle thread
 }
 
 impl ReadyState {
-    pub fn new() -> Self {
-        Self { initialized: false, current_thread: None, ready_queue: VecDeque::new() }
+    pub fn new(cpu: usize) -> Self {
+        Self { cpu, initialized: false, current_thread: None, previous_thread: None, current_ticks: 0, current_start: 0, ready_queue: ReadyQueue::new(), idle_thread: None, tickless: false }
+    }
+
+    fn is_idle(&self, thread: &Arc<Thread>) -> bool {
+        self.idle_thread.as_ref().is_some_and(|idle| Arc::ptr_eq(idle, thread))
+    }
+
+    // number of threads, that want to run on this processor
+    fn load(&self) -> usize {
+        let running = self.current_thread.as_ref().is_some_and(|current| !self.is_idle(current));
+        self.ready_queue.len() + running as usize
     }
+
+    // add the time since the last switch to the cpu time of the current thread and make `next` the current thread
+    fn switch_to(&mut self, next: Arc<Thread>) {
+        if let Some(timer) = timer().try_read() {
+            let now = timer.systime_ns();
+            if let Some(current) = self.current_thread.as_ref() {
+                current.account(now.saturating_sub(self.current_start));
+            }
+
+            self.current_start = now;
+        }
+
+        // Leaving the idle thread -> Periodic timer interrupts are needed again for time slices
+        if self.tickless && !self.is_idle(&next) && apic().resume_periodic_timer() {
+            self.tickless = false;
+        }
+
+        next.set_state(ThreadState::Running);
+        next.count_context_switch();
+        next.set_on_cpu(true);
+        self.previous_thread = self.current_thread.replace(next);
+        self.current_ticks = 0;
+    }
+}
+
+// cause of a thread switch
+#[derive(Copy, Clone, PartialEq)]
+enum Switch {
+    Yield,      // the current thread gives up the processor
+    Tick,       // timer interrupt (time slices and aging)
+    Reschedule  // reschedule IPI from another processor (a new thread has been put on this processor or the current thread has been killed)
 }
 
+/// The scheduler keeps one ready queue per processor. New threads are put on the least loaded processor,
+/// while idle processors take over waiting threads from the others. Sleeping, blocked and joining threads
+/// are shared by all processors and moved into the ready queue of the processor, that notices their wakeup first.
 pub struct Scheduler {
-    ready_state: Mutex<ReadyState>,
-    sleep_list: Mutex<Vec<(Rc<Thread>, usize)>>,
-    join_map: Mutex<Map<usize, Vec<Rc<Thread>>>> // manage which threads are waiting for a thread-id to terminate
+    ready_states: Vec<Mutex<ReadyState>>, // indexed by `smp::current_cpu()`
+    idle_processors: AtomicU64, // bit mask of processors, that are halted by their idle thread
+    sleep_list: Mutex<BinaryHeap<Sleeper>>, // only locked while holding the ready state of the calling processor
+    blocked_list: Mutex<Vec<Arc<Thread>>>, // threads waiting in a wait queue (only locked with interrupts disabled)
+    join_map: Mutex<Map<usize, Vec<Arc<Thread>>>> // manage which threads are waiting for a thread-id to terminate (locked like the sleep list)
 }
 
 unsafe impl Send for Scheduler {}
 unsafe impl Sync for Scheduler {}
 
-/// Called from assembly code, after the thread has been switched
+/// Called from assembly code, after the thread has been switched.
+/// The ready state has been locked on this processor before the switch, but the thread, that locked it, may continue on another processor.
 #[no_mangle]
 pub unsafe extern "C" fn unlock_scheduler() {
-    unsafe { scheduler().ready_state.force_unlock(); }
+    let previous = interrupts::without_interrupts(|| {
+        let state = &scheduler().ready_states[smp::current_cpu()];
+        unsafe { state.force_unlock(); }
+        Scheduler::lock_ready_state(state).previous_thread.take()
+    });
+
+    // The context of the previous thread has been saved -> It may run on another processor now
+    if let Some(previous) = previous {
+        previous.set_on_cpu(false);
+    }
 }
 
 impl Scheduler {
 
     // create and init scheduler
     pub fn new() -> Self {
-        Self { ready_state: Mutex::new(ReadyState::new()), sleep_list: Mutex::new(Vec::new()), join_map: Mutex::new(Map::new()) }
+        Self {
+            ready_states: (0..MAX_CPUS).map(|cpu| Mutex::new(ReadyState::new(cpu))).collect(),
+            idle_processors: AtomicU64::new(0),
+            sleep_list: Mutex::new(BinaryHeap::new()),
+            blocked_list: Mutex::new(Vec::new()),
+            join_map: Mutex::new(Map::new())
+        }
     }
 
-    // called during creation of first thread
+    // called during creation of the first thread on each processor
     pub fn set_init(&self) {
         self.get_ready_state().initialized = true;
     }
 
+    /// Set the thread, which runs on the calling processor when no other thread is ready. It gets the lowest priority.
+    pub fn set_idle_thread(&self, thread: Arc<Thread>) {
+        thread.set_priority(PRIORITY_LEVELS - 1);
+        self.get_ready_state().idle_thread = Some(thread);
+    }
+
+    /// Time in nanoseconds, that the idle threads have been running (i.e. the processors have been idle), averaged over all processors.
+    pub fn idle_time(&self) -> usize {
+        let now = timer().read().systime_ns();
+        let mut idle_time = 0;
+        let mut processors = 0;
+
+        for cpu in 0..self.ready_states.len() {
+            self.with_ready_state(cpu, |state| {
+                if let Some(idle) = state.idle_thread.as_ref() {
+                    idle_time += idle.runtime();
+                    processors += 1;
+
+                    if state.current_thread.as_ref().is_some_and(|current| state.is_idle(current)) {
+                        idle_time += now.saturating_sub(state.current_start);
+                    }
+                }
+            });
+        }
+
+        if processors == 0 { 0 } else { idle_time / processors }
+    }
+
+    /// Called in a loop by the idle thread of each processor: Take over a waiting thread from another processor
+    /// or halt the cpu until the next interrupt occurs. Instead of periodic interrupts, the timer is programmed
+    /// to fire when the next sleeping thread is due.
+    pub fn idle(&self) {
+        let mut state = self.get_ready_state();
+        let cpu = state.cpu;
+
+        // Interrupts are enabled again by 'enable_and_hlt()', so that no wakeup gets lost before halting
+        interrupts::disable();
+        Scheduler::check_blocked_list(&mut state, &mut self.blocked_list.lock());
+        Scheduler::check_sleep_list(&mut state, &mut self.sleep_list.lock());
+
+        if state.ready_queue.is_empty() {
+            if let Some(thread) = self.steal(cpu) {
+                state.ready_queue.push(thread);
+            }
+        }
+
+        let halt = if state.ready_queue.is_empty() {
+            let now = timer().read().systime_ns();
+            let delay = self.sleep_list.lock().peek().map_or(MAX_IDLE_NS, |entry| entry.wakeup_time.saturating_sub(now).min(MAX_IDLE_NS));
+            if apic().start_one_shot_timer(delay) {
+                state.tickless = true;
+            }
+
+            self.idle_processors.fetch_or(1 << cpu, Relaxed);
+            true
+        } else {
+            false
+        };
+
+        drop(state);
+        if halt {
+            interrupts::enable_and_hlt();
+            self.idle_processors.fetch_and(!(1 << cpu), Relaxed);
+        } else {
+            interrupts::enable();
+        }
+
+        self.switch_thread_no_interrupt();
+    }
+
+    /// Ids of all threads (running on any processor, ready, sleeping, blocked or joining), except the idle threads and the calling thread
     pub fn active_thread_ids(&self) -> Vec<usize> {
-        let state = self.get_ready_state();
-        let sleep_list = self.sleep_list.lock();
+        let current = self.current_thread().id();
+        let mut ids = Vec::new();
+        self.for_each_thread(|thread| {
+            if thread.id() != current {
+                ids.push(thread.id());
+            }
+        });
 
-        state.ready_queue.iter().map(|thread| thread.id()).collect::<Vec<usize>>()
-            .into_iter().chain(sleep_list.iter().map(|entry| entry.0.id())).collect()
+        return ids;
     }
 
-    pub fn current_thread(&self) -> Rc<Thread> {
+    pub fn current_thread(&self) -> Arc<Thread> {
         let state = self.get_ready_state();
         return Scheduler::current(&state);
     }
 
     // get thread for given id
-    pub fn thread(&self, thread_id: usize) -> Option<Rc<Thread>> {
-        self.ready_state.lock()
-            .ready_queue.iter().find(|thread| {
-            thread.id() == thread_id
-        }).cloned()
+    pub fn thread(&self, thread_id: usize) -> Option<Arc<Thread>> {
+        self.find_thread(thread_id)
+    }
+
+    // set base priority of a running, ready or sleeping thread
+    pub fn set_priority(&self, thread_id: usize, priority: usize) -> bool {
+        // A ready thread is moved to the queue of its new level
+        for cpu in 0..self.ready_states.len() {
+            let requeued = self.with_ready_state(cpu, |state| match state.ready_queue.remove(thread_id) {
+                Some(thread) => {
+                    thread.set_priority(priority);
+                    state.ready_queue.push(thread);
+                    true
+                }
+                None => false
+            });
+
+            if requeued {
+                return true;
+            }
+        }
+
+        match self.find_thread(thread_id) {
+            Some(thread) => {
+                thread.set_priority(priority);
+                true
+            }
+            None => false
+        }
     }
 
+    // get base priority of a running, ready or sleeping thread
+    pub fn priority(&self, thread_id: usize) -> Option<usize> {
+        self.find_thread(thread_id).map(|thread| thread.priority())
+    }
+
+    fn find_thread(&self, thread_id: usize) -> Option<Arc<Thread>> {
+        let mut found = None;
+        self.for_each_thread(|thread| {
+            if thread.id() == thread_id {
+                found = Some(Arc::clone(thread));
+            }
+        });
+
+        return found;
+    }
+
+    // call `f` for all threads (running on any processor, ready, sleeping, blocked or joining), except the idle threads
+    fn for_each_thread(&self, mut f: impl FnMut(&Arc<Thread>)) {
+        for cpu in 0..self.ready_states.len() {
+            self.with_ready_state(cpu, |state| {
+                if let Some(current) = state.current_thread.as_ref().filter(|current| !state.is_idle(current)) {
+                    f(current);
+                }
+
+                state.ready_queue.iter().for_each(&mut f);
+            });
+        }
+
+        let (_state, join_map) = self.get_ready_state_and_join_map();
+        self.sleep_list.lock().iter().for_each(|entry| f(&entry.thread));
+        interrupts::without_interrupts(|| self.blocked_list.lock().iter().for_each(&mut f));
+        join_map.iter().flat_map(|(_, join_list)| join_list.iter()).for_each(&mut f);
+    }
+
+    /// Start scheduling on the calling processor with the first thread of its ready queue (or its idle thread, if there is none)
     pub fn start(&self) {
         let mut state = self.get_ready_state();
-        state.current_thread = state.ready_queue.pop_back();
+        let first = state.ready_queue.pop().or_else(|| state.idle_thread.clone()).expect("Scheduler: Failed to dequeue first thread!");
+        state.switch_to(first);
 
         unsafe { Thread::start_first(state.current_thread.as_ref().expect("Scheduler: Failed to dequeue first thread!").as_ref()); }
     }
 
-    pub fn ready(&self, thread: Rc<Thread>) {
-        let id = thread.id();
-        let mut join_map;
-        let mut state;
+    /// Put a new thread on the least loaded processor
+    pub fn ready(&self, thread: Arc<Thread>) {
+        // Register the thread in the join map first, so that other threads are able to join it, as soon as it runs
+        {
+            let _state = self.get_ready_state();
+            self.join_map.lock().insert(thread.id(), Vec::new());
+        }
 
-        // If we get the lock on 'self.state' but not on 'self.join_map' the system hangs.
-        // The scheduler is not able to switch threads anymore, because of 'self.state' is locked,
-        // and we will never be able to get the lock on 'self.join_map'.
-        // To solve this, we need to release the lock on 'self.state' in case we do not get
-        // the lock on 'self.join_map' and let the scheduler switch threads until we get both locks.
-        loop {
-            let state_mutex = self.get_ready_state();
-            let join_map_option = self.join_map.try_lock();
+        let cpu = self.least_loaded_cpu();
+        self.with_ready_state(cpu, |state| state.ready_queue.push(thread));
 
-            if join_map_option.is_some() {
-                state = state_mutex;
-                join_map = join_map_option.unwrap();
-                break;
-            } else {
-                self.switch_thread_no_interrupt();
-            }
+        if cpu != smp::current_cpu() {
+            apic().send_ipi(cpu, InterruptVector::Reschedule);
         }
-
-        state.ready_queue.push_front(thread);
-        join_map.insert(id, Vec::new());
     }
 
     pub fn sleep(&self, ms: usize) {
-        let mut state = self.get_ready_state();
+        let wakeup_time = timer().read().systime_ns() + ms * 1000000;
+        self.sleep_until(wakeup_time);
+    }
+
+    /// Block the current thread, until the system time (in nanoseconds) has reached `wakeup_time`.
+    /// Sleeping threads are checked on each thread switch, so the actual wakeup may be delayed by up to one time slice.
+    pub fn sleep_until(&self, wakeup_time: usize) {
+        let state = self.get_ready_state();
         let thread = Scheduler::current(&state);
-        let wakeup_time = timer().read().systime_ms() + ms;
 
         { // Execute in own block, so that the lock is released automatically (block() does not return)
             let mut sleep_list = self.sleep_list.lock();
-            sleep_list.push((thread, wakeup_time));
+            thread.set_state(ThreadState::Sleeping);
+            sleep_list.push(Sleeper { wakeup_time, thread });
         }
 
-        self.block(&mut state);
+        self.block(state);
     }
 
-    fn switch_thread(&self, interrupt: bool) {
-        if let Some(mut state) = self.ready_state.try_lock() {
+    /// Block the current thread on `queue`, if `condition` returns true, until it is woken up via `wake_up()`.
+    /// The thread is blocked and enqueued before checking the condition, so that a wakeup from another processor,
+    /// which changes the condition right after it has been checked, cannot get lost. The condition is checked
+    /// with interrupts disabled, so no interrupt handler can run in between either.
+    /// Returns false, if the thread has not been blocked.
+    pub fn block_if(&self, queue: &WaitQueue, condition: impl FnOnce() -> bool) -> bool {
+        let state = self.get_ready_state();
+        let thread = Scheduler::current(&state);
+
+        let blocked = interrupts::without_interrupts(|| {
+            // The thread must be blocked, before its id becomes visible to `wake_up()` on other processors
+            thread.set_state(ThreadState::Blocked);
+            self.blocked_list.lock().push(Arc::clone(&thread));
+            queue.enqueue(thread.id());
+
+            if condition() {
+                return true;
+            }
+
+            queue.remove(thread.id());
+            let mut blocked_list = self.blocked_list.lock();
+            match blocked_list.iter().position(|blocked| blocked.id() == thread.id()) {
+                Some(index) => {
+                    blocked_list.swap_remove(index);
+                    thread.set_state(ThreadState::Running);
+                    false
+                }
+                // The thread has been woken up in the meantime and already moved into a ready queue,
+                // so it must switch away like a blocked thread (the wakeup counts as spurious)
+                None => true
+            }
+        });
+
+        if blocked {
+            drop(thread); // Decrease reference count manually, because block() only returns after the thread has been woken up
+            self.block(state);
+        }
+
+        return blocked;
+    }
+
+    /// Wake up a thread blocked via `block_if()`. The thread is moved into a ready queue on the next thread switch
+    /// of any processor (an idle processor is woken up for this). Returns false, if there is no blocked thread
+    /// with the given id (e.g. because it has been killed).
+    /// May be called from interrupt handlers, since it does not need to lock a ready queue.
+    pub fn wake_up(&self, thread_id: usize) -> bool {
+        let woken = interrupts::without_interrupts(|| {
+            match self.blocked_list.lock().iter().find(|thread| thread.id() == thread_id && thread.state() == ThreadState::Blocked) {
+                Some(thread) => {
+                    thread.set_state(ThreadState::Ready);
+                    true
+                }
+                None => false
+            }
+        });
+
+        if woken {
+            self.wake_idle_processor();
+        }
+
+        return woken;
+    }
+
+    fn switch_thread(&self, cause: Switch) {
+        // Interrupts are disabled, so that the thread cannot be moved to another processor, before the ready state is locked
+        if let Some(mut state) = interrupts::without_interrupts(|| self.ready_states[smp::current_cpu()].try_lock()) {
             if !state.initialized {
                 return;
             }
@@ -143,48 +553,98 @@ impl Scheduler {
                 Scheduler::check_sleep_list(&mut state, &mut sleep_list);
             }
 
+            interrupts::without_interrupts(|| Scheduler::check_blocked_list(&mut state, &mut self.blocked_list.lock()));
+
             let current = Scheduler::current(&state);
-            let next = match state.ready_queue.pop_back() {
-                Some(thread) => thread,
-                None => return,
-            };
+            let idle = state.is_idle(&current);
+            let killed = current.is_killed();
 
             // Current thread is initializing itself and may not be interrupted
-            if current.stacks_locked() || tss().is_locked() {
+            if current.stacks_locked() {
                 return;
             }
 
+            if cause == Switch::Tick && !idle {
+                state.ready_queue.tick();
+                state.current_ticks += 1;
+            }
+
+            // A killed thread is left immediately and the idle thread as soon as any other thread is ready
+            if cause != Switch::Yield && !idle && !killed {
+                // Keep running, until the time slice is used up or a thread with higher priority is ready
+                let preempted = current.level() > 0 && state.ready_queue.contains_level(current.level() - 1);
+                if cause == Switch::Reschedule && !preempted {
+                    return;
+                }
+
+                if cause == Switch::Tick {
+                    if !preempted && state.current_ticks < time_slice(current.level()) {
+                        self.balance_load(&state);
+                        return;
+                    }
+
+                    if !preempted {
+                        current.demote();
+                    }
+
+                    // No other thread with the same or higher priority -> Start a new time slice
+                    if !state.ready_queue.contains_level(current.level()) {
+                        state.current_ticks = 0;
+                        self.balance_load(&state);
+                        return;
+                    }
+                }
+            }
+
+            let next = match state.ready_queue.pop() {
+                Some(thread) => thread,
+                None if killed => state.idle_thread.clone().expect("Scheduler: Missing idle thread!"),
+                None => return,
+            };
+
             let current_ptr = ptr::from_ref(current.as_ref());
             let next_ptr = ptr::from_ref(next.as_ref());
 
-            state.current_thread = Some(next);
-            state.ready_queue.push_front(current);
+            state.switch_to(next);
+            if idle {
+                current.set_state(ThreadState::Ready);
+                self.idle_processors.fetch_and(!(1 << state.cpu), Relaxed);
+            } else {
+                state.ready_queue.push(current); // A killed thread is dropped here
+            }
 
-            if interrupt {
+            if cause != Switch::Yield {
                 apic().end_of_interrupt();
             }
 
+            // The lock is released by `unlock_scheduler()` on this processor, while this thread may continue on another one
+            mem::forget(state);
             unsafe { Thread::switch(current_ptr, next_ptr); }
         }
     }
 
-    // 
     pub fn switch_thread_no_interrupt(&self) {
-        self.switch_thread(false);
+        self.switch_thread(Switch::Yield);
     }
 
     pub fn switch_thread_from_interrupt(&self) {
-        self.switch_thread(true);
+        self.switch_thread(Switch::Tick);
+    }
+
+    /// Called by the reschedule IPI: Switch to a new thread on this processor, if it has a higher priority than the current one
+    pub fn reschedule_from_interrupt(&self) {
+        self.switch_thread(Switch::Reschedule);
     }
 
     pub fn join(&self, thread_id: usize) {
-        let mut state = self.get_ready_state();
+        let state = self.get_ready_state();
         let thread = Scheduler::current(&state);
 
         { // Execute in own block, so that the lock is released automatically (block() does not return)
             let mut join_map = self.join_map.lock();
             let join_list = join_map.get_mut(&thread_id);
             if join_list.is_some() {
+                thread.set_state(ThreadState::Joining);
                 join_list.unwrap().push(thread);
             } else {
                 // Joining on a non-existent thread has no effect (i.e. the thread has already finished running)
@@ -192,123 +652,214 @@ impl Scheduler {
             }
         }
 
-        self.block(&mut state);
+        self.block(state);
     }
 
     pub fn exit(&self) {
-        let mut ready_state;
-        let current;
-
-        { // Execute in own block, so that join_map is released automatically (block() does not return)
-            let state = self.get_ready_state_and_join_map();
-            ready_state = state.0;
-            let mut join_map = state.1;
-
-            current = Scheduler::current(&ready_state);
-            let join_list = join_map.get_mut(&current.id()).expect(format!("Scheduler: Missing join_map entry for thread id {}!", current.id()).as_str());
+        let (mut ready_state, mut join_map) = self.get_ready_state_and_join_map();
+        let current = Scheduler::current(&ready_state);
 
+        // The join map entry is missing, if the thread has been killed while exiting
+        if let Some(join_list) = join_map.remove(&current.id()) {
             for thread in join_list {
-                ready_state.ready_queue.push_front(Rc::clone(thread));
+                ready_state.ready_queue.push(thread);
             }
-
-            join_map.remove(&current.id());
         }
 
-        drop(current); // Decrease Rc manually, because block() does not return
-        self.block(&mut ready_state);
+        drop(join_map);
+        drop(current); // Decrease reference count manually, because block() does not return
+        self.block(ready_state);
     }
 
+    /// Kill a thread, that is not the calling one. A thread running on another processor is switched away from via a reschedule IPI.
     pub fn kill(&self, thread_id: usize) {
-        { // Check if current thread tries to kill itself (illegal)
-            let ready_state = self.get_ready_state();
-            let current = Scheduler::current(&ready_state);
+        // Check if current thread tries to kill itself (illegal)
+        if self.current_thread().id() == thread_id {
+            panic!("Scheduler: A thread cannot kill itself!");
+        }
+
+        // Killing a thread, that has already terminated, has no effect
+        let thread = match self.find_thread(thread_id) {
+            Some(thread) => thread,
+            None => return
+        };
+
+        thread.kill();
+
+        {
+            let (mut ready_state, mut join_map) = self.get_ready_state_and_join_map();
+            if let Some(join_list) = join_map.remove(&thread_id) {
+                for thread in join_list {
+                    ready_state.ready_queue.push(thread);
+                }
+            }
 
-            if current.id() == thread_id {
-                panic!("Scheduler: A thread cannot kill itself!");
+            self.sleep_list.lock().retain(|entry| entry.thread.id() != thread_id);
+            interrupts::without_interrupts(|| self.blocked_list.lock().retain(|thread| thread.id() != thread_id));
+        }
+
+        for cpu in 0..self.ready_states.len() {
+            let running = self.with_ready_state(cpu, |state| {
+                state.ready_queue.retain(|thread| thread.id() != thread_id);
+                state.current_thread.as_ref().is_some_and(|current| current.id() == thread_id)
+            });
+
+            if running {
+                apic().send_ipi(cpu, InterruptVector::Reschedule);
             }
         }
+    }
+
+    // get scheduling information about all threads, that have not terminated yet
+    pub fn thread_infos(&self) -> Vec<ThreadInfo> {
+        let now = timer().try_read().map(|timer| timer.systime_ns());
+        let mut infos = Vec::new();
+
+        for cpu in 0..self.ready_states.len() {
+            self.with_ready_state(cpu, |state| {
+                if let Some(current) = state.current_thread.as_ref() {
+                    let mut current_info = current.info();
+                    if let Some(now) = now {
+                        current_info.runtime_ms += now.saturating_sub(state.current_start) / 1000000;
+                    }
 
-        let state = self.get_ready_state_and_join_map();
-        let mut ready_state = state.0;
-        let mut join_map = state.1;
+                    infos.push(current_info);
+                }
 
-        let join_list = join_map.get_mut(&thread_id).expect(format!("Scheduler: Missing join_map entry for thread id {}!", thread_id).as_str());
+                if let Some(idle) = state.idle_thread.as_ref().filter(|idle| !state.current_thread.as_ref().is_some_and(|current| Arc::ptr_eq(idle, current))) {
+                    infos.push(idle.info());
+                }
 
-        for thread in join_list {
-            ready_state.ready_queue.push_front(Rc::clone(thread));
+                infos.extend(state.ready_queue.iter().map(|thread| thread.info()));
+            });
         }
 
-        join_map.remove(&thread_id);
-        ready_state.ready_queue.retain(|thread| thread.id() != thread_id);
+        let (_state, join_map) = self.get_ready_state_and_join_map();
+        infos.extend(self.sleep_list.lock().iter().map(|entry| entry.thread.info()));
+        interrupts::without_interrupts(|| infos.extend(self.blocked_list.lock().iter().map(|thread| thread.info())));
+        infos.extend(join_map.iter().flat_map(|(_, join_list)| join_list.iter().map(|thread| thread.info())));
+
+        return infos;
     }
 
-    fn block(&self, state: &mut ReadyState) {
-        let mut next_thread = state.ready_queue.pop_back();
+    fn block(&self, mut state: MutexGuard<ReadyState>) {
+        let mut next_thread = state.ready_queue.pop();
 
         { // Execute in own block, so that the lock is released automatically (block() does not return)
             let mut sleep_list = self.sleep_list.lock();
             while next_thread.is_none() {
-                Scheduler::check_sleep_list(state, &mut sleep_list);
-                next_thread = state.ready_queue.pop_back();
+                Scheduler::check_sleep_list(&mut state, &mut sleep_list);
+                interrupts::without_interrupts(|| Scheduler::check_blocked_list(&mut state, &mut self.blocked_list.lock()));
+                next_thread = state.ready_queue.pop().or_else(|| state.idle_thread.clone());
             }
         }
 
         let current = Scheduler::current(&state);
         let next = next_thread.unwrap();
 
-        // Thread has enqueued itself into sleep list and waited so long, that it dequeued itself in the meantime
-        if current.id() == next.id() {
-            return;
-        }
-
         let current_ptr = ptr::from_ref(current.as_ref());
         let next_ptr = ptr::from_ref(next.as_ref());
 
-        state.current_thread = Some(next);
-        drop(current); // Decrease Rc manually, because Thread::switch does not return
+        state.switch_to(next);
+        drop(current); // Decrease reference count manually, because Thread::switch does not return
 
+        // The lock is released by `unlock_scheduler()` on this processor, while this thread may continue on another one
+        mem::forget(state);
         unsafe { Thread::switch(current_ptr, next_ptr); }
     }
 
-    fn current(state: &ReadyState) -> Rc<Thread> {
-        return Rc::clone(state.current_thread.as_ref().expect("Scheduler: Trying to access current thread before initialization!"));
+    fn current(state: &ReadyState) -> Arc<Thread> {
+        return Arc::clone(state.current_thread.as_ref().expect("Scheduler: Trying to access current thread before initialization!"));
     }
 
-    fn check_sleep_list(state: &mut ReadyState, sleep_list: &mut Vec<(Rc<Thread>, usize)>) {
+    fn check_sleep_list(state: &mut ReadyState, sleep_list: &mut BinaryHeap<Sleeper>) {
         if let Some(timer) = timer().try_read() {
-            let time = timer.systime_ms();
+            let time = timer.systime_ns();
 
-            sleep_list.retain(|entry| {
-                if time >= entry.1 {
-                    state.ready_queue.push_front(Rc::clone(&entry.0));
-                    return false;
-                }
+            while sleep_list.peek().is_some_and(|entry| time >= entry.wakeup_time) {
+                let entry = sleep_list.pop().unwrap();
+                state.ready_queue.push(entry.thread);
+            }
+        }
+    }
 
-                return true;
-            });
+    // move threads, that have been woken up, from the blocked list into the ready queue
+    fn check_blocked_list(state: &mut ReadyState, blocked_list: &mut Vec<Arc<Thread>>) {
+        blocked_list.retain(|thread| {
+            if thread.state() != ThreadState::Blocked {
+                state.ready_queue.push(Arc::clone(thread));
+                return false;
+            }
+
+            return true;
+        });
+    }
+
+    // find the processor with the fewest threads for a new thread (only processors, that are already scheduling, are considered)
+    fn least_loaded_cpu(&self) -> usize {
+        self.ready_states.iter().enumerate()
+            .filter_map(|(cpu, state)| state.try_lock().filter(|state| state.initialized).map(|state| (cpu, state.load())))
+            .min_by_key(|(_, load)| *load)
+            .map_or(smp::current_cpu(), |(cpu, _)| cpu)
+    }
+
+    // take a waiting thread from the processor with the most waiting threads (called by idle processors)
+    fn steal(&self, cpu: usize) -> Option<Arc<Thread>> {
+        let (victim, _) = self.ready_states.iter().enumerate()
+            .filter(|(other, _)| *other != cpu)
+            .filter_map(|(other, state)| state.try_lock().map(|state| (other, state.ready_queue.len())))
+            .filter(|(_, waiting)| *waiting > 0)
+            .max_by_key(|(_, waiting)| *waiting)?;
+
+        self.ready_states[victim].try_lock()?.ready_queue.pop()
+    }
+
+    // threads are waiting on this processor -> Let an idle processor take over one of them
+    fn balance_load(&self, state: &ReadyState) {
+        if !state.ready_queue.is_empty() {
+            self.wake_idle_processor();
+        }
+    }
+
+    // send a reschedule IPI to a halted processor, so that it looks for ready threads
+    // (not needed, if the calling processor is idle itself, since it looks for them after the current interrupt)
+    fn wake_idle_processor(&self) {
+        let idle = self.idle_processors.load(Relaxed);
+        if idle == 0 || idle & (1 << smp::current_cpu()) != 0 {
+            return;
+        }
+
+        let cpu = idle.trailing_zeros() as usize;
+        if self.idle_processors.fetch_and(!(1 << cpu), Relaxed) & (1 << cpu) != 0 {
+            apic().send_ipi(cpu, InterruptVector::Reschedule);
         }
     }
 
+    // lock the ready state of the calling processor
     fn get_ready_state(&self) -> MutexGuard<ReadyState> {
-        let state;
+        // Interrupts are disabled, so that the thread cannot be moved to another processor, before the lock is taken.
+        // Afterwards, the thread is not switched anymore, until the lock is released.
+        interrupts::without_interrupts(|| Scheduler::lock_ready_state(&self.ready_states[smp::current_cpu()]))
+    }
 
-        // We need to make sure, that both the kernel memory manager and the ready queue are currently not locked.
-        // Otherwise, a deadlock may occur: Since we are holding the ready queue lock,
-        // the scheduler won't switch threads anymore, and none of the locks will ever be released
+    // lock the ready state of any processor (with interrupts disabled, so that the calling thread is not switched while holding it)
+    fn with_ready_state<R>(&self, cpu: usize, f: impl FnOnce(&mut ReadyState) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut Scheduler::lock_ready_state(&self.ready_states[cpu])))
+    }
+
+    fn lock_ready_state(state: &Mutex<ReadyState>) -> MutexGuard<'_, ReadyState> {
         loop {
-            let state_tmp = self.ready_state.lock();
-            if allocator().is_locked() {
-                continue;
+            if let Some(state) = state.try_lock() {
+                return state;
             }
-            
-            state = state_tmp;
-            break;
-        }
 
-        return state;
+            // The processor holding the lock might wait for this one to flush its TLB
+            smp::acknowledge_tlb_shootdown();
+            spin_loop();
+        }
     }
 
-    fn get_ready_state_and_join_map(&self) -> (MutexGuard<ReadyState>, MutexGuard<Map<usize, Vec<Rc<Thread>>>>) {
+    fn get_ready_state_and_join_map(&self) -> (MutexGuard<ReadyState>, MutexGuard<Map<usize, Vec<Arc<Thread>>>>) {
         loop {
             let ready_state = self.get_ready_state();
             let join_map = self.join_map.try_lock();
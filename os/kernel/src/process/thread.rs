@@ -21,20 +21,25 @@
 */
 
 use crate::memory::alloc::StackAllocator;
-use crate::memory::r#virtual::{VirtualMemoryArea, VmaType};
-use crate::memory::{MemorySpace, PAGE_SIZE};
+use crate::memory::r#virtual::{with_user_access, VirtualMemoryArea, VmaType};
+use crate::memory::{aslr, phys_to_virt, MemorySpace, PAGE_SIZE};
 use crate::process::process::Process;
 use crate::process::scheduler;
-use crate::syscall::syscall_dispatcher::CORE_LOCAL_STORAGE_TSS_RSP0_PTR_INDEX;
-use crate::{memory, process_manager, scheduler, tss};
-use alloc::rc::Rc;
+use crate::process::scheduler::{DEFAULT_PRIORITY, PRIORITY_LEVELS};
+use crate::syscall::syscall_dispatcher;
+use crate::syscall::syscall_dispatcher::{SyscallFrame, CORE_LOCAL_STORAGE_TSS_RSP0_PTR_INDEX};
+use crate::{memory, process_manager, scheduler};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use core::{mem, ptr};
 use goblin::elf::Elf;
 use goblin::elf64;
+use log::warn;
 use spin::Mutex;
+use syscall::{ThreadInfo, ThreadState};
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::paging::page::PageRange;
 use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
@@ -44,6 +49,7 @@ use x86_64::VirtAddr;
 use crate::consts::KERNEL_STACK_PAGES;
 use crate::consts::MAIN_USER_STACK_START;
 use crate::consts::MAX_USER_STACK_SIZE;
+use crate::consts::{PIE_IMAGE_RANDOM_PAGES, PIE_IMAGE_START, USER_STACK_RANDOM_PAGES};
 
 /// kernel & user stack of a thread 
 struct Stacks {
@@ -59,6 +65,14 @@ pub struct Thread {
     process: Arc<Process>, // reference to my process
     entry: fn(),           // user thread: =0;                 kernel thread: address of entry function
     user_rip: VirtAddr,    // user thread: elf-entry function; kernel thread: =0
+    fork_frame: Option<SyscallFrame>, // forked user thread: registers of the parent thread, when it called fork
+    priority: AtomicUsize, // base priority (0 = highest), the thread never runs in a higher level of the ready queue
+    level: AtomicUsize,    // current level in the multi-level feedback queue of the scheduler
+    state: AtomicUsize,    // ThreadState, as seen by the scheduler
+    runtime: AtomicUsize,  // accumulated cpu time in nanoseconds
+    context_switches: AtomicUsize, // number of times, the thread has been switched to
+    on_cpu: AtomicBool,    // the thread runs on a processor or is still being switched away from (its context is not saved yet)
+    killed: AtomicBool,    // the thread has been killed and must not be scheduled again
 }
 
 impl Stacks {
@@ -80,7 +94,7 @@ impl Thread {
     ///
     /// Parameters: `entry` thread entry function.
     ///
-    pub fn new_kernel_thread(entry: fn()) -> Rc<Thread> {
+    pub fn new_kernel_thread(entry: fn()) -> Arc<Thread> {
         let kernel_stack = Vec::<u64, StackAllocator>::with_capacity_in(
             (KERNEL_STACK_PAGES * PAGE_SIZE) / 8,
             StackAllocator::new(),
@@ -96,63 +110,119 @@ impl Thread {
                 .expect("Trying to create a kernel thread before process initialization!"),
             entry,
             user_rip: VirtAddr::zero(),
+            fork_frame: None,
+            priority: AtomicUsize::new(DEFAULT_PRIORITY),
+            level: AtomicUsize::new(DEFAULT_PRIORITY),
+            state: AtomicUsize::new(ThreadState::Ready as usize),
+            runtime: AtomicUsize::new(0),
+            context_switches: AtomicUsize::new(0),
+            on_cpu: AtomicBool::new(false),
+            killed: AtomicBool::new(false),
         };
 
         thread.prepare_kernel_stack();
-        return Rc::new(thread);
+        return Arc::new(thread);
     }
 
  
-pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
-        let process = process_manager().write().create_process();
+/// Create a process for the application in `elf_buffer` and return its main thread.
+/// Returns `None`, if the application cannot be parsed or uses unsupported relocations.
+pub fn load_application(name: &str, elf_buffer: &[u8]) -> Option<Arc<Thread>> {
+        // Parse elf file headers
+        let Ok(elf) = Elf::parse(elf_buffer) else {
+            warn!("Failed to parse application [{}]", name);
+            return None;
+        };
+
+        // Position independent executables are loaded at a (randomized) base address, other executables at their linked addresses
+        let image_base = if elf.header.e_type == elf64::header::ET_DYN {
+            PIE_IMAGE_START as u64 + aslr::random_page_offset(PIE_IMAGE_RANDOM_PAGES)
+        } else {
+            0
+        };
+
+        // Relocations are checked before creating the process, so that nothing needs to be cleaned up for invalid applications
+        // (only relative relocations are supported, since there is no dynamic linker)
+        if image_base != 0 {
+            if let Some(relocation) = elf.dynrelas.iter().find(|relocation| relocation.r_type != elf64::reloc::R_X86_64_RELATIVE) {
+                warn!("Unsupported relocation type [{}] in application [{}]", relocation.r_type, name);
+                return None;
+            }
+
+            let in_segment = |offset: u64| elf.program_headers.iter()
+                .filter(|header| header.p_type == elf64::program_header::PT_LOAD)
+                .any(|header| offset >= header.p_vaddr && offset.checked_add(8).is_some_and(|end| end <= header.p_vaddr.saturating_add(header.p_memsz)));
+            if elf.dynrelas.iter().any(|relocation| !in_segment(relocation.r_offset)) {
+                warn!("Relocation target outside of loadable segments in application [{}]", name);
+                return None;
+            }
+        }
+
+        let process = process_manager().write().create_process(name);
         let address_space = process.address_space();
 
-        // Parse elf file headers and map code vma if successful
-        let elf = Elf::parse(elf_buffer).expect("Failed to parse application");
+        // Map a vma for each loadable segment
+
         elf.program_headers
             .iter()
-            .filter(|header| header.p_type == elf64::program_header::PT_LOAD)
+            .filter(|header| header.p_type == elf64::program_header::PT_LOAD && header.p_memsz > 0)
             .for_each(|header| {
-                let page_count = if header.p_memsz as usize % PAGE_SIZE == 0 {
-                    header.p_memsz as usize / PAGE_SIZE
-                } else {
-                    (header.p_memsz as usize / PAGE_SIZE) + 1
-                };
-                let frames = memory::physical::alloc(page_count);
-                let virt_start = Page::from_start_address(VirtAddr::new(header.p_vaddr))
-                    .expect("ELF: Program section not page aligned");
+                let vaddr = image_base + header.p_vaddr;
+                let virt_start = Page::containing_address(VirtAddr::new(vaddr));
+                let virt_end = Page::containing_address(VirtAddr::new(vaddr + header.p_memsz - 1)) + 1;
                 let pages = PageRange {
                     start: virt_start,
-                    end: virt_start + page_count as u64,
+                    end: virt_end,
                 };
+                let page_count = (virt_end - virt_start) as usize;
+                let frames = memory::physical::alloc(page_count);
 
                 unsafe {
+                    // Segments do not need to start at a page boundary -> Copy them to the same offset inside the first page
                     let code = elf_buffer.as_ptr().offset(header.p_offset as isize);
-                    let target = frames.start.start_address().as_u64() as *mut u8;
-                    target.copy_from(code, header.p_filesz as usize);
-                    target
-                        .offset(header.p_filesz as isize)
-                        .write_bytes(0, (header.p_memsz - header.p_filesz) as usize);
+                    let target = phys_to_virt(frames.start.start_address()).as_mut_ptr::<u8>();
+                    let offset = (vaddr - virt_start.start_address().as_u64()) as usize;
+                    target.write_bytes(0, page_count * PAGE_SIZE);
+                    target.add(offset).copy_from(code, header.p_filesz as usize);
+                }
+
+                // Translate segment permissions into page flags (text is read-only, only text is executable)
+                let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+                if header.is_write() {
+                    flags |= PageTableFlags::WRITABLE;
+                }
+                if !header.is_executable() {
+                    flags |= PageTableFlags::NO_EXECUTE;
                 }
 
-                process.address_space().map_physical(
-                    frames,
-                    pages,
-                    MemorySpace::User,
-                    PageTableFlags::PRESENT
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::USER_ACCESSIBLE,
-                );
-                process.add_vma(VirtualMemoryArea::new(pages, VmaType::Code));
+                let typ = if header.is_executable() {
+                    VmaType::Code
+                } else if header.is_write() {
+                    VmaType::Data
+                } else {
+                    VmaType::ReadOnlyData
+                };
+
+                address_space.map_physical(frames, pages, MemorySpace::User, flags);
+                process.add_vma(VirtualMemoryArea::new(pages, typ));
             });
 
+        // Relocate position independent executables (relocations have been checked above)
+        if image_base != 0 {
+            for relocation in elf.dynrelas.iter() {
+                let target = address_space.translate(VirtAddr::new(image_base + relocation.r_offset)).expect("Relocation target is not mapped!");
+                let value = image_base.wrapping_add_signed(relocation.r_addend.unwrap_or(0));
+                unsafe { phys_to_virt(target).as_mut_ptr::<u64>().write_unaligned(value); }
+            }
+        }
+
         // create kernel stack for the application
         let kernel_stack = Vec::<u64, StackAllocator>::with_capacity_in(
             (KERNEL_STACK_PAGES * PAGE_SIZE) / 8,
             StackAllocator::new(),
         );
         let user_stack_end = Page::from_start_address(VirtAddr::new(
-            (MAIN_USER_STACK_START + MAX_USER_STACK_SIZE) as u64,
+            (MAIN_USER_STACK_START + MAX_USER_STACK_SIZE) as u64 + aslr::random_page_offset(USER_STACK_RANDOM_PAGES),
         ))
         .unwrap();
         let user_stack_pages = PageRange {
@@ -172,7 +242,7 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
         address_space.map(
             user_stack_pages,
             MemorySpace::User,
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE,
         );
         process.add_vma(VirtualMemoryArea::new(user_stack_pages, VmaType::Stack));
 
@@ -182,11 +252,19 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
             stacks: Mutex::new(Stacks::new(kernel_stack, user_stack)),
             process,
             entry: unsafe { mem::transmute(ptr::null::<fn()>()) },
-            user_rip: VirtAddr::new(elf.entry),
+            user_rip: VirtAddr::new(image_base + elf.entry),
+            fork_frame: None,
+            priority: AtomicUsize::new(DEFAULT_PRIORITY),
+            level: AtomicUsize::new(DEFAULT_PRIORITY),
+            state: AtomicUsize::new(ThreadState::Ready as usize),
+            runtime: AtomicUsize::new(0),
+            context_switches: AtomicUsize::new(0),
+            on_cpu: AtomicBool::new(false),
+            killed: AtomicBool::new(false),
         };
 
         thread.prepare_kernel_stack();
-        return Rc::new(thread);
+        return Some(Arc::new(thread));
     }
 
     ///
@@ -201,7 +279,7 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
         parent: Arc<Process>,
         kickoff_addr: VirtAddr,
         entry: fn(),
-    ) -> Rc<Thread> {
+    ) -> Arc<Thread> {
         // alloc memory for kernel stack
         let kernel_stack = Vec::<u64, StackAllocator>::with_capacity_in(
             (KERNEL_STACK_PAGES * PAGE_SIZE) / 8,
@@ -236,7 +314,7 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
         parent.address_space().map(
             user_stack_pages,
             MemorySpace::User,
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE,
         );
 
         // add the VMA entry for the new user stack
@@ -249,9 +327,68 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
             process: parent,
             entry,
             user_rip: kickoff_addr,
+            fork_frame: None,
+            priority: AtomicUsize::new(DEFAULT_PRIORITY),
+            level: AtomicUsize::new(DEFAULT_PRIORITY),
+            state: AtomicUsize::new(ThreadState::Ready as usize),
+            runtime: AtomicUsize::new(0),
+            context_switches: AtomicUsize::new(0),
+            on_cpu: AtomicBool::new(false),
+            killed: AtomicBool::new(false),
         };
         thread.prepare_kernel_stack();
-        return Rc::new(thread);
+        return Arc::new(thread);
+    }
+
+    ///
+    /// Description: Create a copy of the user thread `parent`, which is currently executing the fork system call.
+    ///              The new thread returns from the system call with the same registers and user stack (but return value 0).
+    ///              Not started yet, nor registered in the scheduler.
+    ///
+    /// Parameters: \
+    ///   `process` process the thread belongs to (the forked copy of `parent`'s process). \
+    ///   `parent` thread to copy
+    ///
+    pub fn new_forked_thread(process: Arc<Process>, parent: &Thread) -> Arc<Thread> {
+        let kernel_stack = Vec::<u64, StackAllocator>::with_capacity_in(
+            (KERNEL_STACK_PAGES * PAGE_SIZE) / 8,
+            StackAllocator::new(),
+        );
+
+        // The user stack lies at the same virtual address in the forked address space
+        let user_stack = {
+            let parent_stacks = parent.stacks.lock();
+            unsafe {
+                Vec::from_raw_parts_in(
+                    parent_stacks.user_stack.as_ptr().cast_mut(),
+                    0,
+                    parent_stacks.user_stack.capacity(),
+                    StackAllocator::new(),
+                )
+            }
+        };
+
+        // The system call handler has stored the user registers on top of the parent's kernel stack
+        let fork_frame = unsafe { (parent.kernel_stack_addr().as_u64() as *const SyscallFrame).sub(1).read() };
+
+        let thread = Thread {
+            id: scheduler::next_thread_id(),
+            stacks: Mutex::new(Stacks::new(kernel_stack, user_stack)),
+            process,
+            entry: unsafe { mem::transmute(ptr::null::<fn()>()) },
+            user_rip: VirtAddr::new(fork_frame.rcx),
+            fork_frame: Some(fork_frame),
+            priority: AtomicUsize::new(parent.priority()),
+            level: AtomicUsize::new(parent.priority()),
+            state: AtomicUsize::new(ThreadState::Ready as usize),
+            runtime: AtomicUsize::new(0),
+            context_switches: AtomicUsize::new(0),
+            on_cpu: AtomicBool::new(false),
+            killed: AtomicBool::new(false),
+        };
+
+        thread.prepare_kernel_stack();
+        return Arc::new(thread);
     }
 
     /// Description: Called first for both a new kernel and a new user thread
@@ -260,7 +397,7 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
         scheduler.set_init(); // scheduler initialized
 
         let thread = scheduler.current_thread();
-        tss().lock().privilege_stack_table[0] = thread.kernel_stack_addr(); // get stack pointer for kernel stack
+        syscall_dispatcher::set_kernel_stack(thread.kernel_stack_addr()); // set stack pointer for kernel stack in the tss of this processor
 
         if thread.is_kernel_thread() {
             (thread.entry)();  // Directly call the entry function of kernel thread
@@ -271,7 +408,10 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
             drop(thread); // Manually decrease reference count, because switch_to_user_mode() will never return
 
             let thread_ref = unsafe { thread_ptr.as_ref().unwrap() };
-            thread_ref.switch_to_user_mode(); // call entry function of user thread
+            match thread_ref.fork_frame {
+                Some(frame) => thread_ref.switch_to_forked_user_mode(&frame), // return from fork system call
+                None => thread_ref.switch_to_user_mode(), // call entry function of user thread
+            }
             // exit is in the entry function -> runtime::lib.rs
         }
     }
@@ -346,6 +486,96 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
         VirtAddr::new(stacks.user_stack.as_ptr() as u64)
     }
 
+    /// Description: Return base priority of the thread (0 = highest, PRIORITY_LEVELS - 1 = lowest)
+    pub fn priority(&self) -> usize {
+        self.priority.load(Relaxed)
+    }
+
+    /// Description: Set base priority of the thread and move it to the corresponding level of the ready queue
+    pub fn set_priority(&self, priority: usize) {
+        let priority = priority.min(PRIORITY_LEVELS - 1);
+        self.priority.store(priority, Relaxed);
+        self.level.store(priority, Relaxed);
+    }
+
+    /// Description: Return current level of the thread in the multi-level feedback queue
+    pub fn level(&self) -> usize {
+        self.level.load(Relaxed)
+    }
+
+    /// Description: Move thread one level down (after it has used up its whole time slice)
+    pub fn demote(&self) {
+        self.level.store((self.level() + 1).min(PRIORITY_LEVELS - 1), Relaxed);
+    }
+
+    /// Description: Move thread one level up (after it has waited long in the ready queue), but not above its base priority
+    pub fn promote(&self) {
+        self.level.store(self.level().saturating_sub(1).max(self.priority()), Relaxed);
+    }
+
+    /// Description: Return scheduling state of the thread
+    pub fn state(&self) -> ThreadState {
+        match self.state.load(Relaxed) {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Sleeping,
+            3 => ThreadState::Blocked,
+            _ => ThreadState::Joining
+        }
+    }
+
+    /// Description: Set scheduling state of the thread (only called by the scheduler)
+    pub fn set_state(&self, state: ThreadState) {
+        self.state.store(state as usize, Relaxed);
+    }
+
+    /// Description: Check if the thread runs on a processor (or is still being switched away from)
+    pub fn is_on_cpu(&self) -> bool {
+        self.on_cpu.load(Acquire)
+    }
+
+    /// Description: Mark the thread as running on a processor; cleared by the scheduler after its context has been saved
+    pub fn set_on_cpu(&self, on_cpu: bool) {
+        self.on_cpu.store(on_cpu, Release);
+    }
+
+    /// Description: Check if the thread has been killed
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Relaxed)
+    }
+
+    /// Description: Mark the thread as killed, so that the scheduler drops it instead of running it again
+    pub fn kill(&self) {
+        self.killed.store(true, Relaxed);
+    }
+
+    /// Description: Return the accumulated cpu time of the thread in nanoseconds
+    pub fn runtime(&self) -> usize {
+        self.runtime.load(Relaxed)
+    }
+
+    /// Description: Add `ns` nanoseconds to the cpu time of the thread
+    pub fn account(&self, ns: usize) {
+        self.runtime.fetch_add(ns, Relaxed);
+    }
+
+    /// Description: Count a context switch to the thread
+    pub fn count_context_switch(&self) {
+        self.context_switches.fetch_add(1, Relaxed);
+    }
+
+    /// Description: Return scheduling information about the thread (for the `ProcessInfo` system call)
+    pub fn info(&self) -> ThreadInfo {
+        ThreadInfo {
+            thread_id: self.id,
+            process_id: self.process.id(),
+            state: self.state(),
+            priority: self.priority(),
+            runtime_ms: self.runtime.load(Relaxed) / 1000000,
+            context_switches: self.context_switches.load(Relaxed),
+        }
+    }
+
     /// Description: Return reference to my process
     pub fn process(&self) -> Arc<Process> {
         return Arc::clone(&self.process);
@@ -417,9 +647,11 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
             let capacity = stacks.kernel_stack.capacity();
 
             // init stack with 0s
-            for _ in 0..stacks.user_stack.capacity() {
-                stacks.user_stack.push(0);
-            }
+            with_user_access(|| {
+                for _ in 0..stacks.user_stack.capacity() {
+                    stacks.user_stack.push(0);
+                }
+            });
 
             stacks.kernel_stack[capacity - 6] = self.user_rip.as_u64(); // Address of entry point for user thread
 
@@ -439,6 +671,40 @@ pub fn load_application(elf_buffer: &[u8]) -> Rc<Thread> {
             thread_user_start(old_rsp0, self.entry);
         }
     }
+
+    /// Description: switch a forked thread to user mode by preparing a fake stackframe with the registers of its parent
+    fn switch_to_forked_user_mode(&self, frame: &SyscallFrame) {
+        let old_rsp0: u64;
+
+        {
+            // Separate block to make sure that the lock is released, before calling `thread_fork_start()`.
+            let mut stacks = self.stacks.lock();
+            let kernel_stack_addr = stacks.kernel_stack.as_ptr() as u64;
+            let capacity = stacks.kernel_stack.capacity();
+
+            stacks.kernel_stack[capacity - 6] = frame.rcx; // rip after syscall instruction
+
+            stacks.kernel_stack[capacity - 5] = SegmentSelector::new(4, Ring3).0 as u64; // cs = user code segment
+            stacks.kernel_stack[capacity - 4] = frame.r11; // rflags at syscall instruction
+            stacks.kernel_stack[capacity - 3] = frame.user_rsp; // rsp for user stack
+            stacks.kernel_stack[capacity - 2] = SegmentSelector::new(3, Ring3).0 as u64; // ss = user data segment
+
+            stacks.kernel_stack[capacity - 1] = 0x00DEAD00u64; // Dummy return address
+
+            // General purpose registers, popped by `thread_fork_start()`
+            let registers = [frame.rbp, frame.r15, frame.r14, frame.r13, frame.r12, frame.r11, frame.r10, frame.r9, frame.r8,
+                frame.rsi, frame.rdi, frame.rdx, frame.rcx, frame.rbx];
+            let registers_start = capacity - 6 - registers.len();
+            stacks.kernel_stack[registers_start..capacity - 6].copy_from_slice(&registers);
+
+            stacks.old_rsp0 = VirtAddr::new(kernel_stack_addr + (registers_start * 8) as u64);
+            old_rsp0 = stacks.old_rsp0.as_u64();
+        }
+
+        unsafe {
+            thread_fork_start(old_rsp0);
+        }
+    }
 }
 
 /// Description: Low-level function for starting a thread in kernel mode
@@ -482,6 +748,32 @@ unsafe extern "C" fn thread_user_start(old_rsp0: u64, entry: fn()) {
     )
 }
 
+/// Description: Low-level function for starting a forked thread in user mode (returning 0 from the fork system call)
+#[naked]
+#[allow(unsafe_op_in_unsafe_fn)]
+unsafe extern "C" fn thread_fork_start(old_rsp0: u64) {
+    asm!(
+        "mov rsp, rdi", // Load 'old_rsp' (first parameter)
+        "pop rbp",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rsi",
+        "pop rdi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "xor rax, rax", // Return value of fork in the child
+        "iretq",        // Switch to user-mode
+        options(noreturn)
+    )
+}
+
 /// Description: Low-level thread switching function
 #[naked]
 #[allow(unsafe_op_in_unsafe_fn)]
@@ -0,0 +1,308 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: smp                                                             ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Startup of application processors. Each processor is started    ║
+   ║         via INIT-SIPI-SIPI and runs the trampoline code in 'boot.asm',  ║
+   ║         which switches to long mode and calls 'ap_entry()'. There, the  ║
+   ║         processor gets its own GDT, TSS and core local storage, before  ║
+   ║         it starts scheduling its own ready queue. Processors notify     ║
+   ║         each other via IPIs to reschedule and to flush their TLBs.      ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::boxed::Box;
+use core::hint::spin_loop;
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use spin::Mutex;
+use log::{info, warn};
+use raw_cpuid::CpuId;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::tlb;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::registers::control::{Cr0, Cr4};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::registers::segmentation::SegmentSelector;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::PrivilegeLevel::Ring0;
+use crate::{apic, idt, interrupt_dispatcher, memory, process_manager, scheduler, timer};
+use crate::consts::{KERNEL_STACK_PAGES, MAX_CPUS};
+use crate::interrupt::interrupt_dispatcher::InterruptVector;
+use crate::interrupt::interrupt_handler::InterruptHandler;
+use crate::process::thread::Thread;
+use crate::memory::MemorySpace;
+use crate::syscall::syscall_dispatcher;
+use crate::syscall::syscall_dispatcher::CoreLocalStorage;
+
+// Physical address, the trampoline code is copied to (must be page aligned and below 1 MiB)
+const TRAMPOLINE_ADDRESS: u64 = 0x8000;
+
+// Time to wait for an application processor to come online
+const STARTUP_TIMEOUT_MS: usize = 100;
+
+// import labels from 'boot.asm'
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_data: u8;
+    static smp_trampoline_end: u8;
+}
+
+/// Parameters for the trampoline code (layout must match the offsets in 'boot.asm')
+#[repr(C)]
+struct TrampolineData {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack: u64,
+    entry: u64,
+    cpu_id: u64,
+}
+
+// Flushing single pages is only worth it for small ranges
+const MAX_FLUSH_PAGES: usize = 32;
+
+// bit mask of running processors (bit 0 = bootstrap processor)
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(1);
+
+// application processors wait for this, before they start scheduling
+static SCHEDULING_STARTED: AtomicBool = AtomicBool::new(false);
+
+// TLB shootdowns are numbered and each processor remembers the last one it has flushed its TLB for
+static TLB_SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+static TLB_SHOOTDOWN_GENERATION: AtomicU64 = AtomicU64::new(0);
+static TLB_FLUSHED_GENERATION: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+pub fn online_processors() -> usize {
+    ONLINE_CPUS.load(Relaxed).count_ones() as usize
+}
+
+/// Index of the calling processor (0 = bootstrap processor, application processors are numbered in the order of the MADT).
+/// Must be called with interrupts disabled, if the result is used to access per-processor data.
+pub fn current_cpu() -> usize {
+    if online_processors() == 1 {
+        return 0;
+    }
+
+    let apic_id = CpuId::new().get_feature_info().expect("Failed to read CPU features").initial_local_apic_id() as u32;
+    apic().cpu_index(apic_id)
+}
+
+/// Let the application processors start scheduling. Called by the bootstrap processor, right before it starts scheduling itself.
+pub fn release_application_processors() {
+    SCHEDULING_STARTED.store(true, Release);
+}
+
+/// Flush the given pages from the TLBs of all processors. Must be called after changing or removing page table entries
+/// (without holding the page table locks, since other processors might need them before acknowledging the shootdown).
+pub fn flush_tlb(pages: PageRange) {
+    if pages.count() > MAX_FLUSH_PAGES {
+        tlb::flush_all();
+    } else {
+        pages.for_each(|page| tlb::flush(page.start_address()));
+    }
+
+    if online_processors() == 1 {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        // Shootdowns of other processors are acknowledged while waiting, since they might be waiting for this processor as well
+        let _lock = loop {
+            match TLB_SHOOTDOWN_LOCK.try_lock() {
+                Some(lock) => break lock,
+                None => acknowledge_tlb_shootdown()
+            }
+        };
+
+        let cpu = current_cpu();
+        let generation = TLB_SHOOTDOWN_GENERATION.fetch_add(1, Release) + 1;
+        TLB_FLUSHED_GENERATION[cpu].store(generation, Release);
+        apic().broadcast_ipi(InterruptVector::TlbShootdown);
+
+        let online = ONLINE_CPUS.load(Acquire) & !(1 << cpu);
+        while (0..MAX_CPUS).any(|other| online & (1 << other) != 0 && TLB_FLUSHED_GENERATION[other].load(Acquire) < generation) {
+            spin_loop();
+        }
+    });
+}
+
+/// Flush the TLB of the calling processor, if another processor has requested a shootdown since the last flush.
+pub fn acknowledge_tlb_shootdown() {
+    if online_processors() == 1 {
+        return;
+    }
+
+    let cpu = current_cpu();
+    let generation = TLB_SHOOTDOWN_GENERATION.load(Acquire);
+    if TLB_FLUSHED_GENERATION[cpu].load(Relaxed) < generation {
+        tlb::flush_all();
+        TLB_FLUSHED_GENERATION[cpu].store(generation, Release);
+    }
+}
+
+// handler for the reschedule IPI, which is sent, when a thread has been put on or killed on another processor
+struct RescheduleHandler;
+
+impl InterruptHandler for RescheduleHandler {
+    fn trigger(&mut self) {
+        scheduler().reschedule_from_interrupt();
+    }
+}
+
+// handler for the TLB shootdown IPI
+struct TlbShootdownHandler;
+
+impl InterruptHandler for TlbShootdownHandler {
+    fn trigger(&mut self) {
+        acknowledge_tlb_shootdown();
+    }
+}
+
+/// Reserve the page frame for the trampoline code, so that it is not handed out by the page frame allocator.
+/// Must be called before the first allocation.
+pub fn reserve_trampoline() {
+    let start = PhysFrame::from_start_address(PhysAddr::new(TRAMPOLINE_ADDRESS)).unwrap();
+    unsafe { memory::physical::reserve(PhysFrameRange { start, end: start + 1 }); }
+}
+
+/// Start all application processors described by the MADT, one after another.
+/// Needs a running timer and interrupts enabled.
+pub fn start_application_processors() {
+    let mut apic_ids = apic().application_processor_ids();
+    if apic_ids.is_empty() {
+        return;
+    }
+
+    if apic_ids.len() >= MAX_CPUS {
+        warn!("Only [{}] of [{}] processors are supported", MAX_CPUS, apic_ids.len() + 1);
+        apic_ids = &apic_ids[..MAX_CPUS - 1];
+    }
+
+    let address_space = process_manager().read().kernel_process().expect("Trying to start application processors before process initialization!").address_space();
+    let page_table = address_space.page_table_address();
+    if page_table.as_u64() > u32::MAX as u64 {
+        warn!("Kernel page table is located above 4 GiB -> Not starting application processors");
+        return;
+    }
+
+    // The trampoline code runs at its physical address, until it calls 'ap_entry()' in the higher half.
+    // Hence, it needs to be identity mapped in the kernel address space while processors are starting.
+    let trampoline_frame = PhysFrame::from_start_address(PhysAddr::new(TRAMPOLINE_ADDRESS)).unwrap();
+    let trampoline_page = Page::from_start_address(VirtAddr::new(TRAMPOLINE_ADDRESS)).unwrap();
+    let trampoline_pages = PageRange { start: trampoline_page, end: trampoline_page + 1 };
+    address_space.map_physical(PhysFrameRange { start: trampoline_frame, end: trampoline_frame + 1 }, trampoline_pages, MemorySpace::Kernel, PageTableFlags::PRESENT);
+
+    let data = unsafe {
+        let start = ptr::from_ref(&smp_trampoline_start);
+        let size = ptr::from_ref(&smp_trampoline_end) as usize - start as usize;
+        let data_offset = ptr::from_ref(&smp_trampoline_data) as usize - start as usize;
+        let trampoline = memory::phys_to_virt(trampoline_frame.start_address());
+
+        trampoline.as_mut_ptr::<u8>().copy_from(start, size);
+        (trampoline + data_offset as u64).as_mut_ptr::<TrampolineData>()
+    };
+
+    interrupt_dispatcher().assign(InterruptVector::Reschedule, Box::new(RescheduleHandler));
+    interrupt_dispatcher().assign(InterruptVector::TlbShootdown, Box::new(TlbShootdownHandler));
+
+    let mut all_online = true;
+
+    for (index, &apic_id) in apic_ids.iter().enumerate() {
+        let cpu_id = index + 1;
+        let stack = memory::physical::alloc(KERNEL_STACK_PAGES);
+        let online = online_processors();
+
+        unsafe {
+            data.write_volatile(TrampolineData {
+                cr0: Cr0::read_raw(),
+                cr3: page_table.as_u64(),
+                cr4: Cr4::read_raw(),
+                efer: Efer::read_raw() & (EferFlags::LONG_MODE_ENABLE | EferFlags::NO_EXECUTE_ENABLE).bits(),
+                stack: memory::phys_to_virt(stack.end.start_address()).as_u64(),
+                entry: ap_entry as *const () as u64,
+                cpu_id: cpu_id as u64,
+            });
+        }
+
+        apic().start_application_processor(apic_id, PhysAddr::new(TRAMPOLINE_ADDRESS));
+
+        let timeout = timer().read().systime_ms() + STARTUP_TIMEOUT_MS;
+        while online_processors() == online && timer().read().systime_ms() < timeout {
+            spin_loop();
+        }
+
+        if online_processors() == online {
+            // The stack is not freed, since the processor might still come up later
+            warn!("CPU [{}] (APIC ID [{}]) did not start", cpu_id, apic_id);
+            all_online = false;
+        }
+    }
+
+    // Keep the trampoline mapped, if a processor might still come up later
+    if all_online {
+        address_space.unmap(trampoline_pages, false);
+    }
+
+    info!("[{}] processors online", online_processors());
+}
+
+/// Called by the trampoline code on each application processor (with interrupts disabled)
+extern "C" fn ap_entry(cpu_id: u64) -> ! {
+    // Setup own GDT and TSS (same layout as the GDT of the bootstrap processor)
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(TaskStateSegment::new()));
+    let gdt: &'static mut GlobalDescriptorTable = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    gdt.append(Descriptor::kernel_code_segment());
+    gdt.append(Descriptor::kernel_data_segment());
+    gdt.append(Descriptor::user_data_segment());
+    gdt.append(Descriptor::user_code_segment());
+    gdt.append(Descriptor::tss_segment(tss));
+
+    let gdt: &'static GlobalDescriptorTable = gdt;
+    gdt.load();
+
+    unsafe {
+        load_tss(SegmentSelector::new(5, Ring0));
+        CS::set_reg(SegmentSelector::new(1, Ring0));
+        SS::set_reg(SegmentSelector::new(2, Ring0));
+        DS::set_reg(SegmentSelector::new(0, Ring0));
+        ES::set_reg(SegmentSelector::new(0, Ring0));
+        FS::set_reg(SegmentSelector::new(0, Ring0));
+        GS::set_reg(SegmentSelector::new(0, Ring0));
+    }
+
+    // The IDT is shared by all processors
+    unsafe { ptr::from_ref(idt().lock().deref()).as_ref().unwrap().load(); }
+
+    syscall_dispatcher::init_application_processor(Box::leak(Box::new(CoreLocalStorage::new())), tss);
+    apic().enable_local_apic();
+
+    // The processor must be marked online first, since 'current_cpu()' only looks up the APIC ID with multiple processors online
+    ONLINE_CPUS.fetch_or(1 << cpu_id, Release);
+    scheduler().set_idle_thread(Thread::new_kernel_thread(|| {
+        loop {
+            scheduler().idle();
+        }
+    }));
+
+    info!("CPU [{}] is online", cpu_id);
+
+    // TLB shootdowns are already broadcast to this processor, while it waits for the bootstrap processor to start scheduling
+    while !SCHEDULING_STARTED.load(Acquire) {
+        acknowledge_tlb_shootdown();
+        spin_loop();
+    }
+
+    apic().start_local_timer();
+    scheduler().start();
+
+    panic!("CPU [{}] returned from scheduler!", cpu_id);
+}
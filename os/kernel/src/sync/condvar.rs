@@ -0,0 +1,55 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: condvar                                                         ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Condition variable for use with the blocking 'Mutex'. 'wait()'  ║
+   ║         releases the mutex and blocks the current thread atomically, so ║
+   ║         that a notification in between cannot get lost.                 ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use crate::scheduler;
+use crate::sync::mutex::MutexGuard;
+use crate::sync::wait_queue::WaitQueue;
+use core::mem;
+
+pub struct CondVar {
+    waiters: WaitQueue
+}
+
+impl CondVar {
+    pub const fn new() -> Self {
+        Self { waiters: WaitQueue::new() }
+    }
+
+    /// Release the mutex belonging to `guard` and block the current thread, until it is notified.
+    /// The mutex is acquired again before returning. As with all condition variables, spurious wakeups are possible,
+    /// so the caller should check its condition in a loop (or use `wait_while()`).
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        mem::forget(guard);
+
+        // The mutex is released after the thread has been enqueued, so that no other thread can notify us in between
+        scheduler().block_if(&self.waiters, || {
+            mutex.unlock();
+            true
+        });
+
+        mutex.lock()
+    }
+
+    /// Block the current thread, as long as `condition` returns true for the data protected by the mutex.
+    pub fn wait_while<'a, T>(&self, mut guard: MutexGuard<'a, T>, mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
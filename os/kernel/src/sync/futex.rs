@@ -0,0 +1,70 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: futex                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Fast user space mutex support. User threads can block on a      ║
+   ║         32-bit value in their address space ('wait()'), as long as it   ║
+   ║         has an expected value, and wake up threads blocked on it        ║
+   ║         ('wake()'). Wait queues are created on demand and removed       ║
+   ║         again, once they are no longer used.                            ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering::Acquire;
+use x86_64::VirtAddr;
+use crate::{futex_table, process_manager};
+use crate::memory::r#virtual::with_user_access;
+use crate::sync::wait_queue::WaitQueue;
+
+pub struct FutexTable {
+    queues: BTreeMap<(usize, u64), Arc<WaitQueue>> // key is (process id, virtual address)
+}
+
+impl FutexTable {
+    pub const fn new() -> Self {
+        Self { queues: BTreeMap::new() }
+    }
+
+    fn queue(&mut self, key: (usize, u64)) -> Arc<WaitQueue> {
+        Arc::clone(self.queues.entry(key).or_insert_with(|| Arc::new(WaitQueue::new())))
+    }
+
+    /// Remove all wait queues of an exited process.
+    pub fn remove_process(&mut self, process_id: usize) {
+        self.queues.retain(|key, _| key.0 != process_id);
+    }
+}
+
+/// Block the current thread, if the value at `address` equals `expected`, until it is woken up via `wake()`.
+/// Returns false, if the value did not match. As with all futexes, the caller must check the value again after returning.
+/// The address must lie in a memory area of the current process (see `sys_futex_wait()`).
+pub fn wait(address: VirtAddr, expected: u32) -> bool {
+    let key = (process_manager().read().current_process().id(), address.as_u64());
+    let queue = futex_table().lock().queue(key);
+    let value = unsafe { address.as_ptr::<AtomicU32>().as_ref().expect("Futex: Invalid address!") };
+
+    // Access the value once, before the scheduler is locked, so that a page fault cannot occur while blocking
+    with_user_access(|| value.load(Acquire));
+
+    queue.block_if(|| with_user_access(|| value.load(Acquire)) == expected)
+}
+
+/// Wake up to `count` threads blocked on `address` and return how many have been woken up.
+pub fn wake(address: VirtAddr, count: usize) -> usize {
+    let key = (process_manager().read().current_process().id(), address.as_u64());
+    let mut table = futex_table().lock();
+    let queue = match table.queues.get(&key) {
+        Some(queue) => Arc::clone(queue),
+        None => return 0
+    };
+
+    let woken = (0..count).take_while(|_| queue.wake_one()).count();
+
+    // Only remove the queue, if no thread is about to block on it (these threads hold a reference)
+    if queue.is_empty() && Arc::strong_count(&queue) == 2 {
+        table.queues.remove(&key);
+    }
+
+    return woken;
+}
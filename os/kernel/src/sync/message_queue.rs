@@ -0,0 +1,86 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: message_queue                                                   ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Named message queues for communication between processes. Each  ║
+   ║         queue holds up to 'capacity' messages of at most                ║
+   ║         'MESSAGE_QUEUE_MESSAGE_SIZE' bytes. Sending to a full queue and ║
+   ║         receiving from an empty queue blocks the calling thread.        ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use syscall::MESSAGE_QUEUE_MESSAGE_SIZE;
+use crate::sync::condvar::CondVar;
+use crate::sync::mutex::Mutex;
+
+pub struct MessageQueue {
+    name: String,
+    capacity: usize,
+    messages: Mutex<VecDeque<Vec<u8>>>,
+    not_empty: CondVar,
+    not_full: CondVar
+}
+
+/// Registry of all named message queues.
+/// Only weak references are stored, so that a queue vanishes as soon as no process has opened it anymore.
+pub struct MessageQueueManager {
+    queues: BTreeMap<String, Weak<MessageQueue>>
+}
+
+impl MessageQueue {
+    fn new(name: &str, capacity: usize) -> Self {
+        Self { name: name.to_string(), capacity, messages: Mutex::new(VecDeque::new()), not_empty: CondVar::new(), not_full: CondVar::new() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Append a message to the queue, blocking while the queue is full.
+    pub fn send(&self, message: Vec<u8>) {
+        assert!(message.len() <= MESSAGE_QUEUE_MESSAGE_SIZE, "MessageQueue: Message is too large!");
+
+        let mut messages = self.not_full.wait_while(self.messages.lock(), |messages| messages.len() >= self.capacity);
+        messages.push_back(message);
+        self.not_empty.notify_one();
+    }
+
+    /// Take the oldest message from the queue, blocking while the queue is empty.
+    pub fn receive(&self) -> Vec<u8> {
+        let mut messages = self.not_empty.wait_while(self.messages.lock(), |messages| messages.is_empty());
+        let message = messages.pop_front().expect("MessageQueue: Queue is empty after waiting!");
+        self.not_full.notify_one();
+
+        return message;
+    }
+}
+
+impl MessageQueueManager {
+    pub const fn new() -> Self {
+        Self { queues: BTreeMap::new() }
+    }
+
+    /// Open the message queue called `name`. If it does not exist yet, it is created with room for `capacity` messages.
+    /// The capacity of an existing queue is not changed.
+    pub fn open(&mut self, name: &str, capacity: usize) -> Option<Arc<MessageQueue>> {
+        self.queues.retain(|_, queue| queue.strong_count() > 0);
+
+        if let Some(queue) = self.queues.get(name).and_then(|queue| queue.upgrade()) {
+            return Some(queue);
+        }
+
+        if capacity == 0 {
+            return None;
+        }
+
+        let queue = Arc::new(MessageQueue::new(name, capacity));
+        self.queues.insert(name.to_string(), Arc::downgrade(&queue));
+        return Some(queue);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod wait_queue;
+pub mod mutex;
+pub mod semaphore;
+pub mod condvar;
+pub mod futex;
+pub mod message_queue;
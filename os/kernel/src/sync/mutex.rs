@@ -0,0 +1,86 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: mutex                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Blocking mutual exclusion lock. In contrast to 'spin::Mutex',   ║
+   ║         threads waiting for the lock are blocked in a wait queue        ║
+   ║         instead of spinning. Must not be used in interrupt handlers.    ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::wait_queue::WaitQueue;
+
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self { locked: AtomicBool::new(false), waiters: WaitQueue::new(), data: UnsafeCell::new(data) }
+    }
+
+    /// Acquire the lock, blocking the current thread until it is available.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            self.waiters.wait_while(|| self.locked.load(Relaxed));
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        match self.locked.compare_exchange(false, true, Acquire, Relaxed) {
+            Ok(_) => Some(MutexGuard { mutex: self }),
+            Err(_) => None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Relaxed)
+    }
+
+    // Release the lock without a guard (used by 'CondVar', which consumes the guard while waiting)
+    pub(super) fn unlock(&self) {
+        self.locked.store(false, Release);
+        self.waiters.wake_one();
+    }
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,47 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: semaphore                                                       ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Counting semaphore. Threads calling 'acquire()' are blocked     ║
+   ║         in a wait queue, while the counter is zero. 'release()' may be  ║
+   ║         called from interrupt handlers.                                 ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::{Acquire, Relaxed};
+use crate::sync::wait_queue::WaitQueue;
+
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self { count: AtomicUsize::new(count), waiters: WaitQueue::new() }
+    }
+
+    /// Decrement the counter, blocking the current thread while it is zero.
+    pub fn acquire(&self) {
+        loop {
+            if self.try_acquire() {
+                return;
+            }
+
+            self.waiters.wait_while(|| self.count.load(Relaxed) == 0);
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count.fetch_update(Acquire, Relaxed, |count| count.checked_sub(1)).is_ok()
+    }
+
+    /// Increment the counter and wake up one waiting thread.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Relaxed);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Relaxed)
+    }
+}
@@ -0,0 +1,82 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: wait_queue                                                      ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Queue of threads waiting for an event (e.g. a lock being        ║
+   ║         released or an interrupt arriving). Waiting threads are blocked ║
+   ║         in the scheduler and do not consume any cpu time, until they    ║
+   ║         are woken up again via 'wake_one()' or 'wake_all()'.            ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::scheduler;
+
+pub struct WaitQueue {
+    threads: Mutex<VecDeque<usize>> // ids of the waiting threads
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { threads: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Block the current thread, until it is woken up by `wake_one()` or `wake_all()`.
+    pub fn block(&self) {
+        self.block_if(|| true);
+    }
+
+    /// Block the current thread once, if `condition` returns true (checked with interrupts disabled,
+    /// after the thread has been enqueued). Returns false, if the thread has not been blocked.
+    pub fn block_if(&self, condition: impl FnOnce() -> bool) -> bool {
+        scheduler().block_if(self, condition)
+    }
+
+    /// Block the current thread, as long as `condition` returns true.
+    /// The condition is checked again each time the thread is woken up. Since it is checked with interrupts disabled
+    /// and after the thread has been enqueued, a wakeup from an interrupt handler or another processor cannot get lost
+    /// between checking the condition and blocking.
+    pub fn wait_while(&self, mut condition: impl FnMut() -> bool) {
+        while scheduler().block_if(self, &mut condition) {}
+    }
+
+    /// Wake up the thread, that has been waiting the longest. Returns false, if no thread is waiting.
+    /// May be called from interrupt handlers.
+    pub fn wake_one(&self) -> bool {
+        loop {
+            match self.dequeue() {
+                // Threads that have been killed while waiting are skipped
+                Some(thread_id) => if scheduler().wake_up(thread_id) {
+                    return true;
+                },
+                None => return false
+            }
+        }
+    }
+
+    /// Wake up all waiting threads and return how many have been woken up.
+    /// May be called from interrupt handlers.
+    pub fn wake_all(&self) -> usize {
+        // Only threads waiting right now are woken up, not the ones enqueueing themselves again in the meantime
+        let waiting = interrupts::without_interrupts(|| self.threads.lock().len());
+        (0..waiting).map_while(|_| self.dequeue()).filter(|&thread_id| scheduler().wake_up(thread_id)).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.threads.lock().is_empty())
+    }
+
+    // The queue is locked with interrupts disabled only, so that interrupt handlers can always acquire it
+    pub(crate) fn enqueue(&self, thread_id: usize) {
+        interrupts::without_interrupts(|| self.threads.lock().push_back(thread_id));
+    }
+
+    // Undo `enqueue()` for a thread, that has not been blocked after all
+    pub(crate) fn remove(&self, thread_id: usize) {
+        interrupts::without_interrupts(|| self.threads.lock().retain(|&id| id != thread_id));
+    }
+
+    fn dequeue(&self) -> Option<usize> {
+        interrupts::without_interrupts(|| self.threads.lock().pop_front())
+    }
+}
@@ -1,24 +1,40 @@
 use alloc::format;
-use alloc::rc::Rc;
-use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
 use core::ptr;
-use core::ptr::slice_from_raw_parts;
-use core::str::from_utf8;
 use chrono::{Datelike, DateTime, TimeDelta, Timelike};
+use log::warn;
+use syscall::{BlockDeviceInfo, KeyEvent, KeyboardLayout, MountInfo, MouseEvent, PciDeviceInfo, ProcessInfo, ThreadInfo, BLOCK_DEVICE_NAME_LENGTH, BLOCK_SECTOR_SIZE, FILE_SYSTEM_NAME_LENGTH, MESSAGE_QUEUE_MESSAGE_SIZE, MOUNT_NAME_LENGTH};
 use uefi::table::runtime::{Time, TimeParams};
 use x86_64::structures::paging::PageTableFlags;
 use x86_64::VirtAddr;
-use crate::{e1000_device, efi_system_table, initrd, process_manager, scheduler, terminal, timer};
-use crate::memory::{MemorySpace, PAGE_SIZE};
-use crate::memory::r#virtual::{VirtualMemoryArea, VmaType};
+use crate::{device_manager, efi_system_table, initrd, message_queue_manager, pci_bus, process_manager, ps2_devices, scheduler, shared_memory_manager, terminal, timer};
+use crate::consts::USER_HEAP_RANDOM_PAGES;
+use crate::memory::{aslr, MemorySpace, PAGE_SIZE};
+use crate::memory::r#virtual::{copy_from_user, copy_to_user, is_user_range, with_user_access, VirtualMemoryArea, VmaType};
 use crate::process::thread::Thread;
-
-use super::device::e1000_interface::{NetworkProtocol, transmit, receive_data};
-use super::device::e1000_descriptor::RxBufferPacket;
+use crate::sync::futex;
+use crate::filesystem;
 
 pub mod syscall_dispatcher;
 
+/// Copy a string from user space into the kernel.
+/// Returns `None`, if the buffer does not lie in user space, or does not contain valid UTF-8.
+fn copy_user_string(buffer: *const u8, length: usize) -> Option<String> {
+    let mut bytes = Vec::new();
+    bytes.try_reserve_exact(length).ok()?;
+    bytes.resize(length, 0);
+
+    if !copy_from_user(&mut bytes, buffer) {
+        return None;
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
 #[no_mangle]
 pub extern "C" fn sys_read() -> usize {
     let terminal = terminal();
@@ -30,20 +46,22 @@ pub extern "C" fn sys_read() -> usize {
 
 #[no_mangle]
 pub extern "C" fn sys_write(buffer: *const u8, length: usize) {
-    let string = from_utf8(unsafe { slice_from_raw_parts(buffer, length).as_ref().unwrap() }).unwrap();
+    let Some(string) = copy_user_string(buffer, length) else {
+        return;
+    };
+
     let terminal = terminal();
-    terminal.write_str(string);
+    terminal.write_str(&string);
 }
 
 #[no_mangle]
 pub extern "C" fn sys_map_user_heap(size: usize) -> usize {
     let process = process_manager().read().current_process();
-    let code_areas = process.find_vmas(VmaType::Code);
-    let code_area = code_areas.get(0).expect("Process does not have code area!");
-    let heap_start = code_area.end().align_up(PAGE_SIZE as u64);
+    let image_end = process.image_end().expect("Process does not have code area!");
+    let heap_start = image_end.align_up(PAGE_SIZE as u64) + aslr::random_page_offset(USER_HEAP_RANDOM_PAGES);
     let heap_area = VirtualMemoryArea::from_address(heap_start, size, VmaType::Heap);
 
-    process.address_space().map(heap_area.range(), MemorySpace::User, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+    process.address_space().map(heap_area.range(), MemorySpace::User, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE);
     process.add_vma(heap_area);
 
     return heap_start.as_u64() as usize;
@@ -60,8 +78,326 @@ pub extern "C" fn sys_process_exit() {
     scheduler().exit();
 }
 
+#[no_mangle]
+pub extern "C" fn sys_process_fork() -> usize {
+    let parent = process_manager().read().current_process();
+    let child = process_manager().write().fork_process(&parent);
+    let thread = Thread::new_forked_thread(Arc::clone(&child), &scheduler().current_thread());
+
+    scheduler().ready(thread);
+    return child.id();
+}
+
+#[no_mangle]
+pub extern "C" fn sys_shared_memory_map(name_buffer: *const u8, name_length: usize, size: usize) -> usize {
+    let Some(name) = copy_user_string(name_buffer, name_length) else {
+        return 0;
+    };
+
+    match shared_memory_manager().lock().open(&name, size) {
+        Some(object) => process_manager().read().current_process().map_shared_memory(object).as_u64() as usize,
+        None => 0
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_shared_memory_unmap(address: usize) -> usize {
+    process_manager().read().current_process().unmap_shared_memory(VirtAddr::new(address as u64)) as usize
+}
+
+#[no_mangle]
+pub extern "C" fn sys_thread_get_priority(thread_id: usize) -> usize {
+    match scheduler().priority(thread_id) {
+        Some(priority) => priority,
+        None => usize::MAX
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_thread_set_priority(thread_id: usize, priority: usize) -> usize {
+    scheduler().set_priority(thread_id, priority) as usize
+}
+
+#[no_mangle]
+pub extern "C" fn sys_process_info(buffer: *mut ThreadInfo, capacity: usize) -> usize {
+    let infos = scheduler().thread_infos();
+    let count = infos.len().min(capacity);
+
+    if !copy_to_user(buffer, &infos[..count]) {
+        return 0;
+    }
+
+    return infos.len();
+}
+
+#[no_mangle]
+pub extern "C" fn sys_process_list(buffer: *mut ProcessInfo, capacity: usize) -> usize {
+    let infos = process_manager().read().process_infos();
+    let count = infos.len().min(capacity);
+
+    if !copy_to_user(buffer, &infos[..count]) {
+        return 0;
+    }
+
+    return infos.len();
+}
+
+/// Check the address of a futex passed by a user thread. It must be aligned, lie in user space
+/// and in a memory area of the current process, so that accessing it cannot fault.
+fn futex_address(address: usize) -> Option<VirtAddr> {
+    if address == 0 || address % 4 != 0 || !is_user_range(address, size_of::<u32>()) {
+        return None;
+    }
+
+    let address = VirtAddr::try_new(address as u64).ok()?;
+    process_manager().read().current_process().is_mapped(address, size_of::<u32>()).then_some(address)
+}
+
+/// Returns 0, if the value did not match or the address is invalid.
+#[no_mangle]
+pub extern "C" fn sys_futex_wait(address: usize, expected: usize) -> usize {
+    match futex_address(address) {
+        Some(address) => futex::wait(address, expected as u32) as usize,
+        None => 0
+    }
+}
+
+/// Returns the number of woken threads (0, if the address is invalid).
+#[no_mangle]
+pub extern "C" fn sys_futex_wake(address: usize, count: usize) -> usize {
+    match futex_address(address) {
+        Some(address) => futex::wake(address, count),
+        None => 0
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_message_queue_open(name_buffer: *const u8, name_length: usize, capacity: usize) -> usize {
+    let Some(name) = copy_user_string(name_buffer, name_length) else {
+        return usize::MAX;
+    };
+
+    match message_queue_manager().lock().open(&name, capacity) {
+        Some(queue) => process_manager().read().current_process().open_message_queue(queue),
+        None => usize::MAX
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_message_queue_close(handle: usize) -> usize {
+    process_manager().read().current_process().close_message_queue(handle) as usize
+}
+
+#[no_mangle]
+pub extern "C" fn sys_message_queue_send(handle: usize, buffer: *const u8, length: usize) -> usize {
+    // The process manager must not be locked while blocking, so the queue is looked up in a separate statement
+    let queue = process_manager().read().current_process().message_queue(handle);
+    match queue {
+        Some(queue) if length <= MESSAGE_QUEUE_MESSAGE_SIZE => {
+            let mut message = vec![0; length];
+            if !copy_from_user(&mut message, buffer) {
+                return false as usize;
+            }
+
+            queue.send(message);
+            true as usize
+        }
+        _ => false as usize
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_message_queue_receive(handle: usize, buffer: *mut u8, capacity: usize) -> usize {
+    let queue = process_manager().read().current_process().message_queue(handle);
+    match queue {
+        // The buffer is checked before receiving, so that no message is lost
+        Some(queue) if is_user_range(buffer as usize, capacity) => {
+            // Messages larger than the buffer are truncated
+            let message = queue.receive();
+            let length = message.len().min(capacity);
+            copy_to_user(buffer, &message[..length]);
+
+            length
+        }
+        _ => usize::MAX
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_get_system_time_ns() -> usize {
+    timer().read().systime_ns()
+}
+
+#[no_mangle]
+pub extern "C" fn sys_thread_sleep_until(wakeup_time_ns: usize) {
+    scheduler().sleep_until(wakeup_time_ns);
+}
+
+#[no_mangle]
+pub extern "C" fn sys_get_idle_time() -> usize {
+    scheduler().idle_time()
+}
+
+#[no_mangle]
+pub extern "C" fn sys_pci_list(buffer: *mut PciDeviceInfo, capacity: usize) -> usize {
+    let infos = pci_bus().device_infos();
+    let count = infos.len().min(capacity);
+
+    if !copy_to_user(buffer, &infos[..count]) {
+        return 0;
+    }
+
+    return infos.len();
+}
+
+#[no_mangle]
+pub extern "C" fn sys_block_device_list(buffer: *mut BlockDeviceInfo, capacity: usize) -> usize {
+    let infos = device_manager().block_devices().iter().map(|(name, disk)| {
+        let mut info = BlockDeviceInfo { sector_count: disk.sector_count(), read_only: disk.is_read_only(), ..Default::default() };
+        info.name_length = name.len().min(BLOCK_DEVICE_NAME_LENGTH);
+        info.name[..info.name_length].copy_from_slice(&name.as_bytes()[..info.name_length]);
+        info
+    }).collect::<Vec<BlockDeviceInfo>>();
+    let count = infos.len().min(capacity);
+
+    if !copy_to_user(buffer, &infos[..count]) {
+        return 0;
+    }
+
+    return infos.len();
+}
+
+/// Read a single sector of the disk with the given index (see `sys_block_device_list()`)
+#[no_mangle]
+pub extern "C" fn sys_block_device_read(index: usize, sector: usize, buffer: *mut u8) -> usize {
+    let Some((_, disk)) = device_manager().block_devices().into_iter().nth(index) else {
+        return 0;
+    };
+
+    let mut data = vec![0; BLOCK_SECTOR_SIZE];
+    match disk.read(sector as u64, &mut data) {
+        Ok(()) => copy_to_user(buffer, &data) as usize,
+        Err(_) => 0
+    }
+}
+
+/// Write a single sector of the disk with the given index and make sure, that it reaches the device
+#[no_mangle]
+pub extern "C" fn sys_block_device_write(index: usize, sector: usize, buffer: *const u8) -> usize {
+    let Some((_, disk)) = device_manager().block_devices().into_iter().nth(index) else {
+        return 0;
+    };
+
+    let mut data = vec![0; BLOCK_SECTOR_SIZE];
+    if !copy_from_user(&mut data, buffer) {
+        return 0;
+    }
+
+    match disk.write(sector as u64, &data).and_then(|_| disk.sync()) {
+        Ok(()) => 1,
+        Err(_) => 0
+    }
+}
+
+/// Wait for mouse events and copy up to `capacity` of them into the buffer.
+/// Returns the number of copied events (0, if there is no mouse).
+#[no_mangle]
+pub extern "C" fn sys_mouse_read(buffer: *mut MouseEvent, capacity: usize) -> usize {
+    let mouse = ps2_devices().mouse();
+    if capacity == 0 {
+        return 0;
+    }
+
+    let Some(first) = mouse.read_event() else {
+        return 0;
+    };
+
+    // Pass all pending events at once, so that applications do not fall behind fast mouse movements
+    let mut events = vec![first];
+    while events.len() < capacity {
+        match mouse.try_read_event() {
+            Some(event) => events.push(event),
+            None => break
+        }
+    }
+
+    if !copy_to_user(buffer, &events) {
+        return 0;
+    }
+
+    return events.len();
+}
+
+/// Wait for the next key press or release. Keys are taken from the same queue as the terminal input,
+/// so each key is either returned here or passed to the terminal, depending on which thread reads it first.
+#[no_mangle]
+pub extern "C" fn sys_keyboard_read(buffer: *mut KeyEvent) {
+    let (event, _) = ps2_devices().keyboard().read_event();
+    copy_to_user(buffer, &[event]);
+}
+
+#[no_mangle]
+pub extern "C" fn sys_keyboard_set_layout(layout: usize) -> usize {
+    let layout = match layout {
+        layout if layout == KeyboardLayout::Us as usize => KeyboardLayout::Us,
+        layout if layout == KeyboardLayout::German as usize => KeyboardLayout::German,
+        _ => return false as usize
+    };
+
+    ps2_devices().keyboard().set_layout(layout);
+    true as usize
+}
+
+/// Mount the file system on a disk (a block device or an image in the initrd, see `filesystem::find_disk()`) at `/<name>`
+#[no_mangle]
+pub extern "C" fn sys_mount(name_buffer: *const u8, name_length: usize) -> usize {
+    let Some(name) = copy_user_string(name_buffer, name_length) else {
+        return false as usize;
+    };
+
+    let Some(disk) = filesystem::find_disk(&name) else {
+        return false as usize;
+    };
+
+    match filesystem::open(disk).and_then(|file_system| filesystem::mount(&name, file_system)) {
+        Ok(()) => true as usize,
+        Err(error) => {
+            warn!("Failed to mount [{}] ({:?})", name, error);
+            false as usize
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unmount(name_buffer: *const u8, name_length: usize) -> usize {
+    match copy_user_string(name_buffer, name_length) {
+        Some(name) => filesystem::unmount(&name).is_ok() as usize,
+        None => false as usize
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_mount_list(buffer: *mut MountInfo, capacity: usize) -> usize {
+    let infos = filesystem::mounts().iter().map(|(name, file_system)| {
+        let mut info = MountInfo::default();
+        info.name_length = name.len().min(MOUNT_NAME_LENGTH);
+        info.name[..info.name_length].copy_from_slice(&name.as_bytes()[..info.name_length]);
+        info.file_system_length = file_system.len().min(FILE_SYSTEM_NAME_LENGTH);
+        info.file_system[..info.file_system_length].copy_from_slice(&file_system.as_bytes()[..info.file_system_length]);
+        info
+    }).collect::<Vec<MountInfo>>();
+    let count = infos.len().min(capacity);
+
+    if !copy_to_user(buffer, &infos[..count]) {
+        return 0;
+    }
+
+    return infos.len();
+}
+
 #[no_mangle]
 #[allow(improper_ctypes_definitions)] // 'entry' takes no arguments and has no return value, so we just assume that the "C" and "Rust" ABIs act the same way in this case
+// 'entry' is only passed on to the kickoff function, so it may also be a pointer to a closure (see 'concurrent::thread::spawn()')
 pub extern "C" fn sys_thread_create(kickoff_addr: u64, entry: fn()) -> usize {
     let thread = Thread::new_user_thread(process_manager().read().current_process(), VirtAddr::new(kickoff_addr), entry);
     let id = thread.id();
@@ -96,12 +432,18 @@ pub extern "C" fn sys_thread_exit() {
 }
 
 #[no_mangle]
-pub extern "C" fn sys_process_execute_binary(name_buffer: *const u8, name_length: usize) -> usize {
-    let app_name = from_utf8(unsafe { slice_from_raw_parts(name_buffer, name_length).as_ref().unwrap() }).unwrap();
-    match initrd().entries().find(|entry| entry.filename().as_str().unwrap() == app_name) {
-        Some(app) => {
-            let thread = Thread::load_application(app.data());
-            scheduler().ready(Rc::clone(&thread));
+pub extern "C" fn sys_process_execute_binary(name_buffer: *const u8, name_length: usize, priority: usize) -> usize {
+    let Some(app_name) = copy_user_string(name_buffer, name_length) else {
+        return 0;
+    };
+
+    match initrd().entries().find(|entry| entry.filename().as_str().unwrap() == app_name).and_then(|app| Thread::load_application(&app_name, app.data())) {
+        Some(thread) => {
+            if priority != usize::MAX { // usize::MAX -> Default priority
+                thread.set_priority(priority);
+            }
+
+            scheduler().ready(Arc::clone(&thread));
             thread.id()
         }
         None => 0
@@ -177,32 +519,63 @@ pub extern "C" fn sys_set_date(date_ms: usize) -> usize {
     return false as usize;
 }
 
-/// Wrapper for the kernel transmit function.
+/// Read the buffer, length and capacity of a vector in user space, which is passed by reference.
+/// Returns `None`, if the vector does not lie in user space, or is not aligned.
+fn user_vec_parts(vec: usize) -> Option<(*mut u8, usize, usize)> {
+    if !is_user_range(vec, size_of::<Vec<u8>>()) || vec % align_of::<Vec<u8>>() != 0 {
+        return None;
+    }
+
+    Some(with_user_access(|| {
+        let vec = unsafe { &mut *(vec as *mut Vec<u8>) };
+        (vec.as_mut_ptr(), vec.len(), vec.capacity())
+    }))
+}
+
+/// Wrapper for the transmit function of the first network device.
 /// only supports Ethernet protocol for now
 #[no_mangle]
 pub extern "C" fn sys_transmit_data(data: usize, protocol: usize) {
 //TODO: return a result so i can re-send the data if it fails
-    let data = unsafe {
-        &*(data as *const Vec<u8>)
+    let Some((buffer, length, _)) = user_vec_parts(data) else {
+        return;
+    };
+
+    let mut data = Vec::new();
+    if data.try_reserve_exact(length).is_err() {
+        return;
+    }
+
+    data.resize(length, 0);
+    if !copy_from_user(&mut data, buffer) {
+        return;
+    }
+
+    let Some(device) = device_manager().network_device() else {
+        return;
     };
-    let device = e1000_device();
-    let protocol = match protocol {
-        0 => NetworkProtocol::Ethernet,
+    match protocol {
+        0 => device.transmit(data),
         _ => panic!("Unsupported network protocol")
     };
-    transmit(data.clone(), protocol, &device);
 }
 
-/// Wrapper for the kernel receive function.
+/// Wrapper for the receive function of the first network device.
+/// The received data is appended to the vector, as far as its capacity allows (the kernel cannot grow it).
 pub fn sys_receive_data(data: usize) {
-    let data = unsafe {
-        &mut *(data as *mut Vec<u8>)
+    let Some((buffer, length, capacity)) = user_vec_parts(data) else {
+        return;
+    };
+    let Some(device) = device_manager().network_device() else {
+        return;
     };
-    let mut device = e1000_device();
-    let received_data = receive_data(&device);
+    let received_data = device.receive();
     match received_data {
         Some(rx_data) => {
-            data.extend_from_slice(&rx_data.data[..rx_data.length]);
+            let count = rx_data.len().min(capacity - length);
+            if copy_to_user(buffer.wrapping_add(length), &rx_data[..count]) {
+                with_user_access(|| unsafe { (*(data as *mut Vec<u8>)).set_len(length + count) });
+            }
         }
         None => {}
     }
@@ -210,8 +583,10 @@ pub fn sys_receive_data(data: usize) {
 
 //implies 64bit system
 pub fn sys_get_mac_address() -> usize{
-    let device = e1000_device();
-    let mac_address = device.mac_address;
+    let Some(device) = device_manager().network_device() else {
+        return 0;
+    };
+    let mac_address = device.mac_address();
     let mac_address_usize = mac_address[0] as usize
     | (mac_address[1] as usize) << 8
     | (mac_address[2] as usize) << 16
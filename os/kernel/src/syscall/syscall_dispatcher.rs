@@ -2,13 +2,16 @@ use core::arch::asm;
 use core::mem::size_of;
 use core::ops::Deref;
 use core::ptr;
+use x86_64::instructions::interrupts;
 use x86_64::registers::control::{Efer, EferFlags};
-use x86_64::registers::model_specific::{KernelGsBase, LStar, Star};
+use x86_64::registers::model_specific::{KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::gdt::SegmentSelector;
+use x86_64::structures::tss::TaskStateSegment;
 use x86_64::{PrivilegeLevel, VirtAddr};
 use syscall::NUM_SYSCALLS;
 use crate::{core_local_storage, tss};
-use crate::syscall::{sys_write, sys_thread_exit, sys_thread_sleep, sys_thread_switch, sys_process_id, sys_thread_id, sys_read, sys_map_user_heap, sys_thread_join, sys_process_execute_binary, sys_get_system_time, sys_get_date, sys_set_date, sys_thread_create, sys_process_exit, sys_receive_data, sys_transmit_data, sys_get_mac_address};
+use crate::syscall::{sys_write, sys_thread_exit, sys_thread_sleep, sys_thread_switch, sys_process_id, sys_thread_id, sys_read, sys_map_user_heap, sys_thread_join, sys_process_execute_binary, sys_get_system_time, sys_get_date, sys_set_date, sys_thread_create, sys_process_exit, sys_receive_data, sys_transmit_data, sys_get_mac_address, sys_process_fork, sys_shared_memory_map, sys_shared_memory_unmap, sys_thread_get_priority, sys_thread_set_priority, sys_process_info, sys_process_list, sys_futex_wait, sys_futex_wake, sys_message_queue_open, sys_message_queue_close, sys_message_queue_send, sys_message_queue_receive, sys_get_system_time_ns, sys_thread_sleep_until, sys_get_idle_time, sys_pci_list, sys_block_device_list, sys_block_device_read, sys_block_device_write, sys_mouse_read, sys_keyboard_read, sys_keyboard_set_layout, sys_mount, sys_unmount, sys_mount_list};
 
 pub const CORE_LOCAL_STORAGE_TSS_RSP0_PTR_INDEX: u64 = 0x00;
 pub const CORE_LOCAL_STORAGE_USER_RSP_INDEX: u64 = 0x08;
@@ -26,7 +29,61 @@ impl CoreLocalStorage {
     }
 }
 
+/// User registers, as they are stored on top of the kernel stack by 'syscall_handler()'
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SyscallFrame {
+    pub rbp: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64, // rflags
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rdx: u64,
+    pub rcx: u64, // rip
+    pub rbx: u64,
+    pub user_rsp: u64,
+}
+
 pub fn init() {
+    init_msrs();
+
+    // Initialize core local storage (accessible via 'swapgs')
+    let mut core_local_storage = core_local_storage().lock();
+    core_local_storage.tss_rsp0_ptr = VirtAddr::new(ptr::from_ref(tss().lock().deref()) as u64 + size_of::<u32>() as u64);
+    KernelGsBase::write(VirtAddr::new(ptr::from_ref(core_local_storage.deref()) as u64));
+}
+
+/// Initialize system calls on an application processor, which has its own TSS and core local storage
+pub fn init_application_processor(core_local_storage: &'static mut CoreLocalStorage, tss: &'static TaskStateSegment) {
+    init_msrs();
+
+    core_local_storage.tss_rsp0_ptr = VirtAddr::new(ptr::from_ref(tss) as u64 + size_of::<u32>() as u64);
+    KernelGsBase::write(VirtAddr::new(ptr::from_ref(core_local_storage) as u64));
+}
+
+/// Set the kernel stack (rsp0 entry of the TSS) of the calling processor via its core local storage
+pub fn set_kernel_stack(stack_end: VirtAddr) {
+    // Interrupts are disabled, so that no interrupt handler runs with the swapped gs base
+    interrupts::without_interrupts(|| unsafe {
+        asm!(
+        "swapgs", // Setup core local storage access via gs base
+        "mov rax, gs:[{CORE_LOCAL_STORAGE_TSS_RSP0_PTR_INDEX}]", // Load pointer to rsp0 entry of tss into rax
+        "mov [rax], {stack_end}", // Set rsp0 entry in tss
+        "swapgs", // Restore gs base
+        stack_end = in(reg) stack_end.as_u64(),
+        CORE_LOCAL_STORAGE_TSS_RSP0_PTR_INDEX = const CORE_LOCAL_STORAGE_TSS_RSP0_PTR_INDEX,
+        out("rax") _,
+        );
+    });
+}
+
+fn init_msrs() {
     // Enable system call extensions
     unsafe { Efer::update(|flags| flags.set(EferFlags::SYSTEM_CALL_EXTENSIONS, true)) }
 
@@ -43,10 +100,8 @@ pub fn init() {
     // Set rip for syscall
     LStar::write(VirtAddr::new(syscall_handler as u64));
 
-    // Initialize core local storage (accessible via 'swapgs')
-    let mut core_local_storage = core_local_storage().lock();
-    core_local_storage.tss_rsp0_ptr = VirtAddr::new(ptr::from_ref(tss().lock().deref()) as u64 + size_of::<u32>() as u64);
-    KernelGsBase::write(VirtAddr::new(ptr::from_ref(core_local_storage.deref()) as u64));
+    // Clear alignment check flag on syscall, so that user space cannot disable SMAP for the kernel
+    SFMask::write(RFlags::ALIGNMENT_CHECK);
 }
 
 #[no_mangle]
@@ -79,7 +134,33 @@ impl SyscallTable {
                 sys_set_date as *const _,
                 sys_transmit_data as *const _,
                 sys_receive_data as *const _,
-                sys_get_mac_address as *const _
+                sys_get_mac_address as *const _,
+                sys_process_fork as *const _,
+                sys_shared_memory_map as *const _,
+                sys_shared_memory_unmap as *const _,
+                sys_thread_get_priority as *const _,
+                sys_thread_set_priority as *const _,
+                sys_process_info as *const _,
+                sys_process_list as *const _,
+                sys_futex_wait as *const _,
+                sys_futex_wake as *const _,
+                sys_message_queue_open as *const _,
+                sys_message_queue_close as *const _,
+                sys_message_queue_send as *const _,
+                sys_message_queue_receive as *const _,
+                sys_get_system_time_ns as *const _,
+                sys_thread_sleep_until as *const _,
+                sys_get_idle_time as *const _,
+                sys_pci_list as *const _,
+                sys_block_device_list as *const _,
+                sys_block_device_read as *const _,
+                sys_block_device_write as *const _,
+                sys_mouse_read as *const _,
+                sys_keyboard_read as *const _,
+                sys_keyboard_set_layout as *const _,
+                sys_mount as *const _,
+                sys_unmount as *const _,
+                sys_mount_list as *const _
             ],
         }
     }
@@ -122,6 +203,7 @@ unsafe extern "C" fn syscall_handler() {
     "push r13",
     "push r14",
     "push r15",
+    "push rbp", // Not touched here, but needed to duplicate the user context in 'sys_process_fork()'
 
     // Enable interrupts (we are now on the kernel stack and can handle them properly)
     "sti",
@@ -134,6 +216,7 @@ unsafe extern "C" fn syscall_handler() {
     "call syscall_disp",
 
     // Restore registers
+    "pop rbp",
     "pop r15",
     "pop r14",
     "pop r13",
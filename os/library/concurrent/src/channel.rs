@@ -0,0 +1,137 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use crate::sync::{Condvar, Mutex};
+
+/// Error returned by `Sender::send()`, if the receiver has been dropped. Contains the message, that could not be sent.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Error returned by `Receiver::recv()`, if the channel is empty and all senders have been dropped.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected
+}
+
+struct State<T> {
+    messages: VecDeque<T>,
+    capacity: Option<usize>, // None for unbounded channels
+    senders: usize,
+    receiver: bool
+}
+
+struct Channel<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar
+}
+
+/// Sending half of a channel. It can be cloned to send messages from several threads.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>
+}
+
+/// Receiving half of a channel.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>
+}
+
+fn create<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        state: Mutex::new(State { messages: VecDeque::new(), capacity, senders: 1, receiver: true }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new()
+    });
+
+    (Sender { channel: Arc::clone(&channel) }, Receiver { channel })
+}
+
+/// Create an unbounded channel for sending messages between threads of a process. Sending never blocks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    create(None)
+}
+
+/// Create a bounded channel, holding at most `capacity` messages. Sending blocks, while the channel is full.
+pub fn sync_channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "Channel: Capacity must not be zero!");
+    create(Some(capacity))
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let channel = &self.channel;
+        let mut state = channel.not_full.wait_while(channel.state.lock(), |state| {
+            state.receiver && state.capacity.is_some_and(|capacity| state.messages.len() >= capacity)
+        });
+
+        if !state.receiver {
+            return Err(SendError(message));
+        }
+
+        state.messages.push_back(message);
+        channel.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.lock().senders += 1;
+        Self { channel: Arc::clone(&self.channel) }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            // Wake up the receiver, so that it notices the disconnect
+            self.channel.not_empty.notify_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Wait for a message. Fails, if the channel is empty and all senders have been dropped.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let channel = &self.channel;
+        let mut state = channel.not_empty.wait_while(channel.state.lock(), |state| state.messages.is_empty() && state.senders > 0);
+
+        match state.messages.pop_front() {
+            Some(message) => {
+                channel.not_full.notify_one();
+                Ok(message)
+            }
+            None => Err(RecvError)
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.channel.state.lock();
+        match state.messages.pop_front() {
+            Some(message) => {
+                self.channel.not_full.notify_one();
+                Ok(message)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Iterate over received messages, until all senders have been dropped.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        core::iter::from_fn(|| self.recv().ok())
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.state.lock().receiver = false;
+        // Wake up blocked senders, so that they notice the disconnect
+        self.channel.not_full.notify_all();
+    }
+}
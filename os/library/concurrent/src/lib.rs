@@ -2,5 +2,9 @@
 
 extern crate alloc;
 
+pub mod channel;
+pub mod message_queue;
 pub mod process;
+pub mod shared_memory;
+pub mod sync;
 pub mod thread;
\ No newline at end of file
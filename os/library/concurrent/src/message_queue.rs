@@ -0,0 +1,53 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use syscall::{syscall1, syscall3, SystemCall};
+
+pub use syscall::MESSAGE_QUEUE_MESSAGE_SIZE;
+
+/// A named message queue in the kernel, which can be opened by several processes to exchange messages.
+/// The queue is removed, when the last process has closed it (or exited).
+pub struct MessageQueue {
+    handle: usize
+}
+
+impl MessageQueue {
+    /// Open the message queue called `name`. If it does not exist yet, it is created with room for `capacity` messages.
+    /// Returns `None`, if the queue does not exist and `capacity` is zero.
+    pub fn open(name: &str, capacity: usize) -> Option<Self> {
+        match syscall3(SystemCall::MessageQueueOpen, name.as_bytes().as_ptr() as usize, name.len(), capacity) {
+            usize::MAX => None,
+            handle => Some(Self { handle })
+        }
+    }
+
+    /// Send a message of at most `MESSAGE_QUEUE_MESSAGE_SIZE` bytes, blocking while the queue is full.
+    /// Returns false, if the message is too large.
+    pub fn send(&self, message: &[u8]) -> bool {
+        syscall3(SystemCall::MessageQueueSend, self.handle, message.as_ptr() as usize, message.len()) != 0
+    }
+
+    /// Receive a message into `buffer`, blocking while the queue is empty, and return its length.
+    /// Messages larger than the buffer are truncated. Returns `None`, if the queue is not opened by this process.
+    pub fn receive_into(&self, buffer: &mut [u8]) -> Option<usize> {
+        match syscall3(SystemCall::MessageQueueReceive, self.handle, buffer.as_mut_ptr() as usize, buffer.len()) {
+            usize::MAX => None,
+            length => Some(length)
+        }
+    }
+
+    /// Receive a message, blocking while the queue is empty.
+    /// Returns `None`, if the queue is not opened by this process.
+    pub fn receive(&self) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; MESSAGE_QUEUE_MESSAGE_SIZE];
+        let length = self.receive_into(&mut buffer)?;
+        buffer.truncate(length);
+
+        Some(buffer)
+    }
+}
+
+impl Drop for MessageQueue {
+    fn drop(&mut self) {
+        syscall1(SystemCall::MessageQueueClose, self.handle);
+    }
+}
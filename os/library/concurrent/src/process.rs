@@ -1,4 +1,7 @@
-use syscall::{syscall0, SystemCall};
+use alloc::vec::Vec;
+use syscall::{syscall0, syscall2, SystemCall};
+
+pub use syscall::{ProcessInfo, ProcessState};
 
 pub struct Process {
     id: usize
@@ -19,6 +22,31 @@ pub fn current() -> Process {
     Process::new(id)
 }
 
+/// Create a copy of the calling process, which continues execution after this call.
+/// Returns the new process in the parent and `None` in the child.
+pub fn fork() -> Option<Process> {
+    match syscall0(SystemCall::ProcessFork) {
+        0 => None,
+        id => Some(Process::new(id))
+    }
+}
+
+/// Get information (parent, name, threads, memory usage) about all processes in the system.
+pub fn infos() -> Vec<ProcessInfo> {
+    let mut infos = Vec::<ProcessInfo>::new();
+
+    loop {
+        // Processes may be created between two calls, so we retry until the buffer is large enough
+        let count = syscall2(SystemCall::ProcessList, infos.as_mut_ptr() as usize, infos.capacity());
+        if count <= infos.capacity() {
+            unsafe { infos.set_len(count); }
+            return infos;
+        }
+
+        infos.reserve(count + 8);
+    }
+}
+
 pub fn exit() {
     syscall0(SystemCall::ProcessExit);
 }
\ No newline at end of file
@@ -0,0 +1,45 @@
+use core::slice;
+use syscall::{syscall1, syscall3, SystemCall};
+
+/// A named block of memory, which is mapped into the address space of every process that opens it.
+/// The memory is released, when the last process has unmapped it (or exited).
+pub struct SharedMemory {
+    address: usize,
+    size: usize
+}
+
+impl SharedMemory {
+    /// Open the shared memory object called `name` and map it into the calling process.
+    /// If the object does not exist yet, it is created with `size` bytes (initialized to zero).
+    /// Returns `None`, if an existing object is smaller than `size`.
+    pub fn open(name: &str, size: usize) -> Option<Self> {
+        match syscall3(SystemCall::SharedMemoryMap, name.as_bytes().as_ptr() as usize, name.len(), size) {
+            0 => None,
+            address => Some(Self { address, size })
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.address as *mut u8
+    }
+
+    /// Other processes may write to the memory at any time, so the caller needs to synchronize the access.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    /// Other processes may access the memory at any time, so the caller needs to synchronize the access.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        syscall1(SystemCall::SharedMemoryUnmap, self.address);
+    }
+}
@@ -0,0 +1,34 @@
+use crate::sync::condvar::Condvar;
+use crate::sync::mutex::Mutex;
+
+/// Synchronization point for a fixed number of threads. Each thread calling `wait()` is blocked,
+/// until all threads have reached the barrier. The barrier can be reused afterwards.
+pub struct Barrier {
+    count: usize,
+    state: Mutex<(usize, usize)>, // (number of waiting threads, generation)
+    condvar: Condvar
+}
+
+impl Barrier {
+    pub const fn new(count: usize) -> Self {
+        Self { count, state: Mutex::new((0, 0)), condvar: Condvar::new() }
+    }
+
+    /// Block until `count` threads have called `wait()`.
+    /// Returns true for exactly one of these threads (the last one to arrive).
+    pub fn wait(&self) -> bool {
+        let mut state = self.state.lock();
+        let generation = state.1;
+
+        state.0 += 1;
+        if state.0 >= self.count {
+            state.0 = 0;
+            state.1 = state.1.wrapping_add(1);
+            self.condvar.notify_all();
+            return true;
+        }
+
+        let _state = self.condvar.wait_while(state, |state| state.1 == generation);
+        false
+    }
+}
@@ -0,0 +1,46 @@
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering::Relaxed;
+use crate::sync::futex;
+use crate::sync::mutex::MutexGuard;
+
+/// Condition variable for use with `Mutex`.
+pub struct Condvar {
+    sequence: AtomicU32 // incremented on each notification
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self { sequence: AtomicU32::new(0) }
+    }
+
+    /// Release the mutex belonging to `guard` and block the calling thread, until it is notified.
+    /// The mutex is acquired again before returning. Spurious wakeups are possible.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let sequence = self.sequence.load(Relaxed);
+        let mutex = guard.mutex();
+        drop(guard);
+
+        // Returns immediately, if a notification has happened after the mutex has been released
+        futex::wait(&self.sequence, sequence);
+        mutex.lock()
+    }
+
+    /// Block the calling thread, as long as `condition` returns true for the data protected by the mutex.
+    pub fn wait_while<'a, T: ?Sized>(&self, mut guard: MutexGuard<'a, T>, mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Relaxed);
+        futex::wake(&self.sequence, 1);
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Relaxed);
+        futex::wake_all(&self.sequence);
+    }
+}
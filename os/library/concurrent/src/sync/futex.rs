@@ -0,0 +1,17 @@
+use core::sync::atomic::AtomicU32;
+use syscall::{syscall2, SystemCall};
+
+/// Block the calling thread, if `value` equals `expected`, until another thread calls `wake()` on it.
+/// Returns false, if the value did not match. Spurious wakeups are possible, so the value must be checked again.
+pub fn wait(value: &AtomicU32, expected: u32) -> bool {
+    syscall2(SystemCall::FutexWait, value.as_ptr() as usize, expected as usize) != 0
+}
+
+/// Wake up to `count` threads blocked on `value` and return how many have been woken up.
+pub fn wake(value: &AtomicU32, count: usize) -> usize {
+    syscall2(SystemCall::FutexWake, value.as_ptr() as usize, count)
+}
+
+pub fn wake_all(value: &AtomicU32) -> usize {
+    wake(value, usize::MAX)
+}
@@ -0,0 +1,10 @@
+pub mod futex;
+mod mutex;
+mod condvar;
+mod rwlock;
+mod barrier;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use condvar::Condvar;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use barrier::Barrier;
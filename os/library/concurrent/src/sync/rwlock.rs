@@ -0,0 +1,127 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::sync::futex;
+
+const WRITE_LOCKED: u32 = u32::MAX;
+const MAX_READERS: u32 = u32::MAX - 1;
+
+/// Reader-writer lock, allowing either multiple readers or a single writer at a time.
+/// Threads waiting for the lock are blocked in the kernel.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicU32, // number of readers or WRITE_LOCKED
+    waiters: AtomicU32, // number of threads waiting for the lock
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self { state: AtomicU32::new(0), waiters: AtomicU32::new(0), data: UnsafeCell::new(data) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            let state = self.state.load(Relaxed);
+            if state < MAX_READERS {
+                if self.state.compare_exchange_weak(state, state + 1, Acquire, Relaxed).is_ok() {
+                    return RwLockReadGuard { lock: self };
+                }
+            } else {
+                self.wait(state);
+            }
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            match self.state.compare_exchange(0, WRITE_LOCKED, Acquire, Relaxed) {
+                Ok(_) => return RwLockWriteGuard { lock: self },
+                Err(state) => self.wait(state)
+            }
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let state = self.state.load(Relaxed);
+        match state < MAX_READERS && self.state.compare_exchange(state, state + 1, Acquire, Relaxed).is_ok() {
+            true => Some(RwLockReadGuard { lock: self }),
+            false => None
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        match self.state.compare_exchange(0, WRITE_LOCKED, Acquire, Relaxed) {
+            Ok(_) => Some(RwLockWriteGuard { lock: self }),
+            Err(_) => None
+        }
+    }
+
+    // Block until the state has changed (returns immediately, if it already has)
+    fn wait(&self, state: u32) {
+        self.waiters.fetch_add(1, Relaxed);
+        futex::wait(&self.state, state);
+        self.waiters.fetch_sub(1, Relaxed);
+    }
+
+    fn wake(&self) {
+        if self.waiters.load(Relaxed) > 0 {
+            futex::wake_all(&self.state);
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // The last reader wakes up waiting writers
+        if self.lock.state.fetch_sub(1, Release) == 1 {
+            self.lock.wake();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Release);
+        self.lock.wake();
+    }
+}
@@ -1,4 +1,10 @@
-use syscall::{syscall0, syscall1, syscall2, SystemCall};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use syscall::{syscall0, syscall1, syscall2, syscall3, SystemCall};
+use crate::sync::Mutex;
+
+pub use syscall::{ThreadInfo, ThreadState};
 
 pub struct Thread {
     id: usize
@@ -18,16 +24,58 @@ impl Thread {
     }
 }
 
+/// Handle for a thread created via `spawn()`, which is used to get the return value of the thread.
+pub struct JoinHandle<T> {
+    thread: Thread,
+    result: Arc<Mutex<Option<T>>>
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    /// Wait for the thread to finish and return the value returned by its closure.
+    pub fn join(self) -> T {
+        self.thread.join();
+        self.result.lock().take().expect("Thread: Missing return value (has the thread been killed?)")
+    }
+}
+
 fn kickoff_user_thread(entry: fn()) {
     entry();
     exit();
 }
 
+fn kickoff_closure_thread(main: *mut Box<dyn FnOnce()>) {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit();
+}
+
 pub fn create(entry: fn()) -> Thread {
     let id = syscall2(SystemCall::ThreadCreate, kickoff_user_thread as usize, entry as usize);
     Thread::new(id)
 }
 
+/// Create a thread running the closure `main`. In contrast to `create()`, the closure may capture state
+/// and its return value can be retrieved via `JoinHandle::join()`.
+pub fn spawn<F, T>(main: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static
+{
+    let result = Arc::new(Mutex::new(None));
+    let thread_result = Arc::clone(&result);
+
+    // The closure is boxed twice, since a pointer to a trait object is too wide to be passed through the system call
+    let main: Box<dyn FnOnce()> = Box::new(move || *thread_result.lock() = Some(main()));
+    let main = Box::into_raw(Box::new(main));
+
+    let id = syscall2(SystemCall::ThreadCreate, kickoff_closure_thread as *const () as usize, main as usize);
+    JoinHandle { thread: Thread::new(id), result }
+}
+
 pub fn current() -> Thread {
     let id = syscall0(SystemCall::ThreadId);
     Thread::new(id)
@@ -48,8 +96,42 @@ pub fn exit() -> ! {
     panic!("System call 'ThreadExit' has returned!")
 }
 
+/// Get the priority of a thread (0 = highest).
+pub fn priority(thread_id: usize) -> Option<usize> {
+    match syscall1(SystemCall::ThreadGetPriority, thread_id) {
+        usize::MAX => None,
+        priority => Some(priority)
+    }
+}
+
+/// Set the priority of a thread (0 = highest). Returns false, if the thread does not exist.
+pub fn set_priority(thread_id: usize, priority: usize) -> bool {
+    syscall2(SystemCall::ThreadSetPriority, thread_id, priority) != 0
+}
+
+/// Get scheduling information (state, priority, cpu time) about all threads in the system.
+pub fn infos() -> Vec<ThreadInfo> {
+    let mut infos = Vec::<ThreadInfo>::new();
+
+    loop {
+        // Threads may be created between two calls, so we retry until the buffer is large enough
+        let count = syscall2(SystemCall::ProcessInfo, infos.as_mut_ptr() as usize, infos.capacity());
+        if count <= infos.capacity() {
+            unsafe { infos.set_len(count); }
+            return infos;
+        }
+
+        infos.reserve(count + 8);
+    }
+}
+
 pub fn start_application(name: &str) -> Option<Thread> {
-    match syscall2(SystemCall::ProcessExecuteBinary, name.as_bytes().as_ptr() as usize, name.len()) {
+    start_application_with_priority(name, usize::MAX) // usize::MAX -> Default priority
+}
+
+/// Start an application, whose main thread runs with the given priority (0 = highest) from the beginning.
+pub fn start_application_with_priority(name: &str, priority: usize) -> Option<Thread> {
+    match syscall3(SystemCall::ProcessExecuteBinary, name.as_bytes().as_ptr() as usize, name.len(), priority) {
         0 => None,
         id => Some(Thread::new(id))
     }
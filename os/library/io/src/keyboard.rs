@@ -0,0 +1,16 @@
+use core::mem::MaybeUninit;
+use syscall::{syscall1, SystemCall};
+
+pub use syscall::{KeyCode, KeyEvent, KeyboardLayout, KEY_MODIFIER_ALT, KEY_MODIFIER_ALT_GR, KEY_MODIFIER_CAPS_LOCK, KEY_MODIFIER_CTRL, KEY_MODIFIER_NUM_LOCK, KEY_MODIFIER_SHIFT};
+
+/// Wait for the next key press or release (unlike `read::read()`, this also reports special keys, e.g. arrow keys)
+pub fn read_key() -> KeyEvent {
+    let mut event = MaybeUninit::<KeyEvent>::uninit();
+    syscall1(SystemCall::KeyboardRead, event.as_mut_ptr() as usize);
+
+    unsafe { event.assume_init() }
+}
+
+pub fn set_layout(layout: KeyboardLayout) -> bool {
+    syscall1(SystemCall::KeyboardSetLayout, layout as usize) != 0
+}
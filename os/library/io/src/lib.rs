@@ -1,4 +1,6 @@
 #![no_std]
 
 pub mod write;
-pub mod read;
\ No newline at end of file
+pub mod read;
+pub mod mouse;
+pub mod keyboard;
@@ -0,0 +1,9 @@
+use syscall::{syscall2, SystemCall};
+
+pub use syscall::{MouseEvent, MOUSE_BUTTON_4, MOUSE_BUTTON_5, MOUSE_BUTTON_LEFT, MOUSE_BUTTON_MIDDLE, MOUSE_BUTTON_RIGHT};
+
+/// Wait for mouse events and store them in `events`.
+/// Returns the number of received events (0, if there is no mouse).
+pub fn read_events(events: &mut [MouseEvent]) -> usize {
+    syscall2(SystemCall::MouseRead, events.as_mut_ptr() as usize, events.len())
+}
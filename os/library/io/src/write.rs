@@ -1,6 +1,6 @@
 use core::fmt;
 use core::fmt::Write;
-use spin::Mutex;
+use concurrent::sync::Mutex;
 use syscall::{syscall2, SystemCall};
 
 #[macro_export]
@@ -1,7 +1,7 @@
 #![no_std]
 
 use core::arch::asm;
-use crate::SystemCall::GetMacAddress;
+use crate::SystemCall::MountList;
 
 #[repr(usize)]
 #[allow(dead_code)]
@@ -23,10 +23,225 @@ pub enum SystemCall {
     SetDate,
     TransmitData,
     ReceiveData,
-    GetMacAddress
+    GetMacAddress,
+    ProcessFork,
+    SharedMemoryMap,
+    SharedMemoryUnmap,
+    ThreadGetPriority,
+    ThreadSetPriority,
+    ProcessInfo,
+    ProcessList,
+    FutexWait,
+    FutexWake,
+    MessageQueueOpen,
+    MessageQueueClose,
+    MessageQueueSend,
+    MessageQueueReceive,
+    GetSystemTimeNs,
+    ThreadSleepUntil,
+    GetIdleTime,
+    PciList,
+    BlockDeviceList,
+    BlockDeviceRead,
+    BlockDeviceWrite,
+    MouseRead,
+    KeyboardRead,
+    KeyboardSetLayout,
+    Mount,
+    Unmount,
+    MountList
 }
 
-pub const NUM_SYSCALLS: usize = GetMacAddress as usize + 1;
+pub const NUM_SYSCALLS: usize = MountList as usize + 1;
+
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ThreadState {
+    #[default]
+    Ready = 0,
+    Running,
+    Sleeping,
+    Blocked,
+    Joining
+}
+
+/// Scheduling information about a thread, as returned by the `ProcessInfo` system call
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ThreadInfo {
+    pub thread_id: usize,
+    pub process_id: usize,
+    pub state: ThreadState,
+    pub priority: usize,
+    pub runtime_ms: usize,
+    pub context_switches: usize
+}
+
+pub const PROCESS_NAME_LENGTH: usize = 32;
+
+// maximum size of a message sent via a message queue
+pub const MESSAGE_QUEUE_MESSAGE_SIZE: usize = 4096;
+
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ProcessState {
+    #[default]
+    Running = 0,
+    Exited // waiting to be cleaned up
+}
+
+/// Information about a process, as returned by the `ProcessList` system call
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ProcessInfo {
+    pub process_id: usize,
+    pub parent_id: usize,
+    pub name: [u8; PROCESS_NAME_LENGTH], // name of the launched binary (truncated, if too long)
+    pub name_length: usize,
+    pub thread_count: usize,
+    pub memory_usage: usize, // size of all memory areas in bytes
+    pub state: ProcessState
+}
+
+pub const PCI_MAX_CAPABILITIES: usize = 16;
+
+/// Information about a PCI function, as returned by the `PciList` system call
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PciDeviceInfo {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub interface: u8,
+    pub revision: u8,
+    pub capabilities: [u8; PCI_MAX_CAPABILITIES], // ids of the standard capabilities (truncated, if there are more)
+    pub capability_count: usize,
+    pub extended_capabilities: [u16; PCI_MAX_CAPABILITIES], // ids of the extended (PCI Express) capabilities
+    pub extended_capability_count: usize
+}
+
+// size of a sector, as read and written by the `BlockDeviceRead` and `BlockDeviceWrite` system calls
+pub const BLOCK_SECTOR_SIZE: usize = 512;
+pub const BLOCK_DEVICE_NAME_LENGTH: usize = 16;
+
+/// Information about a disk, as returned by the `BlockDeviceList` system call.
+/// Disks are addressed by their index in the returned list.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BlockDeviceInfo {
+    pub name: [u8; BLOCK_DEVICE_NAME_LENGTH], // e.g. "vd0" or "ahci0.1"
+    pub name_length: usize,
+    pub sector_count: u64,
+    pub read_only: bool
+}
+
+// buttons in `MouseEvent::buttons`
+pub const MOUSE_BUTTON_LEFT: u8 = 0x01;
+pub const MOUSE_BUTTON_RIGHT: u8 = 0x02;
+pub const MOUSE_BUTTON_MIDDLE: u8 = 0x04;
+pub const MOUSE_BUTTON_4: u8 = 0x08;
+pub const MOUSE_BUTTON_5: u8 = 0x10;
+
+/// Relative movement of the mouse and state of its buttons, as returned by the `MouseRead` system call
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16, // positive values move to the right
+    pub dy: i16, // positive values move down (like screen coordinates)
+    pub wheel: i8, // positive values scroll down (0, if the mouse has no scroll wheel)
+    pub buttons: u8 // currently pressed buttons (see `MOUSE_BUTTON_*`)
+}
+
+/// Physical keys, named after their labels on a US keyboard (independent of the selected layout).
+/// The `Oem*` keys are punctuation keys, whose labels differ between layouts.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, PrintScreen, SysRq, ScrollLock, PauseBreak,
+    Oem8, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, OemMinus, OemPlus, Backspace,
+    Insert, Home, PageUp, NumpadLock, NumpadDivide, NumpadMultiply, NumpadSubtract,
+    Tab, Q, W, E, R, T, Y, U, I, O, P, Oem4, Oem6, Oem5, Oem7, Delete, End, PageDown, Numpad7, Numpad8, Numpad9, NumpadAdd,
+    CapsLock, A, S, D, F, G, H, J, K, L, Oem1, Oem3, Return, Numpad4, Numpad5, Numpad6,
+    LShift, Z, X, C, V, B, N, M, OemComma, OemPeriod, Oem2, RShift, ArrowUp, Numpad1, Numpad2, Numpad3, NumpadEnter,
+    LControl, LWin, LAlt, Spacebar, RAltGr, RWin, Apps, RControl, ArrowLeft, ArrowDown, ArrowRight, Numpad0, NumpadPeriod,
+    Oem9, Oem10, Oem11, Oem12, Oem13,
+    PrevTrack, NextTrack, Mute, Calculator, Play, Stop, VolumeDown, VolumeUp, WWWHome
+}
+
+// modifiers in `KeyEvent::modifiers`
+pub const KEY_MODIFIER_SHIFT: u8 = 0x01;
+pub const KEY_MODIFIER_CTRL: u8 = 0x02;
+pub const KEY_MODIFIER_ALT: u8 = 0x04;
+pub const KEY_MODIFIER_ALT_GR: u8 = 0x08;
+pub const KEY_MODIFIER_CAPS_LOCK: u8 = 0x10;
+pub const KEY_MODIFIER_NUM_LOCK: u8 = 0x20;
+
+/// Press or release of a key, as returned by the `KeyboardRead` system call
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool, // false, if the key has been released
+    pub modifiers: u8, // modifiers, that are active while the key is pressed or released (see `KEY_MODIFIER_*`)
+    pub unicode: u32 // character for the key in the selected layout (0 for releases and keys without a character, e.g. arrow keys)
+}
+
+/// Keyboard layouts, as set by the `KeyboardSetLayout` system call
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    Us = 0,
+    German
+}
+
+pub const MOUNT_NAME_LENGTH: usize = 32;
+pub const FILE_SYSTEM_NAME_LENGTH: usize = 8;
+
+/// Information about a mounted file system, as returned by the `MountList` system call
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MountInfo {
+    pub name: [u8; MOUNT_NAME_LENGTH], // the file system is accessible below '/<name>' (truncated, if too long)
+    pub name_length: usize,
+    pub file_system: [u8; FILE_SYSTEM_NAME_LENGTH], // type of the file system (e.g. "fat32")
+    pub file_system_length: usize
+}
+
+impl ProcessInfo {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length]).unwrap_or("?")
+    }
+}
+
+impl KeyEvent {
+    pub fn char(&self) -> Option<char> {
+        match self.unicode {
+            0 => None,
+            unicode => char::from_u32(unicode)
+        }
+    }
+}
+
+impl BlockDeviceInfo {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length]).unwrap_or("?")
+    }
+}
+
+impl MountInfo {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length]).unwrap_or("?")
+    }
+
+    pub fn file_system(&self) -> &str {
+        core::str::from_utf8(&self.file_system[..self.file_system_length]).unwrap_or("?")
+    }
+}
 
 #[inline(always)]
 pub fn syscall0(call: SystemCall) -> usize {
@@ -1,11 +1,35 @@
 #![no_std]
 
+extern crate alloc;
+
+pub mod timer;
+
 use chrono::{DateTime, TimeDelta, Utc};
 use syscall::{syscall0, syscall1, SystemCall};
 
+/// Time since boot with nanosecond resolution.
 pub fn systime() -> TimeDelta {
-    let systime = syscall0(SystemCall::GetSystemTime);
-    TimeDelta::try_milliseconds(systime as i64).expect("Failed to create TimeDelta struct from systime")
+    let systime = syscall0(SystemCall::GetSystemTimeNs);
+    TimeDelta::nanoseconds(systime as i64)
+}
+
+/// Time, that the cpu has spent idle (halted) since boot.
+pub fn idle_time() -> TimeDelta {
+    let idle_time = syscall0(SystemCall::GetIdleTime);
+    TimeDelta::nanoseconds(idle_time as i64)
+}
+
+/// Block the calling thread for (at least) `duration`.
+pub fn sleep(duration: TimeDelta) {
+    sleep_until(systime() + duration);
+}
+
+/// Block the calling thread, until `systime()` has reached `deadline`.
+pub fn sleep_until(deadline: TimeDelta) {
+    let deadline_ns = deadline.num_nanoseconds().expect("Deadline is too far in the future");
+    if deadline_ns > 0 {
+        syscall1(SystemCall::ThreadSleepUntil, deadline_ns as usize);
+    }
 }
 
 pub fn date() -> DateTime<Utc> {
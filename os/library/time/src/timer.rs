@@ -0,0 +1,66 @@
+use alloc::sync::Arc;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::Relaxed;
+use chrono::TimeDelta;
+use concurrent::thread;
+use concurrent::thread::JoinHandle;
+use crate::{sleep_until, systime};
+
+/// One-shot or periodic timer, which runs a callback in its own thread.
+/// Dropping the timer does not cancel it; use `cancel()` for that.
+pub struct Timer {
+    cancelled: Arc<AtomicBool>,
+    handle: JoinHandle<()>
+}
+
+impl Timer {
+    /// Run `callback` once, after `delay` has passed.
+    pub fn once(delay: TimeDelta, callback: impl FnOnce() + Send + 'static) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let thread_cancelled = Arc::clone(&cancelled);
+        let deadline = systime() + delay;
+
+        let handle = thread::spawn(move || {
+            sleep_until(deadline);
+            if !thread_cancelled.load(Relaxed) {
+                callback();
+            }
+        });
+
+        Self { cancelled, handle }
+    }
+
+    /// Run `callback` every `interval`, until the timer is cancelled.
+    /// Deadlines are computed from the start time, so that delays in the callback do not accumulate.
+    pub fn periodic(interval: TimeDelta, mut callback: impl FnMut() + Send + 'static) -> Self {
+        assert!(interval > TimeDelta::zero(), "Timer: Interval must be positive!");
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let thread_cancelled = Arc::clone(&cancelled);
+        let mut deadline = systime() + interval;
+
+        let handle = thread::spawn(move || {
+            loop {
+                sleep_until(deadline);
+                if thread_cancelled.load(Relaxed) {
+                    break;
+                }
+
+                callback();
+                deadline += interval;
+            }
+        });
+
+        Self { cancelled, handle }
+    }
+
+    /// Prevent further executions of the callback. An execution, that is already running, is not interrupted.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Relaxed);
+    }
+
+    /// Wait for the timer thread to finish (i.e. until a one-shot timer has fired or a cancelled timer has stopped).
+    pub fn join(self) {
+        self.handle.join();
+    }
+}